@@ -1,19 +1,41 @@
 use std::env;
-use crate::db;
+use crate::calendar;
+use crate::db::{self, AuditFilter, Storage};
 use crate::format;
 use crate::message_handler;
 use crate::message_handler::CallbackQuery;
 use crate::reply::*;
-use crate::types::{Configuration, Context, Event, MessageType, User};
-use anyhow::anyhow;
-use chrono::DateTime;
-use r2d2::PooledConnection;
-use r2d2_sqlite::SqliteConnectionManager;
+use crate::types::{Configuration, Context, Event, EventState, EventType, MessageType, Series, TicketType, User, Venue};
+use crate::util::get_unix_time;
+use anyhow::{anyhow, Context as _};
+use chrono::{DateTime, NaiveDateTime, TimeZone};
+use chrono_tz::Tz;
+use url::Url;
 use teloxide::{
-    types::{InlineKeyboardButton, ParseMode},
-    utils::markdown,
+    types::{InlineKeyboardButton, ParseMode, PhotoSize},
+    utils::{html, markdown},
 };
 
+/// Ticket type ids go into callback data, which Telegram limits to 64 bytes.
+const MAX_TICKET_TYPES: usize = 8;
+/// Questionnaires are answered one message at a time, keep them short.
+const MAX_QUESTIONS: usize = 10;
+/// The duplicate button schedules the copy a week after the original, at the same local time.
+const DUPLICATE_SHIFT_DAYS: i64 = 7;
+/// Series repeat at least once a year, in days.
+const MAX_SERIES_PERIOD: u64 = 366;
+/// Imported files are previewed in one message.
+const MAX_IMPORTED_EVENTS: usize = 50;
+
+#[derive(Deserialize, Serialize, Clone, Default, Debug)]
+struct NewTicketType {
+    name: String,
+    capacity: u64,
+    max_per_reservation: u64,
+    /// In the event currency.
+    price: Option<f64>,
+}
+
 #[derive(Deserialize, Serialize, Clone, Default, Debug)]
 struct NewEvent {
     id: Option<u64>,
@@ -21,24 +43,91 @@ struct NewEvent {
     link: String,
     start: String,
     remind: String,
+    tickets: Option<Vec<NewTicketType>>,
+    /// Adults and children, used when `tickets` are not given.
+    #[serde(default)]
     max_adults: u64,
+    #[serde(default)]
     max_children: u64,
+    #[serde(default)]
     max_adults_per_reservation: u64,
+    #[serde(default)]
     max_children_per_reservation: u64,
     adult_ticket_price: Option<f64>,
     child_ticket_price: Option<f64>,
     currency: String,
+    /// Makes a series: "daily", "weekly", "every <n> days" or "every <n> weeks".
+    repeat: Option<String>,
+    /// No occurrences of the series start after it.
+    until: Option<String>,
+    /// Edits the whole series.
+    series_id: Option<u64>,
+    /// Venue id, see /venues.
+    venue: Option<u64>,
+    /// Either the end or the duration in minutes.
+    end: Option<String>,
+    duration: Option<u64>,
+    allow_overlap: Option<bool>,
+    /// Category names, new ones are created.
+    categories: Option<Vec<String>>,
+    /// Registration window, open from publication until the start if not given.
+    registration_opens: Option<String>,
+    registration_closes: Option<String>,
+    /// Asked one by one after booking.
+    questions: Option<Vec<String>>,
+    /// IANA name, e.g. "Europe/Vienna". Times without an offset are taken in it.
+    time_zone: Option<String>,
+    /// Confirm the waiting list automatically when places free up.
+    auto_promote: Option<bool>,
+}
+
+/// Row of an imported CSV file. Columns are named after `NewEvent` fields, lists are
+/// separated by ';'. Reminders are sent a day before the start unless `remind` is given.
+#[derive(Deserialize, Debug)]
+struct ImportedRow {
+    name: String,
+    #[serde(default)]
+    link: String,
+    start: String,
+    remind: Option<String>,
+    end: Option<String>,
+    duration: Option<u64>,
+    max_adults: Option<u64>,
+    max_children: Option<u64>,
+    max_adults_per_reservation: Option<u64>,
+    max_children_per_reservation: Option<u64>,
+    adult_ticket_price: Option<f64>,
+    child_ticket_price: Option<f64>,
+    #[serde(default)]
+    currency: String,
+    venue: Option<u64>,
+    allow_overlap: Option<bool>,
+    categories: Option<String>,
+    questions: Option<String>,
+    registration_opens: Option<String>,
+    registration_closes: Option<String>,
+    time_zone: Option<String>,
+    auto_promote: Option<bool>,
+}
+
+#[derive(Deserialize, Serialize, Clone, Default, Debug)]
+struct NewVenue {
+    id: Option<u64>,
+    name: String,
+    address: String,
+    latitude: f64,
+    longitude: f64,
 }
 
 /// Command line processor.
 pub fn handle_message(
-    conn: &PooledConnection<SqliteConnectionManager>,
+    db: &dyn Storage,
     user: &User,
     data: &str,
     ctx: &Context,
 ) -> anyhow::Result<Reply> {
     let pars: Vec<&str> = data.splitn(4, ' ').collect();
-    if pars.len() == 0 {
+    if pars.is_empty() {
         return Err(anyhow!("Unknown command"));
     }
     match pars[0] {
@@ -53,20 +142,18 @@ pub fn handle_message(
             };
             if waiting_list < 2 {
                 if let Ok(event_id) = pars[2].parse::<u64>() {
-                    match db::get_event(conn, event_id, user.id.0) {
+                    match db.get_event(event_id, user.id.0) {
                         Ok(s) => {
                             let text = format!(
                                     "<a href=\"tg://user?id={}\">{}</a>:\nСообщение по мероприятию {} (Начало: {})\n{}",
                                     user.id.0,
                                     user.user_name1,
                                     format::event_title(&s.event),
-                                    format::ts(s.event.ts),
-                                    pars[3].to_string()
+                                    format::event_ts(&s.event, s.event.ts),
+                                    pars[3]
                                 );
 
-                            if db::enqueue_message(
-                                conn,
-                                event_id,
+                            if db.enqueue_message(event_id,
                                 &user.user_name1,
                                 waiting_list,
                                 MessageType::Direct,
@@ -85,7 +172,7 @@ pub fn handle_message(
                             }
                         }
                         Err(e) => {
-                            return Err(anyhow!("Failed to find event: {}", e));
+                            return Err(e).context("Failed to find event");
                         }
                     }
                 }
@@ -93,90 +180,162 @@ pub fn handle_message(
         }
         "/ban" if pars.len() == 2 => {
             if let Ok(user_id) = pars[1].parse::<u64>() {
-                if db::add_to_black_list(
-                    conn,
-                    user_id,
+                if db.add_to_black_list(user_id,
                     ctx.config.cancel_future_reservations_on_ban,
+                    user.id.0,
                 )
-                .is_ok()
-                    == false
+                .is_err()
                 {
                     error!("Failed to add user {} to black list", user_id);
                 }
-                return show_black_list(conn, &ctx.config, 0);
+                return show_black_list(db, &ctx.config, 0);
             }
         }
         "/remove_from_black_list" if pars.len() == 2 => {
             if let Ok(user_id) = pars[1].parse::<u64>() {
-                if db::remove_from_black_list(conn, user_id).is_ok() == false {
+                if db.remove_from_black_list(user_id).is_err() {
                     error!("Failed to remove user {} from black list", user_id);
                 }
-                return show_black_list(conn, &ctx.config, 0);
+                return show_black_list(db, &ctx.config, 0);
             }
         }
         "/delete_event" if pars.len() == 2 => {
             if let Ok(event_id) = pars[1].parse::<u64>() {
-                match db::delete_event(conn, event_id, ctx.config.automatic_blacklisting,
+                match db.delete_event(event_id, ctx.config.automatic_blacklisting,
                     ctx.config.cancel_future_reservations_on_ban,
-                    &ctx.admins) {
+                    &ctx.admins,
+                    user.id.0) {
                     Ok(_) => {
                         return Ok(ReplyMessage::new("Deleted").into());
                     }
                     Err(e) => {
-                        return Err(anyhow!("Failed to delete event: {}.", e));
+                        return Err(e).context("Failed to delete event");
                     }
                 }
             }
         }
         "/delete_link" if pars.len() == 2 => {
-            match db::delete_link(conn, pars[1]) {
+            match db.delete_link(pars[1]) {
                 Ok(_) => {
                     return Ok(ReplyMessage::new("Deleted").into());
                 }
                 Err(e) => {
-                    return Err(anyhow!("Failed to delete link: {}.", e));
+                    return Err(e).context("Failed to delete link");
                 }
             }
         }
         "/delete_reservation" if pars.len() == 3 => {
             if let (Ok(event_id), Ok(user_id)) = (pars[1].parse::<u64>(), pars[2].parse::<u64>()) {
-                match db::delete_reservation(conn, event_id, user_id) {
+                match db.delete_reservation(event_id, user_id, user.id.0) {
                     Ok(_) => {
                         return Ok(ReplyMessage::new("Reservation deleted.").into());
                     }
                     Err(e) => {
-                        return Err(anyhow!("Failed to delete reservation: {}.", e));
+                        return Err(e).context("Failed to delete reservation");
                     }
                 };
             }
         }
         "/set_group_leader" if pars.len() == 3 => {
             if let (Ok(event_id), Ok(user_id)) = (pars[1].parse::<u64>(), pars[2].parse::<u64>()) {
-                match db::set_group_leader(conn, event_id, user_id) {
+                match db.set_group_leader(event_id, user_id) {
                     Ok(_) => {
                         return Ok(ReplyMessage::new("Group leader set.").into());
                     }
                     Err(e) => {
-                        return Err(anyhow!("Failed to set group leader: {}.", e));
+                        return Err(e).context("Failed to set group leader");
                     }
                 };
             }
         }
+        "/cancel_event" if pars.len() == 2 => {
+            if let Ok(event_id) = pars[1].parse::<u64>() {
+                return confirm_cancel_event(db, event_id);
+            }
+        }
+        "/refunds" => {
+            return show_refunds(db, &ctx.config, 0);
+        }
+        "/clone" if pars.len() > 2 => {
+            if let Ok(event_id) = pars[1].parse::<u64>() {
+                let s = db.find_event(event_id, 0).context("Failed to find event")?;
+                let ts = parse_time(&pars[2..].join(" "), format::time_zone(&s.event))
+                    .ok_or_else(|| anyhow!("Failed to parse date"))?;
+                return clone_event(db, event_id, ts);
+            }
+        }
+        "/series" => {
+            return show_series(db);
+        }
+        "/cancel_series" if pars.len() == 2 => {
+            if let Ok(series_id) = pars[1].parse::<u64>() {
+                db.cancel_series(series_id, user.id.0)
+                    .context("Failed to cancel series")?;
+                return show_series(db);
+            }
+        }
+        "/delete_poster" if pars.len() == 2 => {
+            if let Ok(event_id) = pars[1].parse::<u64>() {
+                db.set_event_poster(event_id, "").context("Failed to delete poster")?;
+                return message_handler::show_event(db, user, event_id, ctx, None, 0);
+            }
+        }
+        "/categories" => {
+            return show_categories(db);
+        }
+        "/venues" => {
+            return show_venues(db);
+        }
+        "/venue" if pars.len() > 1 => {
+            return add_venue(db, user, data["/venue".len()..].trim());
+        }
         "/show_black_list" => {
-            return show_black_list(conn, &ctx.config, 0);
-        }
-        "/set_event_limits" if pars.len() == 4 => {
-            if let (Ok(event_id), Ok(max_adults), Ok(max_children)) = (
-                pars[1].parse::<u64>(),
-                pars[2].parse::<u64>(),
-                pars[3].parse::<u64>(),
-            ) {
-                match db::set_event_limits(conn, event_id, max_adults, max_children) {
+            return show_black_list(db, &ctx.config, 0);
+        }
+        "/archive" => {
+            return show_archive(db, &ctx.config, 0);
+        }
+        "/audit" => {
+            let filter = match (pars.get(1), pars.get(2).map(|id| id.parse::<u64>())) {
+                (None, _) => Some(AuditFilter::All),
+                (Some(&"event"), Some(Ok(event_id))) => Some(AuditFilter::Event(event_id)),
+                (Some(&"user"), Some(Ok(user_id))) => Some(AuditFilter::User(user_id)),
+                _ => None,
+            };
+            if let Some(filter) = filter {
+                return show_audit_log(db, &ctx.config, filter, 0);
+            }
+        }
+        "/users" => {
+            return show_users(db, &ctx.config, 0);
+        }
+        "/user" if pars.len() == 2 => {
+            if let Ok(user_id) = pars[1].parse::<u64>() {
+                return show_user(db, user_id);
+            }
+        }
+        "/set_event_limits" if pars.len() >= 3 => {
+            // One capacity per ticket type follows the event.
+            if let Ok(numbers) = data
+                .split_whitespace()
+                .skip(1)
+                .map(|n| n.parse::<u64>())
+                .collect::<Result<Vec<u64>, _>>()
+            {
+                let (event_id, capacities) = numbers
+                    .split_first()
+                    .ok_or_else(|| anyhow!("Failed to parse command"))?;
+                let event_id = *event_id;
+                let s = db.find_event(event_id, user.id.0).context("Failed to find event")?;
+                if capacities.len() != s.event.tickets.len() {
+                    return Err(anyhow!("Expected {} limits", s.event.tickets.len()));
+                }
+                match db.set_event_limits(event_id, capacities, user.id.0) {
                     Ok(_) => {
                         return Ok(ReplyMessage::new("Event limits updated.").into());
                     }
                     Err(e) => {
-                        return Err(anyhow!("Failed to set event limits: {}.", e));
+                        return Err(e).context("Failed to set event limits");
                     }
                 };
             }
@@ -184,61 +343,132 @@ pub fn handle_message(
         "/help" => {
             return Ok(ReplyMessage::new(markdown::escape(
                         "Добавить мероприятие: \
-                        \n { \"name\":\"тест\", \"link\":\"https://t.me/storiesvienna/21\", \"start\":\"2022-05-29 15:00 +02:00\", \"remind\":\"2022-05-28 15:00 +02:00\", \"max_adults\":15, \"max_children\":15, \"max_adults_per_reservation\":15, \"max_children_per_reservation\":15, \"currency\":\"EUR\" }\
+                        \n { \"name\":\"тест\", \"link\":\"https://t.me/storiesvienna/21\", \"start\":\"2022-05-29 15:00 +02:00\", \"remind\":\"2022-05-28 15:00 +02:00\", \"tickets\":[{ \"name\":\"взрослые\", \"capacity\":15, \"max_per_reservation\":4 }, { \"name\":\"дети\", \"capacity\":15, \"max_per_reservation\":4 }], \"currency\":\"EUR\" }\
                         \n\n Отредактировать: добавьте \"id\":<event> в команду выше \
-                        \n\n Цены билетов: добавьте \"adult_ticket_price\":200, \"child_ticket_price\":100 в выбранной валюте в команду выше \
+                        \n\n Цены билетов: добавьте \"price\":200 в выбранной валюте к типу билета, не больше 8 типов \
+                        \n\n Место проведения: добавьте \"venue\":<venue> \
+                        \n\n Постер: пошлите картинку с номером мероприятия в подписи, удалить: /delete_poster <event> \
+                        \n\n Категории: добавьте \"categories\":[\"дети\", \"онлайн\"] \
+                        \n\n Вопросы к записавшимся: добавьте \"questions\":[\"Возраст детей\", \"Телефон\"], не больше 10 \
+                        \n\n Запись: добавьте \"registration_opens\":\"2022-05-20 10:00 +02:00\" и/или \"registration_closes\":\"2022-05-28 20:00 +02:00\", кроме серий \
+                        \n\n Часовой пояс: добавьте \"time_zone\":\"Europe/Vienna\", тогда время можно указывать без смещения: \"2022-05-29 15:00\" \
+                        \n\n Окончание: добавьте \"end\":\"2022-05-29 17:00 +02:00\" или \"duration\":120 в минутах, разрешить запись на пересекающиеся мероприятия: \"allow_overlap\":true \
+                        \n\n Лист ожидания: добавьте \"auto_promote\":true, чтобы освободившиеся места сразу доставались первым в очереди \
+                        \n\n Серия: добавьте \"repeat\":\"weekly\" (\"daily\", \"every 2 weeks\", \"every 10 days\"), \"until\":\"2022-08-28 15:00 +02:00\" в команду выше, отредактировать все будущие: \"series_id\":<series> \
                         \n \nПослать сообщение: \
                         \n /send confirmed <event> текст \
                         \n /send waiting <event> текст \
                         \n \nЧёрный список: \
                         \n /ban <user> \
                         \n /show_black_list \
+                        \n \nПользователи: \
+                        \n /users \
+                        \n /user <user> \
+                        \n \
+                        \n /series \
+                        \n /cancel_series <series> \
+                        \n /categories \
+                        \n /venues \
+                        \n /venue { \"name\":\"Библиотека\", \"address\":\"Skodagasse 20, Wien\", \"latitude\":48.2098, \"longitude\":16.3480 } \
+                        \n /archive \
+                        \n /audit \
+                        \n /audit event <event> \
+                        \n /audit user <user> \
+                        \n /clone <event> 2022-06-05 15:00 +02:00 \
+                        \n\n Импорт: пошлите файл .ics или .csv с колонками как в команде выше (name, link, start, remind, end, duration, max_adults, adult_ticket_price, currency, venue, categories через ; и т.д.), напоминание по умолчанию за сутки \
                         \n \
+                        \n /cancel_event <event> \
+                        \n /refunds \
                         \n /delete_event <event> \
                         \n /delete_link <url> \
                         \n /delete_reservation <event> <user> \
                         \n /set_group_leader <event> <user> \
-                        \n /set_event_limits <event> <max_adults> <max_children> \
+                        \n /set_event_limits <event> <capacity> [<capacity> ...] \
                         ")).parse_mode(ParseMode::MarkdownV2).into());
         }
         _ => {
             if let Some(ch) = data.chars().next() {
                 if ch == '{' {
-                    return add_event(conn, data);
+                    return add_event(db, user, data, ctx);
                 }
             }
-            return crate::message_handler::handle_message(conn, user, data, ctx);
+            return crate::message_handler::handle_message(db, user, data, ctx);
         }
     }
     Err(anyhow!("Failed to parse command"))
 }
 
+/// Photo with an event id as caption becomes the poster of the event.
+pub fn set_poster(
+    db: &dyn Storage,
+    user: &User,
+    caption: &str,
+    photo: &[PhotoSize],
+    ctx: &Context,
+) -> anyhow::Result<Reply> {
+    let event_id = caption
+        .trim()
+        .parse::<u64>()
+        .map_err(|_| anyhow!("Send the event id as caption of the poster"))?;
+    // The largest size comes last.
+    let file_id = photo.last().map(|p| p.file_id.as_str()).unwrap_or_default();
+    db.set_event_poster(event_id, file_id).context("Failed to set poster")?;
+    message_handler::show_event(db, user, event_id, ctx, None, 0)
+}
+
 /// Callback query processor.
 pub fn handle_callback(
-    conn: &PooledConnection<SqliteConnectionManager>,
+    db: &dyn Storage,
     user: &User,
     data: &str,
     ctx: &Context,
 ) -> anyhow::Result<Reply> {
-    match serde_json::from_str::<CallbackQuery>(&data) {
+    match serde_json::from_str::<CallbackQuery>(data) {
         Ok(q) => {
             use CallbackQuery::*;
             match q {
                 ChangeEventState { event_id, state } => {
-                    match db::change_event_state(conn, event_id, state) {
-                        Ok(_) => message_handler::show_event(conn, user, event_id, ctx, None, 0),
-                        Err(e) => Err(anyhow!("Failed to close event: {}.", e)),
+                    let state: EventState = num::FromPrimitive::from_u64(state)
+                        .ok_or_else(|| anyhow!("Unknown event state {}", state))?;
+                    match db.change_event_state(event_id, state, user.id.0) {
+                        Ok(_) => message_handler::show_event(db, user, event_id, ctx, None, 0),
+                        Err(e) => Err(e).context("Failed to close event"),
                     }
                 }
-                ShowBlackList { offset } => show_black_list(conn, &ctx.config, offset),
+                ShowBlackList { offset } => show_black_list(db, &ctx.config, offset),
                 RemoveFromBlackList { user_id } => {
-                    if db::remove_from_black_list(conn, user_id).is_ok() == false {
+                    if db.remove_from_black_list(user_id).is_err() {
                         error!("Failed to remove user {} from black list", user_id);
                     }
-                    show_black_list(conn, &ctx.config, 0)
+                    show_black_list(db, &ctx.config, 0)
+                }
+                ConfirmCancelEvent { event_id } => confirm_cancel_event(db, event_id),
+                ConfirmCancelSeries { series_id } => {
+                    let s = db.get_series(series_id).context("Failed to find series")?;
+                    let keyboard: Vec<Vec<InlineKeyboardButton>> = vec![vec![
+                        InlineKeyboardButton::callback(
+                            "да",
+                            serde_json::to_string(&CancelSeries { series_id })?,
+                        ),
+                        InlineKeyboardButton::callback(
+                            "нет",
+                            serde_json::to_string(&EventList { offset: 0, filter: Default::default() })?,
+                        ),
+                    ]];
+                    Ok(ReplyMessage::new(format!(
+                        "Отменить серию {} и все её будущие мероприятия? Все записавшиеся получат уведомление.",
+                        format::event_title(&s.template)
+                    ))
+                    .keyboard(keyboard)
+                    .into())
+                }
+                CancelSeries { series_id } => {
+                    db.cancel_series(series_id, user.id.0)
+                        .context("Failed to cancel series")?;
+                    show_series(db)
                 }
                 ConfirmRemoveFromBlackList { user_id } => {
-                    if let Ok(reason) = db::get_ban_reason(conn, user_id) {
+                    if let Ok(reason) = db.get_ban_reason(user_id) {
                         let keyboard: Vec<Vec<InlineKeyboardButton>> = vec![vec![
                             InlineKeyboardButton::callback(
                                 "да",
@@ -254,9 +484,40 @@ pub fn handle_callback(
                         Err(anyhow!("Failed to find ban reason"))
                     }
                 }
+                ShowArchive { offset } => show_archive(db, &ctx.config, offset),
+                ArchivedEvent { event_id, offset } => {
+                    show_archived_event(db, &ctx.config, event_id, offset)
+                }
+                ShowUsers { offset } => show_users(db, &ctx.config, offset),
+                ShowUser { user_id } => show_user(db, user_id),
+                AuditLog {
+                    event_id,
+                    user_id,
+                    offset,
+                } => {
+                    let filter = if event_id != 0 {
+                        AuditFilter::Event(event_id)
+                    } else if user_id != 0 {
+                        AuditFilter::User(user_id)
+                    } else {
+                        AuditFilter::All
+                    };
+                    show_audit_log(db, &ctx.config, filter, offset)
+                }
+                ShowRefunds { offset } => show_refunds(db, &ctx.config, offset),
+                DuplicateEvent { event_id } => {
+                    let s = db.find_event(event_id, 0).context("Failed to find event")?;
+                    clone_event(db, event_id, db::add_days(&s.event, s.event.ts, DUPLICATE_SHIFT_DAYS))
+                }
+                CompleteRefund { refund_id } => {
+                    db.complete_refund(refund_id, user.id.0)
+                        .context("Failed to complete refund")?;
+                    show_refunds(db, &ctx.config, 0)
+                }
+                ImportEvents { create } => create_imported_events(db, user, create),
                 _ => {
                     // Try user message.
-                    message_handler::handle_callback(conn, user, data, ctx)
+                    message_handler::handle_callback(db, user, data, ctx)
                 }
             }
         }
@@ -265,71 +526,497 @@ pub fn handle_callback(
 }
 
 fn add_event(
-    conn: &PooledConnection<SqliteConnectionManager>,
+    db: &dyn Storage,
+    user: &User,
     data: &str,
+    ctx: &Context,
 ) -> anyhow::Result<Reply> {
-    match serde_json::from_str::<NewEvent>(&data) {
-        Ok(v) => {
-            match (
-                DateTime::parse_from_str(&v.start, "%Y-%m-%d %H:%M  %z"),
-                DateTime::parse_from_str(&v.remind, "%Y-%m-%d %H:%M  %z"),
-            ) {
-                (Ok(ts), Ok(remind)) => {
-                    let event = Event {
-                        id: v.id.unwrap_or(0),
-                        name: v.name,
-                        link: v.link,
-                        max_adults: v.max_adults,
-                        max_children: v.max_children,
-                        max_adults_per_reservation: v.max_adults_per_reservation,
-                        max_children_per_reservation: v.max_children_per_reservation,
-                        ts: ts.timestamp() as u64,
-                        remind: remind.timestamp() as u64,
-                        adult_ticket_price: (v.adult_ticket_price.unwrap_or(0.00f64) * 100.0) as u64,
-                        child_ticket_price: (v.child_ticket_price.unwrap_or(0.00f64) * 100.0) as u64,
-                        currency: v.currency,
-                    };
+    let v = serde_json::from_str::<NewEvent>(data)
+        .map_err(|e| anyhow!("Failed to parse json: {}", e))?;
+    let mut event = new_event(db, &v)?;
+    if v.repeat.is_some() || v.series_id.is_some() {
+        if event.registration_opens != 0 || event.registration_closes != 0 {
+            return Err(anyhow!("Series don't support a registration window"));
+        }
+        event.categories = categories(db, &v)?;
+        return add_series(db, user, event, &v, ctx);
+    }
+    event.categories = categories(db, &v)?;
+    match db.add_event(event) {
+        Ok(id) => Ok(event_link(id)),
+        Err(e) => Err(e).context("Failed to add event"),
+    }
+}
 
-                    if event.adult_ticket_price != 0 && event.max_adults == 0
-                        || event.child_ticket_price != 0 && event.max_children == 0
-                    {
-                        return Err(anyhow!("Wrong event format"));
-                    }
-                    match crate::db::add_event(conn, event) {
-                        Ok(id) => {
-                            return Ok(ReplyMessage::new(if id > 0 {
-                                let bot_name = env::var("BOT_NAME").unwrap();
-                                format!("Direct event link: https://t.me/{}?start={}", bot_name, id)
-                            } else {
-                                format!("Failed to add event.")
-                            }).into());
-                        }
-                        Err(e) => {
-                            return Err(anyhow!("Failed to add event: {}.", e));
-                        }
-                    }
-                }
-                _ => {
-                    return Err(anyhow!("Failed to parse date"));
-                }
+/// Parses and checks the event. Categories are left empty, as they are created on saving.
+fn new_event(db: &dyn Storage, v: &NewEvent) -> anyhow::Result<Event> {
+    let tz = match v.time_zone.as_deref() {
+        Some(name) => name
+            .parse::<Tz>()
+            .map_err(|_| anyhow!("Unknown time zone {}", name))?,
+        None => format::default_time_zone(),
+    };
+    let (ts, remind) = match (parse_time(&v.start, tz), parse_time(&v.remind, tz)) {
+        (Some(ts), Some(remind)) => (ts, remind),
+        _ => return Err(anyhow!("Failed to parse date")),
+    };
+    let duration = match (v.end.as_deref(), v.duration) {
+        (Some(end), _) => match parse_time(end, tz) {
+            Some(end) if end > ts => end - ts,
+            _ => return Err(anyhow!("Failed to parse end")),
+        },
+        (None, Some(minutes)) => minutes * 60,
+        (None, None) => 0,
+    };
+    let window = |t: &Option<String>| -> anyhow::Result<u64> {
+        match t.as_deref() {
+            Some(t) => parse_time(t, tz)
+                .ok_or_else(|| anyhow!("Failed to parse registration window")),
+            None => Ok(0),
+        }
+    };
+    let (registration_opens, registration_closes) =
+        (window(&v.registration_opens)?, window(&v.registration_closes)?);
+    if registration_closes != 0 && registration_closes <= registration_opens {
+        return Err(anyhow!("Registration closes before it opens"));
+    }
+    let event = Event {
+        id: v.id.unwrap_or(0),
+        name: v.name.clone(),
+        link: v.link.clone(),
+        ts,
+        remind,
+        tickets: ticket_types(v),
+        currency: v.currency.clone(),
+        series_id: 0,
+        venue_id: v.venue.unwrap_or(0),
+        duration,
+        allow_overlap: v.allow_overlap.unwrap_or(false),
+        categories: Vec::new(),
+        poster: String::new(),
+        registration_opens,
+        registration_closes,
+        questions: questions(v)?,
+        time_zone: v.time_zone.clone().unwrap_or_default(),
+        auto_promote: v.auto_promote.unwrap_or(false),
+    };
+
+    if event.tickets.len() > MAX_TICKET_TYPES
+        || event.tickets.iter().any(|t| t.price != 0 && t.capacity == 0)
+    {
+        return Err(anyhow!("Wrong event format"));
+    }
+    // Announcements are shown as links.
+    if event.get_type() == EventType::Announcement && Url::parse(&event.link).is_err() {
+        return Err(anyhow!("Announcements need a valid link"));
+    }
+    if event.venue_id != 0 {
+        db.get_venue(event.venue_id).context("Failed to find venue")?;
+    }
+    Ok(event)
+}
+
+/// Events from a CSV or iCalendar document, created after the admin confirms the preview.
+pub fn import_events(
+    db: &dyn Storage,
+    user: &User,
+    file_name: &str,
+    content: &[u8],
+) -> anyhow::Result<Reply> {
+    let content = std::str::from_utf8(content).map_err(|_| anyhow!("The file is not in UTF-8"))?;
+    let content = content.trim_start_matches('\u{feff}');
+    let file_name = file_name.to_lowercase();
+    let rows: Vec<anyhow::Result<NewEvent>> = if file_name.ends_with(".ics") {
+        calendar::parse(content)
+            .map_err(|e| anyhow!(e))?
+            .into_iter()
+            .map(|e| e.map(imported_event).map_err(|e| anyhow!(e)))
+            .collect()
+    } else if file_name.ends_with(".csv") {
+        csv::Reader::from_reader(content.as_bytes())
+            .deserialize::<ImportedRow>()
+            .map(|row| row.map(imported_row).map_err(|e| anyhow!("{}", e)))
+            .collect()
+    } else {
+        return Err(anyhow!("Send a .csv or .ics file"));
+    };
+    if rows.is_empty() {
+        return Err(anyhow!("No events found"));
+    }
+    if rows.len() > MAX_IMPORTED_EVENTS {
+        return Err(anyhow!("Too many events, at most {} at once", MAX_IMPORTED_EVENTS));
+    }
+
+    let mut text = "Импорт мероприятий:".to_string();
+    let mut events = Vec::new();
+    let mut errors = 0;
+    for (i, row) in rows.into_iter().enumerate() {
+        match row.and_then(|v| new_event(db, &v).map(|event| (v, event))) {
+            Ok((v, event)) => {
+                text.push_str(&format!(
+                    "\n{}. {} {}",
+                    i + 1,
+                    format::event_ts(&event, event.ts),
+                    html::escape(&event.name)
+                ));
+                events.push(v);
+            }
+            Err(e) => {
+                text.push_str(&format!("\n{}. ❌ {}", i + 1, html::escape(&format!("{:#}", e))));
+                errors += 1;
             }
         }
+    }
+    if errors > 0 {
+        text.push_str("\n\nИсправьте ошибки и пошлите файл ещё раз.");
+        return Ok(ReplyMessage::new(text).into());
+    }
+    db.save_import(user.id.0, &serde_json::to_string(&events)?)
+        .context("Failed to save import")?;
+    let keyboard: Vec<Vec<InlineKeyboardButton>> = vec![vec![
+        InlineKeyboardButton::callback(
+            format!("Создать ({})", events.len()),
+            serde_json::to_string(&CallbackQuery::ImportEvents { create: true })?,
+        ),
+        InlineKeyboardButton::callback(
+            "Отмена",
+            serde_json::to_string(&CallbackQuery::ImportEvents { create: false })?,
+        ),
+    ]];
+    Ok(ReplyMessage::new(text).keyboard(keyboard).into())
+}
+
+fn create_imported_events(db: &dyn Storage, user: &User, create: bool) -> anyhow::Result<Reply> {
+    let events = db
+        .take_import(user.id.0)
+        .context("Failed to get import")?
+        .ok_or_else(|| anyhow!("Nothing to import, send the file again"))?;
+    if !create {
+        return Ok(ReplyMessage::new("Импорт отменён.").into());
+    }
+    let created = match add_imported_events(db, &events) {
+        Ok(created) => created,
         Err(e) => {
-            return Err(anyhow!("Failed to parse json: {}", e));
+            // Nothing is created, so the import can be confirmed again.
+            db.save_import(user.id.0, &events).context("Failed to save import")?;
+            return Err(e);
+        }
+    };
+    let bot_name = env::var("BOT_NAME").unwrap();
+    let mut text = String::new();
+    for (id, event) in created {
+        text.push_str(&format!(
+            "\nhttps://t.me/{}?start={} {}",
+            bot_name,
+            id,
+            html::escape(&event.name)
+        ));
+    }
+    Ok(ReplyMessage::new(format!("Созданы мероприятия:{}", text)).into())
+}
+
+/// Creates all the imported events or none of them.
+fn add_imported_events(db: &dyn Storage, events: &str) -> anyhow::Result<Vec<(u64, Event)>> {
+    let mut res = Vec::new();
+    for v in serde_json::from_str::<Vec<NewEvent>>(events)? {
+        let mut event = new_event(db, &v)?;
+        event.categories = categories(db, &v)?;
+        res.push(event);
+    }
+    let ids = db.add_events(&res).context("Failed to add events")?;
+    Ok(ids.into_iter().zip(res).collect())
+}
+
+fn imported_row(row: ImportedRow) -> NewEvent {
+    let list = |s: Option<String>| {
+        s.map(|s| s.split(';').map(|item| item.trim().to_string()).collect::<Vec<String>>())
+    };
+    NewEvent {
+        remind: row.remind.unwrap_or_else(|| day_before(&row.start)),
+        name: row.name,
+        link: row.link,
+        start: row.start,
+        end: row.end,
+        duration: row.duration,
+        max_adults: row.max_adults.unwrap_or(0),
+        max_children: row.max_children.unwrap_or(0),
+        max_adults_per_reservation: row.max_adults_per_reservation.unwrap_or(0),
+        max_children_per_reservation: row.max_children_per_reservation.unwrap_or(0),
+        adult_ticket_price: row.adult_ticket_price,
+        child_ticket_price: row.child_ticket_price,
+        currency: row.currency,
+        venue: row.venue,
+        allow_overlap: row.allow_overlap,
+        categories: list(row.categories),
+        questions: list(row.questions),
+        registration_opens: row.registration_opens,
+        registration_closes: row.registration_closes,
+        time_zone: row.time_zone,
+        auto_promote: row.auto_promote,
+        ..Default::default()
+    }
+}
+
+fn imported_event(e: calendar::ImportedEvent) -> NewEvent {
+    NewEvent {
+        remind: day_before(&e.start),
+        name: e.name,
+        link: e.link,
+        start: e.start,
+        end: e.end,
+        time_zone: e.time_zone,
+        ..Default::default()
+    }
+}
+
+/// The time a day earlier, in the same format. Unparsable times are left as they are to
+/// fail with the start.
+fn day_before(t: &str) -> String {
+    let earlier = t
+        .get(..16)
+        .and_then(|date| NaiveDateTime::parse_from_str(date, "%Y-%m-%d %H:%M").ok())
+        .map(|date| date - chrono::Duration::days(1));
+    match earlier {
+        Some(date) => format!("{}{}", date.format("%Y-%m-%d %H:%M"), &t[16..]),
+        None => t.to_string(),
+    }
+}
+
+/// Time with an offset, e.g. "2022-05-29 15:00 +02:00", or without one in the given zone.
+fn parse_time(t: &str, tz: Tz) -> Option<u64> {
+    match DateTime::parse_from_str(t, "%Y-%m-%d %H:%M  %z") {
+        Ok(t) => Some(t.timestamp() as u64),
+        Err(_) => NaiveDateTime::parse_from_str(t.trim(), "%Y-%m-%d %H:%M")
+            .ok()
+            .and_then(|t| tz.from_local_datetime(&t).earliest())
+            .map(|t| t.timestamp() as u64),
+    }
+}
+
+fn event_link(event_id: u64) -> Reply {
+    ReplyMessage::new(if event_id > 0 {
+        let bot_name = env::var("BOT_NAME").unwrap();
+        format!("Direct event link: https://t.me/{}?start={}", bot_name, event_id)
+    } else {
+        "Failed to add event.".to_string()
+    })
+    .into()
+}
+
+/// Copies the event with all its settings to a new draft starting at `ts`.
+fn clone_event(db: &dyn Storage, event_id: u64, ts: u64) -> anyhow::Result<Reply> {
+    let s = db.find_event(event_id, 0).context("Failed to find event")?;
+    let id = db
+        .add_event(db::clone_event(&s.event, ts))
+        .context("Failed to clone event")?;
+    Ok(event_link(id))
+}
+
+fn categories(db: &dyn Storage, v: &NewEvent) -> anyhow::Result<Vec<u64>> {
+    let mut res = Vec::new();
+    for name in v.categories.iter().flatten().map(|name| name.trim()) {
+        if name.is_empty() {
+            continue;
+        }
+        let id = db.add_category(name).context("Failed to add category")?;
+        if !res.contains(&id) {
+            res.push(id);
+        }
+    }
+    Ok(res)
+}
+
+fn questions(v: &NewEvent) -> anyhow::Result<Vec<String>> {
+    let res: Vec<String> = v
+        .questions
+        .iter()
+        .flatten()
+        .map(|q| q.trim().to_string())
+        .filter(|q| !q.is_empty())
+        .collect();
+    if res.len() > MAX_QUESTIONS {
+        return Err(anyhow!("Too many questions"));
+    }
+    Ok(res)
+}
+
+/// Explicit ticket types or, for older commands, adults and children, each of them only
+/// if there are places or a price for it.
+fn ticket_types(v: &NewEvent) -> Vec<TicketType> {
+    let cents = |price: Option<f64>| (price.unwrap_or(0.00f64) * 100.0) as u64;
+    match &v.tickets {
+        Some(tickets) => tickets
+            .iter()
+            .map(|t| TicketType {
+                name: t.name.clone(),
+                capacity: t.capacity,
+                max_per_reservation: t.max_per_reservation,
+                price: cents(t.price),
+            })
+            .collect(),
+        None => [
+            ("взрослые", v.max_adults, v.max_adults_per_reservation, cents(v.adult_ticket_price)),
+            ("дети", v.max_children, v.max_children_per_reservation, cents(v.child_ticket_price)),
+        ]
+        .into_iter()
+        .filter(|(_, capacity, _, price)| *capacity > 0 || *price > 0)
+        .map(|(name, capacity, max_per_reservation, price)| TicketType {
+            name: name.to_string(),
+            capacity,
+            max_per_reservation,
+            price,
+        })
+        .collect(),
+    }
+}
+
+fn add_series(
+    db: &dyn Storage,
+    user: &User,
+    template: Event,
+    v: &NewEvent,
+    ctx: &Context,
+) -> anyhow::Result<Reply> {
+    if template.id != 0 {
+        return Err(anyhow!("Use series_id to edit a series"));
+    }
+    if let Some(series_id) = v.series_id {
+        if db.get_series(series_id).context("Failed to find series")?.cancelled {
+            return Err(anyhow!("Series {} is cancelled", series_id));
         }
     }
+    let period = v
+        .repeat
+        .as_deref()
+        .and_then(parse_period)
+        .ok_or_else(|| anyhow!("Failed to parse repeat"))?;
+    let until = v
+        .until
+        .as_deref()
+        .and_then(|until| parse_time(until, format::time_zone(&template)))
+        .ok_or_else(|| anyhow!("Failed to parse until"))?;
+    let series_id = db
+        .add_series(
+            Series {
+                id: v.series_id.unwrap_or(0),
+                template,
+                period,
+                until,
+                next_ts: 0,
+                cancelled: false,
+            },
+            user.id.0,
+        )
+        .context("Failed to save series")?;
+    let created = db
+        .materialize_series(get_unix_time() + ctx.config.series_horizon())
+        .context("Failed to create occurrences")?;
+    Ok(ReplyMessage::new(format!(
+        "Серия {} сохранена. Создано мероприятий: {}.",
+        series_id,
+        created.len()
+    ))
+    .into())
+}
+
+/// Days between occurrences of a series.
+fn parse_period(repeat: &str) -> Option<u64> {
+    let words: Vec<&str> = repeat.split_whitespace().collect();
+    let period = match words[..] {
+        ["daily"] => 1,
+        ["weekly"] => 7,
+        ["every", n, "days"] => n.parse::<u64>().ok()?,
+        ["every", n, "weeks"] => n.parse::<u64>().ok()?.checked_mul(7)?,
+        _ => return None,
+    };
+    if period == 0 || period > MAX_SERIES_PERIOD {
+        None
+    } else {
+        Some(period)
+    }
+}
+
+fn show_series(db: &dyn Storage) -> anyhow::Result<Reply> {
+    let list = db.get_series_list().context("Failed to get series")?;
+    if list.is_empty() {
+        return Ok(ReplyMessage::new("Серий нет.").into());
+    }
+    let mut text = "Серии:".to_string();
+    for s in list {
+        text.push_str(&format!(
+            "\n{} {}, с {} каждые {} дн. до {}",
+            s.id,
+            format::event_title(&s.template),
+            format::event_ts(&s.template, s.template.ts),
+            s.period,
+            format::event_ts(&s.template, s.until)
+        ));
+    }
+    text.push_str("\n\nОтменить серию: /cancel_series и номер серии.");
+    Ok(ReplyMessage::new(text).into())
+}
+
+fn add_venue(db: &dyn Storage, user: &User, data: &str) -> anyhow::Result<Reply> {
+    let v = serde_json::from_str::<NewVenue>(data)
+        .map_err(|e| anyhow!("Failed to parse json: {}", e))?;
+    if !(-90.0..=90.0).contains(&v.latitude) || !(-180.0..=180.0).contains(&v.longitude) {
+        return Err(anyhow!("Wrong coordinates"));
+    }
+    db.add_venue(
+        Venue {
+            id: v.id.unwrap_or(0),
+            name: v.name,
+            address: v.address,
+            latitude: v.latitude,
+            longitude: v.longitude,
+        },
+        user.id.0,
+    )
+    .context("Failed to save venue")?;
+    show_venues(db)
+}
+
+fn show_categories(db: &dyn Storage) -> anyhow::Result<Reply> {
+    let list = db.get_categories().context("Failed to get categories")?;
+    if list.is_empty() {
+        return Ok(ReplyMessage::new("Категорий нет.").into());
+    }
+    let names: Vec<String> = list.iter().map(|c| html::escape(&c.name)).collect();
+    Ok(ReplyMessage::new(format!(
+        "Категории: {}\n\nУкажите \"categories\" со списком названий в описании мероприятия, новые категории создаются автоматически.",
+        names.join(", ")
+    ))
+    .into())
+}
+
+fn show_venues(db: &dyn Storage) -> anyhow::Result<Reply> {
+    let list = db.get_venues().context("Failed to get venues")?;
+    if list.is_empty() {
+        return Ok(ReplyMessage::new("Мест нет.").into());
+    }
+    let mut text = "Места:".to_string();
+    for v in list {
+        text.push_str(&format!(
+            "\n{} <b>{}</b>, {}",
+            v.id,
+            html::escape(&v.name),
+            html::escape(&v.address)
+        ));
+    }
+    text.push_str("\n\nУкажите \"venue\" и номер места в описании мероприятия.");
+    Ok(ReplyMessage::new(text).into())
 }
 
 fn show_black_list(
-    conn: &PooledConnection<SqliteConnectionManager>,
+    db: &dyn Storage,
     config: &Configuration,
     offset: u64,
 ) -> anyhow::Result<Reply> {
-    match db::get_black_list(conn, offset, config.presence_page_size) {
+    match db.get_black_list(offset, config.presence_page_size) {
         Ok(participants) => {
             Ok(
                 // header
-                ReplyMessage::new(if participants.len() != 0 || offset > 0 {
+                ReplyMessage::new(if !participants.is_empty() || offset > 0 {
                     "Чёрный список. Нажмите кнопку чтобы удалить из списка."
                 } else {
                     "Чёрный список пуст."
@@ -340,7 +1027,7 @@ fn show_black_list(
                         .iter()
                         .map(|u| {
                             vec![InlineKeyboardButton::callback(
-                                if u.user_name2.len() > 0 {
+                                if !u.user_name2.is_empty() {
                                     format!("{} ({}) {}", u.user_name1, u.user_name2, u.id)
                                 } else {
                                     format!("{} {}", u.user_name1, u.id)
@@ -366,6 +1053,377 @@ fn show_black_list(
                 .into(),
             )
         }
-        Err(e) => Err(anyhow!("Failed to get black list: {}", e)),
+        Err(e) => Err(e).context("Failed to get black list"),
     }
 }
+
+fn show_archive(
+    db: &dyn Storage,
+    config: &Configuration,
+    offset: u64,
+) -> anyhow::Result<Reply> {
+    match db.get_archived_events(offset, config.event_list_page_size) {
+        Ok(events) => {
+            Ok(
+                // header
+                ReplyMessage::new(if !events.is_empty() || offset > 0 {
+                    "Архив мероприятий. Нажмите кнопку чтобы посмотреть посещаемость."
+                } else {
+                    "Архив пуст."
+                })
+                // list
+                .keyboard(
+                    events
+                        .iter()
+                        .map(|s| {
+                            vec![InlineKeyboardButton::callback(
+                                format!(
+                                    "{} {} ({})",
+                                    format::event_ts(&s.event, s.event.ts),
+                                    s.event.name,
+                                    s.reserved()
+                                ),
+                                serde_json::to_string(&CallbackQuery::ArchivedEvent {
+                                    event_id: s.event.id,
+                                    offset: 0,
+                                })
+                                .unwrap(),
+                            )]
+                        })
+                        .collect(),
+                )
+                // pagination
+                .pagination(
+                    &CallbackQuery::ShowArchive {
+                        offset: offset.saturating_sub(1),
+                    },
+                    &CallbackQuery::ShowArchive { offset: offset + 1 },
+                    events.len() as u64,
+                    config.event_list_page_size,
+                    offset,
+                )?
+                .into(),
+            )
+        }
+        Err(e) => Err(e).context("Failed to get archived events"),
+    }
+}
+
+fn show_archived_event(
+    db: &dyn Storage,
+    config: &Configuration,
+    event_id: u64,
+    offset: u64,
+) -> anyhow::Result<Reply> {
+    let s = db.find_event(event_id, 0).context("Failed to find event")?;
+    let list = db
+        .get_attendance_list(event_id, offset, config.presence_page_size)
+        .context("Failed to get attendance list")?;
+    let mut text = format!(
+        "{}\nНачало: {}\nЗаписались: {}, пришли: {}.\n",
+        format::event_title(&s.event),
+        format::event_ts(&s.event, s.event.ts),
+        s.reserved(),
+        list.iter().filter(|p| p.present).map(|p| p.reserved).sum::<u64>(),
+    );
+    for p in &list {
+        text.push_str(&format!(
+            "\n{} <a href=\"tg://user?id={}\">{}</a> {}",
+            if p.present { "✅" } else { "❌" },
+            p.user_id,
+            html::escape(&p.user_name1),
+            p.reserved
+        ));
+        if let Some(a) = &p.attachment {
+            text.push_str(&format!(" - {}", html::escape(a)));
+        }
+        if p.answers.iter().any(|a| !a.is_empty()) {
+            text.push_str(&format!(" ({})", format::answers(&s.event, &p.answers)));
+        }
+    }
+    Ok(ReplyMessage::new(text)
+        .keyboard(vec![vec![InlineKeyboardButton::callback(
+            "Назад",
+            serde_json::to_string(&CallbackQuery::ShowArchive { offset: 0 })?,
+        )]])
+        .pagination(
+            &CallbackQuery::ArchivedEvent {
+                event_id,
+                offset: offset.saturating_sub(1),
+            },
+            &CallbackQuery::ArchivedEvent {
+                event_id,
+                offset: offset + 1,
+            },
+            list.len() as u64,
+            config.presence_page_size,
+            offset,
+        )?
+        .into())
+}
+
+fn show_users(
+    db: &dyn Storage,
+    config: &Configuration,
+    offset: u64,
+) -> anyhow::Result<Reply> {
+    let users = db
+        .get_users(offset, config.presence_page_size)
+        .context("Failed to get users")?;
+    Ok(
+        // header
+        ReplyMessage::new(if !users.is_empty() || offset > 0 {
+            "Пользователи, начиная с последних активных. ❌ - бот не может отправить сообщение."
+        } else {
+            "Пользователей пока нет."
+        })
+        // list
+        .keyboard(
+            users
+                .iter()
+                .map(|p| {
+                    let u = &p.user;
+                    vec![InlineKeyboardButton::callback(
+                        format!(
+                            "{}{}{} {}",
+                            if p.reachable { "" } else { "❌ " },
+                            u.user_name1,
+                            if !u.user_name2.is_empty() {
+                                format!(" (@{})", u.user_name2)
+                            } else {
+                                "".to_string()
+                            },
+                            format::ts(p.last_seen)
+                        ),
+                        serde_json::to_string(&CallbackQuery::ShowUser { user_id: u.id.0 })
+                            .unwrap(),
+                    )]
+                })
+                .collect(),
+        )
+        // pagination
+        .pagination(
+            &CallbackQuery::ShowUsers {
+                offset: offset.saturating_sub(1),
+            },
+            &CallbackQuery::ShowUsers { offset: offset + 1 },
+            users.len() as u64,
+            config.presence_page_size,
+            offset,
+        )?
+        .into(),
+    )
+}
+
+fn show_user(db: &dyn Storage, user_id: u64) -> anyhow::Result<Reply> {
+    let p = match db.get_user(user_id).context("Failed to get user")? {
+        Some(p) => p,
+        None => return Ok(ReplyMessage::new(format!("Пользователь {} не найден.", user_id)).into()),
+    };
+    let u = &p.user;
+    let mut text = format!(
+        "<a href=\"tg://user?id={}\">{}</a> {}",
+        user_id,
+        html::escape(&u.user_name1),
+        user_id
+    );
+    if !u.user_name2.is_empty() {
+        text.push_str(&format!("\n@{}", html::escape(&u.user_name2)));
+    }
+    text.push_str(&format!(
+        "\nЯзык: {}\nПервое обращение: {}\nПоследнее обращение: {}\nДоступен: {}",
+        u.language_code.as_deref().unwrap_or("-"),
+        format::ts(p.first_seen),
+        format::ts(p.last_seen),
+        if p.reachable { "да" } else { "нет" }
+    ));
+    Ok(ReplyMessage::new(text)
+        .keyboard(vec![vec![InlineKeyboardButton::callback(
+            "Назад",
+            serde_json::to_string(&CallbackQuery::ShowUsers { offset: 0 })?,
+        )]])
+        .into())
+}
+
+/// The event keeps its record, participants and the waiting list are notified.
+fn confirm_cancel_event(db: &dyn Storage, event_id: u64) -> anyhow::Result<Reply> {
+    let s = db.find_event(event_id, 0).context("Failed to find event")?;
+    let keyboard: Vec<Vec<InlineKeyboardButton>> = vec![vec![
+        InlineKeyboardButton::callback(
+            "да",
+            serde_json::to_string(&CallbackQuery::ChangeEventState {
+                event_id,
+                state: EventState::Cancelled as u64,
+            })?,
+        ),
+        InlineKeyboardButton::callback(
+            "нет",
+            serde_json::to_string(&CallbackQuery::Event { event_id, offset: 0 })?,
+        ),
+    ]];
+    let refunds = if s.event.get_type() == EventType::Paid {
+        " Оплаты попадут в список возвратов /refunds."
+    } else {
+        ""
+    };
+    Ok(ReplyMessage::new(format!(
+        "Отменить мероприятие {}? Все записавшиеся и ожидающие получат уведомление.{}",
+        html::escape(&db::event_name(&s.event)),
+        refunds
+    ))
+    .keyboard(keyboard)
+    .into())
+}
+
+fn show_refunds(db: &dyn Storage, config: &Configuration, offset: u64) -> anyhow::Result<Reply> {
+    let refunds = db
+        .get_refunds(offset, config.presence_page_size)
+        .context("Failed to get refunds")?;
+    let mut text = if !refunds.is_empty() || offset > 0 {
+        "Возвраты. Нажмите кнопку, когда оплата возвращена.".to_string()
+    } else {
+        "Возвратов нет.".to_string()
+    };
+    for r in &refunds {
+        text.push_str("\n\n");
+        text.push_str(&format::refund(r));
+    }
+    let keyboard = refunds
+        .iter()
+        .filter(|r| r.refunded == 0)
+        .map(|r| {
+            Ok(vec![InlineKeyboardButton::callback(
+                format!("Возвращено №{}", r.id),
+                serde_json::to_string(&CallbackQuery::CompleteRefund { refund_id: r.id })?,
+            )])
+        })
+        .collect::<anyhow::Result<Vec<_>>>()?;
+    Ok(ReplyMessage::new(text)
+        .keyboard(keyboard)
+        .pagination(
+            &CallbackQuery::ShowRefunds {
+                offset: offset.saturating_sub(1),
+            },
+            &CallbackQuery::ShowRefunds { offset: offset + 1 },
+            refunds.len() as u64,
+            config.presence_page_size,
+            offset,
+        )?
+        .into())
+}
+
+fn show_audit_log(
+    db: &dyn Storage,
+    config: &Configuration,
+    filter: AuditFilter,
+    offset: u64,
+) -> anyhow::Result<Reply> {
+    let records = db
+        .get_audit_log(filter, offset, config.presence_page_size)
+        .context("Failed to get audit log")?;
+    let mut text = match filter {
+        AuditFilter::All => "Журнал действий".to_string(),
+        AuditFilter::Event(event_id) => format!("Журнал действий по мероприятию {}", event_id),
+        AuditFilter::User(user_id) => format!("Журнал действий пользователя {}", user_id),
+    };
+    if records.is_empty() && offset == 0 {
+        text.push_str(" пуст.");
+    }
+    for r in &records {
+        text.push('\n');
+        text.push_str(&format::audit_record(r));
+    }
+    let (event_id, user_id) = match filter {
+        AuditFilter::All => (0, 0),
+        AuditFilter::Event(event_id) => (event_id, 0),
+        AuditFilter::User(user_id) => (0, user_id),
+    };
+    Ok(ReplyMessage::new(text)
+        .pagination(
+            &CallbackQuery::AuditLog {
+                event_id,
+                user_id,
+                offset: offset.saturating_sub(1),
+            },
+            &CallbackQuery::AuditLog {
+                event_id,
+                user_id,
+                offset: offset + 1,
+            },
+            records.len() as u64,
+            config.presence_page_size,
+            offset,
+        )?
+        .into())
+}
+
+#[test]
+fn test_import_events() -> anyhow::Result<()> {
+    use crate::db::tests::{test_context, user};
+
+    env::set_var("BOT_NAME", "test_bot");
+    let ctx = test_context(&[1]);
+    let db = ctx.storage.as_ref();
+    let mut admin = user(1);
+    admin.is_admin = true;
+    let message = |reply: Reply| match reply {
+        Reply::Message(m) => m,
+        _ => panic!("unexpected reply"),
+    };
+
+    // Rows failing the checks of add_event are reported and nothing is saved.
+    let csv = "name,link,start,max_adults,adult_ticket_price,currency\n\
+        Сказки,https://example.com/1,2030-05-29 15:00 +02:00,10,,EUR\n\
+        Концерт,https://example.com/2,2030-05-30 15:00 +02:00,,5,EUR\n\
+        Лекция,not a link,2030-05-31 15:00 +02:00,,,\n\
+        Ярмарка,https://example.com/4,tomorrow,,,\n";
+    let m = message(import_events(db, &admin, "programme.CSV", csv.as_bytes())?);
+    assert!(m.message.contains("1. ср 29.05.2030 13:00 Сказки"), "{}", m.message);
+    assert!(m.message.contains("2. ❌ Wrong event format"));
+    assert!(m.message.contains("3. ❌ Announcements need a valid link"));
+    assert!(m.message.contains("4. ❌ Failed to parse date"));
+    assert!(m.keyboard.is_none());
+    assert_eq!(db.take_import(1)?, None);
+    assert!(import_events(db, &admin, "programme.txt", csv.as_bytes()).is_err());
+
+    // Valid rows are created after confirmation.
+    let csv = "name,link,start,remind,duration,max_adults,currency,categories,time_zone\n\
+        Сказки,https://example.com/1,2030-05-29 15:00,,90,10,EUR,дети; книги,Europe/Vienna\n\
+        Концерт,https://example.com/2,2030-05-30 15:00 +02:00,2030-05-30 10:00 +02:00,,,,,\n";
+    let m = message(import_events(db, &admin, "programme.csv", csv.as_bytes())?);
+    assert_eq!(m.keyboard.map(|k| k[0].len()), Some(2));
+    let create = serde_json::to_string(&CallbackQuery::ImportEvents { create: true })?;
+    let m = message(handle_callback(db, &admin, &create, &ctx)?);
+    assert_eq!(m.message.lines().count(), 3);
+    let events = db.get_events(1, true, &Default::default(), 0, 10)?;
+    assert_eq!(events.len(), 2);
+    let e = &events[0].event;
+    assert_eq!((e.name.as_str(), e.duration, e.categories.len()), ("Сказки", 90 * 60, 2));
+    assert_eq!(e.time_zone, "Europe/Vienna");
+    assert_eq!(e.ts - e.remind, 24 * 60 * 60);
+    assert_eq!(events[1].event.ts - events[1].event.remind, 5 * 60 * 60);
+    // The import is created once.
+    assert!(handle_callback(db, &admin, &create, &ctx).is_err());
+
+    // An import that fails is not created in part and can be confirmed again.
+    let m = message(import_events(db, &admin, "programme.csv", csv.as_bytes())?);
+    assert!(m.keyboard.is_some());
+    let mut import: Vec<NewEvent> = serde_json::from_str(&db.take_import(1)?.unwrap())?;
+    import[1].link = "not a link".to_string();
+    db.save_import(1, &serde_json::to_string(&import)?)?;
+    assert!(handle_callback(db, &admin, &create, &ctx).is_err());
+    assert_eq!(db.get_events(1, true, &Default::default(), 0, 10)?.len(), 2);
+    assert!(db.take_import(1)?.is_some());
+    Ok(())
+}
+
+#[test]
+fn test_parse_period() {
+    assert_eq!(parse_period("daily"), Some(1));
+    assert_eq!(parse_period("every 2 weeks"), Some(14));
+    assert_eq!(parse_period("every 10 days"), Some(10));
+    assert_eq!(parse_period("every 0 days"), None);
+    assert_eq!(parse_period("every 400 days"), None);
+    assert_eq!(parse_period("every 18446744073709551615 weeks"), None);
+    assert_eq!(parse_period("monthly"), None);
+}
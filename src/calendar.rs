@@ -0,0 +1,307 @@
+use crate::db::EventStats;
+use crate::types::{EventState, Venue};
+use chrono::{DateTime, NaiveDate, NaiveDateTime, Utc};
+
+/// Lines longer than this are folded, in octets without the line break.
+const MAX_LINE_LENGTH: usize = 75;
+
+/// iCalendar file with the events for import into calendar apps.
+/// UIDs are made of event ids, so importing an event again updates the entry.
+pub fn calendar(events: &[(EventStats, Option<Venue>)], domain: &str, now: u64) -> String {
+    let mut lines = vec![
+        "BEGIN:VCALENDAR".to_string(),
+        "VERSION:2.0".to_string(),
+        "PRODID:-//event-manager-telegram-bot//RU".to_string(),
+        "CALSCALE:GREGORIAN".to_string(),
+        "METHOD:PUBLISH".to_string(),
+    ];
+    for (s, venue) in events {
+        let e = &s.event;
+        lines.push("BEGIN:VEVENT".to_string());
+        lines.push(format!("UID:event-{}@{}", e.id, domain));
+        lines.push(format!("DTSTAMP:{}", ts(now)));
+        // Calendar apps replace their copy only if the sequence grows.
+        lines.push(format!("SEQUENCE:{}", s.revision));
+        lines.push(format!("DTSTART:{}", ts(e.ts)));
+        if e.duration > 0 {
+            lines.push(format!("DTEND:{}", ts(e.end())));
+        }
+        lines.push(format!("SUMMARY:{}", text(&e.name)));
+        if !e.link.is_empty() {
+            lines.push(format!("URL:{}", e.link));
+            lines.push(format!("DESCRIPTION:{}", text(&e.link)));
+        }
+        if let Some(v) = venue {
+            lines.push(format!("LOCATION:{}", text(&format!("{}, {}", v.name, v.address))));
+            lines.push(format!("GEO:{};{}", v.latitude, v.longitude));
+        }
+        lines.push(
+            if s.state == EventState::Cancelled {
+                "STATUS:CANCELLED"
+            } else {
+                "STATUS:CONFIRMED"
+            }
+            .to_string(),
+        );
+        lines.push("END:VEVENT".to_string());
+    }
+    lines.push("END:VCALENDAR".to_string());
+    lines.iter().map(|line| fold(line)).collect()
+}
+
+/// Date and time in UTC.
+fn ts(ts: u64) -> String {
+    let datetime: DateTime<Utc> = DateTime::from_timestamp(ts as i64, 0).unwrap_or_default();
+    datetime.format("%Y%m%dT%H%M%SZ").to_string()
+}
+
+/// Escapes a text value.
+fn text(value: &str) -> String {
+    value
+        .replace('\\', "\\\\")
+        .replace(';', "\\;")
+        .replace(',', "\\,")
+        .replace("\r\n", "\\n")
+        .replace('\n', "\\n")
+}
+
+/// The line ended by CRLF, long ones are continued on lines starting with a space.
+fn fold(line: &str) -> String {
+    let mut res = String::new();
+    let mut length = 0;
+    for c in line.chars() {
+        if length + c.len_utf8() > MAX_LINE_LENGTH {
+            res.push_str("\r\n ");
+            // The space counts.
+            length = 1;
+        }
+        res.push(c);
+        length += c.len_utf8();
+    }
+    res.push_str("\r\n");
+    res
+}
+
+/// Event read from an imported iCalendar file, times are written the way admins type them.
+pub struct ImportedEvent {
+    pub name: String,
+    pub link: String,
+    pub start: String,
+    pub end: Option<String>,
+    /// TZID of the start, if given.
+    pub time_zone: Option<String>,
+}
+
+/// Events of an iCalendar file in the order they come, or why one can't be read.
+/// Fails if the components aren't properly nested.
+pub fn parse(content: &str) -> Result<Vec<Result<ImportedEvent, String>>, String> {
+    let mut res = Vec::new();
+    // Components open at the current line, the innermost last.
+    let mut components: Vec<String> = Vec::new();
+    // Properties of the current event, components nested in it are skipped.
+    let mut properties = Vec::new();
+    for line in unfold(content) {
+        let (name, params, value) = match property(&line) {
+            Some(p) => p,
+            None => continue,
+        };
+        match name.as_str() {
+            "BEGIN" => {
+                if value == "VEVENT" {
+                    properties.clear();
+                }
+                components.push(value);
+            }
+            "END" => {
+                if components.pop().as_deref() != Some(value.as_str()) {
+                    return Err(format!("Unexpected END:{}", value));
+                }
+                if value == "VEVENT" {
+                    res.push(imported_event(&properties));
+                }
+            }
+            _ if components.last().map(String::as_str) == Some("VEVENT") => {
+                properties.push((name, params, value));
+            }
+            _ => {}
+        }
+    }
+    if let Some(component) = components.pop() {
+        return Err(format!("No END:{}", component));
+    }
+    Ok(res)
+}
+
+fn imported_event(properties: &[(String, String, String)]) -> Result<ImportedEvent, String> {
+    let get = |name: &str| properties.iter().find(|(n, _, _)| n == name);
+    let name = get("SUMMARY")
+        .map(|(_, _, value)| unescape(value))
+        .ok_or_else(|| "No SUMMARY".to_string())?;
+    let (_, params, value) = get("DTSTART").ok_or_else(|| "No DTSTART".to_string())?;
+    let start = time(value).ok_or_else(|| format!("Failed to parse DTSTART {}", value))?;
+    let end = match get("DTEND") {
+        Some((_, _, value)) => {
+            Some(time(value).ok_or_else(|| format!("Failed to parse DTEND {}", value))?)
+        }
+        None => None,
+    };
+    Ok(ImportedEvent {
+        name,
+        link: get("URL").map(|(_, _, value)| value.clone()).unwrap_or_default(),
+        start,
+        end,
+        time_zone: params
+            .split(';')
+            .find_map(|p| p.strip_prefix("TZID="))
+            .map(|tz| tz.trim_matches('"').to_string()),
+    })
+}
+
+/// Joins folded lines.
+fn unfold(content: &str) -> Vec<String> {
+    let mut lines: Vec<String> = Vec::new();
+    for line in content.lines() {
+        match (line.strip_prefix(' ').or_else(|| line.strip_prefix('\t')), lines.last_mut()) {
+            (Some(rest), Some(last)) => last.push_str(rest),
+            _ => lines.push(line.to_string()),
+        }
+    }
+    lines
+}
+
+/// Name in upper case, parameters and value of a content line.
+fn property(line: &str) -> Option<(String, String, String)> {
+    let (head, value) = line.split_once(':')?;
+    let (name, params) = head.split_once(';').unwrap_or((head, ""));
+    Some((name.to_uppercase(), params.to_string(), value.to_string()))
+}
+
+/// UTC times get an offset, local and floating ones are taken in the event time zone.
+fn time(value: &str) -> Option<String> {
+    if let Some(utc) = value.strip_suffix('Z') {
+        let t = NaiveDateTime::parse_from_str(utc, "%Y%m%dT%H%M%S").ok()?;
+        Some(t.format("%Y-%m-%d %H:%M +0000").to_string())
+    } else if let Ok(t) = NaiveDateTime::parse_from_str(value, "%Y%m%dT%H%M%S") {
+        Some(t.format("%Y-%m-%d %H:%M").to_string())
+    } else {
+        // All-day events start at midnight.
+        let d = NaiveDate::parse_from_str(value, "%Y%m%d").ok()?;
+        Some(d.format("%Y-%m-%d 00:00").to_string())
+    }
+}
+
+fn unescape(value: &str) -> String {
+    let mut res = String::new();
+    let mut chars = value.chars();
+    while let Some(c) = chars.next() {
+        if c == '\\' {
+            match chars.next() {
+                Some('n') | Some('N') => res.push('\n'),
+                Some(c) => res.push(c),
+                None => {}
+            }
+        } else {
+            res.push(c);
+        }
+    }
+    res
+}
+
+#[test]
+fn test_calendar() {
+    let venue = Venue {
+        id: 1,
+        name: "Библиотека".to_string(),
+        address: "Skodagasse 20, Wien".to_string(),
+        latitude: 48.2098,
+        longitude: 16.348,
+    };
+    let mut event = crate::db::tests::event(0, 0, 1650445800);
+    event.id = 7;
+    event.name = "Сказки; чтение, игры".to_string();
+    event.tickets = Vec::new();
+    event.venue_id = 1;
+    event.duration = 90 * 60;
+    event.time_zone = "Europe/Vienna".to_string();
+    let s = EventStats {
+        event,
+        tickets: Vec::new(),
+        state: EventState::Open,
+        revision: 2,
+    };
+    let ics = calendar(&[(s, Some(venue))], "test_bot", 1650000000);
+    assert!(ics.starts_with("BEGIN:VCALENDAR\r\nVERSION:2.0\r\n"));
+    assert!(ics.ends_with("END:VEVENT\r\nEND:VCALENDAR\r\n"));
+    for line in [
+        "UID:event-7@test_bot",
+        "DTSTAMP:20220415T052000Z",
+        "SEQUENCE:2",
+        "DTSTART:20220420T091000Z",
+        "DTEND:20220420T104000Z",
+        "SUMMARY:Сказки\\; чтение\\, игры",
+        "URL:https://example.com/1",
+        "LOCATION:Библиотека\\, Skodagasse 20\\, Wien",
+        "GEO:48.2098;16.348",
+        "STATUS:CONFIRMED",
+    ] {
+        assert!(ics.contains(&format!("\r\n{}\r\n", line)), "{}", line);
+    }
+
+    let folded = fold(&"я".repeat(50));
+    let lines: Vec<&str> = folded.trim_end().split("\r\n").collect();
+    assert_eq!(lines.len(), 2);
+    assert!(lines.iter().all(|line| line.len() <= MAX_LINE_LENGTH));
+    assert_eq!(lines.concat().replace(' ', ""), "я".repeat(50));
+
+    // Exported calendars are read back.
+    let e = parse(&ics).unwrap().pop().unwrap().unwrap();
+    assert_eq!(e.name, "Сказки; чтение, игры");
+    assert_eq!(e.start, "2022-04-20 09:10 +0000");
+    assert_eq!(e.end.as_deref(), Some("2022-04-20 10:40 +0000"));
+}
+
+#[test]
+fn test_parse() {
+    let ics = "BEGIN:VCALENDAR\r\n\
+        VERSION:2.0\r\n\
+        BEGIN:VTIMEZONE\r\n\
+        TZID:Europe/Vienna\r\n\
+        END:VTIMEZONE\r\n\
+        BEGIN:VEVENT\r\n\
+        UID:1@example.com\r\n\
+        SUMMARY:Сказки\\; чтение\\, игры и очень длинное название\r\n  \
+         на двух строках\r\n\
+        DTSTART;TZID=Europe/Vienna:20220420T111000\r\n\
+        DTEND;TZID=Europe/Vienna:20220420T124000\r\n\
+        URL:https://example.com/1\r\n\
+        BEGIN:VALARM\r\n\
+        SUMMARY:Напоминание\r\n\
+        END:VALARM\r\n\
+        END:VEVENT\r\n\
+        BEGIN:VEVENT\r\n\
+        SUMMARY:Ярмарка\r\n\
+        DTSTART;VALUE=DATE:20220501\r\n\
+        END:VEVENT\r\n\
+        BEGIN:VEVENT\r\n\
+        SUMMARY:Без даты\r\n\
+        END:VEVENT\r\n\
+        END:VCALENDAR\r\n";
+    let events = parse(ics).unwrap();
+    assert_eq!(events.len(), 3);
+    let e = events[0].as_ref().unwrap();
+    assert_eq!(e.name, "Сказки; чтение, игры и очень длинное название на двух строках");
+    assert_eq!(e.link, "https://example.com/1");
+    assert_eq!(e.start, "2022-04-20 11:10");
+    assert_eq!(e.end.as_deref(), Some("2022-04-20 12:40"));
+    assert_eq!(e.time_zone.as_deref(), Some("Europe/Vienna"));
+    let e = events[1].as_ref().unwrap();
+    assert_eq!((e.start.as_str(), e.end.as_deref(), e.time_zone.as_deref()), ("2022-05-01 00:00", None, None));
+    assert_eq!(events[2].as_ref().err().map(|e| e.as_str()), Some("No DTSTART"));
+
+    // Components must be closed in the order they are opened.
+    let event = "BEGIN:VEVENT\r\nSUMMARY:Ярмарка\r\nDTSTART:20220501T100000\r\n";
+    assert_eq!(parse(&format!("{}END:VALARM\r\nEND:VEVENT\r\n", event)).err().as_deref(), Some("Unexpected END:VALARM"));
+    assert_eq!(parse(&format!("{}BEGIN:VALARM\r\nEND:VEVENT\r\n", event)).err().as_deref(), Some("Unexpected END:VEVENT"));
+    assert_eq!(parse(event).err().as_deref(), Some("No END:VEVENT"));
+    assert_eq!(parse("END:VCALENDAR\r\n").err().as_deref(), Some("Unexpected END:VCALENDAR"));
+}
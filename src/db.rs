@@ -1,1164 +1,863 @@
-use crate::types::{Event, EventState, EventType, MessageBatch, MessageType, Participant, Presence, User, OrderInfo, ReservationState, Booking};
-use crate::util::{self, get_unix_time};
-use fallible_streaming_iterator::FallibleStreamingIterator;
-use rusqlite::{params, Result, Row};
+use crate::types::{
+    AuditAction, Booking, Event, EventState, EventType, MessageBatch, MessageType, OrderInfo, Participant,
+    Category, Presence, ReservationState, Series, User, Venue,
+};
+use crate::format;
+use chrono::{DateTime, TimeDelta, TimeZone};
 use std::collections::HashSet;
-use url::Url;
+use std::fmt;
 
-use r2d2::PooledConnection;
-use r2d2_sqlite::SqliteConnectionManager;
-use anyhow::anyhow;
-use crate::format;
+#[cfg(test)]
+mod memory;
+mod migrations;
+mod postgres;
+mod sqlite;
+#[cfg(test)]
+pub(crate) mod tests;
 
 #[cfg(test)]
-mod tests;
+pub use memory::MemoryStorage;
+pub use self::postgres::PostgresStorage;
+pub use sqlite::SqliteStorage;
+
+/// Opens the storage selected by a connection URL:
+/// `postgres://` or `postgresql://` for PostgreSQL, `sqlite://` or a plain file path for SQLite.
+pub fn open(url: &str) -> anyhow::Result<Box<dyn Storage>> {
+    if url.starts_with("postgres://") || url.starts_with("postgresql://") {
+        Ok(Box::new(PostgresStorage::open(url)?))
+    } else {
+        Ok(Box::new(SqliteStorage::open(
+            url.strip_prefix("sqlite://").unwrap_or(url),
+        )?))
+    }
+}
+
+/// Errors reported by storage backends.
+/// Domain failures have own variants, so that handlers can explain them to users.
+#[derive(Debug)]
+pub enum Error {
+    EventNotFound(u64),
+    SeriesNotFound(u64),
+    VenueNotFound(u64),
+    RefundNotFound(u64),
+    ReservationNotFound,
+    SoldOut,
+    RegistrationClosed,
+    /// Registration opens later.
+    RegistrationNotOpen,
+    TimeConflict,
+    WrongAmount,
+    /// Announcements don't take reservations.
+    NotBookable,
+    InvalidLink(String),
+    /// The ticket type has reservations, so it can't be removed or moved.
+    TicketTypeBooked(String),
+    /// Cancelled, completed and archived events can't be edited.
+    EventNotEditable(u64),
+    /// The event can't move from the first state to the second one.
+    InvalidStateChange(EventState, EventState),
+    /// Failure of the database itself. Never shown to users.
+    Storage(Box<dyn std::error::Error + Send + Sync>),
+}
+
+pub type Result<T> = std::result::Result<T, Error>;
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Error::EventNotFound(event_id) => write!(f, "event {} not found", event_id),
+            Error::SeriesNotFound(series_id) => write!(f, "series {} not found", series_id),
+            Error::VenueNotFound(venue_id) => write!(f, "venue {} not found", venue_id),
+            Error::RefundNotFound(refund_id) => write!(f, "refund {} not found", refund_id),
+            Error::ReservationNotFound => write!(f, "reservation not found"),
+            Error::SoldOut => write!(f, "no vacancies left"),
+            Error::RegistrationClosed => write!(f, "registration closed"),
+            Error::RegistrationNotOpen => write!(f, "registration not open yet"),
+            Error::TimeConflict => write!(f, "conflicting reservation of an overlapping event"),
+            Error::WrongAmount => write!(f, "wrong transaction amount"),
+            Error::NotBookable => write!(f, "event doesn't take reservations"),
+            Error::InvalidLink(link) => write!(f, "failed to parse url: {}", link),
+            Error::TicketTypeBooked(name) => write!(f, "ticket type {} has reservations", name),
+            Error::EventNotEditable(event_id) => write!(f, "event {} is over and can't be edited", event_id),
+            Error::InvalidStateChange(from, to) => write!(f, "event can't change from {:?} to {:?}", from, to),
+            Error::Storage(e) => write!(f, "storage error: {}", e),
+        }
+    }
+}
+
+impl std::error::Error for Error {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Error::Storage(e) => Some(e.as_ref()),
+            _ => None,
+        }
+    }
+}
+
+impl From<rusqlite::Error> for Error {
+    fn from(e: rusqlite::Error) -> Self {
+        Error::Storage(Box::new(e))
+    }
+}
+
+impl From<::postgres::Error> for Error {
+    fn from(e: ::postgres::Error) -> Self {
+        Error::Storage(Box::new(e))
+    }
+}
+
+impl From<r2d2::Error> for Error {
+    fn from(e: r2d2::Error) -> Self {
+        Error::Storage(Box::new(e))
+    }
+}
 
+impl From<serde_json::Error> for Error {
+    fn from(e: serde_json::Error) -> Self {
+        Error::Storage(Box::new(e))
+    }
+}
+
+#[derive(Default)]
 pub struct Counter {
     pub reserved: u64,
     pub my_reservation: u64,
     pub my_waiting: u64,
 }
 
-impl Counter {
-    pub fn new(
-        reserved: Result<u64, rusqlite::Error>,
-        my_reservation: Result<u64, rusqlite::Error>,
-        my_waiting: Result<u64, rusqlite::Error>,
-    ) -> Result<Counter, rusqlite::Error> {
-        Ok(Counter {
-            reserved: match reserved {
-                Ok(v) => v,
-                Err(_) => 0,
-            },
-            my_reservation: match my_reservation {
-                Ok(v) => v,
-                Err(_) => 0,
-            },
-            my_waiting: match my_waiting {
-                Ok(v) => v,
-                Err(_) => 0,
-            },
-        })
-    }
-}
-
 pub struct EventStats {
     pub event: Event,
-    pub adults: Counter,
-    pub children: Counter,
+    /// Reservations by ticket type, in the order of `event.tickets`.
+    pub tickets: Vec<Counter>,
     pub state: EventState,
+    /// Number of edits and state changes, the SEQUENCE of exported calendars.
+    pub revision: u64,
 }
 
 impl EventStats {
-    pub fn new(row: &Row) -> Result<EventStats, rusqlite::Error> {
-        let state: u64 = row.get("state")?;
-        Ok(EventStats {
-            event: Event {
-                id: row.get("id")?,
-                name: row.get("name")?,
-                link: row.get("link")?,
-                max_adults: row.get("max_adults")?,
-                max_children: row.get("max_children")?,
-                max_adults_per_reservation: row.get("max_adults_per_reservation")?,
-                max_children_per_reservation: row.get("max_children_per_reservation")?,
-                ts: row.get("ts")?,
-                remind: 0,
-                adult_ticket_price: row.get::<&str, u64>("adult_ticket_price")?,
-                child_ticket_price: row.get::<&str, u64>("child_ticket_price")?,
-                currency: row.get("currency")?,
-            },
-            adults: Counter::new(
-                row.get("adults"),
-                row.get("my_adults"),
-                row.get("my_wait_adults"),
-            )?,
-            children: Counter::new(
-                row.get("children"),
-                row.get("my_children"),
-                row.get("my_wait_children"),
-            )?,
-            state: match state {
-                0 => EventState::Open,
-                _ => EventState::Closed,
-            },
-        })
+    /// Places left of the ticket type, negative if overbooked.
+    pub fn vacancies(&self, ticket: usize) -> i64 {
+        match (self.event.tickets.get(ticket), self.tickets.get(ticket)) {
+            (Some(t), Some(c)) => t.capacity as i64 - c.reserved as i64,
+            _ => 0,
+        }
     }
-}
-
-pub struct GroupMessage {
-    pub sender: String,
-    pub text: String,
-    pub ts: u64,
-    pub waiting_list: u64,
-}
 
+    pub fn has_vacancies(&self) -> bool {
+        (0..self.event.tickets.len()).any(|i| self.vacancies(i) > 0)
+    }
 
-pub fn add_event(conn: &PooledConnection<SqliteConnectionManager>, e: Event) -> Result<u64, rusqlite::Error> {
-    let event_type = e.get_type();
-    if event_type == EventType::Announcement {
-        if let Err(err) = Url::parse(&e.link) {
-            // todo: fix error
-            return Err(rusqlite::Error::InvalidParameterName(
-                format!("Failed to parse url: {}. {}", e.link, err),
-            ));
-        }
+    /// Confirmed places of all types.
+    pub fn reserved(&self) -> u64 {
+        self.tickets.iter().map(|c| c.reserved).sum()
     }
-    let mut event_id = e.id;
-    if e.id == 0 {
-        let res = conn.execute(
-            "INSERT INTO events (name, link, max_adults, max_children, max_adults_per_reservation, max_children_per_reservation, ts, remind, adult_ticket_price, child_ticket_price, currency) VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?10, ?11)",
-            params![e.name, e.link, e.max_adults, e.max_children, e.max_adults_per_reservation, e.max_children_per_reservation, e.ts, e.remind, e.adult_ticket_price, e.child_ticket_price, e.currency],
-        )?;
-        if res > 0 {
-            let mut stmt = conn
-                .prepare("SELECT id FROM events WHERE name = ?1 AND link = ?2 AND ts = ?3")?;
-            let mut rows = stmt.query(params![e.name, e.link, e.ts])?;
-            if let Some(row) = rows.next()? {
-                event_id = row.get::<&str, u64>("id")?;
-            }
-        }
-    } else {
-        conn.execute(
-            "UPDATE events SET name = ?1, link = ?2, max_adults = ?3, max_children = ?4, max_adults_per_reservation = ?5, max_children_per_reservation = ?6, ts = ?7, remind = ?8 \
-                WHERE id = ?9",
-            params![e.name, e.link, e.max_adults, e.max_children, e.max_adults_per_reservation, e.max_children_per_reservation, e.ts, e.remind, e.id],
-        )?;
-        delete_enqueued_messages(conn, e.id, MessageType::Reminder)?;
+
+    pub fn my_reservation(&self) -> u64 {
+        self.tickets.iter().map(|c| c.my_reservation).sum()
     }
 
-    if event_id != 0 && event_type != EventType::Announcement {
-        let text = format!("\nЗдравствуйте!\nНе забудьте, пожалуйста, что вы записались на\n<a href=\"{}\">{}</a>\
-            \nНачало: {}\nПожалуйста, вовремя откажитесь от мест, если ваши планы изменились.\n",
-            e.link, e.name, format::ts(e.ts), );
-        enqueue_message(conn, 
-            event_id,
-            "Bot",
-            0,
-            MessageType::Reminder,
-            &text,
-            e.remind,
-        )?;
+    pub fn my_waiting(&self) -> u64 {
+        self.tickets.iter().map(|c| c.my_waiting).sum()
     }
-    Ok(event_id)
 }
 
-pub fn enqueue_message(
-    conn: &PooledConnection<SqliteConnectionManager>,
-    event_id: u64,
-    sender: &str,
-    waiting_list: u64,
-    message_type: MessageType,
-    text: &str,
-    send_at: u64,
-) -> Result<(), rusqlite::Error> {
-    debug!("enqueue message {} {}", util::get_unix_time(), send_at);
-    conn.execute(
-        "INSERT INTO messages (event, type, sender, waiting_list, text, ts) VALUES (?1, ?2, ?3, ?4, ?5, ?6)",
-        params![event_id, message_type as u64, sender, waiting_list, text, util::get_unix_time()],
-    )?;
-    let mut stmt = conn.prepare("SELECT last_insert_rowid()")?;
-    let mut rows = stmt.query([])?;
-    if let Some(row) = rows.next()? {
-        let message_id: u64 = row.get(0)?;
-        conn.execute(
-            "INSERT INTO message_outbox (message, send_at) VALUES (?1, ?2)",
-            params![message_id, send_at],
-        )?;
-    }
-    Ok(())
+/// Which events are listed, all of them by default.
+#[derive(Default, Clone, Debug)]
+pub struct EventFilter {
+    /// Category id, 0 for any.
+    pub category: u64,
+    /// Open events with free places only.
+    pub vacancies: bool,
+    /// Events starting before it, 0 for any.
+    pub until: u64,
+    /// Events the user has reservations for, including the waiting list.
+    pub mine: bool,
+    /// Every word has to start a word of the event name, link or venue name, empty for any.
+    /// Events have no description of their own, the link points to the announcement post.
+    pub text: String,
 }
 
-pub fn delete_enqueued_messages(
-    conn: &PooledConnection<SqliteConnectionManager>,
-    event_id: u64,
-    message_type: MessageType,
-) -> Result<(), rusqlite::Error> {
-    let mut stmt = conn.prepare("SELECT id FROM messages WHERE event = ?1 AND type = ?2")?;
-    let mut rows = stmt.query([event_id, message_type as u64])?;
-    while let Some(row) = rows.next()? {
-        let message_id: u64 = row.get(0)?;
-        conn.execute(
-            "DELETE FROM message_outbox WHERE message = ?1",
-            params![message_id],
-        )?;
-        conn.execute(
-            "DELETE FROM messages WHERE id = ?1",
-            params![message_id],
-        )?;
+/// Only events that may still be cancelled are edited, so finished ones don't get new reminders
+/// and change notices.
+pub fn check_editable(s: &EventStats, archived: bool) -> Result<()> {
+    if archived || !s.state.can_change_to(EventState::Cancelled) {
+        return Err(Error::EventNotEditable(s.event.id));
     }
     Ok(())
 }
 
-pub fn prompt_waiting_list(conn: &PooledConnection<SqliteConnectionManager>, event_id: u64) -> Result<(), rusqlite::Error> {
-    if have_vacancies(conn, event_id)? == false {
-        debug!("prompt_waiting_list - no tickets, event {}", event_id);
-        return Ok(());
-    }
-
-    let send_at = get_unix_time() + 10; // give some time to finish multiple cancellations
-    let mut stmt = conn
-        .prepare("SELECT id FROM messages WHERE event = ?1 AND type = ?2")?;
-    let mut rows = stmt.query([event_id, MessageType::WaitingListPrompt as u64])?;
-    if let Some(row) = rows.next()? {
-        let message_id: u64 = row.get("id")?;
-        conn.execute(
-            "INSERT INTO message_outbox (message, send_at) VALUES (?1, ?2)",
-            params![message_id, send_at],
-        )?;
-    } else {
-        if let Ok(event_name) = get_event_name(conn, event_id) {
-            enqueue_message(conn, 
-                event_id,
-                "Bot",
-                1,
-                MessageType::WaitingListPrompt,
-                &format!("Кто-то отменил бронирование на мероприятие: \"{}\".\nВы можете попробовать записаться.", event_name),
-                send_at
-            )?;
+/// Reservations refer to ticket types by position. An edit may change limits and prices and add
+/// new types, but a type with reservations, `booked`, has to stay in its place under its name.
+pub fn check_ticket_types(old: &Event, new: &Event, booked: impl IntoIterator<Item = u64>) -> Result<()> {
+    for ticket in booked {
+        match (old.tickets.get(ticket as usize), new.tickets.get(ticket as usize)) {
+            (Some(o), Some(n)) if o.name == n.name => {}
+            (Some(o), _) => return Err(Error::TicketTypeBooked(o.name.clone())),
+            (None, _) => return Err(Error::TicketTypeBooked(ticket.to_string())),
         }
     }
     Ok(())
 }
 
-pub fn blacklist_absent_participants(
-    conn: &PooledConnection<SqliteConnectionManager>,
-    event_id: u64,
-    admins: &HashSet<u64>,
-    cancel_future_reservations: bool,
-) -> Result<(), rusqlite::Error> {
-    let mut stmt = conn.prepare(
-        "select r.*, p.user from (select event, user, user_name1, user_name2, count(user) as count from reservations where event = ?1 and waiting_list = 0 group by user) as r 
-        left join presence as p on r.event = p.event and r.user = p.user"
-    )?;
-    let mut rows = stmt.query(params![event_id])?;
-    let mut list: Vec<Presence> = Vec::new();
-    let mut presence_checked = false;
-    while let Some(row) = rows.next()? {
-        let present: rusqlite::Result<u64> = row.get(5);
-        if let Err(_) = present {
-            list.push(Presence {
-                user_id: row.get(1)?,
-                user_name1: row.get(2)?,
-                user_name2: row.get(3)?,
-                reserved: row.get(4)?,
-                attachment: None,
-            });
-        } else {
-            presence_checked = true;
-        }
-    }
-    if presence_checked && list.len() > 0 {
-        // Check at least one present.
-        if let Ok(reason) = get_event_name(conn, event_id) {
-            list.iter()
-                .filter(|p| !admins.contains(&p.user_id))
-                .try_for_each(|p| {
-                    ban_user(conn, 
-                        p.user_id,
-                        &p.user_name1,
-                        &p.user_name2,
-                        &reason,
-                        cancel_future_reservations,
-                    )
-                })?;
-        } else {
-            warn!("Failed to get event {}", event_id);
+/// Sums up reservations of an event by ticket type.
+/// Takes (ticket, waiting list, booked by the user, quantity) for groups of reservations.
+pub fn counters(
+    ticket_types: usize,
+    reservations: impl IntoIterator<Item = (u64, u64, bool, u64)>,
+) -> Vec<Counter> {
+    let mut res: Vec<Counter> = (0..ticket_types).map(|_| Counter::default()).collect();
+    for (ticket, waiting_list, mine, quantity) in reservations {
+        if let Some(c) = res.get_mut(ticket as usize) {
+            if waiting_list == 0 {
+                c.reserved += quantity;
+                if mine {
+                    c.my_reservation += quantity;
+                }
+            } else if mine {
+                c.my_waiting += quantity;
+            }
         }
     }
-    Ok(())
+    res
 }
 
-pub fn get_ban_reason(conn: &PooledConnection<SqliteConnectionManager>, user_id: u64) -> Result<String, rusqlite::Error> {
-    let mut stmt = conn
-        .prepare("SELECT reason FROM black_list WHERE user = ?1")?;
-    let mut rows = stmt.query([user_id])?;
-    if let Some(row) = rows.next()? {
-        let reason: String = row.get("reason")?;
-        Ok(reason)
-    } else {
-        Ok("unknown user".to_string())
-    }
+/// A reservation row in the waiting list.
+pub struct WaitingReservation {
+    pub id: u64,
+    pub user: u64,
+    pub ticket: u64,
+    pub quantity: u64,
+    pub ts: u64,
 }
 
-pub fn delete_event(
-    conn: &PooledConnection<SqliteConnectionManager>,
-    event_id: u64,
-    automatic_blacklisting: bool,
-    cancel_future_reservations_on_ban: bool,
-    admins: &HashSet<u64>
-) -> Result<(), rusqlite::Error> {
-    let s = get_event(conn, event_id, 0)?;
-    if automatic_blacklisting && s.event.adult_ticket_price == 0 && s.event.child_ticket_price == 0 {
-        if let Err(e) = blacklist_absent_participants(
-            conn,
-            event_id,
-            admins,
-            cancel_future_reservations_on_ban,
-        ) {
-            // todo: fix error
-            return Err(rusqlite::Error::InvalidParameterName(
-                format!("Failed to blacklist absent participants: {}.", e),
-            ));
+/// Waiting reservations of a user confirmed at once.
+pub struct Promotion {
+    pub user: u64,
+    /// Reservation ids.
+    pub reservations: Vec<u64>,
+    /// Places by ticket type.
+    pub tickets: Vec<u64>,
+}
+
+/// Waiting reservations to confirm when places free up, by user in the order of joining.
+/// A sign-up moves as a whole, only if every ticket type in it has enough free places,
+/// so adults and children are never split; sign-ups that don't fit are skipped.
+pub fn promotions(s: &EventStats, waiting: &[WaitingReservation]) -> Vec<Promotion> {
+    if !s.event.auto_promote || s.state != EventState::Open {
+        return Vec::new();
+    }
+    let mut free: Vec<i64> = (0..s.event.tickets.len()).map(|i| s.vacancies(i)).collect();
+    let mut sorted: Vec<&WaitingReservation> = waiting.iter().collect();
+    sorted.sort_by_key(|w| (w.ts, w.id));
+    // Rows of one sign-up share the user and the time.
+    let mut sign_ups: Vec<Vec<&WaitingReservation>> = Vec::new();
+    for w in sorted {
+        match sign_ups.iter_mut().find(|g| g[0].user == w.user && g[0].ts == w.ts) {
+            Some(g) => g.push(w),
+            None => sign_ups.push(vec![w]),
         }
     }
 
-    if let Err(e) = conn
-        .execute("DELETE FROM reservations WHERE event=?1", params![event_id])
-    {
-        error!("{}", e);
-    }
-    if let Err(e) = conn
-        .execute("DELETE FROM events WHERE id=?1", params![event_id])
-    {
-        error!("{}", e);
-    }
-    if let Err(e) = conn
-        .execute("DELETE FROM attachments WHERE event=?1", params![event_id])
-    {
-        error!("{}", e);
-    }
-    if let Err(e) = conn
-        .execute("DELETE FROM presence WHERE event=?1", params![event_id])
-    {
-        error!("{}", e);
-    }
-    if let Err(e) = conn.execute(
-        "DELETE FROM group_leaders WHERE event=?1",
-        params![event_id],
-    ) {
-        error!("{}", e);
-    }
-    if let Err(e) = conn
-        .execute("DELETE FROM messages WHERE event=?1", params![event_id])
-    {
-        error!("{}", e);
+    let mut res: Vec<Promotion> = Vec::new();
+    for rows in sign_ups {
+        let mut needed = vec![0u64; free.len()];
+        for w in &rows {
+            if let Some(n) = needed.get_mut(w.ticket as usize) {
+                *n += w.quantity;
+            }
+        }
+        if needed.iter().zip(&free).any(|(n, f)| *n as i64 > *f) {
+            continue;
+        }
+        for (f, n) in free.iter_mut().zip(&needed) {
+            *f -= *n as i64;
+        }
+        let user = rows[0].user;
+        if !res.iter().any(|p| p.user == user) {
+            res.push(Promotion { user, reservations: Vec::new(), tickets: vec![0; free.len()] });
+        }
+        let p = res.iter_mut().find(|p| p.user == user).unwrap();
+        p.reservations.extend(rows.iter().map(|w| w.id));
+        for (t, n) in p.tickets.iter_mut().zip(&needed) {
+            *t += n;
+        }
     }
-    Ok(())
+    res
 }
 
-pub fn delete_link(
-    conn: &PooledConnection<SqliteConnectionManager>,
-    link: &str,
-) -> Result<(), rusqlite::Error> {
-    let mut stmt = conn.prepare("select id from events where link = ?1")?;
-    let mut rows = stmt.query(params![link])?;
-    if let Some(row) = rows.next()? {
-        let event_id: u64 = row.get("id")?;
-        delete_event(conn, event_id, false, false, &HashSet::new())
-    } else {
-        Ok(())
-    }
+/// Everything known about a user who interacted with the bot.
+pub struct UserProfile {
+    pub user: User,
+    pub first_seen: u64,
+    pub last_seen: u64,
+    /// False once a message couldn't be delivered, e.g. the user blocked the bot.
+    pub reachable: bool,
+}
+
+/// Actor of the audit records the bot makes on its own, e.g. promotions from the waiting list.
+pub const SYSTEM_ACTOR: u64 = 0;
+
+/// Who did what and when.
+#[derive(Clone)]
+pub struct AuditRecord {
+    pub ts: u64,
+    /// User who performed the action, `SYSTEM_ACTOR` for the bot itself.
+    pub actor: u64,
+    pub action: AuditAction,
+    /// Affected event, 0 if none.
+    pub event_id: u64,
+    /// Affected user, 0 if none.
+    pub user_id: u64,
+    pub details: String,
+}
+
+/// Selects audit records.
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum AuditFilter {
+    All,
+    Event(u64),
+    /// Actions performed by or affecting the user.
+    User(u64),
+}
+
+/// Payment to give back after the event was cancelled.
+#[derive(Clone)]
+pub struct Refund {
+    pub id: u64,
+    pub event_id: u64,
+    /// Event name with its start, the event itself may be deleted by then.
+    pub event_name: String,
+    pub user_id: u64,
+    /// Payer name given at checkout.
+    pub name: String,
+    /// Telegram payment charge id.
+    pub payment: String,
+    pub amount: u64,
+    pub currency: String,
+    pub ts: u64,
+    /// When the money was returned, 0 while pending.
+    pub refunded: u64,
+}
+
+pub struct GroupMessage {
+    pub sender: String,
+    pub text: String,
+    pub ts: u64,
+    pub waiting_list: u64,
 }
 
-pub fn sign_up(
-    conn: &PooledConnection<SqliteConnectionManager>,
-    event_id: u64,
+/// Persistent state of the bot.
+pub trait Storage: Send + Sync {
+    // Events.
+    /// Creates a draft if `e.id` is 0, otherwise updates the event, see `check_ticket_types`.
+    fn add_event(&self, e: Event) -> Result<u64>;
+    /// Creates drafts of all the events or, if any of them fails, of none. Returns the ids in order.
+    fn add_events(&self, events: &[Event]) -> Result<Vec<u64>>;
+    /// Admins see events in every state, other users only listed ones.
+    fn get_events(
+        &self,
+        user: u64,
+        is_admin: bool,
+        filter: &EventFilter,
+        offset: u64,
+        limit: u64,
+    ) -> Result<Vec<EventStats>>;
+    /// Also remembers the event as the current one of the user, for attachments.
+    fn get_event(&self, event_id: u64, user: u64) -> Result<EventStats>;
+    /// Same as `get_event` without touching the current event, for admin and background reads.
+    fn find_event(&self, event_id: u64, user: u64) -> Result<EventStats>;
+    fn get_current_event(&self, user: u64) -> Result<u64>;
+    /// Asks to notify the user when registration for the event opens, or withdraws the request.
+    fn subscribe_to_registration(&self, event_id: u64, user: u64, subscribe: bool) -> Result<()>;
+    fn is_subscribed_to_registration(&self, event_id: u64, user: u64) -> Result<bool>;
+    /// Cancelling an event notifies everybody holding a reservation or waiting for a place
+    /// and creates refunds for completed payments.
+    fn change_event_state(&self, event_id: u64, state: EventState, actor: u64) -> Result<()>;
+    /// Sets capacities of the ticket types in their order.
+    fn set_event_limits(&self, event_id: u64, capacities: &[u64], actor: u64) -> Result<()>;
+    /// Empty `file_id` removes the poster.
+    fn set_event_poster(&self, event_id: u64, file_id: &str) -> Result<()>;
+    fn delete_event(
+        &self,
+        event_id: u64,
+        automatic_blacklisting: bool,
+        cancel_future_reservations_on_ban: bool,
+        admins: &HashSet<u64>,
+        actor: u64,
+    ) -> Result<()>;
+    fn delete_link(&self, link: &str) -> Result<()>;
+    /// Moves events started before `ts` into the archive.
+    /// Reservations and presence are kept, pending messages are dropped.
+    fn archive_old_events(
+        &self,
+        ts: u64,
+        automatic_blacklisting: bool,
+        cancel_future_reservations: bool,
+        admins: &HashSet<u64>,
+    ) -> Result<()>;
+    fn get_archived_events(&self, offset: u64, limit: u64) -> Result<Vec<EventStats>>;
+    /// Deletes archived events started before `ts` with all their data.
+    fn purge_archive(&self, ts: u64) -> Result<()>;
+
+    // Reservations.
+    /// `tickets` are numbers of tickets by type.
+    fn sign_up(
+        &self,
+        event_id: u64,
+        user: &User,
+        tickets: &[u64],
+        wait: u64,
+        ts: u64,
+        amount: u64,
+    ) -> Result<(usize, bool)>;
+    fn checkout(&self, booking: &Booking, order_info: OrderInfo) -> Result<()>;
+    /// Gives back one ticket of the type, a waiting one first.
+    fn cancel(&self, event_id: u64, user: u64, ticket: u64) -> Result<()>;
+    fn wontgo(&self, event_id: u64, user: u64) -> Result<()>;
+    fn delete_reservation(&self, event_id: u64, user_id: u64, actor: u64) -> Result<()>;
+    fn get_participants(
+        &self,
+        event_id: u64,
+        waiting_list: u64,
+        offset: u64,
+        limit: u64,
+        state: ReservationState,
+    ) -> Result<Vec<Participant>>;
+    fn add_attachment(&self, event_id: u64, user: u64, attachment: &str) -> Result<usize>;
+    fn get_attachment(&self, event_id: u64, user: u64) -> Result<Option<String>>;
+    fn clear_failed_payments(&self, ts: u64) -> Result<()>;
+
+    // Black list.
+    fn add_to_black_list(
+        &self,
+        user: u64,
+        cancel_future_reservations: bool,
+        actor: u64,
+    ) -> Result<()>;
+    fn ban_user(
+        &self,
+        user: u64,
+        user_name1: &str,
+        user_name2: &str,
+        reason: &str,
+        cancel_future_reservations: bool,
+    ) -> Result<()>;
+    fn remove_from_black_list(&self, user: u64) -> Result<()>;
+    fn get_black_list(&self, offset: u64, limit: u64) -> Result<Vec<User>>;
+    fn get_ban_reason(&self, user_id: u64) -> Result<String>;
+    fn clear_black_list(&self, ts: u64) -> Result<()>;
+
+    // Messages.
+    fn enqueue_message(
+        &self,
+        event_id: u64,
+        sender: &str,
+        waiting_list: u64,
+        message_type: MessageType,
+        text: &str,
+        send_at: u64,
+    ) -> Result<()>;
+    /// Registration notifications go to subscribers instead of participants.
+    fn get_pending_messages(&self, ts: u64, max_messages: u64) -> Result<Vec<MessageBatch>>;
+    fn save_receipt(&self, message_id: u64, user: u64) -> Result<()>;
+    fn get_group_messages(
+        &self,
+        event_id: u64,
+        waiting_list: Option<u64>,
+    ) -> Result<Vec<GroupMessage>>;
+
+    // Presence.
+    fn get_presence_list(&self, event_id: u64, offset: u64, limit: u64) -> Result<Vec<Presence>>;
+    /// Confirmed participants, whether present or not.
+    fn get_attendance_list(&self, event_id: u64, offset: u64, limit: u64) -> Result<Vec<Presence>>;
+    fn confirm_presence(&self, event_id: u64, user_id: u64) -> Result<()>;
+    fn is_group_leader(&self, event_id: u64, user_id: u64) -> Result<bool>;
+    fn set_group_leader(&self, event_id: u64, user_id: u64) -> Result<()>;
+
+    // Users.
+    /// Records an update from the user: creates the profile on first contact, refreshes names
+    /// and marks the user reachable on later ones.
+    fn save_user(&self, user: &User, ts: u64) -> Result<()>;
+    fn set_user_reachable(&self, user_id: u64, reachable: bool) -> Result<()>;
+    fn get_user(&self, user_id: u64) -> Result<Option<UserProfile>>;
+    /// Everyone who ever interacted with the bot, most recently seen first.
+    fn get_users(&self, offset: u64, limit: u64) -> Result<Vec<UserProfile>>;
+
+    // Series.
+    /// Creates a series if `s.id` is 0. Otherwise updates it, upcoming occurrences take over
+    /// the changes and their participants are notified.
+    fn add_series(&self, s: Series, actor: u64) -> Result<u64>;
+    fn get_series(&self, series_id: u64) -> Result<Series>;
+    /// Series which are not cancelled.
+    fn get_series_list(&self) -> Result<Vec<Series>>;
+    /// Stops the series and cancels its upcoming occurrences.
+    fn cancel_series(&self, series_id: u64, actor: u64) -> Result<()>;
+    /// Opens registration for occurrences starting before `ts`. Returns ids of created events.
+    fn materialize_series(&self, ts: u64) -> Result<Vec<u64>>;
+
+    // Venues.
+    /// Creates a venue if `v.id` is 0, otherwise updates it.
+    fn add_venue(&self, v: Venue, actor: u64) -> Result<u64>;
+    fn get_venue(&self, venue_id: u64) -> Result<Venue>;
+    /// All venues by name.
+    fn get_venues(&self) -> Result<Vec<Venue>>;
+
+    // Categories.
+    /// Returns the id of the category with this name, creating it if needed.
+    fn add_category(&self, name: &str) -> Result<u64>;
+    /// All categories by name.
+    fn get_categories(&self) -> Result<Vec<Category>>;
+
+    // Questionnaires.
+    /// Answers of the user to the event questions by position, empty for unanswered ones.
+    fn get_answers(&self, event_id: u64, user: u64) -> Result<Vec<String>>;
+    /// Only users holding a reservation or a place in the waiting list answer. Answers to
+    /// questions the event doesn't have are ignored.
+    fn save_answer(&self, event_id: u64, user: u64, question: u64, answer: &str) -> Result<()>;
+    fn clear_answers(&self, event_id: u64, user: u64) -> Result<()>;
+    /// Event whose questions the user is answering, 0 if none.
+    fn get_questionnaire(&self, user: u64) -> Result<u64>;
+    /// 0 finishes the questionnaire.
+    fn set_questionnaire(&self, user: u64, event_id: u64) -> Result<()>;
+
+    // Refunds.
+    /// Pending refunds first, the latest ones on top.
+    fn get_refunds(&self, offset: u64, limit: u64) -> Result<Vec<Refund>>;
+    /// Records that the payment was given back.
+    fn complete_refund(&self, refund_id: u64, actor: u64) -> Result<()>;
+
+    // Search.
+    /// Text the event list of the user is searched by, kept for paging through the results.
+    /// Empty text ends the search.
+    fn save_search(&self, user: u64, text: &str) -> Result<()>;
+    /// Empty if the user isn't searching.
+    fn get_search(&self, user: u64) -> Result<String>;
+
+    // Imports.
+    /// Keeps events parsed from a document until the admin confirms them, replacing the
+    /// previous ones of this admin.
+    fn save_import(&self, user: u64, events: &str) -> Result<()>;
+    /// Returns the saved events and forgets them, so they are created once.
+    fn take_import(&self, user: u64) -> Result<Option<String>>;
+
+    // Audit log.
+    /// Latest records first.
+    fn get_audit_log(
+        &self,
+        filter: AuditFilter,
+        offset: u64,
+        limit: u64,
+    ) -> Result<Vec<AuditRecord>>;
+}
+
+/// What to do with a sign up request.
+pub enum SignUp {
+    /// Add a new reservation in the given state.
+    Reserve(ReservationState),
+    /// Confirm a place of the ticket type the user already holds on the waiting list.
+    Confirm { ticket: u64 },
+    /// The user has reached the reservation limit.
+    Rejected,
+    BlackListed,
+}
+
+/// Facts about the user which a backend looks up before applying the booking rules.
+pub struct SignUpChecks {
+    pub black_listed: bool,
+    /// The user holds a reservation for another overlapping event which doesn't allow overlaps.
+    pub time_conflict: bool,
+}
+
+/// Booking rules shared by all storage backends.
+pub fn check_sign_up(
+    s: &EventStats,
     user: &User,
-    adults: u64,
-    children: u64,
+    tickets: &[u64],
     wait: u64,
     ts: u64,
     amount: u64,
-) -> anyhow::Result<(usize, bool)> {
-    let user_id = user.id.0;
-    let s = get_event(conn, event_id, user_id)?;
+    checks: SignUpChecks,
+) -> Result<SignUp> {
     let event_type = s.event.get_type();
 
-    if ts > s.event.ts || (s.state != EventState::Open && user.is_admin == false) {
-        return Err(anyhow!("Запись остановлена."));
+    let open = match s.state {
+        EventState::Open => true,
+        EventState::Closed | EventState::Draft => user.is_admin,
+        EventState::Cancelled | EventState::Completed => false,
+    };
+    if ts > s.event.ts || !open {
+        return Err(Error::RegistrationClosed);
+    }
+    if !user.is_admin && !s.event.registration_open(ts) {
+        return Err(if ts < s.event.registration_opens {
+            Error::RegistrationNotOpen
+        } else {
+            Error::RegistrationClosed
+        });
+    }
+
+    if tickets.len() > s.event.tickets.len() || tickets.iter().sum::<u64>() == 0 {
+        return Err(Error::NotBookable);
     }
 
     // Check event limits
-    if (wait == 0 || event_type == EventType::Paid) &&
-        (adults as i64 > s.event.max_adults as i64 - s.adults.reserved as i64 || 
-        children as i64 > s.event.max_children as i64 - s.children.reserved as i64) {
-        return Err(anyhow!("К сожалению, свободные места закончились."));
+    if (wait == 0 || event_type == EventType::Paid)
+        && tickets.iter().enumerate().any(|(i, n)| *n as i64 > s.vacancies(i))
+    {
+        return Err(Error::SoldOut);
     }
 
     let state = match event_type {
-        EventType::Free => { 
-            if let Ok(black_listed) = is_in_black_list(conn, user_id) {
-                if black_listed {
-                    return Ok((0, true));
-                }
+        EventType::Free => {
+            if checks.black_listed {
+                return Ok(SignUp::BlackListed);
             }
 
             // Check conflicting time
-            let mut stmt = conn
-                .prepare("select events.id from events join reservations as r on events.id = r.event where events.ts = ?1 and r.user = ?2 and events.id != ?3")?;
-            let mut rows = stmt.query(params![s.event.ts, user_id, s.event.id])?;
-            if let Some(_) = rows.next()? {
-                return Err(anyhow!(
-                    "Вы уже записаны на другое мероприятие в это время."
-                ));
+            if checks.time_conflict && !s.event.allow_overlap {
+                return Err(Error::TimeConflict);
             }
             ReservationState::Free
         }
         EventType::Paid => {
             // pre checkout?
-            if s.event.adult_ticket_price * adults + s.event.child_ticket_price * children != amount {
-                return Err(anyhow!("Wrong tranaction amount"));
+            if s.event.price(tickets) != amount {
+                return Err(Error::WrongAmount);
             }
             ReservationState::PaymentPending
         }
         _ => {
-            return Err(anyhow!("Wrong event type"));
+            return Err(Error::NotBookable);
         }
     };
 
     // Check user limits
-    if s.adults.my_reservation + s.adults.my_waiting + adults
-        > s.event.max_adults_per_reservation
+    for (i, (n, (t, c))) in tickets
+        .iter()
+        .zip(s.event.tickets.iter().zip(s.tickets.iter()))
+        .enumerate()
     {
-        if s.adults.my_reservation + adults > s.event.max_adults_per_reservation {
-            return Ok((0, false));
-        } else {
-            move_from_waiting_list(conn, event_id, user_id, 1, 0)?;
-            return Ok((1, false));
-        }
-    }
-    if s.children.my_reservation + s.children.my_waiting + children
-        > s.event.max_children_per_reservation
-    {
-        if s.children.my_reservation + children > s.event.max_children_per_reservation {
-            return Ok((0, false));
-        } else {
-            move_from_waiting_list(conn, event_id, user_id, 0, 1)?;
-            return Ok((1, false));
+        if *n > 0 && c.my_reservation + c.my_waiting + n > t.max_per_reservation {
+            if c.my_reservation + n > t.max_per_reservation {
+                return Ok(SignUp::Rejected);
+            } else {
+                return Ok(SignUp::Confirm { ticket: i as u64 });
+            }
         }
     }
-
-    Ok((conn.execute(
-        "INSERT INTO reservations (event, user, user_name1, user_name2, adults, children, waiting_list, ts, state) VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9)",
-        params![event_id, user_id, user.user_name1, user.user_name2, adults, children, wait, ts, state as u64],
-    )?, false))    
+    Ok(SignUp::Reserve(state))
 }
 
-pub fn checkout(
-    conn: &PooledConnection<SqliteConnectionManager>,
-    booking: &Booking,
-    order_info: OrderInfo,
-) -> anyhow::Result<()> {
-    let s = get_event(conn, booking.event_id, booking.user_id)?;
-    if s.event.adult_ticket_price * booking.adults + s.event.child_ticket_price * booking.children != order_info.amount {
-        return Err(anyhow!("Wrong tranaction amount"));
+/// Checks that the paid amount matches the booked tickets.
+pub fn check_amount(s: &EventStats, booking: &Booking, order_info: &OrderInfo) -> Result<()> {
+    if s.event.price(&booking.tickets) != order_info.amount {
+        return Err(Error::WrongAmount);
     }
-
-    let mut stmt = conn
-        .prepare("select id from reservations where event = ?1 and user = ?2 and state = ?3 and adults = ?4 and children = ?5 limit 1")?;
-    let mut rows = stmt.query(params![booking.event_id, booking.user_id, ReservationState::PaymentPending as u64, booking.adults, booking.children])?;
-    if let Some(row) = rows.next()? {
-        let id: u64 = row.get("id")?;
-        conn.execute("UPDATE reservations SET state = ?1, payment = ?2, user_name1 = ?3 WHERE id = ?4",
-            params![ReservationState::PaymentCompleted as u64, serde_json::to_string(&order_info)?, order_info.name, id],
-        )?;
-        Ok(())
-    } else {
-        Err(anyhow!("Failed to find reservation for event {}, user {}.", booking.event_id, booking.user_id))
-    }
-}
-
-fn move_from_waiting_list(
-    conn: &PooledConnection<SqliteConnectionManager>,
-    event_id: u64,
-    user_id: u64,
-    adults: u64,
-    children: u64,
-) -> Result<(), rusqlite::Error> {
-    conn.execute("UPDATE reservations SET waiting_list = 0  WHERE id in \
-        (SELECT id FROM reservations where event = ?1 and user = ?2 and waiting_list = 1 and adults = ?3 and children = ?4 order by ts limit 1)",
-        params![event_id, user_id, adults, children],
-    )?;
     Ok(())
 }
 
-pub fn add_attachment(
-    conn: &PooledConnection<SqliteConnectionManager>,
-    event_id: u64,
-    user: u64,
-    attachment: &str,
-) -> Result<usize, rusqlite::Error> {
-    let msg = if attachment.len() < 256 {
-        format!("{}...", attachment.chars().take(256).collect::<String>())
-    } else {
-        attachment.to_string()
-    };
-
-    let s = get_event(conn, event_id, user)?;
-    if s.adults.my_reservation > 0
-        || s.adults.my_waiting > 0
-        || s.children.my_reservation > 0
-        || s.children.my_waiting > 0
-    {
-        conn.execute(
-            "INSERT INTO attachments (event, user, attachment) VALUES (?1, ?2, ?3) ON CONFLICT (event, user) DO \
-            UPDATE SET attachment=excluded.attachment",
-            params![event_id, user, msg],
-        )
-    } else {
-        Ok(0)
-    }
-}
-
-pub fn cancel(conn: &PooledConnection<SqliteConnectionManager>, event_id: u64, user: u64, adults: u64) -> Result<(), rusqlite::Error> {
-    let state_changed = have_vacancies(conn, event_id)? == false;
-    conn.execute(
-        "DELETE FROM reservations WHERE id IN (SELECT id FROM reservations WHERE event=?1 AND user=?2 AND adults = ?3 ORDER BY waiting_list DESC LIMIT 1)",
-        params![event_id, user, adults],
-    )?;
-    if state_changed {
-        prompt_waiting_list(conn, event_id)
-    } else {
-        Ok(())
-    }
+/// Lower case words of a search query or a searched text.
+pub fn search_words(text: &str) -> Vec<String> {
+    text.split(|c: char| !c.is_alphanumeric())
+        .filter(|w| !w.is_empty())
+        .map(|w| w.to_lowercase())
+        .collect()
 }
 
-pub fn wontgo(conn: &PooledConnection<SqliteConnectionManager>, event_id: u64, user: u64) -> Result<(), rusqlite::Error> {
-    let state_changed = have_vacancies(conn, event_id)? == false;
-    conn.execute(
-        "DELETE FROM reservations WHERE event=?1 AND user=?2",
-        params![event_id, user],
-    )?;
-    if state_changed {
-        prompt_waiting_list(conn, event_id)
-    } else {
-        Ok(())
-    }
-}
-
-fn have_vacancies(conn: &PooledConnection<SqliteConnectionManager>, event_id: u64) -> Result<bool, rusqlite::Error> {
-    let (vacant_adults, vacant_children) = get_vacancies(conn, event_id)?;
-    if vacant_adults + vacant_children > 0 {
-        Ok(true)
-    } else {
-        Ok(false)
+/// Refunds of completed payments for the cancelled event.
+/// Takes (user, order info) of paid reservations. A checkout of several ticket types spans
+/// several reservations, so it is refunded once.
+pub fn refunds(
+    s: &EventStats,
+    payments: impl IntoIterator<Item = (u64, String)>,
+    ts: u64,
+) -> Result<Vec<Refund>> {
+    let mut seen = HashSet::new();
+    let mut res = Vec::new();
+    for (user_id, payment) in payments {
+        if !seen.insert((user_id, payment.clone())) {
+            continue;
+        }
+        let order_info: OrderInfo = serde_json::from_str(&payment)?;
+        res.push(Refund {
+            id: 0,
+            event_id: s.event.id,
+            event_name: event_name(&s.event),
+            user_id,
+            name: order_info.name,
+            payment: order_info.id,
+            amount: order_info.amount,
+            currency: s.event.currency.clone(),
+            ts,
+            refunded: 0,
+        });
     }
+    Ok(res)
 }
 
-fn get_vacancies(conn: &PooledConnection<SqliteConnectionManager>, event_id: u64) -> Result<(u64, u64), rusqlite::Error> {
-    let mut vacant_adults: u64 = 0;
-    let mut vacant_children: u64 = 0;
-    let mut stmt = conn.prepare(
-        "SELECT a.max_adults, a.max_children, b.adults, b.children, a.id FROM events as a \
-        LEFT JOIN (SELECT sum(adults) as adults, sum(children) as children, event FROM reservations WHERE event = ?1 AND waiting_list = 0 group by event) as b \
-        ON a.id = b.event WHERE id = ?1 group by id"
-    )?;
-    let mut rows = stmt.query(params![event_id])?;
-    if let Some(row) = rows.next()? {
-        let max_adults: u64 = row.get(0)?;
-        let max_children: u64 = row.get(1)?;
-        let reserved_adults: u64 = match row.get(2) {
-            Ok(v) => v,
-            Err(_) => 0,
-        };
-        let reserved_children: u64 = match row.get(3) {
-            Ok(v) => v,
-            Err(_) => 0,
-        };
-        vacant_adults = max_adults - reserved_adults;
-        vacant_children = max_children - reserved_children;
-    }
-    Ok((vacant_adults, vacant_children))
+/// Absent participants are blacklisted only for events which actually took place.
+pub fn took_place(s: &EventStats, now: u64) -> bool {
+    s.state != EventState::Cancelled && s.event.ts <= now
 }
 
-pub fn get_attachment(
-    conn: &PooledConnection<SqliteConnectionManager>,
-    event_id: u64,
-    user: u64,
-) -> Result<Option<String>, rusqlite::Error> {
-    let mut stmt = conn
-        .prepare("SELECT attachment FROM attachments WHERE event = ?1 AND user = ?2")?;
-    let mut rows = stmt.query(params![event_id, user])?;
-    if let Some(row) = rows.next()? {
-        let attachment: String = row.get(0)?;
-        Ok(Some(attachment))
-    } else {
-        Ok(None)
+/// The same local time `days` later in the zone of the event, so that times don't move when
+/// daylight saving time starts or ends. Times skipped by the change are shifted by whole days.
+pub fn add_days(e: &Event, ts: u64, days: i64) -> u64 {
+    if days == 0 {
+        return ts;
     }
-}
-
-pub fn get_events(
-    conn: &PooledConnection<SqliteConnectionManager>,
-    user: u64,
-    offset: u64,
-    limit: u64,
-) -> Result<Vec<EventStats>, rusqlite::Error> {
-    let mut stmt = conn.prepare(
-        "select a.*, b.my_adults, b.my_children, c.my_wait_adults, c.my_wait_children FROM \
-        (SELECT events.id, events.name, events.link, events.max_adults, events.max_children, events.max_adults_per_reservation, events.max_children_per_reservation, events.ts, r.adults, r.children, events.state, events.adult_ticket_price, events.child_ticket_price, events.currency FROM events \
-        LEFT JOIN (SELECT sum(adults) as adults, sum(children) as children, event FROM reservations WHERE waiting_list = 0 GROUP BY event) as r ON events.id = r.event ORDER BY ts LIMIT ?2 OFFSET ?3) as a \
-        LEFT JOIN (SELECT sum(adults) as my_adults, sum(children) as my_children, event FROM reservations WHERE waiting_list = 0 AND user = ?1 GROUP BY event) as b ON a.id = b.event \
-        LEFT JOIN (SELECT sum(adults) as my_wait_adults, sum(children) as my_wait_children, event FROM reservations WHERE waiting_list = 1 AND user = ?1 GROUP BY event) as c ON a.id = c.event"
-    )?;
-    let mut rows = stmt.query([user, limit, offset * limit])?;
-    let mut res = Vec::new();
-    while let Some(row) = rows.next()? {
-        res.push(EventStats::new(row)?);
+    let tz = format::time_zone(e);
+    let local = DateTime::from_timestamp(ts as i64, 0)
+        .zip(TimeDelta::try_days(days))
+        .and_then(|(t, d)| t.with_timezone(&tz).naive_local().checked_add_signed(d));
+    match local.and_then(|t| tz.from_local_datetime(&t).earliest()) {
+        Some(t) => t.timestamp().max(0) as u64,
+        None => (ts as i64).saturating_add(days.saturating_mul(24 * 60 * 60)).max(0) as u64,
     }
-    Ok(res)
 }
 
-pub fn get_event(conn: &PooledConnection<SqliteConnectionManager>, event_id: u64, user: u64) -> Result<EventStats, rusqlite::Error> {
-    let mut stmt = conn.prepare(
-        "select a.*, b.my_adults, b.my_children, c.my_wait_adults, c.my_wait_children FROM \
-        (SELECT events.id, events.name, events.link, events.max_adults, events.max_children, events.max_adults_per_reservation, events.max_children_per_reservation, events.ts, r.adults, r.children, events.state, events.adult_ticket_price, events.child_ticket_price, events.currency FROM events \
-        LEFT JOIN (SELECT sum(adults) as adults, sum(children) as children, event FROM reservations WHERE waiting_list = 0 GROUP BY event) as r ON events.id = r.event) as a \
-        LEFT JOIN (SELECT sum(adults) as my_adults, sum(children) as my_children, event FROM reservations WHERE waiting_list = 0 AND user = ?1 GROUP BY event) as b ON a.id = b.event \
-        LEFT JOIN (SELECT sum(adults) as my_wait_adults, sum(children) as my_wait_children, event FROM reservations WHERE waiting_list = 1 AND user = ?1 GROUP BY event) as c ON a.id = c.event WHERE a.id = ?2"
-
-    )?;
-    let mut rows = stmt.query([user, event_id])?;
-    if let Some(row) = rows.next()? {
-        set_current_event(conn, user, event_id)?;
-        Ok(EventStats::new(row)?)
-    } else {
-        Err(rusqlite::Error::InvalidParameterName(
-            format!("Failed to find event {}", event_id),
-        ))
-    }
+/// `t` moved along with the start moving from `from` to `to`: by whole days in the local time
+/// of the event and by the rest in seconds.
+pub fn shift_local(e: &Event, from: u64, to: u64, t: u64) -> u64 {
+    let tz = format::time_zone(e);
+    let date = |ts: u64| DateTime::from_timestamp(ts as i64, 0).unwrap_or_default().with_timezone(&tz).date_naive();
+    let days = (date(to) - date(from)).num_days();
+    let rest = to as i64 - add_days(e, from, days) as i64;
+    (add_days(e, t, days) as i64 + rest).max(0) as u64
 }
 
-pub fn get_event_name(conn: &PooledConnection<SqliteConnectionManager>, event_id: u64) -> Result<String, rusqlite::Error> {
-    let mut stmt = conn
-        .prepare("SELECT events.name, events.ts FROM events WHERE id = ?1")?;
-    let mut rows = stmt.query([event_id])?;
-    if let Some(row) = rows.next()? {
-        let name: String = row.get("name")?;
-        let ts: u64 = row.get("ts")?;
-        Ok(format!("{} {}", format::ts(ts), name))
-    } else {
-        Err(rusqlite::Error::InvalidParameterName(
-            "Failed to find event".to_string(),
-        ))
+/// Occurrence of the series starting at `ts`, not saved yet. The reminder keeps its local time
+/// relative to the start.
+pub fn occurrence(s: &Series, ts: u64) -> Event {
+    Event {
+        id: 0,
+        ts,
+        remind: shift_local(&s.template, s.template.ts, ts, s.template.remind),
+        series_id: s.id,
+        ..s.template.clone()
     }
 }
 
-pub fn get_participants(
-    conn: &PooledConnection<SqliteConnectionManager>,
-    event_id: u64,
-    waiting_list: u64,
-    offset: u64,
-    limit: u64,
-    state: ReservationState,
-) -> Result<Vec<Participant>, rusqlite::Error> {
-    let mut stmt;
-    let mut rows = if limit == 0 {
-        stmt = conn.prepare(
-        "SELECT a.*, b.attachment FROM (SELECT sum(adults) as adults, sum(children) as children, user, user_name1, user_name2, event, ts FROM reservations WHERE waiting_list = ?1 AND event = ?2 AND state = ?3 group by event, user ORDER BY ts) as a \
-        LEFT JOIN attachments as b ON a.event = b.event and a.user = b.user"
-        )?;
-        stmt.query([waiting_list, event_id, state as u64])?
-    } else {
-        stmt = conn.prepare(
-            "SELECT a.*, b.attachment FROM (SELECT sum(adults) as adults, sum(children) as children, user, user_name1, user_name2, event, ts FROM reservations WHERE waiting_list = ?1 AND event = ?2 AND state = ?3 group by event, user ORDER BY ts LIMIT ?4 OFFSET ?5) as a \
-            LEFT JOIN attachments as b ON a.event = b.event and a.user = b.user"
-            )?;
-        stmt.query([waiting_list, event_id, state as u64, limit, offset * limit])?
+/// Copy of the event starting at `ts`, not saved yet. The reminder and the registration
+/// window keep their local time relative to the start.
+pub fn clone_event(e: &Event, ts: u64) -> Event {
+    let shift = |t: u64| match t {
+        0 => 0,
+        t => shift_local(e, e.ts, ts, t),
     };
-    let mut res = Vec::new();
-    while let Some(row) = rows.next()? {
-        res.push(Participant {
-            adults: row.get(0)?,
-            children: row.get(1)?,
-            user_id: row.get(2)?,
-            user_name1: row.get(3)?,
-            user_name2: row.get(4)?,
-            attachment: match row.get(7) {
-                Ok(v) => Some(v),
-                Err(_) => None,
-            },
-        });
+    Event {
+        id: 0,
+        ts,
+        remind: shift(e.remind),
+        series_id: 0,
+        registration_opens: shift(e.registration_opens),
+        registration_closes: shift(e.registration_closes),
+        ..e.clone()
     }
-    Ok(res)
 }
 
-pub fn get_presence_list(
-    conn: &PooledConnection<SqliteConnectionManager>,
-    event_id: u64,
-    offset: u64,
-    limit: u64,
-) -> Result<Vec<Presence>, rusqlite::Error> {
-    let mut stmt = conn.prepare(
-            "select r.*, p.user, a.attachment from (select event, user, user_name1, user_name2, count(user) from reservations where event = ?1 and waiting_list = 0 group by user) as r \
-            left join presence as p on r.event = p.event and r.user = p.user \
-            left join attachments as a on r.event = a.event and r.user = a.user \
-            where p.user IS NULL order by r.user_name1 LIMIT ?2 OFFSET ?3"
-    )?;
-    let mut rows = stmt.query([event_id, limit, offset * limit])?;
-    let mut res = Vec::new();
-    while let Some(row) = rows.next()? {
-        res.push(Presence {
-            user_id: row.get(1)?,
-            user_name1: row.get(2)?,
-            user_name2: row.get(3)?,
-            reserved: row.get(4)?,
-            attachment: match row.get(6) {
-                Ok(v) => Some(v),
-                Err(_) => None,
-            },
-        });
-    }
-    Ok(res)
+/// Occurrences affected by changes of the whole series: not started and not called off yet.
+pub fn is_upcoming(s: &EventStats, now: u64) -> bool {
+    s.event.ts > now && matches!(s.state, EventState::Open | EventState::Closed | EventState::Draft)
 }
 
-pub fn confirm_presence(conn: &PooledConnection<SqliteConnectionManager>, event_id: u64, user_id: u64) -> Result<(), rusqlite::Error> {
-    conn.execute(
-        "insert into presence (event, user) values (?1, ?2)",
-        params![event_id, user_id],
-    )?;
-    Ok(())
-}
-
-pub fn is_group_leader(conn: &PooledConnection<SqliteConnectionManager>, event_id: u64, user_id: u64) -> Result<bool, rusqlite::Error> {
-    let mut stmt = conn
-        .prepare("SELECT event FROM group_leaders WHERE event = ?1 AND user = ?2")?;
-    let mut rows = stmt.query(params![event_id, user_id])?;
-    if let Some(_) = rows.next()? {
-        Ok(true)
-    } else {
-        Ok(false)
+/// Start of the next occurrence to create after the series changed from `old` to `new`.
+/// Occurrences created before are moved along with the start of the series.
+pub fn next_occurrence(old: &Series, new: &Series) -> u64 {
+    if old.next_ts <= old.template.ts {
+        return new.template.ts;
     }
+    let last = add_days(&old.template, old.next_ts, -(old.period as i64));
+    let last = shift_local(&new.template, old.template.ts, new.template.ts, last);
+    add_days(&new.template, last, new.period as i64)
 }
 
-pub fn set_group_leader(conn: &PooledConnection<SqliteConnectionManager>, event_id: u64, user_id: u64) -> Result<(), rusqlite::Error> {
-    conn.execute(
-        "insert into group_leaders (event, user) values (?1, ?2)",
-        params![event_id, user_id],
-    )?;
-    Ok(())
+/// Numbers of tickets by type for a single ticket.
+pub fn one_ticket(ticket: u64) -> Vec<u64> {
+    let mut tickets = vec![0; ticket as usize + 1];
+    tickets[ticket as usize] = 1;
+    tickets
 }
 
-pub fn delete_reservation(conn: &PooledConnection<SqliteConnectionManager>, event_id: u64, user_id: u64) -> Result<(), rusqlite::Error> {
-    let state_changed = have_vacancies(conn, event_id)? == false;
-    conn.execute(
-        "delete from reservations where event = ?1 and user = ?2",
-        params![event_id, user_id],
-    )?;
-    if state_changed {
-        prompt_waiting_list(conn, event_id)
-    } else {
-        Ok(())
-    }
-}
-
-pub fn get_pending_messages(
-    conn: &PooledConnection<SqliteConnectionManager>,
-    ts: u64,
-    mut max_messages: u64,
-) -> Result<Vec<MessageBatch>, rusqlite::Error> {
-    //debug!("get_pending_messages {}", ts);
-    let mut stmt = conn.prepare(
-        "SELECT m.*, o.send_at, e.adult_ticket_price, e.child_ticket_price FROM message_outbox as o \
-        JOIN messages as m ON o.message = m.id \
-        JOIN events as e ON m.event = e.id \
-        WHERE o.send_at < ?1",
-    )?;
-    let mut rows = stmt.query([ts])?;
-    let mut res = Vec::new();
-    while let Some(row) = rows.next()? {
-        let message_type: u64 = row.get("type")?;
-        let batch = MessageBatch {
-            message_id: row.get("id")?,
-            event_id: row.get("event")?,
-            sender: row.get("sender")?,
-            message_type: num::FromPrimitive::from_u64(message_type).unwrap(),
-            waiting_list: row.get("waiting_list")?,
-            text: row.get("text")?,
-            is_paid: row.get::<&str, u64>("adult_ticket_price")? != 0 || row.get::<&str, u64>("child_ticket_price")? != 0,
-            recipients: Vec::new(),
-        };
-        res.push(batch);
-
-        let batch = res.last_mut().unwrap();
-        let mut collect_users = true;
-        if batch.message_type == MessageType::WaitingListPrompt
-            && have_vacancies(conn, batch.event_id)? == false
-        {
-            collect_users = false;
-        }
-
-        if collect_users {
-            let mut stmt = conn.prepare(
-                "SELECT r.user, s.message as sent FROM \
-                        (select user, ts from reservations WHERE event = ?1 AND waiting_list = ?2 GROUP BY user) as r 
-                        LEFT JOIN (select user, message from message_sent where message = ?3) as s 
-                        ON r.user = s.user
-                        WHERE sent is null ORDER BY r.ts LIMIT ?4"
-            )?;
-            let mut rows = stmt.query([
-                batch.event_id,
-                batch.waiting_list,
-                batch.message_id,
-                max_messages,
-            ])?;
-
-            while let Some(row) = rows.next()? {
-                let recipient: u64 = row.get("user")?;
-                batch.recipients.push(recipient);
-                max_messages -= 1;
-                if max_messages == 0 {
-                    return Ok(res);
-                }
-
-                if batch.message_type == MessageType::WaitingListPrompt {
-                    break; // take not more than one at a time
-                }
-            }
-        }
-        if batch.recipients.len() == 0 {
-            // Done with the message.
-            debug!("finished sending message {}", batch.message_id);
-            conn.execute(
-                "DELETE FROM message_outbox WHERE message = ?1",
-                params![batch.message_id],
-            )?;
-            conn.execute(
-                "DELETE FROM message_sent WHERE message = ?1",
-                params![batch.message_id],
-            )?;
-        }
+/// Audit record details of a reservation change.
+pub fn reservation_details(e: &Event, tickets: &[u64], waiting_list: u64) -> String {
+    let mut details: Vec<String> = tickets
+        .iter()
+        .enumerate()
+        .filter(|(_, n)| **n > 0)
+        .map(|(i, n)| match e.tickets.get(i) {
+            Some(t) => format!("{} {}", t.name, n),
+            None => format!("ticket {} {}", i, n),
+        })
+        .collect();
+    if waiting_list > 0 {
+        details.push("waiting list".to_string());
     }
-    Ok(res)
+    details.join(", ")
 }
 
-
-fn set_current_event(conn: &PooledConnection<SqliteConnectionManager>, user_id: u64, event_id: u64) -> Result<(), rusqlite::Error> {
-    conn.execute(
-        "insert or replace into current_events (user, event) values (?1, ?2)",
-        params![user_id, event_id],
-    )?;
-    Ok(())
+pub fn reminder_text(e: &Event) -> String {
+    format!("\nЗдравствуйте!\nНе забудьте, пожалуйста, что вы записались на\n<a href=\"{}\">{}</a>\
+        \nНачало: {}\nПожалуйста, вовремя откажитесь от мест, если ваши планы изменились.\n",
+        e.link, e.name, crate::format::event_ts(e, e.ts), )
 }
 
-pub fn get_current_event(conn: &PooledConnection<SqliteConnectionManager>, user_id: u64) -> Result<u64, rusqlite::Error> {
-    let mut stmt = conn
-        .prepare("SELECT event FROM current_events WHERE user=?1")?;
-    let mut rows = stmt.query([user_id])?;
-    if let Some(row) = rows.next()? {
-        let event_id: u64 = row.get(0)?;
-        Ok(event_id)
-    } else {
-        Ok(0)
+/// Summary of changes participants should know about, `None` if nothing important changed.
+pub fn event_change_text(old: &Event, new: &Event) -> Option<String> {
+    let mut changes = String::new();
+    if old.name != new.name {
+        changes.push_str(&format!("\nНазвание изменено, прежнее: \"{}\"", old.name));
     }
-}
-
-pub fn clear_old_events(
-    conn: &PooledConnection<SqliteConnectionManager>,
-    ts: u64,
-    automatic_blacklisting: bool,
-    cancel_future_reservations: bool,
-    admins: &HashSet<u64>,
-) -> Result<(), rusqlite::Error> {
-    let mut stmt = conn.prepare("SELECT id FROM events WHERE ts < ?1")?;
-    let mut rows = stmt.query([ts - util::get_seconds_before_midnight(ts)])?;
-    while let Some(row) = rows.next()? {
-        let event_id: u64 = row.get(0)?;
-        delete_event(conn, event_id, automatic_blacklisting, cancel_future_reservations, admins)?;
+    if old.ts != new.ts {
+        changes.push_str(&format!(
+            "\nНовое время начала: {} (было {})",
+            crate::format::event_ts(new, new.ts),
+            crate::format::event_ts(old, old.ts)
+        ));
     }
-    Ok(())
-}
-
-pub fn create(conn: &PooledConnection<SqliteConnectionManager>) -> Result<(), rusqlite::Error> {
-
-    let mut stmt =
-        conn.prepare("SELECT name FROM sqlite_master WHERE type='table' AND name='events'")?;
-    match stmt.query([]) {
-        Ok(rows) => match rows.count() {
-            Ok(count) => {
-                if count == 0 {
-                    conn.execute(
-                        "CREATE TABLE events (
-                            id              INTEGER PRIMARY KEY AUTOINCREMENT,
-                            name            TEXT NOT NULL,
-                            link            TEXT NOT NULL,
-                            max_adults      INTEGER NOT NULL,
-                            max_children    INTEGER NOT NULL,
-                            max_adults_per_reservation   INTEGER NOT NULL,
-                            max_children_per_reservation INTEGER NOT NULL,
-                            ts              INTEGER NOT NULL,
-                            remind          INTEGER NOT NULL,
-                            state           INTEGER default 0,
-                            adult_ticket_price INTEGER default 0,
-                            child_ticket_price INTEGER default 0,
-                            currency        TEXT default 'EUR'
-                            )",
-                        [],
-                    )?;
-                    conn.execute(
-                        "CREATE TABLE reservations (
-                            id              INTEGER PRIMARY KEY,
-                            event           INTEGER NOT NULL,
-                            user            INTEGER NOT NULL,
-                            user_name1      TEXT NOT NULL,
-                            user_name2      TEXT NOT NULL,
-                            adults          INTEGER NOT NULL,
-                            children        INTEGER NOT NULL,
-                            waiting_list    INTEGER DEFAULT 0 NOT NULL,
-                            ts              INTEGER NOT NULL,
-                            payment         TEXT DEFAULT NULL,
-                            state           INTEGER default 0
-                            )",
-                        [],
-                    )?;
-                    conn.execute(
-                        "CREATE INDEX reservations_event_index ON reservations (event)",
-                        [],
-                    )?;
-                    conn.execute(
-                        "CREATE INDEX reservations_user_index ON reservations (user)",
-                        [],
-                    )?;
-
-                    conn.execute(
-                        "CREATE TABLE attachments (
-                            event           INTEGER NOT NULL,
-                            user            INTEGER NOT NULL,
-                            attachment      TEXT NOT NULL
-                            )",
-                        [],
-                    )?;
-                    conn.execute(
-                        "CREATE INDEX attachments_event_index ON attachments (event)",
-                        [],
-                    )?;
-                    conn.execute("CREATE UNIQUE INDEX attachments_unique_event_user_idx ON attachments (event, user)", [])?;
-
-                    conn.execute(
-                        "CREATE TABLE black_list (
-                            user            INTEGER PRIMARY KEY,
-                            user_name1      TEXT NOT NULL,
-                            user_name2      TEXT NOT NULL,
-                            ts              INTEGER NOT NULL,
-                            reason          TEXT default ''
-                            )",
-                        [],
-                    )?;
-
-                    conn.execute(
-                        "CREATE TABLE presence (
-                            event           INTEGER NOT NULL,
-                            user            INTEGER NOT NULL
-                            )",
-                        [],
-                    )?;
-                    conn.execute("CREATE INDEX presence_event_index ON presence (event)", [])?;
-                    conn.execute("CREATE UNIQUE INDEX presence_event_user_unique_idx ON presence (event, user)", [])?;
-
-                    conn.execute(
-                        "CREATE TABLE group_leaders (
-                            event           INTEGER NOT NULL,
-                            user            INTEGER NOT NULL
-                            )",
-                        [],
-                    )?;
-                    conn.execute("CREATE UNIQUE INDEX group_leaders_event_user_unique_idx ON presence (event, user)", [])?;
-
-                    conn.execute(
-                        "CREATE TABLE messages (
-                            id              INTEGER PRIMARY KEY AUTOINCREMENT,
-                            event           INTEGER NOT NULL,
-                            type            INTEGER NOT NULL,
-                            sender          text NOT NULL,
-                            waiting_list    INTEGER NOT NULL,
-                            text            text NOT NULL,
-                            ts              INTEGER NOT NULL
-                            )",
-                        [],
-                    )?;
-                    conn.execute("CREATE INDEX messages_event_index ON messages (event)", [])?;
-
-                    conn.execute(
-                        "CREATE TABLE message_outbox (
-                            message         INTEGER NOT NULL,
-                            send_at         INTEGER NOT NULL
-                            )",
-                        [],
-                    )?;
-                    conn.execute(
-                        "CREATE TABLE message_sent (
-                            message         INTEGER NOT NULL,
-                            user            INTEGER NOT NULL,
-                            ts              INTEGER NOT NULL
-                            )",
-                        [],
-                    )?;
-                    conn.execute(
-                        "CREATE TABLE current_events (
-                            user            INTEGER NOT NULL PRIMARY KEY,
-                            event           INTEGER NOT NULL
-                            )",
-                        [],
-                    )?;
-                }
-            }
-            _ => panic!("DB is corrupt."),
-        },
-        _ => {
-            error!("Failed to query db.");
-        }
+    if old.end() != new.end() && new.duration > 0 {
+        changes.push_str(&format!("\nНовое время окончания: {}", crate::format::event_ts(new, new.end())));
     }
-    Ok(())
-}
-
-pub fn save_receipt(conn: &PooledConnection<SqliteConnectionManager>, message_id: u64, user: u64) -> Result<(), rusqlite::Error> {
-    conn.execute(
-        "INSERT INTO message_sent (message, user, ts) VALUES (?1, ?2, ?3)",
-        params![message_id, user, util::get_unix_time()],
-    )?;
-    Ok(())
-}
-
-pub fn add_to_black_list(
-    conn: &PooledConnection<SqliteConnectionManager>,
-    user: u64,
-    cancel_future_reservations: bool,
-) -> Result<(), rusqlite::Error> {
-    let mut user_name1 = user.to_string();
-    let mut user_name2 = "".to_string();
-
-    let mut stmt = conn
-        .prepare("SELECT user_name1, user_name2 FROM reservations WHERE user = ?1 LIMIT 1")?;
-    let mut rows = stmt.query([user])?;
-    if let Some(row) = rows.next()? {
-        user_name1 = row.get(0)?;
-        user_name2 = row.get(1)?;
+    if old.link != new.link {
+        changes.push_str(&format!("\nНовая ссылка: {}", new.link));
     }
-
-    ban_user(conn, 
-        user,
-        &user_name1,
-        &user_name2,
-        "banned by admin",
-        cancel_future_reservations,
-    )
-}
-
-pub fn ban_user(
-    conn: &PooledConnection<SqliteConnectionManager>,
-    user: u64,
-    user_name1: &str,
-    user_name2: &str,
-    reason: &str,
-    cancel_future_reservations: bool,
-) -> Result<(), rusqlite::Error> {
-    conn.execute(
-        "INSERT INTO black_list (user, user_name1, user_name2, ts, reason) VALUES (?1, ?2, ?3, ?4, ?5)",
-        params![user, user_name1, user_name2, util::get_unix_time(), reason],
-    )?;
-
-    if cancel_future_reservations {
-        if let Err(e) = conn
-            .execute("DELETE FROM reservations where user = ?1", params![user])
-        {
-            warn!("{}", e);
-        }
+    if old.venue_id != new.venue_id {
+        changes.push_str("\nИзменилось место проведения");
     }
-    Ok(())
-}
-
-pub fn remove_from_black_list(conn: &PooledConnection<SqliteConnectionManager>, user: u64) -> Result<(), rusqlite::Error> {
-    conn
-        .execute("DELETE FROM black_list WHERE user=?1", params![user])?;
-    Ok(())
-}
-pub fn get_black_list(conn: &PooledConnection<SqliteConnectionManager>, offset: u64, limit: u64) -> Result<Vec<User>, rusqlite::Error> {
-    let mut stmt = conn
-        .prepare("SELECT * FROM black_list order by user_name1 LIMIT ?1 OFFSET ?2")?;
-    let mut rows = stmt.query([limit, offset * limit])?;
-    let mut res = Vec::new();
-    while let Some(row) = rows.next()? {
-        let user_id: u64 = row.get(0)?;
-        res.push(User {
-            id: teloxide::types::UserId(user_id),
-            user_name1: row.get(1)?,
-            user_name2: row.get(2)?,
-            is_admin: false,
-        });
+    if changes.is_empty() {
+        return None;
     }
-    Ok(res)
+    Some(format!(
+        "\nЗдравствуйте!\nИзменились данные мероприятия <a href=\"{}\">{}</a>:{}\n",
+        new.link, new.name, changes
+    ))
 }
 
-pub fn is_in_black_list(conn: &PooledConnection<SqliteConnectionManager>, user: u64) -> Result<bool, rusqlite::Error> {
-    let mut stmt = conn
-        .prepare("SELECT * FROM black_list WHERE user = ?1")?;
-    let mut rows = stmt.query([user])?;
-    if let Some(_) = rows.next()? {
-        Ok(true)
+pub fn event_cancelled_text(e: &Event) -> String {
+    let refund = if e.get_type() == EventType::Paid {
+        "Оплата будет возвращена.\n"
     } else {
-        Ok(false)
-    }
-}
-
-pub fn clear_black_list(conn: &PooledConnection<SqliteConnectionManager>, ts: u64) -> Result<(), rusqlite::Error> {
-    conn
-        .execute("DELETE FROM black_list WHERE ts < ?1", params![ts])?;
-    Ok(())
+        ""
+    };
+    format!("\nЗдравствуйте!\nК сожалению, мероприятие <a href=\"{}\">{}</a> ({}) отменено.\n{}",
+        e.link, e.name, crate::format::event_ts(e, e.ts), refund)
 }
 
-pub fn clear_failed_payments(conn: &PooledConnection<SqliteConnectionManager>, ts: u64) -> Result<(), rusqlite::Error> {
-    conn
-        .execute("DELETE FROM reservations WHERE state = ?1 AND ts < ?2", params![ReservationState::PaymentPending as u64, ts])?;
-    Ok(())
+pub fn registration_opened_text(e: &Event) -> String {
+    format!("\nЗдравствуйте!\nОткрылась запись на мероприятие <a href=\"{}\">{}</a> ({}).\n",
+        e.link, e.name, crate::format::event_ts(e, e.ts))
 }
 
-pub fn change_event_state(conn: &PooledConnection<SqliteConnectionManager>, event_id: u64, state: u64) -> Result<(), rusqlite::Error> {
-    conn.execute(
-        "UPDATE events SET state = ?1 WHERE id = ?2",
-        params![state, event_id],
-    )?;
-    Ok(())
+pub fn waiting_list_prompt_text(event_name: &str) -> String {
+    format!("Кто-то отменил бронирование на мероприятие: \"{}\".\nВы можете попробовать записаться.", event_name)
 }
 
-pub fn set_event_limits(
-    conn: &PooledConnection<SqliteConnectionManager>,
-    event_id: u64,
-    max_adults: u64,
-    max_children: u64,
-) -> Result<(), rusqlite::Error> {
-    let state_changed = have_vacancies(conn, event_id)? == false;
-    conn.execute(
-        "UPDATE events SET max_adults = ?1, max_children = ?2 WHERE id = ?3",
-        params![max_adults, max_children, event_id],
-    )?;
-    if state_changed {
-        prompt_waiting_list(conn, event_id)
-    } else {
-        Ok(())
-    }
+pub fn promoted_text(event_name: &str) -> String {
+    format!("Освободилось место, и вы записаны из листа ожидания на мероприятие: \"{}\".\n\
+        Если планы изменились, отмените бронь.", event_name)
 }
 
-pub fn get_group_messages(
-    conn: &PooledConnection<SqliteConnectionManager>,
-    event_id: u64,
-    waiting_list: Option<u64>,
-) -> Result<Vec<GroupMessage>, rusqlite::Error> {
-    let mut stmt;
-    let mut rows = if let Some(waiting_list) = waiting_list {
-        stmt = conn.prepare(
-            "SELECT sender, text, ts, waiting_list FROM messages WHERE event = ?1 AND type = 0 AND waiting_list = ?2 ORDER BY ts DESC LIMIT 3"
-        )?;
-        stmt.query(params![event_id, waiting_list])?
-    } else {
-        stmt = conn.prepare(
-            "SELECT sender, text, ts, waiting_list FROM messages WHERE event = ?1 AND type = 0 ORDER BY ts DESC LIMIT 3",
-        )?;
-        stmt.query(params![event_id])?
-    };
-    let mut messages = Vec::new();
-    while let Some(row) = rows.next()? {
-        let msg = GroupMessage {
-            sender: row.get("sender")?,
-            text: row.get("text")?,
-            ts: row.get("ts")?,
-            waiting_list: row.get("waiting_list")?,
-        };
-        // todo: remove after message format migration
-        if msg.sender.len() == 0 {
-            continue;
-        }
-        messages.push(msg);
-    }
-    messages.reverse();
-    Ok(messages)
+/// Event name as used in ban reasons and notifications.
+pub fn event_name(e: &Event) -> String {
+    format!("{} {}", crate::format::event_ts(e, e.ts), e.name)
 }
-
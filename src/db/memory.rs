@@ -0,0 +1,1608 @@
+use super::{
+    add_days, check_amount, check_editable, check_sign_up, check_ticket_types, counters, event_cancelled_text, event_change_text, event_name, is_upcoming, next_occurrence, occurrence,
+    one_ticket, promoted_text, promotions, refunds, registration_opened_text, reminder_text, reservation_details, search_words, shift_local,
+    took_place, waiting_list_prompt_text, AuditFilter, AuditRecord, Error, EventFilter, EventStats, GroupMessage,
+    Refund, Result, SignUp, SignUpChecks, Storage, SYSTEM_ACTOR, UserProfile, WaitingReservation,
+};
+use crate::types::{
+    AuditAction, Booking, Category, Event, EventState, EventType, MessageBatch, MessageType, OrderInfo, Participant,
+    Presence, ReservationState, Series, User, Venue,
+};
+use crate::util::{self, get_unix_time};
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::sync::{Mutex, MutexGuard};
+use url::Url;
+
+/// Storage keeping everything in memory. Mirrors the semantics of the SQLite backend,
+/// so handler logic can be tested without touching the file system.
+#[derive(Default)]
+pub struct MemoryStorage {
+    tables: Mutex<Tables>,
+}
+
+impl MemoryStorage {
+    pub fn new() -> MemoryStorage {
+        MemoryStorage::default()
+    }
+
+    fn tables(&self) -> MutexGuard<'_, Tables> {
+        // A panic in another test thread must not hide the actual failure.
+        self.tables.lock().unwrap_or_else(|e| e.into_inner())
+    }
+}
+
+struct EventRow {
+    event: Event,
+    state: u64,
+    archived: bool,
+    revision: u64,
+}
+
+struct Reservation {
+    id: u64,
+    event: u64,
+    user: u64,
+    ticket: u64,
+    quantity: u64,
+    waiting_list: u64,
+    ts: u64,
+    state: u64,
+    payment: Option<String>,
+}
+
+struct Message {
+    id: u64,
+    event: u64,
+    message_type: u64,
+    sender: String,
+    waiting_list: u64,
+    text: String,
+    ts: u64,
+}
+
+struct UserRow {
+    user_name1: String,
+    user_name2: String,
+    language_code: Option<String>,
+    first_seen: u64,
+    last_seen: u64,
+    reachable: bool,
+}
+
+struct BlackListEntry {
+    ts: u64,
+    reason: String,
+}
+
+#[derive(Default)]
+struct Tables {
+    last_event_id: u64,
+    last_reservation_id: u64,
+    last_message_id: u64,
+    last_series_id: u64,
+    last_venue_id: u64,
+    last_category_id: u64,
+    events: BTreeMap<u64, EventRow>,
+    series: BTreeMap<u64, Series>,
+    venues: BTreeMap<u64, Venue>,
+    categories: BTreeMap<u64, Category>,
+    reservations: Vec<Reservation>,
+    attachments: HashMap<(u64, u64), String>,
+    black_list: HashMap<u64, BlackListEntry>,
+    users: HashMap<u64, UserRow>,
+    presence: HashSet<(u64, u64)>,
+    group_leaders: HashSet<(u64, u64)>,
+    messages: Vec<Message>,
+    /// (message, send_at)
+    message_outbox: Vec<(u64, u64)>,
+    /// (message, user)
+    message_sent: Vec<(u64, u64)>,
+    current_events: HashMap<u64, u64>,
+    /// (event, user) -> subscription time
+    registration_subscriptions: BTreeMap<(u64, u64), u64>,
+    /// (event, user) -> promotion time, until the message is sent
+    promotions: BTreeMap<(u64, u64), u64>,
+    /// (event, user, question) -> answer
+    answers: BTreeMap<(u64, u64, u64), String>,
+    /// user -> event
+    questionnaires: HashMap<u64, u64>,
+    refunds: Vec<Refund>,
+    /// user -> events
+    imports: HashMap<u64, String>,
+    /// user -> text
+    searches: HashMap<u64, String>,
+    audit_log: Vec<AuditRecord>,
+}
+
+/// Reservations of one user for an event, summed up like `GROUP BY user` does.
+struct UserReservations {
+    user: u64,
+    user_name1: String,
+    user_name2: String,
+    /// Quantities by ticket type.
+    tickets: Vec<u64>,
+    quantity: u64,
+    ts: u64,
+}
+
+/// Same condition as the conflict query of the SQL backends: events without a known end
+/// only conflict with those starting at the same time.
+fn overlaps(a: &Event, b: &Event) -> bool {
+    a.ts == b.ts || (a.ts < b.end() && b.ts < a.end())
+}
+
+impl Tables {
+    fn answers(&self, event_id: u64, user: u64) -> Vec<String> {
+        let questions = self.events.get(&event_id).map(|row| row.event.questions.len()).unwrap_or(0);
+        (0..questions as u64)
+            .map(|q| self.answers.get(&(event_id, user, q)).cloned().unwrap_or_default())
+            .collect()
+    }
+
+    fn stats(&self, row: &EventRow, user: u64) -> EventStats {
+        let reservations = self
+            .reservations
+            .iter()
+            .filter(|r| r.event == row.event.id)
+            .map(|r| (r.ticket, r.waiting_list, r.user == user, r.quantity));
+        EventStats {
+            tickets: counters(row.event.tickets.len(), reservations),
+            event: row.event.clone(),
+            state: num::FromPrimitive::from_u64(row.state).unwrap_or(EventState::Closed),
+            revision: row.revision,
+        }
+    }
+
+    fn find_event(&self, event_id: u64, user: u64) -> Result<EventStats> {
+        match self.events.get(&event_id) {
+            Some(row) => Ok(self.stats(row, user)),
+            None => Err(Error::EventNotFound(event_id)),
+        }
+    }
+
+    fn get_event(&mut self, event_id: u64, user: u64) -> Result<EventStats> {
+        let s = self.find_event(event_id, user)?;
+        self.current_events.insert(user, event_id);
+        Ok(s)
+    }
+
+    fn get_event_name(&self, event_id: u64) -> Result<String> {
+        match self.events.get(&event_id) {
+            Some(row) => Ok(event_name(&row.event)),
+            None => Err(Error::EventNotFound(event_id)),
+        }
+    }
+
+    /// Reservations grouped by user in booking order.
+    fn group_by_user<'a>(
+        &self,
+        reservations: impl Iterator<Item = &'a Reservation>,
+    ) -> Vec<UserReservations> {
+        let mut res: Vec<UserReservations> = Vec::new();
+        for r in reservations {
+            let i = match res.iter().position(|u| u.user == r.user) {
+                Some(i) => i,
+                None => {
+                    let (user_name1, user_name2) = self.user_names(r.user);
+                    res.push(UserReservations {
+                        user: r.user,
+                        user_name1,
+                        user_name2,
+                        tickets: Vec::new(),
+                        quantity: 0,
+                        ts: r.ts,
+                    });
+                    res.len() - 1
+                }
+            };
+            let u = &mut res[i];
+            let ticket = r.ticket as usize;
+            if u.tickets.len() <= ticket {
+                u.tickets.resize(ticket + 1, 0);
+            }
+            u.tickets[ticket] += r.quantity;
+            u.quantity += r.quantity;
+            u.ts = u.ts.min(r.ts);
+        }
+        res.sort_by_key(|u| u.ts);
+        res
+    }
+
+    fn user_names(&self, user: u64) -> (String, String) {
+        match self.users.get(&user) {
+            Some(u) => (u.user_name1.clone(), u.user_name2.clone()),
+            None => (String::new(), String::new()),
+        }
+    }
+
+    fn save_user(&mut self, user: &User, ts: u64) {
+        let row = self.users.entry(user.id.0).or_insert_with(|| UserRow {
+            user_name1: String::new(),
+            user_name2: String::new(),
+            language_code: None,
+            first_seen: ts,
+            last_seen: ts,
+            reachable: true,
+        });
+        row.user_name1 = user.user_name1.clone();
+        row.user_name2 = user.user_name2.clone();
+        if user.language_code.is_some() {
+            row.language_code = user.language_code.clone();
+        }
+        row.last_seen = row.last_seen.max(ts);
+        row.reachable = true;
+    }
+
+    fn audit(
+        &mut self,
+        ts: u64,
+        actor: u64,
+        action: AuditAction,
+        event_id: u64,
+        user_id: u64,
+        details: &str,
+    ) {
+        self.audit_log.push(AuditRecord {
+            ts,
+            actor,
+            action,
+            event_id,
+            user_id,
+            details: details.to_string(),
+        });
+    }
+
+    fn have_vacancies(&self, event_id: u64) -> bool {
+        match self.events.get(&event_id) {
+            Some(row) => self.stats(row, 0).has_vacancies(),
+            None => false,
+        }
+    }
+
+    fn enqueue_message(
+        &mut self,
+        event_id: u64,
+        sender: &str,
+        waiting_list: u64,
+        message_type: MessageType,
+        text: &str,
+        send_at: u64,
+    ) {
+        self.last_message_id += 1;
+        self.messages.push(Message {
+            id: self.last_message_id,
+            event: event_id,
+            message_type: message_type as u64,
+            sender: sender.to_string(),
+            waiting_list,
+            text: text.to_string(),
+            ts: get_unix_time(),
+        });
+        self.message_outbox.push((self.last_message_id, send_at));
+    }
+
+    /// Sends the message to both confirmed participants and the waiting list.
+    fn notify_participants(&mut self, event_id: u64, message_type: MessageType, text: &str) {
+        for waiting_list in [0, 1] {
+            if self
+                .reservations
+                .iter()
+                .any(|r| r.event == event_id && r.waiting_list == waiting_list)
+            {
+                self.enqueue_message(event_id, "Bot", waiting_list, message_type, text, get_unix_time());
+            }
+        }
+    }
+
+    fn delete_enqueued_messages(&mut self, event_id: u64, message_type: MessageType) {
+        let message_type = message_type as u64;
+        let ids: HashSet<u64> = self
+            .messages
+            .iter()
+            .filter(|m| m.event == event_id && m.message_type == message_type)
+            .map(|m| m.id)
+            .collect();
+        self.message_outbox.retain(|(m, _)| !ids.contains(m));
+        self.messages.retain(|m| !ids.contains(&m.id));
+    }
+
+    fn prompt_waiting_list(&mut self, event_id: u64) {
+        if !self.have_vacancies(event_id) {
+            debug!("prompt_waiting_list - no tickets, event {}", event_id);
+            return;
+        }
+
+        let send_at = get_unix_time() + 10; // give some time to finish multiple cancellations
+        let prompt = self.messages.iter().find(|m| {
+            m.event == event_id && m.message_type == MessageType::WaitingListPrompt as u64
+        });
+        if let Some(m) = prompt {
+            self.message_outbox.push((m.id, send_at));
+        } else if let Ok(event_name) = self.get_event_name(event_id) {
+            self.enqueue_message(
+                event_id,
+                "Bot",
+                1,
+                MessageType::WaitingListPrompt,
+                &waiting_list_prompt_text(&event_name),
+                send_at,
+            );
+        }
+    }
+
+    /// Gives freed places to the waiting list: the oldest sign-ups that fit are confirmed if the event
+    /// promotes automatically, otherwise the list is invited to sign up once places appear.
+    fn fill_vacancies(&mut self, event_id: u64, state_changed: bool) {
+        let s = match self.events.get(&event_id) {
+            Some(row) => self.stats(row, 0),
+            None => return,
+        };
+        if s.event.auto_promote {
+            self.promote_waiting_list(&s);
+        } else if state_changed {
+            self.prompt_waiting_list(event_id);
+        }
+    }
+
+    fn promote_waiting_list(&mut self, s: &EventStats) {
+        let event_id = s.event.id;
+        let waiting: Vec<WaitingReservation> = self
+            .reservations
+            .iter()
+            .filter(|r| r.event == event_id && r.waiting_list == 1)
+            .map(|r| WaitingReservation { id: r.id, user: r.user, ticket: r.ticket, quantity: r.quantity, ts: r.ts })
+            .collect();
+        let promoted = promotions(s, &waiting);
+        if promoted.is_empty() {
+            return;
+        }
+
+        let ts = get_unix_time();
+        let message_id = self
+            .messages
+            .iter()
+            .find(|m| m.event == event_id && m.message_type == MessageType::Promoted as u64)
+            .map(|m| m.id);
+        for p in &promoted {
+            for r in self.reservations.iter_mut().filter(|r| p.reservations.contains(&r.id)) {
+                r.waiting_list = 0;
+            }
+            self.promotions.insert((event_id, p.user), ts);
+            if let Some(message_id) = message_id {
+                // Promoted again since the last message, tell once more.
+                self.message_sent.retain(|(m, u)| (*m, *u) != (message_id, p.user));
+            }
+            self.audit(ts, SYSTEM_ACTOR, AuditAction::Promote, event_id, p.user, &reservation_details(&s.event, &p.tickets, 0));
+        }
+        match message_id {
+            Some(message_id) => {
+                if !self.message_outbox.iter().any(|(m, _)| *m == message_id) {
+                    self.message_outbox.push((message_id, ts));
+                }
+            }
+            None => {
+                self.enqueue_message(event_id, "Bot", 0, MessageType::Promoted, &promoted_text(&event_name(&s.event)), ts);
+            }
+        }
+    }
+
+    /// Deletes reservations matching `filter` and gives the freed places to the waiting list.
+    fn release(&mut self, event_id: u64, filter: impl Fn(&Reservation) -> bool) {
+        let state_changed = !self.have_vacancies(event_id);
+        self.reservations.retain(|r| !filter(r));
+        self.fill_vacancies(event_id, state_changed);
+    }
+
+    fn ban_user(
+        &mut self,
+        user: u64,
+        user_name1: &str,
+        user_name2: &str,
+        reason: &str,
+        cancel_future_reservations: bool,
+    ) -> Result<()> {
+        if self.black_list.contains_key(&user) {
+            return Err(Error::Storage(format!("User {} is already in the black list", user).into()));
+        }
+        let ts = util::get_unix_time();
+        self.users.entry(user).or_insert_with(|| UserRow {
+            user_name1: user_name1.to_string(),
+            user_name2: user_name2.to_string(),
+            language_code: None,
+            first_seen: ts,
+            last_seen: ts,
+            reachable: true,
+        });
+        self.black_list.insert(
+            user,
+            BlackListEntry {
+                ts,
+                reason: reason.to_string(),
+            },
+        );
+        if cancel_future_reservations {
+            // Past events keep the reservations for attendance and statistics.
+            let mut events: Vec<u64> = self.reservations.iter().filter(|r| r.user == user).map(|r| r.event).collect();
+            events.sort_unstable();
+            events.dedup();
+            for event_id in events {
+                let upcoming = self
+                    .events
+                    .get(&event_id)
+                    .is_some_and(|row| !row.archived && is_upcoming(&self.stats(row, 0), ts));
+                if upcoming {
+                    self.release(event_id, |r| r.event == event_id && r.user == user);
+                }
+            }
+        }
+        Ok(())
+    }
+
+    fn blacklist_absent_participants(
+        &mut self,
+        event_id: u64,
+        admins: &HashSet<u64>,
+        cancel_future_reservations: bool,
+    ) -> Result<()> {
+        let reserved = self.group_by_user(
+            self.reservations
+                .iter()
+                .filter(|r| r.event == event_id && r.waiting_list == 0),
+        );
+        let presence_checked = reserved
+            .iter()
+            .any(|u| self.presence.contains(&(event_id, u.user)));
+        let absent: Vec<UserReservations> = reserved
+            .into_iter()
+            .filter(|u| !self.presence.contains(&(event_id, u.user)))
+            .collect();
+        if presence_checked && !absent.is_empty() {
+            // Check at least one present.
+            if let Ok(reason) = self.get_event_name(event_id) {
+                absent
+                    .iter()
+                    .filter(|u| !admins.contains(&u.user))
+                    .try_for_each(|u| {
+                        self.ban_user(
+                            u.user,
+                            &u.user_name1,
+                            &u.user_name2,
+                            &reason,
+                            cancel_future_reservations,
+                        )
+                    })?;
+            } else {
+                warn!("Failed to get event {}", event_id);
+            }
+        }
+        Ok(())
+    }
+
+    /// Ticket types of the event with reservations, including the waiting list.
+    fn booked_tickets(&self, event_id: u64) -> Vec<u64> {
+        let mut res: Vec<u64> = self.reservations.iter().filter(|r| r.event == event_id).map(|r| r.ticket).collect();
+        res.sort();
+        res.dedup();
+        res
+    }
+
+    /// Inserts the event in the given state or updates it.
+    fn save_event(&mut self, e: &Event, state: EventState) -> Result<u64> {
+        let event_type = e.get_type();
+        if event_type == EventType::Announcement && Url::parse(&e.link).is_err() {
+            return Err(Error::InvalidLink(e.link.clone()));
+        }
+        let mut event_id = e.id;
+        // Subscribers are told once, unless the opening is moved to the future again.
+        let mut announce_registration = e.registration_opens > get_unix_time();
+        if e.id == 0 {
+            self.last_event_id += 1;
+            event_id = self.last_event_id;
+            self.events.insert(
+                event_id,
+                EventRow {
+                    event: Event {
+                        id: event_id,
+                        ..e.clone()
+                    },
+                    state: state as u64,
+                    archived: false,
+                    revision: 0,
+                },
+            );
+        } else {
+            let old = self.find_event(e.id, 0)?;
+            check_editable(&old, self.events.get(&e.id).is_some_and(|row| row.archived))?;
+            let old = old.event;
+            check_ticket_types(&old, e, self.booked_tickets(e.id))?;
+            if let Some(row) = self.events.get_mut(&e.id) {
+                row.event = Event {
+                    series_id: row.event.series_id,
+                    poster: row.event.poster.clone(),
+                    ..e.clone()
+                };
+                row.revision += 1;
+            }
+            self.delete_enqueued_messages(e.id, MessageType::Reminder);
+            self.delete_enqueued_messages(e.id, MessageType::RegistrationOpened);
+            announce_registration |= old.registration_opens > get_unix_time();
+            if let Some(text) = event_change_text(&old, e) {
+                self.notify_participants(e.id, MessageType::EventChanged, &text);
+            }
+        }
+
+        if event_id != 0 && event_type != EventType::Announcement {
+            let text = reminder_text(e);
+            self.enqueue_message(event_id, "Bot", 0, MessageType::Reminder, &text, e.remind);
+        }
+        if event_id != 0 && event_type != EventType::Announcement && announce_registration {
+            let text = registration_opened_text(e);
+            self.enqueue_message(event_id, "Bot", 0, MessageType::RegistrationOpened, &text, e.registration_opens);
+        }
+        Ok(event_id)
+    }
+
+    fn set_event_state(&mut self, event_id: u64, state: EventState, actor: u64) -> Result<()> {
+        let s = self.find_event(event_id, 0)?;
+        if !s.state.can_change_to(state) {
+            return Err(Error::InvalidStateChange(s.state, state));
+        }
+        if state == EventState::Cancelled {
+            self.delete_enqueued_messages(event_id, MessageType::Reminder);
+            self.delete_enqueued_messages(event_id, MessageType::WaitingListPrompt);
+            self.delete_enqueued_messages(event_id, MessageType::RegistrationOpened);
+            self.notify_participants(event_id, MessageType::EventCancelled, &event_cancelled_text(&s.event));
+            let payments: Vec<(u64, String)> = self
+                .reservations
+                .iter()
+                .filter(|r| r.event == event_id && r.state == ReservationState::PaymentCompleted as u64)
+                .filter_map(|r| r.payment.clone().map(|p| (r.user, p)))
+                .collect();
+            for mut r in refunds(&s, payments, get_unix_time())? {
+                r.id = self.refunds.len() as u64 + 1;
+                self.refunds.push(r);
+            }
+        }
+        if let Some(row) = self.events.get_mut(&event_id) {
+            row.state = state as u64;
+            row.revision += 1;
+        }
+        self.audit(get_unix_time(), actor, AuditAction::ChangeEventState, event_id, 0,
+            &format!("state {}", state as u64));
+        Ok(())
+    }
+
+    fn get_series(&self, series_id: u64) -> Result<Series> {
+        self.series
+            .get(&series_id)
+            .cloned()
+            .ok_or(Error::SeriesNotFound(series_id))
+    }
+
+    fn get_upcoming_occurrences(&self, series_id: u64) -> Vec<EventStats> {
+        let now = get_unix_time();
+        let mut res: Vec<EventStats> = self
+            .events
+            .values()
+            .filter(|row| row.event.series_id == series_id && !row.archived)
+            .map(|row| self.stats(row, 0))
+            .filter(|s| is_upcoming(s, now))
+            .collect();
+        res.sort_by_key(|s| s.event.ts);
+        res
+    }
+
+    fn delete_event(
+        &mut self,
+        event_id: u64,
+        automatic_blacklisting: bool,
+        cancel_future_reservations_on_ban: bool,
+        admins: &HashSet<u64>,
+    ) -> Result<()> {
+        let s = self.find_event(event_id, 0)?;
+        if automatic_blacklisting && s.event.get_type() != EventType::Paid && took_place(&s, get_unix_time()) {
+            self.blacklist_absent_participants(
+                event_id,
+                admins,
+                cancel_future_reservations_on_ban,
+            )?;
+        }
+
+        self.reservations.retain(|r| r.event != event_id);
+        self.events.remove(&event_id);
+        self.attachments.retain(|(event, _), _| *event != event_id);
+        self.presence.retain(|(event, _)| *event != event_id);
+        self.group_leaders.retain(|(event, _)| *event != event_id);
+        self.messages.retain(|m| m.event != event_id);
+        self.registration_subscriptions.retain(|(event, _), _| *event != event_id);
+        self.answers.retain(|(event, _, _), _| *event != event_id);
+        self.questionnaires.retain(|_, event| *event != event_id);
+        self.promotions.retain(|(event, _), _| *event != event_id);
+        Ok(())
+    }
+}
+
+impl Storage for MemoryStorage {
+    fn add_event(&self, e: Event) -> Result<u64> {
+        self.tables().save_event(&e, EventState::Draft)
+    }
+
+    fn add_events(&self, events: &[Event]) -> Result<Vec<u64>> {
+        let mut t = self.tables();
+        // Nothing is created if any of the events can't be.
+        if let Some(e) = events.iter().find(|e| e.get_type() == EventType::Announcement && Url::parse(&e.link).is_err()) {
+            return Err(Error::InvalidLink(e.link.clone()));
+        }
+        events.iter().map(|e| t.save_event(e, EventState::Draft)).collect()
+    }
+
+    fn get_events(
+        &self,
+        user: u64,
+        is_admin: bool,
+        filter: &EventFilter,
+        offset: u64,
+        limit: u64,
+    ) -> Result<Vec<EventStats>> {
+        let t = self.tables();
+        let words = search_words(&filter.text);
+        let mut events: Vec<EventStats> = t
+            .events
+            .values()
+            .filter(|row| !row.archived)
+            .filter(|row| {
+                // Every word starts a word of the name, link or venue name.
+                let venue = t.venues.get(&row.event.venue_id).map(|v| v.name.as_str()).unwrap_or_default();
+                let text = search_words(&format!("{} {} {}", row.event.name, row.event.link, venue));
+                words.iter().all(|w| text.iter().any(|t| t.starts_with(w.as_str())))
+            })
+            .map(|row| t.stats(row, user))
+            .filter(|s| {
+                (is_admin || matches!(s.state, EventState::Open | EventState::Closed | EventState::Cancelled))
+                    && (filter.category == 0 || s.event.categories.contains(&filter.category))
+                    && (!filter.vacancies || (s.state == EventState::Open && s.has_vacancies()))
+                    && (filter.until == 0 || s.event.ts < filter.until)
+                    && (!filter.mine || s.my_reservation() + s.my_waiting() > 0)
+            })
+            .collect();
+        events.sort_by_key(|s| s.event.ts);
+        Ok(events
+            .into_iter()
+            .skip((offset * limit) as usize)
+            .take(limit as usize)
+            .collect())
+    }
+
+    fn get_event(&self, event_id: u64, user: u64) -> Result<EventStats> {
+        self.tables().get_event(event_id, user)
+    }
+
+    fn find_event(&self, event_id: u64, user: u64) -> Result<EventStats> {
+        self.tables().find_event(event_id, user)
+    }
+
+    fn get_current_event(&self, user: u64) -> Result<u64> {
+        Ok(self.tables().current_events.get(&user).copied().unwrap_or(0))
+    }
+
+    fn subscribe_to_registration(&self, event_id: u64, user: u64, subscribe: bool) -> Result<()> {
+        let mut t = self.tables();
+        t.find_event(event_id, 0)?;
+        if subscribe {
+            t.registration_subscriptions.entry((event_id, user)).or_insert_with(get_unix_time);
+        } else {
+            t.registration_subscriptions.remove(&(event_id, user));
+        }
+        Ok(())
+    }
+
+    fn is_subscribed_to_registration(&self, event_id: u64, user: u64) -> Result<bool> {
+        Ok(self.tables().registration_subscriptions.contains_key(&(event_id, user)))
+    }
+
+    fn change_event_state(&self, event_id: u64, state: EventState, actor: u64) -> Result<()> {
+        self.tables().set_event_state(event_id, state, actor)
+    }
+
+    fn set_event_limits(&self, event_id: u64, capacities: &[u64], actor: u64) -> Result<()> {
+        let mut t = self.tables();
+        let state_changed = !t.have_vacancies(event_id);
+        let row = t.events.get_mut(&event_id).ok_or(Error::EventNotFound(event_id))?;
+        for (ticket, capacity) in row.event.tickets.iter_mut().zip(capacities) {
+            ticket.capacity = *capacity;
+        }
+        let details = reservation_details(&row.event, capacities, 0);
+        t.audit(get_unix_time(), actor, AuditAction::SetEventLimits, event_id, 0, &details);
+        t.fill_vacancies(event_id, state_changed);
+        Ok(())
+    }
+
+    fn set_event_poster(&self, event_id: u64, file_id: &str) -> Result<()> {
+        let mut t = self.tables();
+        let row = t.events.get_mut(&event_id).ok_or(Error::EventNotFound(event_id))?;
+        row.event.poster = file_id.to_string();
+        Ok(())
+    }
+
+    fn delete_event(
+        &self,
+        event_id: u64,
+        automatic_blacklisting: bool,
+        cancel_future_reservations_on_ban: bool,
+        admins: &HashSet<u64>,
+        actor: u64,
+    ) -> Result<()> {
+        let mut t = self.tables();
+        let s = t.find_event(event_id, 0)?;
+        t.delete_event(
+            event_id,
+            automatic_blacklisting,
+            cancel_future_reservations_on_ban,
+            admins,
+        )?;
+        t.audit(get_unix_time(), actor, AuditAction::DeleteEvent, event_id, 0,
+            &event_name(&s.event));
+        Ok(())
+    }
+
+    fn delete_link(&self, link: &str) -> Result<()> {
+        let mut t = self.tables();
+        let event_id = t
+            .events
+            .values()
+            .find(|row| row.event.link == link)
+            .map(|row| row.event.id);
+        match event_id {
+            Some(event_id) => t.delete_event(event_id, false, false, &HashSet::new()),
+            None => Ok(()),
+        }
+    }
+
+    fn archive_old_events(
+        &self,
+        ts: u64,
+        automatic_blacklisting: bool,
+        cancel_future_reservations: bool,
+        admins: &HashSet<u64>,
+    ) -> Result<()> {
+        let mut t = self.tables();
+        let threshold = ts - util::get_seconds_before_midnight(ts);
+        let old: Vec<u64> = t
+            .events
+            .values()
+            .filter(|row| row.event.ts < threshold && !row.archived)
+            .map(|row| row.event.id)
+            .collect();
+        for event_id in old {
+            let s = t.find_event(event_id, 0)?;
+            if automatic_blacklisting && s.event.get_type() != EventType::Paid && took_place(&s, get_unix_time()) {
+                t.blacklist_absent_participants(event_id, admins, cancel_future_reservations)?;
+            }
+            let ids: HashSet<u64> = t
+                .messages
+                .iter()
+                .filter(|m| m.event == event_id)
+                .map(|m| m.id)
+                .collect();
+            t.message_outbox.retain(|(m, _)| !ids.contains(m));
+            if let Some(row) = t.events.get_mut(&event_id) {
+                row.archived = true;
+            }
+        }
+        Ok(())
+    }
+
+    fn get_archived_events(&self, offset: u64, limit: u64) -> Result<Vec<EventStats>> {
+        let t = self.tables();
+        let mut events: Vec<&EventRow> = t.events.values().filter(|row| row.archived).collect();
+        events.sort_by_key(|row| std::cmp::Reverse(row.event.ts));
+        Ok(events
+            .into_iter()
+            .skip((offset * limit) as usize)
+            .take(limit as usize)
+            .map(|row| t.stats(row, 0))
+            .collect())
+    }
+
+    fn purge_archive(&self, ts: u64) -> Result<()> {
+        let mut t = self.tables();
+        let old: Vec<u64> = t
+            .events
+            .values()
+            .filter(|row| row.event.ts < ts && row.archived)
+            .map(|row| row.event.id)
+            .collect();
+        for event_id in old {
+            t.delete_event(event_id, false, false, &HashSet::new())?;
+        }
+        Ok(())
+    }
+
+    fn sign_up(
+        &self,
+        event_id: u64,
+        user: &User,
+        tickets: &[u64],
+        wait: u64,
+        ts: u64,
+        amount: u64,
+    ) -> Result<(usize, bool)> {
+        let mut t = self.tables();
+        let user_id = user.id.0;
+        let s = t.get_event(event_id, user_id)?;
+        t.save_user(user, ts);
+        let black_listed = t.black_list.contains_key(&user_id);
+        let time_conflict = t.reservations.iter().any(|r| {
+            r.user == user_id
+                && r.event != event_id
+                && r.waiting_list == 0
+                && t.events.get(&r.event).is_some_and(|row| {
+                    row.state != EventState::Cancelled as u64
+                        && !row.event.allow_overlap
+                        && overlaps(&row.event, &s.event)
+                })
+        });
+
+        match check_sign_up(
+            &s,
+            user,
+            tickets,
+            wait,
+            ts,
+            amount,
+            SignUpChecks { black_listed, time_conflict },
+        )? {
+            SignUp::Reserve(state) => {
+                let state = state as u64;
+                let mut res = 0;
+                for (ticket, quantity) in tickets.iter().enumerate().filter(|(_, n)| **n > 0) {
+                    t.last_reservation_id += 1;
+                    let id = t.last_reservation_id;
+                    t.reservations.push(Reservation {
+                        id,
+                        event: event_id,
+                        user: user_id,
+                        ticket: ticket as u64,
+                        quantity: *quantity,
+                        waiting_list: wait,
+                        ts,
+                        state,
+                        payment: None,
+                    });
+                    res += 1;
+                }
+                t.audit(ts, user_id, AuditAction::SignUp, event_id, user_id,
+                    &reservation_details(&s.event, tickets, wait));
+                Ok((res, false))
+            }
+            SignUp::Confirm { ticket } => {
+                let waiting = t
+                    .reservations
+                    .iter_mut()
+                    .filter(|r| {
+                        r.event == event_id
+                            && r.user == user_id
+                            && r.waiting_list == 1
+                            && r.ticket == ticket
+                            && r.quantity == 1
+                    })
+                    .min_by_key(|r| r.ts);
+                if let Some(r) = waiting {
+                    r.waiting_list = 0;
+                }
+                t.audit(ts, user_id, AuditAction::SignUp, event_id, user_id,
+                    &reservation_details(&s.event, &one_ticket(ticket), 0));
+                Ok((1, false))
+            }
+            SignUp::Rejected => Ok((0, false)),
+            SignUp::BlackListed => Ok((0, true)),
+        }
+    }
+
+    fn checkout(&self, booking: &Booking, order_info: OrderInfo) -> Result<()> {
+        let mut t = self.tables();
+        let s = t.get_event(booking.event_id, booking.user_id)?;
+        check_amount(&s, booking, &order_info)?;
+
+        let payment = serde_json::to_string(&order_info)?;
+        // The booking was reserved as one row per ticket type.
+        let mut ids = Vec::new();
+        for (ticket, quantity) in booking.tickets.iter().enumerate().filter(|(_, n)| **n > 0) {
+            let reservation = t
+                .reservations
+                .iter()
+                .filter(|r| {
+                    r.event == booking.event_id
+                        && r.user == booking.user_id
+                        && r.state == ReservationState::PaymentPending as u64
+                        && r.ticket == ticket as u64
+                        && r.quantity == *quantity
+                })
+                .max_by_key(|r| r.ts);
+            match reservation {
+                Some(r) => ids.push(r.id),
+                None => return Err(Error::ReservationNotFound),
+            }
+        }
+        if ids.is_empty() {
+            return Err(Error::ReservationNotFound);
+        }
+        for r in t.reservations.iter_mut().filter(|r| ids.contains(&r.id)) {
+            r.state = ReservationState::PaymentCompleted as u64;
+            r.payment = Some(payment.clone());
+        }
+        Ok(())
+    }
+
+    fn cancel(&self, event_id: u64, user: u64, ticket: u64) -> Result<()> {
+        let mut t = self.tables();
+        let event = match t.events.get(&event_id) {
+            Some(row) => row.event.clone(),
+            None => return Err(Error::EventNotFound(event_id)),
+        };
+        // Waiting list places go first.
+        let id = t
+            .reservations
+            .iter()
+            .filter(|r| r.event == event_id && r.user == user && r.ticket == ticket)
+            .max_by_key(|r| r.waiting_list)
+            .map(|r| r.id);
+        t.audit(get_unix_time(), user, AuditAction::Cancel, event_id, user,
+            &reservation_details(&event, &one_ticket(ticket), 0));
+        t.release(event_id, |r| Some(r.id) == id);
+        Ok(())
+    }
+
+    fn wontgo(&self, event_id: u64, user: u64) -> Result<()> {
+        let mut t = self.tables();
+        t.audit(get_unix_time(), user, AuditAction::Cancel, event_id, user, "all");
+        t.release(event_id, |r| r.event == event_id && r.user == user);
+        Ok(())
+    }
+
+    fn delete_reservation(&self, event_id: u64, user_id: u64, actor: u64) -> Result<()> {
+        let mut t = self.tables();
+        t.audit(get_unix_time(), actor, AuditAction::DeleteReservation, event_id, user_id, "");
+        t.release(event_id, |r| r.event == event_id && r.user == user_id);
+        Ok(())
+    }
+
+    fn get_participants(
+        &self,
+        event_id: u64,
+        waiting_list: u64,
+        offset: u64,
+        limit: u64,
+        state: ReservationState,
+    ) -> Result<Vec<Participant>> {
+        let t = self.tables();
+        let ticket_types = match t.events.get(&event_id) {
+            Some(row) => row.event.tickets.len(),
+            None => return Err(Error::EventNotFound(event_id)),
+        };
+        let state = state as u64;
+        let list = t.group_by_user(t.reservations.iter().filter(|r| {
+            r.event == event_id && r.waiting_list == waiting_list && r.state == state
+        }));
+        let limit = if limit == 0 { list.len() as u64 } else { limit };
+        Ok(list
+            .into_iter()
+            .skip((offset * limit) as usize)
+            .take(limit as usize)
+            .map(|u| Participant {
+                attachment: t.attachments.get(&(event_id, u.user)).cloned(),
+                answers: t.answers(event_id, u.user),
+                user_id: u.user,
+                user_name1: u.user_name1,
+                user_name2: u.user_name2,
+                tickets: {
+                    let mut tickets = u.tickets;
+                    tickets.resize(ticket_types, 0);
+                    tickets
+                },
+            })
+            .collect())
+    }
+
+    fn add_attachment(&self, event_id: u64, user: u64, attachment: &str) -> Result<usize> {
+        let msg = if attachment.len() < 256 {
+            format!("{}...", attachment.chars().take(256).collect::<String>())
+        } else {
+            attachment.to_string()
+        };
+
+        let mut t = self.tables();
+        let s = t.get_event(event_id, user)?;
+        if s.my_reservation() > 0 || s.my_waiting() > 0 {
+            t.attachments.insert((event_id, user), msg);
+            Ok(1)
+        } else {
+            Ok(0)
+        }
+    }
+
+    fn get_attachment(&self, event_id: u64, user: u64) -> Result<Option<String>> {
+        Ok(self.tables().attachments.get(&(event_id, user)).cloned())
+    }
+
+    fn clear_failed_payments(&self, ts: u64) -> Result<()> {
+        self.tables().reservations.retain(|r| {
+            r.state != ReservationState::PaymentPending as u64 || r.ts >= ts
+        });
+        Ok(())
+    }
+
+    fn add_to_black_list(
+        &self,
+        user: u64,
+        cancel_future_reservations: bool,
+        actor: u64,
+    ) -> Result<()> {
+        let reason = "banned by admin";
+        let mut t = self.tables();
+        // Users who never talked to the bot are known by id only.
+        t.ban_user(
+            user,
+            &user.to_string(),
+            "",
+            reason,
+            cancel_future_reservations,
+        )?;
+        t.audit(get_unix_time(), actor, AuditAction::Ban, 0, user, reason);
+        Ok(())
+    }
+
+    fn ban_user(
+        &self,
+        user: u64,
+        user_name1: &str,
+        user_name2: &str,
+        reason: &str,
+        cancel_future_reservations: bool,
+    ) -> Result<()> {
+        self.tables().ban_user(
+            user,
+            user_name1,
+            user_name2,
+            reason,
+            cancel_future_reservations,
+        )
+    }
+
+    fn remove_from_black_list(&self, user: u64) -> Result<()> {
+        self.tables().black_list.remove(&user);
+        Ok(())
+    }
+
+    fn get_black_list(&self, offset: u64, limit: u64) -> Result<Vec<User>> {
+        let t = self.tables();
+        let mut list: Vec<(&u64, &UserRow)> = t
+            .black_list
+            .keys()
+            .filter_map(|user| t.users.get(user).map(|row| (user, row)))
+            .collect();
+        list.sort_by(|a, b| a.1.user_name1.cmp(&b.1.user_name1).then(a.0.cmp(b.0)));
+        Ok(list
+            .into_iter()
+            .skip((offset * limit) as usize)
+            .take(limit as usize)
+            .map(|(user, row)| User {
+                id: teloxide::types::UserId(*user),
+                user_name1: row.user_name1.clone(),
+                user_name2: row.user_name2.clone(),
+                language_code: row.language_code.clone(),
+                is_admin: false,
+            })
+            .collect())
+    }
+
+    fn get_ban_reason(&self, user_id: u64) -> Result<String> {
+        Ok(match self.tables().black_list.get(&user_id) {
+            Some(entry) => entry.reason.clone(),
+            None => "unknown user".to_string(),
+        })
+    }
+
+    fn clear_black_list(&self, ts: u64) -> Result<()> {
+        self.tables().black_list.retain(|_, entry| entry.ts >= ts);
+        Ok(())
+    }
+
+    fn enqueue_message(
+        &self,
+        event_id: u64,
+        sender: &str,
+        waiting_list: u64,
+        message_type: MessageType,
+        text: &str,
+        send_at: u64,
+    ) -> Result<()> {
+        self.tables()
+            .enqueue_message(event_id, sender, waiting_list, message_type, text, send_at);
+        Ok(())
+    }
+
+    fn get_pending_messages(&self, ts: u64, mut max_messages: u64) -> Result<Vec<MessageBatch>> {
+        let mut t = self.tables();
+        let pending: Vec<u64> = t
+            .message_outbox
+            .iter()
+            .filter(|(_, send_at)| *send_at < ts)
+            .map(|(message, _)| *message)
+            .collect();
+        let mut res = Vec::new();
+        for message_id in pending {
+            let m = match t.messages.iter().find(|m| m.id == message_id) {
+                Some(m) => m,
+                None => continue,
+            };
+            let row = match t.events.get(&m.event) {
+                Some(row) => row,
+                None => continue,
+            };
+            let mut batch = MessageBatch {
+                message_id: m.id,
+                event_id: m.event,
+                sender: m.sender.clone(),
+                message_type: num::FromPrimitive::from_u64(m.message_type).unwrap(),
+                waiting_list: m.waiting_list,
+                text: m.text.clone(),
+                is_paid: row.event.get_type() == EventType::Paid,
+                recipients: Vec::new(),
+            };
+
+            let collect_users = batch.message_type != MessageType::WaitingListPrompt
+                || t.have_vacancies(batch.event_id);
+            if collect_users {
+                let recipients: Vec<u64> = if batch.message_type == MessageType::RegistrationOpened {
+                    let mut subscribers: Vec<(u64, u64)> = t
+                        .registration_subscriptions
+                        .iter()
+                        .filter(|((event, _), _)| *event == batch.event_id)
+                        .map(|((_, user), ts)| (*ts, *user))
+                        .collect();
+                    subscribers.sort();
+                    subscribers.into_iter().map(|(_, user)| user).collect()
+                } else if batch.message_type == MessageType::Promoted {
+                    // Promoted users are told only while they still hold the place.
+                    let mut promoted: Vec<(u64, u64)> = t
+                        .promotions
+                        .iter()
+                        .filter(|((event, user), _)| {
+                            *event == batch.event_id
+                                && t.reservations.iter().any(|r| r.event == *event && r.user == *user && r.waiting_list == 0)
+                        })
+                        .map(|((_, user), ts)| (*ts, *user))
+                        .collect();
+                    promoted.sort();
+                    promoted.into_iter().map(|(_, user)| user).collect()
+                } else {
+                    t.group_by_user(t.reservations.iter().filter(|r| {
+                        r.event == batch.event_id && r.waiting_list == batch.waiting_list
+                    }))
+                    .iter()
+                    .map(|u| u.user)
+                    .collect()
+                };
+                for user in recipients
+                    .into_iter()
+                    .filter(|user| !t.message_sent.contains(&(batch.message_id, *user)))
+                    .take(max_messages as usize)
+                {
+                    batch.recipients.push(user);
+                    max_messages -= 1;
+                    if max_messages == 0 {
+                        res.push(batch);
+                        return Ok(res);
+                    }
+
+                    if batch.message_type == MessageType::WaitingListPrompt {
+                        break; // take not more than one at a time
+                    }
+                }
+            }
+            if batch.recipients.is_empty() {
+                // Done with the message.
+                debug!("finished sending message {}", batch.message_id);
+                if batch.message_type == MessageType::Promoted {
+                    t.promotions.retain(|(event, _), _| *event != batch.event_id);
+                }
+                t.message_outbox.retain(|(m, _)| *m != message_id);
+                t.message_sent.retain(|(m, _)| *m != message_id);
+            }
+            res.push(batch);
+        }
+        Ok(res)
+    }
+
+    fn save_receipt(&self, message_id: u64, user: u64) -> Result<()> {
+        self.tables().message_sent.push((message_id, user));
+        Ok(())
+    }
+
+    fn get_group_messages(
+        &self,
+        event_id: u64,
+        waiting_list: Option<u64>,
+    ) -> Result<Vec<GroupMessage>> {
+        let t = self.tables();
+        let mut messages: Vec<&Message> = t
+            .messages
+            .iter()
+            .filter(|m| {
+                m.event == event_id
+                    && m.message_type == MessageType::Direct as u64
+                    && waiting_list.is_none_or(|w| m.waiting_list == w)
+            })
+            .collect();
+        messages.sort_by(|a, b| b.ts.cmp(&a.ts).then(b.id.cmp(&a.id)));
+        let mut messages: Vec<GroupMessage> = messages
+            .into_iter()
+            .take(3)
+            .filter(|m| !m.sender.is_empty())
+            .map(|m| GroupMessage {
+                sender: m.sender.clone(),
+                text: m.text.clone(),
+                ts: m.ts,
+                waiting_list: m.waiting_list,
+            })
+            .collect();
+        messages.reverse();
+        Ok(messages)
+    }
+
+    fn get_presence_list(&self, event_id: u64, offset: u64, limit: u64) -> Result<Vec<Presence>> {
+        let t = self.tables();
+        let mut list = t.group_by_user(
+            t.reservations
+                .iter()
+                .filter(|r| r.event == event_id && r.waiting_list == 0),
+        );
+        list.retain(|u| !t.presence.contains(&(event_id, u.user)));
+        list.sort_by(|a, b| a.user_name1.cmp(&b.user_name1));
+        Ok(list
+            .into_iter()
+            .skip((offset * limit) as usize)
+            .take(limit as usize)
+            .map(|u| Presence {
+                attachment: t.attachments.get(&(event_id, u.user)).cloned(),
+                answers: t.answers(event_id, u.user),
+                user_id: u.user,
+                user_name1: u.user_name1,
+                user_name2: u.user_name2,
+                reserved: u.quantity,
+                present: false,
+            })
+            .collect())
+    }
+
+    fn get_attendance_list(&self, event_id: u64, offset: u64, limit: u64) -> Result<Vec<Presence>> {
+        let t = self.tables();
+        let mut list = t.group_by_user(
+            t.reservations
+                .iter()
+                .filter(|r| r.event == event_id && r.waiting_list == 0),
+        );
+        list.sort_by(|a, b| a.user_name1.cmp(&b.user_name1));
+        Ok(list
+            .into_iter()
+            .skip((offset * limit) as usize)
+            .take(limit as usize)
+            .map(|u| Presence {
+                attachment: t.attachments.get(&(event_id, u.user)).cloned(),
+                answers: t.answers(event_id, u.user),
+                present: t.presence.contains(&(event_id, u.user)),
+                user_id: u.user,
+                user_name1: u.user_name1,
+                user_name2: u.user_name2,
+                reserved: u.quantity,
+            })
+            .collect())
+    }
+
+    fn confirm_presence(&self, event_id: u64, user_id: u64) -> Result<()> {
+        if !self.tables().presence.insert((event_id, user_id)) {
+            return Err(Error::Storage(format!("Presence of user {} is already confirmed", user_id).into()));
+        }
+        Ok(())
+    }
+
+    fn is_group_leader(&self, event_id: u64, user_id: u64) -> Result<bool> {
+        Ok(self.tables().group_leaders.contains(&(event_id, user_id)))
+    }
+
+    fn set_group_leader(&self, event_id: u64, user_id: u64) -> Result<()> {
+        if !self.tables().group_leaders.insert((event_id, user_id)) {
+            return Err(Error::Storage(format!("User {} is already a group leader", user_id).into()));
+        }
+        Ok(())
+    }
+
+    fn save_user(&self, user: &User, ts: u64) -> Result<()> {
+        self.tables().save_user(user, ts);
+        Ok(())
+    }
+
+    fn set_user_reachable(&self, user_id: u64, reachable: bool) -> Result<()> {
+        if let Some(row) = self.tables().users.get_mut(&user_id) {
+            row.reachable = reachable;
+        }
+        Ok(())
+    }
+
+    fn get_user(&self, user_id: u64) -> Result<Option<UserProfile>> {
+        Ok(self
+            .tables()
+            .users
+            .get(&user_id)
+            .map(|row| user_profile(user_id, row)))
+    }
+
+    fn get_users(&self, offset: u64, limit: u64) -> Result<Vec<UserProfile>> {
+        let t = self.tables();
+        let mut list: Vec<(&u64, &UserRow)> = t.users.iter().collect();
+        list.sort_by(|a, b| b.1.last_seen.cmp(&a.1.last_seen).then(a.0.cmp(b.0)));
+        Ok(list
+            .into_iter()
+            .skip((offset * limit) as usize)
+            .take(limit as usize)
+            .map(|(user, row)| user_profile(*user, row))
+            .collect())
+    }
+
+    fn add_series(&self, s: Series, actor: u64) -> Result<u64> {
+        let mut t = self.tables();
+        if s.id == 0 {
+            t.last_series_id += 1;
+            let series_id = t.last_series_id;
+            t.series.insert(
+                series_id,
+                Series {
+                    id: series_id,
+                    next_ts: s.template.ts,
+                    cancelled: false,
+                    ..s
+                },
+            );
+            return Ok(series_id);
+        }
+
+        let old = t.get_series(s.id)?;
+        // Nothing is changed if any of the occurrences can't be.
+        for o in t.get_upcoming_occurrences(s.id) {
+            check_ticket_types(&o.event, &s.template, t.booked_tickets(o.event.id))?;
+        }
+        for o in t.get_upcoming_occurrences(s.id) {
+            let mut changed = occurrence(&s, shift_local(&s.template, old.template.ts, s.template.ts, o.event.ts));
+            changed.id = o.event.id;
+            t.save_event(&changed, o.state)?;
+        }
+        let next_ts = next_occurrence(&old, &s);
+        t.series.insert(
+            s.id,
+            Series {
+                next_ts,
+                cancelled: old.cancelled,
+                ..s
+            },
+        );
+        t.audit(get_unix_time(), actor, AuditAction::EditSeries, 0, 0, &format!("series {}", s.id));
+        Ok(s.id)
+    }
+
+    fn get_series(&self, series_id: u64) -> Result<Series> {
+        self.tables().get_series(series_id)
+    }
+
+    fn get_series_list(&self) -> Result<Vec<Series>> {
+        Ok(self
+            .tables()
+            .series
+            .values()
+            .filter(|s| !s.cancelled)
+            .cloned()
+            .collect())
+    }
+
+    fn cancel_series(&self, series_id: u64, actor: u64) -> Result<()> {
+        let mut t = self.tables();
+        match t.series.get_mut(&series_id) {
+            Some(s) => s.cancelled = true,
+            None => return Err(Error::SeriesNotFound(series_id)),
+        }
+        for o in t.get_upcoming_occurrences(series_id) {
+            t.set_event_state(o.event.id, EventState::Cancelled, actor)?;
+        }
+        t.audit(get_unix_time(), actor, AuditAction::CancelSeries, 0, 0, &format!("series {}", series_id));
+        Ok(())
+    }
+
+    fn materialize_series(&self, ts: u64) -> Result<Vec<u64>> {
+        let now = get_unix_time();
+        let mut t = self.tables();
+        let mut created = Vec::new();
+        let ids: Vec<u64> = t.series.values().filter(|s| !s.cancelled).map(|s| s.id).collect();
+        for series_id in ids {
+            let mut s = t.get_series(series_id)?;
+            while s.next_ts < ts && s.next_ts <= s.until {
+                // Occurrences missed while the bot was down are not created retroactively.
+                if s.next_ts > now {
+                    created.push(t.save_event(&occurrence(&s, s.next_ts), EventState::Open)?);
+                }
+                s.next_ts = add_days(&s.template, s.next_ts, s.period as i64);
+            }
+            t.series.insert(series_id, s);
+        }
+        Ok(created)
+    }
+
+    fn add_venue(&self, v: Venue, actor: u64) -> Result<u64> {
+        let mut t = self.tables();
+        let venue_id = if v.id == 0 {
+            t.last_venue_id += 1;
+            t.last_venue_id
+        } else if t.venues.contains_key(&v.id) {
+            v.id
+        } else {
+            return Err(Error::VenueNotFound(v.id));
+        };
+        t.venues.insert(venue_id, Venue { id: venue_id, ..v });
+        t.audit(get_unix_time(), actor, AuditAction::EditVenue, 0, 0, &format!("venue {}", venue_id));
+        Ok(venue_id)
+    }
+
+    fn get_venue(&self, venue_id: u64) -> Result<Venue> {
+        self.tables()
+            .venues
+            .get(&venue_id)
+            .cloned()
+            .ok_or(Error::VenueNotFound(venue_id))
+    }
+
+    fn get_venues(&self) -> Result<Vec<Venue>> {
+        let mut res: Vec<Venue> = self.tables().venues.values().cloned().collect();
+        res.sort_by(|a, b| a.name.cmp(&b.name).then(a.id.cmp(&b.id)));
+        Ok(res)
+    }
+
+    fn add_category(&self, name: &str) -> Result<u64> {
+        let mut t = self.tables();
+        if let Some(c) = t.categories.values().find(|c| c.name == name) {
+            return Ok(c.id);
+        }
+        t.last_category_id += 1;
+        let id = t.last_category_id;
+        t.categories.insert(id, Category { id, name: name.to_string() });
+        Ok(id)
+    }
+
+    fn get_categories(&self) -> Result<Vec<Category>> {
+        let mut res: Vec<Category> = self.tables().categories.values().cloned().collect();
+        res.sort_by(|a, b| a.name.cmp(&b.name));
+        Ok(res)
+    }
+
+    fn get_answers(&self, event_id: u64, user: u64) -> Result<Vec<String>> {
+        let t = self.tables();
+        t.find_event(event_id, 0)?;
+        Ok(t.answers(event_id, user))
+    }
+
+    fn save_answer(&self, event_id: u64, user: u64, question: u64, answer: &str) -> Result<()> {
+        let mut t = self.tables();
+        let s = t.get_event(event_id, user)?;
+        if s.my_reservation() == 0 && s.my_waiting() == 0 {
+            return Err(Error::ReservationNotFound);
+        }
+        if question < s.event.questions.len() as u64 {
+            t.answers.insert((event_id, user, question), answer.to_string());
+        }
+        Ok(())
+    }
+
+    fn clear_answers(&self, event_id: u64, user: u64) -> Result<()> {
+        self.tables().answers.retain(|(event, u, _), _| (*event, *u) != (event_id, user));
+        Ok(())
+    }
+
+    fn get_questionnaire(&self, user: u64) -> Result<u64> {
+        Ok(self.tables().questionnaires.get(&user).copied().unwrap_or(0))
+    }
+
+    fn set_questionnaire(&self, user: u64, event_id: u64) -> Result<()> {
+        let mut t = self.tables();
+        if event_id == 0 {
+            t.questionnaires.remove(&user);
+        } else {
+            t.find_event(event_id, 0)?;
+            t.questionnaires.insert(user, event_id);
+        }
+        Ok(())
+    }
+
+    fn get_refunds(&self, offset: u64, limit: u64) -> Result<Vec<Refund>> {
+        let t = self.tables();
+        let mut list: Vec<&Refund> = t.refunds.iter().collect();
+        list.sort_by_key(|r| (r.refunded > 0, std::cmp::Reverse((r.ts, r.id))));
+        Ok(list
+            .into_iter()
+            .skip((offset * limit) as usize)
+            .take(limit as usize)
+            .cloned()
+            .collect())
+    }
+
+    fn complete_refund(&self, refund_id: u64, actor: u64) -> Result<()> {
+        let mut t = self.tables();
+        let now = get_unix_time();
+        let r = t
+            .refunds
+            .iter_mut()
+            .find(|r| r.id == refund_id && r.refunded == 0)
+            .ok_or(Error::RefundNotFound(refund_id))?;
+        r.refunded = now;
+        let (event_id, user_id) = (r.event_id, r.user_id);
+        let details = format!("{} {} {}", r.amount as f32 / 100f32, r.currency, r.payment);
+        t.audit(now, actor, AuditAction::CompleteRefund, event_id, user_id, &details);
+        Ok(())
+    }
+
+    fn save_search(&self, user: u64, text: &str) -> Result<()> {
+        self.tables().searches.insert(user, text.to_string());
+        Ok(())
+    }
+
+    fn get_search(&self, user: u64) -> Result<String> {
+        Ok(self.tables().searches.get(&user).cloned().unwrap_or_default())
+    }
+
+    fn save_import(&self, user: u64, events: &str) -> Result<()> {
+        self.tables().imports.insert(user, events.to_string());
+        Ok(())
+    }
+
+    fn take_import(&self, user: u64) -> Result<Option<String>> {
+        Ok(self.tables().imports.remove(&user))
+    }
+
+    fn get_audit_log(
+        &self,
+        filter: AuditFilter,
+        offset: u64,
+        limit: u64,
+    ) -> Result<Vec<AuditRecord>> {
+        let t = self.tables();
+        let mut list: Vec<&AuditRecord> = t
+            .audit_log
+            .iter()
+            .rev()
+            .filter(|r| match filter {
+                AuditFilter::All => true,
+                AuditFilter::Event(event_id) => r.event_id == event_id,
+                AuditFilter::User(user_id) => r.actor == user_id || r.user_id == user_id,
+            })
+            .collect();
+        list.sort_by_key(|r| std::cmp::Reverse(r.ts));
+        Ok(list
+            .into_iter()
+            .skip((offset * limit) as usize)
+            .take(limit as usize)
+            .cloned()
+            .collect())
+    }
+}
+
+fn user_profile(user_id: u64, row: &UserRow) -> UserProfile {
+    UserProfile {
+        user: User {
+            id: teloxide::types::UserId(user_id),
+            user_name1: row.user_name1.clone(),
+            user_name2: row.user_name2.clone(),
+            language_code: row.language_code.clone(),
+            is_admin: false,
+        },
+        first_seen: row.first_seen,
+        last_seen: row.last_seen,
+        reachable: row.reachable,
+    }
+}
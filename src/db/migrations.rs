@@ -0,0 +1,621 @@
+use anyhow::anyhow;
+use rusqlite::{params, Connection, Transaction};
+
+use crate::types::TicketType;
+use crate::util;
+
+/// A single schema change. Migrations are applied in order, each one in its own transaction,
+/// and recorded in the `schema_migrations` table.
+struct Migration {
+    version: u64,
+    description: &'static str,
+    apply: fn(&Transaction) -> Result<(), rusqlite::Error>,
+}
+
+const MIGRATIONS: &[Migration] = &[
+    Migration {
+        version: 1,
+        description: "initial schema",
+        apply: initial_schema,
+    },
+    Migration {
+        version: 2,
+        description: "add columns introduced before versioning",
+        apply: add_unversioned_columns,
+    },
+    Migration {
+        version: 3,
+        description: "move group_leaders_event_user_unique_idx to group_leaders",
+        apply: fix_group_leaders_index,
+    },
+    Migration {
+        version: 4,
+        description: "archive past events instead of deleting them",
+        apply: add_archived_flag,
+    },
+    Migration {
+        version: 5,
+        description: "users table referenced by reservations and black list",
+        apply: add_users,
+    },
+    Migration {
+        version: 6,
+        description: "audit log",
+        apply: add_audit_log,
+    },
+    Migration {
+        version: 7,
+        description: "event series",
+        apply: add_series,
+    },
+    Migration {
+        version: 8,
+        description: "ticket types instead of adults and children",
+        apply: add_ticket_types,
+    },
+    Migration {
+        version: 9,
+        description: "venues",
+        apply: add_venues,
+    },
+    Migration {
+        version: 10,
+        description: "event duration and overlaps",
+        apply: add_event_duration,
+    },
+    Migration {
+        version: 11,
+        description: "event categories",
+        apply: add_categories,
+    },
+    Migration {
+        version: 12,
+        description: "event posters",
+        apply: add_posters,
+    },
+    Migration {
+        version: 13,
+        description: "registration window",
+        apply: add_registration_window,
+    },
+    Migration {
+        version: 14,
+        description: "event questions",
+        apply: add_questions,
+    },
+    Migration {
+        version: 15,
+        description: "refunds",
+        apply: add_refunds,
+    },
+    Migration {
+        version: 16,
+        description: "event time zones",
+        apply: add_time_zones,
+    },
+    Migration {
+        version: 17,
+        description: "imports",
+        apply: add_imports,
+    },
+    Migration {
+        version: 18,
+        description: "event search",
+        apply: add_event_search,
+    },
+    Migration {
+        version: 19,
+        description: "waiting list promotion",
+        apply: add_promotions,
+    },
+    Migration {
+        version: 20,
+        description: "event revisions",
+        apply: add_event_revisions,
+    },
+    Migration {
+        version: 21,
+        description: "series period in days",
+        apply: count_series_period_in_days,
+    },
+];
+
+/// Latest schema version known to this binary.
+pub fn latest_version() -> u64 {
+    MIGRATIONS.last().map(|m| m.version).unwrap_or(0)
+}
+
+/// Brings the database schema up to date.
+/// Fails if the database has been migrated by a newer version of the bot.
+pub fn migrate(conn: &mut Connection) -> anyhow::Result<u64> {
+    conn.execute(
+        "CREATE TABLE IF NOT EXISTS schema_migrations (
+            version         INTEGER PRIMARY KEY,
+            description     TEXT NOT NULL,
+            ts              INTEGER NOT NULL
+            )",
+        [],
+    )?;
+
+    let current = get_version(conn)?;
+    if current > latest_version() {
+        return Err(anyhow!(
+            "Database schema version {} is newer than the latest supported version {}.",
+            current,
+            latest_version()
+        ));
+    }
+
+    for m in MIGRATIONS.iter().filter(|m| m.version > current) {
+        info!("Applying migration {}: {}", m.version, m.description);
+        let tx = conn.transaction()?;
+        (m.apply)(&tx)
+            .map_err(|e| anyhow!("Migration {} ({}) failed: {}", m.version, m.description, e))?;
+        tx.execute(
+            "INSERT INTO schema_migrations (version, description, ts) VALUES (?1, ?2, ?3)",
+            params![m.version, m.description, util::get_unix_time()],
+        )?;
+        tx.commit()?;
+    }
+    Ok(latest_version())
+}
+
+pub fn get_version(conn: &Connection) -> Result<u64, rusqlite::Error> {
+    conn.query_row(
+        "SELECT IFNULL(MAX(version), 0) FROM schema_migrations",
+        [],
+        |row| row.get(0),
+    )
+}
+
+fn has_column(conn: &Connection, table: &str, column: &str) -> Result<bool, rusqlite::Error> {
+    let mut stmt = conn.prepare(&format!("PRAGMA table_info({})", table))?;
+    let mut rows = stmt.query([])?;
+    while let Some(row) = rows.next()? {
+        let name: String = row.get("name")?;
+        if name == column {
+            return Ok(true);
+        }
+    }
+    Ok(false)
+}
+
+fn add_column(
+    conn: &Connection,
+    table: &str,
+    column: &str,
+    definition: &str,
+) -> Result<(), rusqlite::Error> {
+    if !has_column(conn, table, column)? {
+        conn.execute(
+            &format!("ALTER TABLE {} ADD COLUMN {} {}", table, column, definition),
+            [],
+        )?;
+    }
+    Ok(())
+}
+
+/// Schema as it was created by `db::create` before migrations were introduced.
+/// Existing databases already have these tables, so everything is created only if missing.
+fn initial_schema(tx: &Transaction) -> Result<(), rusqlite::Error> {
+    tx.execute_batch(
+        "CREATE TABLE IF NOT EXISTS events (
+            id              INTEGER PRIMARY KEY AUTOINCREMENT,
+            name            TEXT NOT NULL,
+            link            TEXT NOT NULL,
+            max_adults      INTEGER NOT NULL,
+            max_children    INTEGER NOT NULL,
+            max_adults_per_reservation   INTEGER NOT NULL,
+            max_children_per_reservation INTEGER NOT NULL,
+            ts              INTEGER NOT NULL,
+            remind          INTEGER NOT NULL
+            );
+
+        CREATE TABLE IF NOT EXISTS reservations (
+            id              INTEGER PRIMARY KEY,
+            event           INTEGER NOT NULL,
+            user            INTEGER NOT NULL,
+            user_name1      TEXT NOT NULL,
+            user_name2      TEXT NOT NULL,
+            adults          INTEGER NOT NULL,
+            children        INTEGER NOT NULL,
+            waiting_list    INTEGER DEFAULT 0 NOT NULL,
+            ts              INTEGER NOT NULL
+            );
+        CREATE INDEX IF NOT EXISTS reservations_event_index ON reservations (event);
+        CREATE INDEX IF NOT EXISTS reservations_user_index ON reservations (user);
+
+        CREATE TABLE IF NOT EXISTS attachments (
+            event           INTEGER NOT NULL,
+            user            INTEGER NOT NULL,
+            attachment      TEXT NOT NULL
+            );
+        CREATE INDEX IF NOT EXISTS attachments_event_index ON attachments (event);
+        CREATE UNIQUE INDEX IF NOT EXISTS attachments_unique_event_user_idx ON attachments (event, user);
+
+        CREATE TABLE IF NOT EXISTS black_list (
+            user            INTEGER PRIMARY KEY,
+            user_name1      TEXT NOT NULL,
+            user_name2      TEXT NOT NULL,
+            ts              INTEGER NOT NULL,
+            reason          TEXT default ''
+            );
+
+        CREATE TABLE IF NOT EXISTS presence (
+            event           INTEGER NOT NULL,
+            user            INTEGER NOT NULL
+            );
+        CREATE INDEX IF NOT EXISTS presence_event_index ON presence (event);
+        CREATE UNIQUE INDEX IF NOT EXISTS presence_event_user_unique_idx ON presence (event, user);
+
+        CREATE TABLE IF NOT EXISTS group_leaders (
+            event           INTEGER NOT NULL,
+            user            INTEGER NOT NULL
+            );
+
+        CREATE TABLE IF NOT EXISTS messages (
+            id              INTEGER PRIMARY KEY AUTOINCREMENT,
+            event           INTEGER NOT NULL,
+            type            INTEGER NOT NULL,
+            sender          text NOT NULL,
+            waiting_list    INTEGER NOT NULL,
+            text            text NOT NULL,
+            ts              INTEGER NOT NULL
+            );
+        CREATE INDEX IF NOT EXISTS messages_event_index ON messages (event);
+
+        CREATE TABLE IF NOT EXISTS message_outbox (
+            message         INTEGER NOT NULL,
+            send_at         INTEGER NOT NULL
+            );
+
+        CREATE TABLE IF NOT EXISTS message_sent (
+            message         INTEGER NOT NULL,
+            user            INTEGER NOT NULL,
+            ts              INTEGER NOT NULL
+            );
+
+        CREATE TABLE IF NOT EXISTS current_events (
+            user            INTEGER NOT NULL PRIMARY KEY,
+            event           INTEGER NOT NULL
+            );",
+    )
+}
+
+/// Columns that used to be added to production databases by hand.
+fn add_unversioned_columns(tx: &Transaction) -> Result<(), rusqlite::Error> {
+    add_column(tx, "events", "state", "INTEGER default 0")?;
+    add_column(tx, "events", "adult_ticket_price", "INTEGER default 0")?;
+    add_column(tx, "events", "child_ticket_price", "INTEGER default 0")?;
+    add_column(tx, "events", "currency", "TEXT default 'EUR'")?;
+    add_column(tx, "reservations", "payment", "TEXT DEFAULT NULL")?;
+    add_column(tx, "reservations", "state", "INTEGER default 0")?;
+    Ok(())
+}
+
+/// The unique index for group leaders used to be created on the presence table by mistake.
+fn fix_group_leaders_index(tx: &Transaction) -> Result<(), rusqlite::Error> {
+    tx.execute_batch(
+        "DROP INDEX IF EXISTS group_leaders_event_user_unique_idx;
+        DELETE FROM group_leaders WHERE rowid NOT IN (SELECT MIN(rowid) FROM group_leaders GROUP BY event, user);
+        CREATE UNIQUE INDEX group_leaders_event_user_unique_idx ON group_leaders (event, user);",
+    )
+}
+
+fn add_archived_flag(tx: &Transaction) -> Result<(), rusqlite::Error> {
+    tx.execute_batch("ALTER TABLE events ADD COLUMN archived INTEGER NOT NULL DEFAULT 0;")
+}
+
+/// Names move from reservations and black list into users. Known users are taken over with
+/// their latest names. SQLite can't add foreign keys to existing tables, so both are rebuilt.
+fn add_users(tx: &Transaction) -> Result<(), rusqlite::Error> {
+    tx.execute_batch(
+        "CREATE TABLE users (
+            user            INTEGER PRIMARY KEY,
+            user_name1      TEXT NOT NULL,
+            user_name2      TEXT NOT NULL,
+            language_code   TEXT,
+            first_seen      INTEGER NOT NULL,
+            last_seen       INTEGER NOT NULL,
+            reachable       INTEGER NOT NULL DEFAULT 1
+            );
+
+        INSERT INTO users (user, user_name1, user_name2, first_seen, last_seen)
+            SELECT user, user_name1, user_name2, 0, max(ts) FROM
+            (SELECT user, user_name1, user_name2, ts FROM reservations UNION ALL SELECT user, user_name1, user_name2, ts FROM black_list)
+            GROUP BY user;
+        UPDATE users SET first_seen = (SELECT min(ts) FROM
+            (SELECT user, ts FROM reservations UNION ALL SELECT user, ts FROM black_list) AS u WHERE u.user = users.user);
+
+        CREATE TABLE reservations_new (
+            id              INTEGER PRIMARY KEY,
+            event           INTEGER NOT NULL,
+            user            INTEGER NOT NULL REFERENCES users (user),
+            adults          INTEGER NOT NULL,
+            children        INTEGER NOT NULL,
+            waiting_list    INTEGER DEFAULT 0 NOT NULL,
+            ts              INTEGER NOT NULL,
+            payment         TEXT DEFAULT NULL,
+            state           INTEGER default 0
+            );
+        INSERT INTO reservations_new (id, event, user, adults, children, waiting_list, ts, payment, state)
+            SELECT id, event, user, adults, children, waiting_list, ts, payment, state FROM reservations;
+        DROP TABLE reservations;
+        ALTER TABLE reservations_new RENAME TO reservations;
+        CREATE INDEX reservations_event_index ON reservations (event);
+        CREATE INDEX reservations_user_index ON reservations (user);
+
+        CREATE TABLE black_list_new (
+            user            INTEGER PRIMARY KEY REFERENCES users (user),
+            ts              INTEGER NOT NULL,
+            reason          TEXT default ''
+            );
+        INSERT INTO black_list_new (user, ts, reason) SELECT user, ts, reason FROM black_list;
+        DROP TABLE black_list;
+        ALTER TABLE black_list_new RENAME TO black_list;",
+    )
+}
+
+fn add_audit_log(tx: &Transaction) -> Result<(), rusqlite::Error> {
+    tx.execute_batch(
+        "CREATE TABLE audit_log (
+            id              INTEGER PRIMARY KEY,
+            ts              INTEGER NOT NULL,
+            actor           INTEGER NOT NULL,
+            action          INTEGER NOT NULL,
+            event           INTEGER NOT NULL,
+            user            INTEGER NOT NULL,
+            details         TEXT NOT NULL
+            );
+        CREATE INDEX audit_log_event_index ON audit_log (event);
+        CREATE INDEX audit_log_user_index ON audit_log (user);
+        CREATE INDEX audit_log_actor_index ON audit_log (actor);",
+    )
+}
+
+fn add_series(tx: &Transaction) -> Result<(), rusqlite::Error> {
+    tx.execute_batch(
+        "CREATE TABLE series (
+            id              INTEGER PRIMARY KEY AUTOINCREMENT,
+            name            TEXT NOT NULL,
+            link            TEXT NOT NULL,
+            max_adults      INTEGER NOT NULL,
+            max_children    INTEGER NOT NULL,
+            max_adults_per_reservation   INTEGER NOT NULL,
+            max_children_per_reservation INTEGER NOT NULL,
+            ts              INTEGER NOT NULL,
+            remind          INTEGER NOT NULL,
+            adult_ticket_price INTEGER NOT NULL,
+            child_ticket_price INTEGER NOT NULL,
+            currency        TEXT NOT NULL,
+            period          INTEGER NOT NULL,
+            until           INTEGER NOT NULL,
+            next_ts         INTEGER NOT NULL,
+            cancelled       INTEGER NOT NULL DEFAULT 0
+            );
+
+        ALTER TABLE events ADD COLUMN series INTEGER NOT NULL DEFAULT 0;
+        CREATE INDEX events_series_index ON events (series);",
+    )
+}
+
+/// Adults and children become ticket types of their own, unless the event had no places for them.
+/// Reservations refer to ticket types by index, bookings of both are split.
+fn add_ticket_types(tx: &Transaction) -> Result<(), rusqlite::Error> {
+    tx.execute_batch(
+        "ALTER TABLE events ADD COLUMN tickets TEXT NOT NULL DEFAULT '[]';
+        ALTER TABLE series ADD COLUMN tickets TEXT NOT NULL DEFAULT '[]';",
+    )?;
+    for table in ["events", "series"] {
+        let mut stmt = tx.prepare(&format!(
+            "SELECT id, max_adults, max_children, max_adults_per_reservation, max_children_per_reservation, \
+            adult_ticket_price, child_ticket_price FROM {}",
+            table
+        ))?;
+        let mut rows = stmt.query([])?;
+        while let Some(row) = rows.next()? {
+            let id: u64 = row.get(0)?;
+            let mut tickets = Vec::new();
+            for (name, capacity, max_per_reservation, price) in [
+                ("взрослые", row.get(1)?, row.get(3)?, row.get(5)?),
+                ("дети", row.get(2)?, row.get(4)?, row.get(6)?),
+            ] {
+                if capacity > 0 || price > 0 {
+                    tickets.push(TicketType {
+                        name: name.to_string(),
+                        capacity,
+                        max_per_reservation,
+                        price,
+                    });
+                }
+            }
+            let tickets = serde_json::to_string(&tickets)
+                .map_err(|e| rusqlite::Error::ToSqlConversionFailure(Box::new(e)))?;
+            tx.execute(
+                &format!("UPDATE {} SET tickets = ?1 WHERE id = ?2", table),
+                params![tickets, id],
+            )?;
+        }
+    }
+
+    tx.execute_batch(
+        "ALTER TABLE reservations ADD COLUMN ticket INTEGER NOT NULL DEFAULT 0;
+        ALTER TABLE reservations ADD COLUMN quantity INTEGER NOT NULL DEFAULT 0;
+
+        INSERT INTO reservations (event, user, adults, children, waiting_list, ts, payment, state)
+            SELECT event, user, 0, children, waiting_list, ts, payment, state FROM reservations WHERE adults > 0 AND children > 0;
+        UPDATE reservations SET children = 0 WHERE adults > 0 AND children > 0;
+        UPDATE reservations SET quantity = adults + children, ticket = CASE WHEN adults > 0 THEN 0 ELSE
+            IFNULL((SELECT e.max_adults > 0 OR e.adult_ticket_price > 0 FROM events AS e WHERE e.id = reservations.event), 0) END;
+
+        ALTER TABLE reservations DROP COLUMN adults;
+        ALTER TABLE reservations DROP COLUMN children;
+        ALTER TABLE events DROP COLUMN max_adults;
+        ALTER TABLE events DROP COLUMN max_children;
+        ALTER TABLE events DROP COLUMN max_adults_per_reservation;
+        ALTER TABLE events DROP COLUMN max_children_per_reservation;
+        ALTER TABLE events DROP COLUMN adult_ticket_price;
+        ALTER TABLE events DROP COLUMN child_ticket_price;
+        ALTER TABLE series DROP COLUMN max_adults;
+        ALTER TABLE series DROP COLUMN max_children;
+        ALTER TABLE series DROP COLUMN max_adults_per_reservation;
+        ALTER TABLE series DROP COLUMN max_children_per_reservation;
+        ALTER TABLE series DROP COLUMN adult_ticket_price;
+        ALTER TABLE series DROP COLUMN child_ticket_price;",
+    )
+}
+
+fn add_venues(tx: &Transaction) -> Result<(), rusqlite::Error> {
+    tx.execute_batch(
+        "CREATE TABLE venues (
+            id        INTEGER PRIMARY KEY AUTOINCREMENT,
+            name      TEXT NOT NULL,
+            address   TEXT NOT NULL,
+            latitude  REAL NOT NULL,
+            longitude REAL NOT NULL
+        );
+        ALTER TABLE events ADD COLUMN venue INTEGER NOT NULL DEFAULT 0;
+        ALTER TABLE series ADD COLUMN venue INTEGER NOT NULL DEFAULT 0;",
+    )
+}
+
+fn add_event_duration(tx: &Transaction) -> Result<(), rusqlite::Error> {
+    tx.execute_batch(
+        "ALTER TABLE events ADD COLUMN duration INTEGER NOT NULL DEFAULT 0;
+        ALTER TABLE events ADD COLUMN allow_overlap INTEGER NOT NULL DEFAULT 0;
+        ALTER TABLE series ADD COLUMN duration INTEGER NOT NULL DEFAULT 0;
+        ALTER TABLE series ADD COLUMN allow_overlap INTEGER NOT NULL DEFAULT 0;",
+    )
+}
+
+fn add_categories(tx: &Transaction) -> Result<(), rusqlite::Error> {
+    tx.execute_batch(
+        "CREATE TABLE categories (
+            id      INTEGER PRIMARY KEY AUTOINCREMENT,
+            name    TEXT NOT NULL UNIQUE
+        );
+        ALTER TABLE events ADD COLUMN categories TEXT NOT NULL DEFAULT '[]';
+        ALTER TABLE series ADD COLUMN categories TEXT NOT NULL DEFAULT '[]';",
+    )
+}
+
+fn add_posters(tx: &Transaction) -> Result<(), rusqlite::Error> {
+    tx.execute_batch("ALTER TABLE events ADD COLUMN poster TEXT NOT NULL DEFAULT '';")
+}
+
+fn add_registration_window(tx: &Transaction) -> Result<(), rusqlite::Error> {
+    tx.execute_batch(
+        "ALTER TABLE events ADD COLUMN registration_opens INTEGER NOT NULL DEFAULT 0;
+        ALTER TABLE events ADD COLUMN registration_closes INTEGER NOT NULL DEFAULT 0;
+        CREATE TABLE registration_subscriptions (
+            event   INTEGER NOT NULL,
+            user    INTEGER NOT NULL,
+            ts      INTEGER NOT NULL,
+            PRIMARY KEY (event, user)
+        );",
+    )
+}
+
+fn add_questions(tx: &Transaction) -> Result<(), rusqlite::Error> {
+    tx.execute_batch(
+        "ALTER TABLE events ADD COLUMN questions TEXT NOT NULL DEFAULT '[]';
+        ALTER TABLE series ADD COLUMN questions TEXT NOT NULL DEFAULT '[]';
+        CREATE TABLE answers (
+            event       INTEGER NOT NULL,
+            user        INTEGER NOT NULL,
+            question    INTEGER NOT NULL,
+            answer      TEXT NOT NULL,
+            PRIMARY KEY (event, user, question)
+        );
+        CREATE TABLE questionnaires (
+            user        INTEGER PRIMARY KEY,
+            event       INTEGER NOT NULL
+        );",
+    )
+}
+
+fn add_refunds(tx: &Transaction) -> Result<(), rusqlite::Error> {
+    tx.execute_batch(
+        "CREATE TABLE refunds (
+            id          INTEGER PRIMARY KEY AUTOINCREMENT,
+            event       INTEGER NOT NULL,
+            event_name  TEXT NOT NULL,
+            user        INTEGER NOT NULL,
+            name        TEXT NOT NULL,
+            payment     TEXT NOT NULL,
+            amount      INTEGER NOT NULL,
+            currency    TEXT NOT NULL,
+            ts          INTEGER NOT NULL,
+            refunded    INTEGER NOT NULL DEFAULT 0
+        );",
+    )
+}
+
+fn add_time_zones(tx: &Transaction) -> Result<(), rusqlite::Error> {
+    tx.execute_batch(
+        "ALTER TABLE events ADD COLUMN time_zone TEXT NOT NULL DEFAULT '';
+        ALTER TABLE series ADD COLUMN time_zone TEXT NOT NULL DEFAULT '';",
+    )
+}
+
+fn add_imports(tx: &Transaction) -> Result<(), rusqlite::Error> {
+    tx.execute_batch(
+        "CREATE TABLE imports (
+            user        INTEGER PRIMARY KEY,
+            events      TEXT NOT NULL
+        );",
+    )
+}
+
+/// Full-text index of event names, links and venue names, kept up to date by triggers.
+/// Events have no description column, it's the post the link points to.
+fn add_event_search(tx: &Transaction) -> Result<(), rusqlite::Error> {
+    tx.execute_batch(
+        "CREATE VIRTUAL TABLE event_search USING fts5(name, link, venue);
+        INSERT INTO event_search (rowid, name, link, venue)
+            SELECT events.id, events.name, events.link, IFNULL(venues.name, '')
+            FROM events LEFT JOIN venues ON venues.id = events.venue;
+        CREATE TRIGGER event_search_insert AFTER INSERT ON events BEGIN
+            INSERT INTO event_search (rowid, name, link, venue)
+            VALUES (new.id, new.name, new.link, IFNULL((SELECT name FROM venues WHERE id = new.venue), ''));
+        END;
+        CREATE TRIGGER event_search_update AFTER UPDATE OF name, link, venue ON events BEGIN
+            DELETE FROM event_search WHERE rowid = old.id;
+            INSERT INTO event_search (rowid, name, link, venue)
+            VALUES (new.id, new.name, new.link, IFNULL((SELECT name FROM venues WHERE id = new.venue), ''));
+        END;
+        CREATE TRIGGER event_search_delete AFTER DELETE ON events BEGIN
+            DELETE FROM event_search WHERE rowid = old.id;
+        END;
+        CREATE TRIGGER event_search_venue AFTER UPDATE OF name ON venues BEGIN
+            UPDATE event_search SET venue = new.name WHERE rowid IN (SELECT id FROM events WHERE venue = new.id);
+        END;
+        CREATE TABLE searches (
+            user        INTEGER PRIMARY KEY,
+            text        TEXT NOT NULL
+        );",
+    )
+}
+
+fn add_promotions(tx: &Transaction) -> Result<(), rusqlite::Error> {
+    tx.execute_batch(
+        "ALTER TABLE events ADD COLUMN auto_promote INTEGER NOT NULL DEFAULT 0;
+        ALTER TABLE series ADD COLUMN auto_promote INTEGER NOT NULL DEFAULT 0;
+        CREATE TABLE promotions (
+            event       INTEGER NOT NULL,
+            user        INTEGER NOT NULL,
+            ts          INTEGER NOT NULL,
+            PRIMARY KEY (event, user)
+        );",
+    )
+}
+
+fn add_event_revisions(tx: &Transaction) -> Result<(), rusqlite::Error> {
+    tx.execute_batch("ALTER TABLE events ADD COLUMN revision INTEGER NOT NULL DEFAULT 0;")
+}
+
+/// Occurrences are counted in local days, so that they keep their time when daylight saving
+/// time starts or ends.
+fn count_series_period_in_days(tx: &Transaction) -> Result<(), rusqlite::Error> {
+    tx.execute_batch("UPDATE series SET period = MAX(period / 86400, 1);")
+}
@@ -0,0 +1,2150 @@
+use super::{
+    add_days, check_amount, check_editable, check_sign_up, check_ticket_types, counters, event_cancelled_text, event_change_text, event_name, is_upcoming, next_occurrence, occurrence,
+    one_ticket, promoted_text, promotions, refunds, registration_opened_text, reminder_text, reservation_details, search_words, shift_local,
+    took_place, waiting_list_prompt_text, AuditFilter, AuditRecord, Error, EventFilter, EventStats, GroupMessage,
+    Refund, Result, SignUp, SignUpChecks, Storage, SYSTEM_ACTOR, UserProfile, WaitingReservation,
+};
+use crate::types::{
+    AuditAction, Booking, Category, Event, EventState, EventType, MessageBatch, MessageType, OrderInfo, Participant,
+    Presence, ReservationState, Series, TicketType, User, Venue,
+};
+use crate::util::{self, get_unix_time};
+use anyhow::anyhow;
+use postgres::{GenericClient, NoTls, Row};
+use r2d2_postgres::PostgresConnectionManager;
+use std::collections::HashSet;
+use url::Url;
+
+type Pool = r2d2::Pool<PostgresConnectionManager<NoTls>>;
+
+/// PostgreSQL storage. Several bot instances can share one database.
+pub struct PostgresStorage {
+    pool: Pool,
+}
+
+/// Schema changes, applied in order and recorded in the `schema_migrations` table.
+/// Column `user` of the SQLite schema is called `user_id` here, since `user` is reserved.
+const MIGRATIONS: &[(u64, &str, &str)] = &[
+    (
+        1,
+        "initial schema",
+        "CREATE TABLE events (
+        id              BIGSERIAL PRIMARY KEY,
+        name            TEXT NOT NULL,
+        link            TEXT NOT NULL,
+        max_adults      BIGINT NOT NULL,
+        max_children    BIGINT NOT NULL,
+        max_adults_per_reservation   BIGINT NOT NULL,
+        max_children_per_reservation BIGINT NOT NULL,
+        ts              BIGINT NOT NULL,
+        remind          BIGINT NOT NULL,
+        state           BIGINT NOT NULL DEFAULT 0,
+        adult_ticket_price BIGINT NOT NULL DEFAULT 0,
+        child_ticket_price BIGINT NOT NULL DEFAULT 0,
+        currency        TEXT NOT NULL DEFAULT 'EUR'
+        );
+
+    CREATE TABLE reservations (
+        id              BIGSERIAL PRIMARY KEY,
+        event           BIGINT NOT NULL,
+        user_id         BIGINT NOT NULL,
+        user_name1      TEXT NOT NULL,
+        user_name2      TEXT NOT NULL,
+        adults          BIGINT NOT NULL,
+        children        BIGINT NOT NULL,
+        waiting_list    BIGINT NOT NULL DEFAULT 0,
+        ts              BIGINT NOT NULL,
+        payment         TEXT DEFAULT NULL,
+        state           BIGINT NOT NULL DEFAULT 0
+        );
+    CREATE INDEX reservations_event_index ON reservations (event);
+    CREATE INDEX reservations_user_index ON reservations (user_id);
+
+    CREATE TABLE attachments (
+        event           BIGINT NOT NULL,
+        user_id         BIGINT NOT NULL,
+        attachment      TEXT NOT NULL,
+        PRIMARY KEY (event, user_id)
+        );
+
+    CREATE TABLE black_list (
+        user_id         BIGINT PRIMARY KEY,
+        user_name1      TEXT NOT NULL,
+        user_name2      TEXT NOT NULL,
+        ts              BIGINT NOT NULL,
+        reason          TEXT NOT NULL DEFAULT ''
+        );
+
+    CREATE TABLE presence (
+        event           BIGINT NOT NULL,
+        user_id         BIGINT NOT NULL,
+        PRIMARY KEY (event, user_id)
+        );
+
+    CREATE TABLE group_leaders (
+        event           BIGINT NOT NULL,
+        user_id         BIGINT NOT NULL,
+        PRIMARY KEY (event, user_id)
+        );
+
+    CREATE TABLE messages (
+        id              BIGSERIAL PRIMARY KEY,
+        event           BIGINT NOT NULL,
+        type            BIGINT NOT NULL,
+        sender          TEXT NOT NULL,
+        waiting_list    BIGINT NOT NULL,
+        text            TEXT NOT NULL,
+        ts              BIGINT NOT NULL
+        );
+    CREATE INDEX messages_event_index ON messages (event);
+
+    CREATE TABLE message_outbox (
+        message         BIGINT NOT NULL,
+        send_at         BIGINT NOT NULL
+        );
+
+    CREATE TABLE message_sent (
+        message         BIGINT NOT NULL,
+        user_id         BIGINT NOT NULL,
+        ts              BIGINT NOT NULL
+        );
+
+    CREATE TABLE current_events (
+        user_id         BIGINT PRIMARY KEY,
+        event           BIGINT NOT NULL
+        );",
+    ),
+    (
+        2,
+        "archive past events instead of deleting them",
+        "ALTER TABLE events ADD COLUMN archived BIGINT NOT NULL DEFAULT 0;",
+    ),
+    (
+        3,
+        "users table referenced by reservations and black list",
+        "CREATE TABLE users (
+            user_id         BIGINT PRIMARY KEY,
+            user_name1      TEXT NOT NULL,
+            user_name2      TEXT NOT NULL,
+            language_code   TEXT,
+            first_seen      BIGINT NOT NULL,
+            last_seen       BIGINT NOT NULL,
+            reachable       BOOLEAN NOT NULL DEFAULT TRUE
+            );
+
+        INSERT INTO users (user_id, user_name1, user_name2, first_seen, last_seen)
+            SELECT DISTINCT ON (user_id) user_id, user_name1, user_name2,
+                min(ts) OVER (PARTITION BY user_id), max(ts) OVER (PARTITION BY user_id)
+            FROM (SELECT user_id, user_name1, user_name2, ts FROM reservations
+                UNION ALL SELECT user_id, user_name1, user_name2, ts FROM black_list) AS u
+            ORDER BY user_id, ts DESC;
+
+        ALTER TABLE reservations DROP COLUMN user_name1, DROP COLUMN user_name2,
+            ADD FOREIGN KEY (user_id) REFERENCES users (user_id);
+        ALTER TABLE black_list DROP COLUMN user_name1, DROP COLUMN user_name2,
+            ADD FOREIGN KEY (user_id) REFERENCES users (user_id);",
+    ),
+    (
+        4,
+        "audit log",
+        "CREATE TABLE audit_log (
+            id              BIGSERIAL PRIMARY KEY,
+            ts              BIGINT NOT NULL,
+            actor           BIGINT NOT NULL,
+            action          BIGINT NOT NULL,
+            event           BIGINT NOT NULL,
+            user_id         BIGINT NOT NULL,
+            details         TEXT NOT NULL
+            );
+        CREATE INDEX audit_log_event_index ON audit_log (event);
+        CREATE INDEX audit_log_user_index ON audit_log (user_id);
+        CREATE INDEX audit_log_actor_index ON audit_log (actor);",
+    ),
+    (
+        5,
+        "event series",
+        "CREATE TABLE series (
+            id              BIGSERIAL PRIMARY KEY,
+            name            TEXT NOT NULL,
+            link            TEXT NOT NULL,
+            max_adults      BIGINT NOT NULL,
+            max_children    BIGINT NOT NULL,
+            max_adults_per_reservation   BIGINT NOT NULL,
+            max_children_per_reservation BIGINT NOT NULL,
+            ts              BIGINT NOT NULL,
+            remind          BIGINT NOT NULL,
+            adult_ticket_price BIGINT NOT NULL,
+            child_ticket_price BIGINT NOT NULL,
+            currency        TEXT NOT NULL,
+            period          BIGINT NOT NULL,
+            until           BIGINT NOT NULL,
+            next_ts         BIGINT NOT NULL,
+            cancelled       BOOLEAN NOT NULL DEFAULT FALSE
+            );
+
+        ALTER TABLE events ADD COLUMN series BIGINT NOT NULL DEFAULT 0;
+        CREATE INDEX events_series_index ON events (series);",
+    ),
+    (
+        6,
+        "ticket types instead of adults and children",
+        "ALTER TABLE events ADD COLUMN tickets TEXT NOT NULL DEFAULT '[]';
+        ALTER TABLE series ADD COLUMN tickets TEXT NOT NULL DEFAULT '[]';
+        UPDATE events SET tickets = (SELECT COALESCE(json_agg(json_build_object(
+                'name', t.name, 'capacity', t.capacity, 'max_per_reservation', t.max_per_reservation, 'price', t.price) ORDER BY t.i), '[]')::text
+            FROM (VALUES (0, 'взрослые', max_adults, max_adults_per_reservation, adult_ticket_price),
+                (1, 'дети', max_children, max_children_per_reservation, child_ticket_price)) AS t (i, name, capacity, max_per_reservation, price)
+            WHERE t.capacity > 0 OR t.price > 0);
+        UPDATE series SET tickets = (SELECT COALESCE(json_agg(json_build_object(
+                'name', t.name, 'capacity', t.capacity, 'max_per_reservation', t.max_per_reservation, 'price', t.price) ORDER BY t.i), '[]')::text
+            FROM (VALUES (0, 'взрослые', max_adults, max_adults_per_reservation, adult_ticket_price),
+                (1, 'дети', max_children, max_children_per_reservation, child_ticket_price)) AS t (i, name, capacity, max_per_reservation, price)
+            WHERE t.capacity > 0 OR t.price > 0);
+
+        ALTER TABLE reservations ADD COLUMN ticket BIGINT NOT NULL DEFAULT 0,
+            ADD COLUMN quantity BIGINT NOT NULL DEFAULT 0;
+        INSERT INTO reservations (event, user_id, adults, children, waiting_list, ts, payment, state)
+            SELECT event, user_id, 0, children, waiting_list, ts, payment, state FROM reservations WHERE adults > 0 AND children > 0;
+        UPDATE reservations SET children = 0 WHERE adults > 0 AND children > 0;
+        UPDATE reservations AS r SET quantity = r.adults + r.children, ticket = CASE WHEN r.adults > 0 THEN 0 ELSE
+            COALESCE((SELECT CASE WHEN e.max_adults > 0 OR e.adult_ticket_price > 0 THEN 1 ELSE 0 END FROM events AS e WHERE e.id = r.event), 0) END;
+
+        ALTER TABLE reservations DROP COLUMN adults, DROP COLUMN children;
+        ALTER TABLE events DROP COLUMN max_adults, DROP COLUMN max_children,
+            DROP COLUMN max_adults_per_reservation, DROP COLUMN max_children_per_reservation,
+            DROP COLUMN adult_ticket_price, DROP COLUMN child_ticket_price;
+        ALTER TABLE series DROP COLUMN max_adults, DROP COLUMN max_children,
+            DROP COLUMN max_adults_per_reservation, DROP COLUMN max_children_per_reservation,
+            DROP COLUMN adult_ticket_price, DROP COLUMN child_ticket_price;",
+    ),
+    (
+        7,
+        "venues",
+        "CREATE TABLE venues (
+            id              BIGSERIAL PRIMARY KEY,
+            name            TEXT NOT NULL,
+            address         TEXT NOT NULL,
+            latitude        DOUBLE PRECISION NOT NULL,
+            longitude       DOUBLE PRECISION NOT NULL
+            );
+
+        ALTER TABLE events ADD COLUMN venue BIGINT NOT NULL DEFAULT 0;
+        ALTER TABLE series ADD COLUMN venue BIGINT NOT NULL DEFAULT 0;",
+    ),
+    (
+        8,
+        "event duration and overlaps",
+        "ALTER TABLE events ADD COLUMN duration BIGINT NOT NULL DEFAULT 0,
+            ADD COLUMN allow_overlap BOOLEAN NOT NULL DEFAULT FALSE;
+        ALTER TABLE series ADD COLUMN duration BIGINT NOT NULL DEFAULT 0,
+            ADD COLUMN allow_overlap BOOLEAN NOT NULL DEFAULT FALSE;",
+    ),
+    (
+        9,
+        "event categories",
+        "CREATE TABLE categories (
+            id              BIGSERIAL PRIMARY KEY,
+            name            TEXT NOT NULL UNIQUE
+            );
+
+        ALTER TABLE events ADD COLUMN categories TEXT NOT NULL DEFAULT '[]';
+        ALTER TABLE series ADD COLUMN categories TEXT NOT NULL DEFAULT '[]';",
+    ),
+    (
+        10,
+        "event posters",
+        "ALTER TABLE events ADD COLUMN poster TEXT NOT NULL DEFAULT '';",
+    ),
+    (
+        11,
+        "registration window",
+        "ALTER TABLE events ADD COLUMN registration_opens BIGINT NOT NULL DEFAULT 0;
+        ALTER TABLE events ADD COLUMN registration_closes BIGINT NOT NULL DEFAULT 0;
+
+        CREATE TABLE registration_subscriptions (
+            event           BIGINT NOT NULL,
+            user_id         BIGINT NOT NULL,
+            ts              BIGINT NOT NULL,
+            PRIMARY KEY (event, user_id)
+            );",
+    ),
+    (
+        12,
+        "event questions",
+        "ALTER TABLE events ADD COLUMN questions TEXT NOT NULL DEFAULT '[]';
+        ALTER TABLE series ADD COLUMN questions TEXT NOT NULL DEFAULT '[]';
+
+        CREATE TABLE answers (
+            event           BIGINT NOT NULL,
+            user_id         BIGINT NOT NULL,
+            question        BIGINT NOT NULL,
+            answer          TEXT NOT NULL,
+            PRIMARY KEY (event, user_id, question)
+            );
+
+        CREATE TABLE questionnaires (
+            user_id         BIGINT PRIMARY KEY,
+            event           BIGINT NOT NULL
+            );",
+    ),
+    (
+        13,
+        "refunds",
+        "CREATE TABLE refunds (
+            id              BIGSERIAL PRIMARY KEY,
+            event           BIGINT NOT NULL,
+            event_name      TEXT NOT NULL,
+            user_id         BIGINT NOT NULL,
+            name            TEXT NOT NULL,
+            payment         TEXT NOT NULL,
+            amount          BIGINT NOT NULL,
+            currency        TEXT NOT NULL,
+            ts              BIGINT NOT NULL,
+            refunded        BIGINT NOT NULL DEFAULT 0
+            );",
+    ),
+    (
+        14,
+        "event time zones",
+        "ALTER TABLE events ADD COLUMN time_zone TEXT NOT NULL DEFAULT '';
+        ALTER TABLE series ADD COLUMN time_zone TEXT NOT NULL DEFAULT '';",
+    ),
+    (
+        15,
+        "imports",
+        "CREATE TABLE imports (
+            user_id         BIGINT PRIMARY KEY,
+            events          TEXT NOT NULL
+            );",
+    ),
+    (
+        16,
+        "searches",
+        "CREATE TABLE searches (
+            user_id         BIGINT PRIMARY KEY,
+            text            TEXT NOT NULL
+            );",
+    ),
+    (
+        17,
+        "waiting list promotion",
+        "ALTER TABLE events ADD COLUMN auto_promote BOOLEAN NOT NULL DEFAULT false;
+        ALTER TABLE series ADD COLUMN auto_promote BOOLEAN NOT NULL DEFAULT false;
+        CREATE TABLE promotions (
+            event           BIGINT NOT NULL,
+            user_id         BIGINT NOT NULL,
+            ts              BIGINT NOT NULL,
+            PRIMARY KEY (event, user_id)
+            );",
+    ),
+    (
+        18,
+        "event revisions",
+        "ALTER TABLE events ADD COLUMN revision BIGINT NOT NULL DEFAULT 0;",
+    ),
+    (
+        19,
+        "series period in days",
+        "UPDATE series SET period = GREATEST(period / 86400, 1);",
+    ),
+];
+
+impl PostgresStorage {
+    /// Connects to the database and brings its schema up to date.
+    pub fn open(url: &str) -> anyhow::Result<PostgresStorage> {
+        let manager = PostgresConnectionManager::new(url.parse()?, NoTls);
+        let pool = tokio::task::block_in_place(|| r2d2::Pool::new(manager))?;
+        let version = tokio::task::block_in_place(|| migrate(&mut *pool.get()?))?;
+        info!("Database schema version {}", version);
+        Ok(PostgresStorage { pool })
+    }
+
+    /// The synchronous client drives its own runtime, so it must not block async workers.
+    fn run<T>(&self, f: impl FnOnce(&mut postgres::Client) -> Result<T>) -> Result<T> {
+        tokio::task::block_in_place(|| {
+            let mut client = self.pool.get()?;
+            f(&mut client)
+        })
+    }
+
+    fn transaction<T>(
+        &self,
+        f: impl FnOnce(&mut postgres::Transaction) -> Result<T>,
+    ) -> Result<T> {
+        self.run(|client| {
+            let mut tx = client.transaction()?;
+            let res = f(&mut tx)?;
+            tx.commit()?;
+            Ok(res)
+        })
+    }
+}
+
+fn migrate(client: &mut postgres::Client) -> anyhow::Result<u64> {
+    let latest = MIGRATIONS.last().map(|m| m.0).unwrap_or(0);
+    let mut tx = client.transaction()?;
+    // Instances starting at the same time wait for each other.
+    tx.execute("SELECT pg_advisory_xact_lock(1)", &[])?;
+    tx.batch_execute(
+        "CREATE TABLE IF NOT EXISTS schema_migrations (
+            version         BIGINT PRIMARY KEY,
+            description     TEXT NOT NULL,
+            ts              BIGINT NOT NULL
+            )",
+    )?;
+    let current = uint(
+        &tx.query_one("SELECT COALESCE(MAX(version), 0) AS version FROM schema_migrations", &[])?,
+        "version",
+    )?;
+    if current > latest {
+        return Err(anyhow!(
+            "Database schema version {} is newer than the latest supported version {}.",
+            current,
+            latest
+        ));
+    }
+    for (version, description, sql) in MIGRATIONS.iter().filter(|m| m.0 > current) {
+        info!("Applying migration {}: {}", version, description);
+        tx.batch_execute(sql)
+            .map_err(|e| anyhow!("Migration {} ({}) failed: {}", version, description, e))?;
+        tx.execute(
+            "INSERT INTO schema_migrations (version, description, ts) VALUES ($1, $2, $3)",
+            &[&(*version as i64), description, &(get_unix_time() as i64)],
+        )?;
+    }
+    tx.commit()?;
+    Ok(latest)
+}
+
+fn uint(row: &Row, column: &str) -> Result<u64> {
+    Ok(row.try_get::<_, i64>(column)? as u64)
+}
+
+/// Locks the event row until the end of the transaction, so that vacancies can't change
+/// between the check and the update, even if other instances book the same event.
+fn lock_event(tx: &mut impl GenericClient, event_id: u64) -> Result<()> {
+    tx.execute(
+        "SELECT id FROM events WHERE id = $1 FOR UPDATE",
+        &[&(event_id as i64)],
+    )?;
+    Ok(())
+}
+
+const EVENT_STATS: &str = "SELECT e.id, e.name, e.link, e.ts, e.remind, e.state, e.tickets, e.currency, e.series, e.venue, e.duration, e.allow_overlap, e.categories, e.poster, e.registration_opens, e.registration_closes, e.questions, e.time_zone, e.auto_promote, e.revision FROM events AS e";
+
+fn event_stats(c: &mut impl GenericClient, row: &Row, user: u64) -> Result<EventStats> {
+    let tickets: String = row.try_get("tickets")?;
+    let categories: String = row.try_get("categories")?;
+    let questions: String = row.try_get("questions")?;
+    let event = Event {
+        id: uint(row, "id")?,
+        name: row.try_get("name")?,
+        link: row.try_get("link")?,
+        ts: uint(row, "ts")?,
+        remind: uint(row, "remind")?,
+        tickets: serde_json::from_str(&tickets)?,
+        currency: row.try_get("currency")?,
+        series_id: uint(row, "series")?,
+        venue_id: uint(row, "venue")?,
+        duration: uint(row, "duration")?,
+        allow_overlap: row.try_get("allow_overlap")?,
+        categories: serde_json::from_str(&categories)?,
+        poster: row.try_get("poster")?,
+        registration_opens: uint(row, "registration_opens")?,
+        registration_closes: uint(row, "registration_closes")?,
+        questions: serde_json::from_str(&questions)?,
+        time_zone: row.try_get("time_zone")?,
+        auto_promote: row.try_get("auto_promote")?,
+    };
+    let mut reservations = Vec::new();
+    for r in c.query(
+        "SELECT ticket, waiting_list, user_id = $2 AS mine, sum(quantity)::bigint AS quantity FROM reservations \
+        WHERE event = $1 GROUP BY ticket, waiting_list, mine",
+        &[&(event.id as i64), &(user as i64)],
+    )? {
+        reservations.push((uint(&r, "ticket")?, uint(&r, "waiting_list")?, r.try_get("mine")?, uint(&r, "quantity")?));
+    }
+    Ok(EventStats {
+        tickets: counters(event.tickets.len(), reservations),
+        event,
+        state: num::FromPrimitive::from_u64(uint(row, "state")?).unwrap_or(EventState::Closed),
+        revision: uint(row, "revision")?,
+    })
+}
+
+/// Like `get_event`, but doesn't remember the event as the user's current one.
+fn find_event(c: &mut impl GenericClient, event_id: u64, user: u64) -> Result<EventStats> {
+    match c.query_opt(
+        format!("{} WHERE e.id = $1", EVENT_STATS).as_str(),
+        &[&(event_id as i64)],
+    )? {
+        Some(row) => event_stats(c, &row, user),
+        None => Err(Error::EventNotFound(event_id)),
+    }
+}
+
+/// Answers to the first `questions` questions of the event.
+fn get_answers(c: &mut impl GenericClient, event_id: u64, user: u64, questions: usize) -> Result<Vec<String>> {
+    let mut res = vec![String::new(); questions];
+    for row in c.query(
+        "SELECT question, answer FROM answers WHERE event = $1 AND user_id = $2",
+        &[&(event_id as i64), &(user as i64)],
+    )? {
+        if let Some(a) = res.get_mut(uint(&row, "question")? as usize) {
+            *a = row.try_get("answer")?;
+        }
+    }
+    Ok(res)
+}
+
+fn presence_answers(c: &mut impl GenericClient, event_id: u64, list: &mut [Presence]) -> Result<()> {
+    let questions = find_event(c, event_id, 0)?.event.questions.len();
+    for p in list.iter_mut() {
+        p.answers = get_answers(c, event_id, p.user_id, questions)?;
+    }
+    Ok(())
+}
+
+fn get_event(c: &mut impl GenericClient, event_id: u64, user: u64) -> Result<EventStats> {
+    let s = find_event(c, event_id, user)?;
+    set_current_event(c, user, event_id)?;
+    Ok(s)
+}
+
+fn get_event_name(c: &mut impl GenericClient, event_id: u64) -> Result<String> {
+    Ok(event_name(&find_event(c, event_id, 0)?.event))
+}
+
+fn set_current_event(c: &mut impl GenericClient, user_id: u64, event_id: u64) -> Result<()> {
+    c.execute(
+        "INSERT INTO current_events (user_id, event) VALUES ($1, $2) \
+        ON CONFLICT (user_id) DO UPDATE SET event = excluded.event",
+        &[&(user_id as i64), &(event_id as i64)],
+    )?;
+    Ok(())
+}
+
+fn enqueue_message(
+    c: &mut impl GenericClient,
+    event_id: u64,
+    sender: &str,
+    waiting_list: u64,
+    message_type: MessageType,
+    text: &str,
+    send_at: u64,
+) -> Result<()> {
+    debug!("enqueue message {} {}", util::get_unix_time(), send_at);
+    let row = c.query_one(
+        "INSERT INTO messages (event, type, sender, waiting_list, text, ts) VALUES ($1, $2, $3, $4, $5, $6) RETURNING id",
+        &[&(event_id as i64), &(message_type as i64), &sender, &(waiting_list as i64), &text, &(util::get_unix_time() as i64)],
+    )?;
+    let message_id: i64 = row.try_get("id")?;
+    c.execute(
+        "INSERT INTO message_outbox (message, send_at) VALUES ($1, $2)",
+        &[&message_id, &(send_at as i64)],
+    )?;
+    Ok(())
+}
+
+/// Sends the message to both confirmed participants and the waiting list.
+fn notify_participants(
+    c: &mut impl GenericClient,
+    event_id: u64,
+    message_type: MessageType,
+    text: &str,
+) -> Result<()> {
+    for waiting_list in [0, 1] {
+        let found = c
+            .query_opt(
+                "SELECT id FROM reservations WHERE event = $1 AND waiting_list = $2 LIMIT 1",
+                &[&(event_id as i64), &(waiting_list as i64)],
+            )?
+            .is_some();
+        if found {
+            enqueue_message(c, event_id, "Bot", waiting_list, message_type, text, get_unix_time())?;
+        }
+    }
+    Ok(())
+}
+
+fn delete_enqueued_messages(
+    c: &mut impl GenericClient,
+    event_id: u64,
+    message_type: MessageType,
+) -> Result<()> {
+    let message_type = message_type as i64;
+    c.execute(
+        "DELETE FROM message_outbox WHERE message IN (SELECT id FROM messages WHERE event = $1 AND type = $2)",
+        &[&(event_id as i64), &message_type],
+    )?;
+    c.execute(
+        "DELETE FROM messages WHERE event = $1 AND type = $2",
+        &[&(event_id as i64), &message_type],
+    )?;
+    Ok(())
+}
+
+fn have_vacancies(c: &mut impl GenericClient, event_id: u64) -> Result<bool> {
+    match find_event(c, event_id, 0) {
+        Ok(s) => Ok(s.has_vacancies()),
+        Err(Error::EventNotFound(_)) => Ok(false),
+        Err(e) => Err(e),
+    }
+}
+
+fn prompt_waiting_list(c: &mut impl GenericClient, event_id: u64) -> Result<()> {
+    if !have_vacancies(c, event_id)? {
+        debug!("prompt_waiting_list - no tickets, event {}", event_id);
+        return Ok(());
+    }
+
+    let send_at = get_unix_time() + 10; // give some time to finish multiple cancellations
+    let prompt = c.query_opt(
+        "SELECT id FROM messages WHERE event = $1 AND type = $2 LIMIT 1",
+        &[&(event_id as i64), &(MessageType::WaitingListPrompt as i64)],
+    )?;
+    if let Some(row) = prompt {
+        let message_id: i64 = row.try_get("id")?;
+        c.execute(
+            "INSERT INTO message_outbox (message, send_at) VALUES ($1, $2)",
+            &[&message_id, &(send_at as i64)],
+        )?;
+    } else if let Ok(event_name) = get_event_name(c, event_id) {
+        enqueue_message(
+            c,
+            event_id,
+            "Bot",
+            1,
+            MessageType::WaitingListPrompt,
+            &waiting_list_prompt_text(&event_name),
+            send_at,
+        )?;
+    }
+    Ok(())
+}
+
+/// Gives freed places to the waiting list: the oldest sign-ups that fit are confirmed if the event
+/// promotes automatically, otherwise the list is invited to sign up once places appear.
+fn fill_vacancies(c: &mut impl GenericClient, event_id: u64, state_changed: bool) -> Result<()> {
+    let s = find_event(c, event_id, 0)?;
+    if s.event.auto_promote {
+        promote_waiting_list(c, &s)
+    } else if state_changed {
+        prompt_waiting_list(c, event_id)
+    } else {
+        Ok(())
+    }
+}
+
+fn promote_waiting_list(c: &mut impl GenericClient, s: &EventStats) -> Result<()> {
+    let event_id = s.event.id as i64;
+    let mut waiting = Vec::new();
+    for row in c.query(
+        "SELECT id, user_id, ticket, quantity, ts FROM reservations WHERE event = $1 AND waiting_list = 1",
+        &[&event_id],
+    )? {
+        waiting.push(WaitingReservation {
+            id: uint(&row, "id")?,
+            user: uint(&row, "user_id")?,
+            ticket: uint(&row, "ticket")?,
+            quantity: uint(&row, "quantity")?,
+            ts: uint(&row, "ts")?,
+        });
+    }
+    let promoted = promotions(s, &waiting);
+    if promoted.is_empty() {
+        return Ok(());
+    }
+
+    let ts = get_unix_time();
+    let message_id: Option<i64> = match c.query_opt(
+        "SELECT id FROM messages WHERE event = $1 AND type = $2 LIMIT 1",
+        &[&event_id, &(MessageType::Promoted as i64)],
+    )? {
+        Some(row) => Some(row.try_get("id")?),
+        None => None,
+    };
+    for p in &promoted {
+        let ids: Vec<i64> = p.reservations.iter().map(|id| *id as i64).collect();
+        c.execute("UPDATE reservations SET waiting_list = 0 WHERE id = ANY($1)", &[&ids])?;
+        c.execute(
+            "INSERT INTO promotions (event, user_id, ts) VALUES ($1, $2, $3) \
+            ON CONFLICT (event, user_id) DO UPDATE SET ts = excluded.ts",
+            &[&event_id, &(p.user as i64), &(ts as i64)],
+        )?;
+        if let Some(message_id) = message_id {
+            // Promoted again since the last message, tell once more.
+            c.execute(
+                "DELETE FROM message_sent WHERE message = $1 AND user_id = $2",
+                &[&message_id, &(p.user as i64)],
+            )?;
+        }
+        audit(c, ts, SYSTEM_ACTOR, AuditAction::Promote, s.event.id, p.user, &reservation_details(&s.event, &p.tickets, 0))?;
+    }
+    match message_id {
+        Some(message_id) => {
+            c.execute(
+                "INSERT INTO message_outbox (message, send_at) SELECT $1, $2 \
+                WHERE NOT EXISTS (SELECT 1 FROM message_outbox WHERE message = $1)",
+                &[&message_id, &(ts as i64)],
+            )?;
+        }
+        None => {
+            enqueue_message(c, s.event.id, "Bot", 0, MessageType::Promoted, &promoted_text(&event_name(&s.event)), ts)?;
+        }
+    }
+    Ok(())
+}
+
+fn save_user(c: &mut impl GenericClient, user: &User, ts: u64) -> Result<()> {
+    c.execute(
+        "INSERT INTO users (user_id, user_name1, user_name2, language_code, first_seen, last_seen) VALUES ($1, $2, $3, $4, $5, $5) \
+        ON CONFLICT (user_id) DO UPDATE SET user_name1 = excluded.user_name1, user_name2 = excluded.user_name2, \
+        language_code = COALESCE(excluded.language_code, users.language_code), last_seen = GREATEST(users.last_seen, excluded.last_seen), reachable = TRUE",
+        &[&(user.id.0 as i64), &user.user_name1, &user.user_name2, &user.language_code, &(ts as i64)],
+    )?;
+    Ok(())
+}
+
+fn user_profile(row: &Row) -> Result<UserProfile> {
+    Ok(UserProfile {
+        user: User {
+            id: teloxide::types::UserId(uint(row, "user_id")?),
+            user_name1: row.try_get("user_name1")?,
+            user_name2: row.try_get("user_name2")?,
+            language_code: row.try_get("language_code")?,
+            is_admin: false,
+        },
+        first_seen: uint(row, "first_seen")?,
+        last_seen: uint(row, "last_seen")?,
+        reachable: row.try_get("reachable")?,
+    })
+}
+
+fn audit(
+    c: &mut impl GenericClient,
+    ts: u64,
+    actor: u64,
+    action: AuditAction,
+    event_id: u64,
+    user_id: u64,
+    details: &str,
+) -> Result<()> {
+    c.execute(
+        "INSERT INTO audit_log (ts, actor, action, event, user_id, details) VALUES ($1, $2, $3, $4, $5, $6)",
+        &[&(ts as i64), &(actor as i64), &(action as i64), &(event_id as i64), &(user_id as i64), &details],
+    )?;
+    Ok(())
+}
+
+/// Removes all reservations of the user for the event and gives the freed places to the
+/// waiting list.
+fn delete_reservation(tx: &mut postgres::Transaction, event_id: u64, user_id: u64) -> Result<()> {
+    lock_event(tx, event_id)?;
+    let state_changed = !have_vacancies(tx, event_id)?;
+    tx.execute(
+        "DELETE FROM reservations WHERE event = $1 AND user_id = $2",
+        &[&(event_id as i64), &(user_id as i64)],
+    )?;
+    fill_vacancies(tx, event_id, state_changed)?;
+    Ok(())
+}
+
+fn ban_user(
+    c: &mut impl GenericClient,
+    user: u64,
+    user_name1: &str,
+    user_name2: &str,
+    reason: &str,
+    cancel_future_reservations: bool,
+) -> Result<()> {
+    let ts = util::get_unix_time() as i64;
+    c.execute(
+        "INSERT INTO users (user_id, user_name1, user_name2, first_seen, last_seen) VALUES ($1, $2, $3, $4, $4) \
+        ON CONFLICT (user_id) DO NOTHING",
+        &[&(user as i64), &user_name1, &user_name2, &ts],
+    )?;
+    c.execute(
+        "INSERT INTO black_list (user_id, ts, reason) VALUES ($1, $2, $3)",
+        &[&(user as i64), &ts, &reason],
+    )?;
+    if cancel_future_reservations {
+        // Past events keep the reservations for attendance and statistics.
+        let events = c.query(
+            "SELECT DISTINCT r.event FROM reservations AS r JOIN events AS e ON r.event = e.id \
+            WHERE r.user_id = $1 AND e.archived = 0",
+            &[&(user as i64)],
+        )?;
+        for row in events {
+            let event_id = uint(&row, "event")?;
+            lock_event(c, event_id)?;
+            let s = find_event(c, event_id, 0)?;
+            if is_upcoming(&s, ts as u64) {
+                let state_changed = !s.has_vacancies();
+                c.execute(
+                    "DELETE FROM reservations WHERE event = $1 AND user_id = $2",
+                    &[&(event_id as i64), &(user as i64)],
+                )?;
+                fill_vacancies(c, event_id, state_changed)?;
+            }
+        }
+    }
+    Ok(())
+}
+
+fn blacklist_absent_participants(
+    c: &mut impl GenericClient,
+    event_id: u64,
+    admins: &HashSet<u64>,
+    cancel_future_reservations: bool,
+) -> Result<()> {
+    let rows = c.query(
+        "SELECT r.user_id, u.user_name1, u.user_name2, p.user_id AS present FROM \
+        (SELECT DISTINCT user_id FROM reservations WHERE event = $1 AND waiting_list = 0) AS r \
+        JOIN users AS u ON r.user_id = u.user_id \
+        LEFT JOIN presence AS p ON p.event = $1 AND r.user_id = p.user_id",
+        &[&(event_id as i64)],
+    )?;
+    let mut absent = Vec::new();
+    let mut presence_checked = false;
+    for row in rows {
+        if row.try_get::<_, Option<i64>>("present")?.is_none() {
+            absent.push((
+                uint(&row, "user_id")?,
+                row.try_get::<_, String>("user_name1")?,
+                row.try_get::<_, String>("user_name2")?,
+            ));
+        } else {
+            presence_checked = true;
+        }
+    }
+    if presence_checked && !absent.is_empty() {
+        // Check at least one present.
+        if let Ok(reason) = get_event_name(c, event_id) {
+            for (user_id, user_name1, user_name2) in absent {
+                if !admins.contains(&user_id) {
+                    ban_user(c, user_id, &user_name1, &user_name2, &reason, cancel_future_reservations)?;
+                }
+            }
+        } else {
+            warn!("Failed to get event {}", event_id);
+        }
+    }
+    Ok(())
+}
+
+fn delete_event(
+    c: &mut impl GenericClient,
+    event_id: u64,
+    automatic_blacklisting: bool,
+    cancel_future_reservations_on_ban: bool,
+    admins: &HashSet<u64>,
+) -> Result<()> {
+    let s = find_event(c, event_id, 0)?;
+    if automatic_blacklisting && s.event.get_type() != EventType::Paid && took_place(&s, get_unix_time()) {
+        blacklist_absent_participants(c, event_id, admins, cancel_future_reservations_on_ban)?;
+    }
+
+    for table in [
+        "reservations",
+        "attachments",
+        "presence",
+        "group_leaders",
+        "messages",
+        "registration_subscriptions",
+        "answers",
+        "questionnaires",
+        "promotions",
+    ] {
+        c.execute(
+            format!("DELETE FROM {} WHERE event = $1", table).as_str(),
+            &[&(event_id as i64)],
+        )?;
+    }
+    c.execute("DELETE FROM events WHERE id = $1", &[&(event_id as i64)])?;
+    Ok(())
+}
+
+/// Inserts the event in the given state or updates it.
+fn save_event(tx: &mut postgres::Transaction, e: &Event, state: EventState) -> Result<u64> {
+    let event_type = e.get_type();
+    if event_type == EventType::Announcement && Url::parse(&e.link).is_err() {
+        return Err(Error::InvalidLink(e.link.clone()));
+    }
+    let tickets = serde_json::to_string(&e.tickets)?;
+    let categories = serde_json::to_string(&e.categories)?;
+    let questions = serde_json::to_string(&e.questions)?;
+    // Subscribers are told once, unless the opening is moved to the future again.
+    let mut announce_registration = e.registration_opens > util::get_unix_time();
+    let event_id = if e.id == 0 {
+        let row = tx.query_one(
+            "INSERT INTO events (name, link, ts, remind, tickets, currency, state, series, venue, duration, allow_overlap, categories, poster, \
+            registration_opens, registration_closes, questions, time_zone, auto_promote) \
+            VALUES ($1, $2, $3, $4, $5, $6, $7, $8, $9, $10, $11, $12, $13, $14, $15, $16, $17, $18) RETURNING id",
+            &[&e.name, &e.link, &(e.ts as i64), &(e.remind as i64), &tickets, &e.currency, &(state as i64), &(e.series_id as i64),
+                &(e.venue_id as i64), &(e.duration as i64), &e.allow_overlap, &categories, &e.poster,
+                &(e.registration_opens as i64), &(e.registration_closes as i64), &questions, &e.time_zone, &e.auto_promote],
+        )?;
+        uint(&row, "id")?
+    } else {
+        lock_event(tx, e.id)?;
+        let old = find_event(tx, e.id, 0)?;
+        let archived = uint(&tx.query_one("SELECT archived FROM events WHERE id = $1", &[&(e.id as i64)])?, "archived")?;
+        check_editable(&old, archived != 0)?;
+        let old = old.event;
+        let booked = tx
+            .query("SELECT DISTINCT ticket FROM reservations WHERE event = $1 ORDER BY ticket", &[&(e.id as i64)])?
+            .iter()
+            .map(|row| uint(row, "ticket"))
+            .collect::<Result<Vec<u64>>>()?;
+        check_ticket_types(&old, e, booked)?;
+        tx.execute(
+            "UPDATE events SET name = $1, link = $2, ts = $3, remind = $4, tickets = $5, currency = $6, venue = $7, duration = $8, \
+            allow_overlap = $9, categories = $10, registration_opens = $11, registration_closes = $12, questions = $13, time_zone = $14, \
+            auto_promote = $15, revision = revision + 1 WHERE id = $16",
+            &[&e.name, &e.link, &(e.ts as i64), &(e.remind as i64), &tickets, &e.currency, &(e.venue_id as i64),
+                &(e.duration as i64), &e.allow_overlap, &categories, &(e.registration_opens as i64),
+                &(e.registration_closes as i64), &questions, &e.time_zone, &e.auto_promote, &(e.id as i64)],
+        )?;
+        delete_enqueued_messages(tx, e.id, MessageType::Reminder)?;
+        delete_enqueued_messages(tx, e.id, MessageType::RegistrationOpened)?;
+        announce_registration |= old.registration_opens > util::get_unix_time();
+        if let Some(text) = event_change_text(&old, e) {
+            notify_participants(tx, e.id, MessageType::EventChanged, &text)?;
+        }
+        e.id
+    };
+
+    if event_id != 0 && event_type != EventType::Announcement {
+        let text = reminder_text(e);
+        enqueue_message(tx, event_id, "Bot", 0, MessageType::Reminder, &text, e.remind)?;
+    }
+    if event_id != 0 && event_type != EventType::Announcement && announce_registration {
+        let text = registration_opened_text(e);
+        enqueue_message(tx, event_id, "Bot", 0, MessageType::RegistrationOpened, &text, e.registration_opens)?;
+    }
+    Ok(event_id)
+}
+
+fn set_event_state(tx: &mut postgres::Transaction, event_id: u64, state: EventState, actor: u64) -> Result<()> {
+    lock_event(tx, event_id)?;
+    let s = find_event(tx, event_id, 0)?;
+    if !s.state.can_change_to(state) {
+        return Err(Error::InvalidStateChange(s.state, state));
+    }
+    if state == EventState::Cancelled {
+        delete_enqueued_messages(tx, event_id, MessageType::Reminder)?;
+        delete_enqueued_messages(tx, event_id, MessageType::WaitingListPrompt)?;
+        delete_enqueued_messages(tx, event_id, MessageType::RegistrationOpened)?;
+        notify_participants(tx, event_id, MessageType::EventCancelled, &event_cancelled_text(&s.event))?;
+        add_refunds(tx, &s)?;
+    }
+    tx.execute(
+        "UPDATE events SET state = $1, revision = revision + 1 WHERE id = $2",
+        &[&(state as i64), &(event_id as i64)],
+    )?;
+    audit(tx, get_unix_time(), actor, AuditAction::ChangeEventState, event_id, 0,
+        &format!("state {}", state as u64))
+}
+
+fn add_refunds(tx: &mut postgres::Transaction, s: &EventStats) -> Result<()> {
+    let rows = tx.query(
+        "SELECT user_id, payment FROM reservations \
+        WHERE event = $1 AND state = $2 AND payment IS NOT NULL ORDER BY id",
+        &[&(s.event.id as i64), &(ReservationState::PaymentCompleted as i64)],
+    )?;
+    let mut payments = Vec::new();
+    for row in rows {
+        payments.push((uint(&row, "user_id")?, row.try_get("payment")?));
+    }
+    for r in refunds(s, payments, get_unix_time())? {
+        tx.execute(
+            "INSERT INTO refunds (event, event_name, user_id, name, payment, amount, currency, ts) \
+            VALUES ($1, $2, $3, $4, $5, $6, $7, $8)",
+            &[&(r.event_id as i64), &r.event_name, &(r.user_id as i64), &r.name, &r.payment,
+                &(r.amount as i64), &r.currency, &(r.ts as i64)],
+        )?;
+    }
+    Ok(())
+}
+
+fn refund(row: &Row) -> Result<Refund> {
+    Ok(Refund {
+        id: uint(row, "id")?,
+        event_id: uint(row, "event")?,
+        event_name: row.try_get("event_name")?,
+        user_id: uint(row, "user_id")?,
+        name: row.try_get("name")?,
+        payment: row.try_get("payment")?,
+        amount: uint(row, "amount")?,
+        currency: row.try_get("currency")?,
+        ts: uint(row, "ts")?,
+        refunded: uint(row, "refunded")?,
+    })
+}
+
+fn series(row: &Row) -> Result<Series> {
+    let tickets: String = row.try_get("tickets")?;
+    let categories: String = row.try_get("categories")?;
+    let questions: String = row.try_get("questions")?;
+    Ok(Series {
+        id: uint(row, "id")?,
+        template: Event {
+            id: 0,
+            name: row.try_get("name")?,
+            link: row.try_get("link")?,
+            ts: uint(row, "ts")?,
+            remind: uint(row, "remind")?,
+            tickets: serde_json::from_str(&tickets)?,
+            currency: row.try_get("currency")?,
+            series_id: 0,
+            venue_id: uint(row, "venue")?,
+            duration: uint(row, "duration")?,
+            allow_overlap: row.try_get("allow_overlap")?,
+            categories: serde_json::from_str(&categories)?,
+            poster: String::new(),
+            registration_opens: 0,
+            registration_closes: 0,
+            questions: serde_json::from_str(&questions)?,
+            time_zone: row.try_get("time_zone")?,
+            auto_promote: row.try_get("auto_promote")?,
+        },
+        period: uint(row, "period")?,
+        until: uint(row, "until")?,
+        next_ts: uint(row, "next_ts")?,
+        cancelled: row.try_get("cancelled")?,
+    })
+}
+
+fn get_series(c: &mut impl GenericClient, series_id: u64) -> Result<Series> {
+    match c.query_opt("SELECT * FROM series WHERE id = $1", &[&(series_id as i64)])? {
+        Some(row) => series(&row),
+        None => Err(Error::SeriesNotFound(series_id)),
+    }
+}
+
+/// Locks the series row until the end of the transaction.
+fn venue(row: &Row) -> Result<Venue> {
+    Ok(Venue {
+        id: uint(row, "id")?,
+        name: row.try_get("name")?,
+        address: row.try_get("address")?,
+        latitude: row.try_get("latitude")?,
+        longitude: row.try_get("longitude")?,
+    })
+}
+
+fn lock_series(tx: &mut postgres::Transaction, series_id: u64) -> Result<Series> {
+    match tx.query_opt("SELECT * FROM series WHERE id = $1 FOR UPDATE", &[&(series_id as i64)])? {
+        Some(row) => series(&row),
+        None => Err(Error::SeriesNotFound(series_id)),
+    }
+}
+
+fn get_upcoming_occurrences(c: &mut impl GenericClient, series_id: u64) -> Result<Vec<EventStats>> {
+    let now = get_unix_time();
+    let mut res = Vec::new();
+    for row in c.query(
+        "SELECT id FROM events WHERE series = $1 AND archived = 0 ORDER BY ts",
+        &[&(series_id as i64)],
+    )? {
+        let s = find_event(c, uint(&row, "id")?, 0)?;
+        if is_upcoming(&s, now) {
+            res.push(s);
+        }
+    }
+    Ok(res)
+}
+
+impl Storage for PostgresStorage {
+    fn add_event(&self, e: Event) -> Result<u64> {
+        self.transaction(|tx| save_event(tx, &e, EventState::Draft))
+    }
+
+    fn add_events(&self, events: &[Event]) -> Result<Vec<u64>> {
+        self.transaction(|tx| events.iter().map(|e| save_event(tx, e, EventState::Draft)).collect())
+    }
+
+    fn get_events(
+        &self,
+        user: u64,
+        is_admin: bool,
+        filter: &EventFilter,
+        offset: u64,
+        limit: u64,
+    ) -> Result<Vec<EventStats>> {
+        self.run(|c| {
+            let listed = [EventState::Open as i64, EventState::Closed as i64, EventState::Cancelled as i64];
+            // Words are matched as prefixes of the words `search_words` would split the text into,
+            // as SQLite's FTS5 tokenizer and the memory storage do. to_tsvector would stem words
+            // and keep links as whole URL and host tokens, so results would depend on the backend.
+            // Archived events are left out, so the scanned list stays short.
+            let rows = c.query(
+                format!(
+                    "{} WHERE e.archived = 0 AND ($3 OR e.state = ANY($4)) \
+                    AND ($5::bigint = 0 OR e.categories::jsonb @> jsonb_build_array($5)) \
+                    AND (NOT $6 OR (e.state = $7 AND EXISTS (SELECT 1 FROM jsonb_array_elements(e.tickets::jsonb) WITH ORDINALITY AS t (v, i) \
+                        WHERE (t.v->>'capacity')::bigint > (SELECT COALESCE(SUM(r.quantity), 0) FROM reservations AS r \
+                        WHERE r.event = e.id AND r.ticket = t.i - 1 AND r.waiting_list = 0)))) \
+                    AND ($8::bigint = 0 OR e.ts < $8) \
+                    AND (NOT $9 OR EXISTS (SELECT 1 FROM reservations AS r WHERE r.event = e.id AND r.user_id = $10)) \
+                    AND NOT EXISTS (SELECT 1 FROM unnest($11::text[]) AS w \
+                        WHERE lower(e.name || ' ' || e.link || ' ' || COALESCE((SELECT v.name FROM venues AS v WHERE v.id = e.venue), '')) \
+                        !~ ('(^|[^[:alnum:]])' || w)) \
+                    ORDER BY e.ts, e.id LIMIT $1 OFFSET $2",
+                    EVENT_STATS
+                )
+                .as_str(),
+                &[&(limit as i64), &((offset * limit) as i64), &is_admin, &&listed[..], &(filter.category as i64), &filter.vacancies,
+                    &(EventState::Open as i64), &(filter.until as i64), &filter.mine, &(user as i64), &search_words(&filter.text)],
+            )?;
+            let mut res = Vec::new();
+            for row in rows {
+                res.push(event_stats(c, &row, user)?);
+            }
+            Ok(res)
+        })
+    }
+
+    fn get_event(&self, event_id: u64, user: u64) -> Result<EventStats> {
+        self.run(|c| get_event(c, event_id, user))
+    }
+
+    fn find_event(&self, event_id: u64, user: u64) -> Result<EventStats> {
+        self.run(|c| find_event(c, event_id, user))
+    }
+
+    fn get_current_event(&self, user: u64) -> Result<u64> {
+        self.run(|c| {
+            match c.query_opt("SELECT event FROM current_events WHERE user_id = $1", &[&(user as i64)])? {
+                Some(row) => Ok(uint(&row, "event")?),
+                None => Ok(0),
+            }
+        })
+    }
+
+    fn subscribe_to_registration(&self, event_id: u64, user: u64, subscribe: bool) -> Result<()> {
+        self.run(|c| {
+            find_event(c, event_id, 0)?;
+            if subscribe {
+                c.execute(
+                    "INSERT INTO registration_subscriptions (event, user_id, ts) VALUES ($1, $2, $3) ON CONFLICT DO NOTHING",
+                    &[&(event_id as i64), &(user as i64), &(util::get_unix_time() as i64)],
+                )?;
+            } else {
+                c.execute(
+                    "DELETE FROM registration_subscriptions WHERE event = $1 AND user_id = $2",
+                    &[&(event_id as i64), &(user as i64)],
+                )?;
+            }
+            Ok(())
+        })
+    }
+
+    fn is_subscribed_to_registration(&self, event_id: u64, user: u64) -> Result<bool> {
+        self.run(|c| {
+            Ok(c.query_opt(
+                "SELECT 1 FROM registration_subscriptions WHERE event = $1 AND user_id = $2",
+                &[&(event_id as i64), &(user as i64)],
+            )?
+            .is_some())
+        })
+    }
+
+    fn change_event_state(&self, event_id: u64, state: EventState, actor: u64) -> Result<()> {
+        self.transaction(|tx| set_event_state(tx, event_id, state, actor))
+    }
+
+    fn set_event_limits(&self, event_id: u64, capacities: &[u64], actor: u64) -> Result<()> {
+        self.transaction(|tx| {
+            lock_event(tx, event_id)?;
+            let mut s = find_event(tx, event_id, 0)?;
+            let state_changed = !s.has_vacancies();
+            for (t, capacity) in s.event.tickets.iter_mut().zip(capacities) {
+                t.capacity = *capacity;
+            }
+            tx.execute(
+                "UPDATE events SET tickets = $1 WHERE id = $2",
+                &[&serde_json::to_string(&s.event.tickets)?, &(event_id as i64)],
+            )?;
+            audit(tx, get_unix_time(), actor, AuditAction::SetEventLimits, event_id, 0,
+                &reservation_details(&s.event, capacities, 0))?;
+            fill_vacancies(tx, event_id, state_changed)?;
+            Ok(())
+        })
+    }
+
+    fn set_event_poster(&self, event_id: u64, file_id: &str) -> Result<()> {
+        self.run(|c| {
+            if c.execute("UPDATE events SET poster = $1 WHERE id = $2", &[&file_id, &(event_id as i64)])? == 0 {
+                return Err(Error::EventNotFound(event_id));
+            }
+            Ok(())
+        })
+    }
+
+    fn delete_event(
+        &self,
+        event_id: u64,
+        automatic_blacklisting: bool,
+        cancel_future_reservations_on_ban: bool,
+        admins: &HashSet<u64>,
+        actor: u64,
+    ) -> Result<()> {
+        self.transaction(|tx| {
+            let s = find_event(tx, event_id, 0)?;
+            delete_event(
+                tx,
+                event_id,
+                automatic_blacklisting,
+                cancel_future_reservations_on_ban,
+                admins,
+            )?;
+            audit(tx, get_unix_time(), actor, AuditAction::DeleteEvent, event_id, 0,
+                &event_name(&s.event))
+        })
+    }
+
+    fn delete_link(&self, link: &str) -> Result<()> {
+        self.transaction(|tx| {
+            match tx.query_opt("SELECT id FROM events WHERE link = $1 LIMIT 1", &[&link])? {
+                Some(row) => delete_event(tx, uint(&row, "id")?, false, false, &HashSet::new()),
+                None => Ok(()),
+            }
+        })
+    }
+
+    fn archive_old_events(
+        &self,
+        ts: u64,
+        automatic_blacklisting: bool,
+        cancel_future_reservations: bool,
+        admins: &HashSet<u64>,
+    ) -> Result<()> {
+        self.transaction(|tx| {
+            let rows = tx.query(
+                "SELECT id FROM events WHERE ts < $1 AND archived = 0",
+                &[&((ts - util::get_seconds_before_midnight(ts)) as i64)],
+            )?;
+            for row in rows {
+                let event_id = uint(&row, "id")?;
+                let s = find_event(tx, event_id, 0)?;
+                if automatic_blacklisting && s.event.get_type() != EventType::Paid && took_place(&s, get_unix_time()) {
+                    blacklist_absent_participants(tx, event_id, admins, cancel_future_reservations)?;
+                }
+                tx.execute(
+                    "DELETE FROM message_outbox WHERE message IN (SELECT id FROM messages WHERE event = $1)",
+                    &[&(event_id as i64)],
+                )?;
+                tx.execute("UPDATE events SET archived = 1 WHERE id = $1", &[&(event_id as i64)])?;
+            }
+            Ok(())
+        })
+    }
+
+    fn get_archived_events(&self, offset: u64, limit: u64) -> Result<Vec<EventStats>> {
+        self.run(|c| {
+            let rows = c.query(
+                format!("{} WHERE e.archived = 1 ORDER BY e.ts DESC, e.id LIMIT $1 OFFSET $2", EVENT_STATS).as_str(),
+                &[&(limit as i64), &((offset * limit) as i64)],
+            )?;
+            let mut res = Vec::new();
+            for row in rows {
+                res.push(event_stats(c, &row, 0)?);
+            }
+            Ok(res)
+        })
+    }
+
+    fn purge_archive(&self, ts: u64) -> Result<()> {
+        self.transaction(|tx| {
+            let rows = tx.query(
+                "SELECT id FROM events WHERE ts < $1 AND archived = 1",
+                &[&(ts as i64)],
+            )?;
+            for row in rows {
+                delete_event(tx, uint(&row, "id")?, false, false, &HashSet::new())?;
+            }
+            Ok(())
+        })
+    }
+
+    fn sign_up(
+        &self,
+        event_id: u64,
+        user: &User,
+        tickets: &[u64],
+        wait: u64,
+        ts: u64,
+        amount: u64,
+    ) -> Result<(usize, bool)> {
+        self.transaction(|tx| {
+            lock_event(tx, event_id)?;
+            let user_id = user.id.0;
+            let s = get_event(tx, event_id, user_id)?;
+            save_user(tx, user, ts)?;
+            let black_listed = tx
+                .query_opt("SELECT user_id FROM black_list WHERE user_id = $1", &[&(user_id as i64)])?
+                .is_some();
+            let time_conflict = tx
+                .query_opt(
+                    "SELECT e.id FROM events AS e JOIN reservations AS r ON e.id = r.event \
+                    WHERE r.user_id = $3 AND e.id != $4 AND r.waiting_list = 0 AND e.state != $5 AND NOT e.allow_overlap \
+                    AND (e.ts = $1 OR (e.ts < $2 AND e.ts + e.duration > $1)) LIMIT 1",
+                    &[
+                        &(s.event.ts as i64),
+                        &(s.event.end() as i64),
+                        &(user_id as i64),
+                        &(event_id as i64),
+                        &(EventState::Cancelled as i64),
+                    ],
+                )?
+                .is_some();
+
+            match check_sign_up(
+                &s,
+                user,
+                tickets,
+                wait,
+                ts,
+                amount,
+                SignUpChecks { black_listed, time_conflict },
+            )? {
+                SignUp::Reserve(state) => {
+                    let state = state as i64;
+                    let mut res = 0;
+                    for (ticket, quantity) in tickets.iter().enumerate().filter(|(_, n)| **n > 0) {
+                        res += tx.execute(
+                            "INSERT INTO reservations (event, user_id, ticket, quantity, waiting_list, ts, state) VALUES ($1, $2, $3, $4, $5, $6, $7)",
+                            &[&(event_id as i64), &(user_id as i64), &(ticket as i64), &(*quantity as i64), &(wait as i64), &(ts as i64), &state],
+                        )?;
+                    }
+                    audit(tx, ts, user_id, AuditAction::SignUp, event_id, user_id,
+                        &reservation_details(&s.event, tickets, wait))?;
+                    Ok((res as usize, false))
+                }
+                SignUp::Confirm { ticket } => {
+                    tx.execute(
+                        "UPDATE reservations SET waiting_list = 0 WHERE id IN \
+                        (SELECT id FROM reservations WHERE event = $1 AND user_id = $2 AND waiting_list = 1 AND ticket = $3 AND quantity = 1 ORDER BY ts LIMIT 1)",
+                        &[&(event_id as i64), &(user_id as i64), &(ticket as i64)],
+                    )?;
+                    audit(tx, ts, user_id, AuditAction::SignUp, event_id, user_id,
+                        &reservation_details(&s.event, &one_ticket(ticket), 0))?;
+                    Ok((1, false))
+                }
+                SignUp::Rejected => Ok((0, false)),
+                SignUp::BlackListed => Ok((0, true)),
+            }
+        })
+    }
+
+    fn checkout(&self, booking: &Booking, order_info: OrderInfo) -> Result<()> {
+        self.transaction(|tx| {
+            lock_event(tx, booking.event_id)?;
+            let s = get_event(tx, booking.event_id, booking.user_id)?;
+            check_amount(&s, booking, &order_info)?;
+
+            // The booking was reserved as one row per ticket type.
+            let mut ids = Vec::new();
+            for (ticket, quantity) in booking.tickets.iter().enumerate().filter(|(_, n)| **n > 0) {
+                match tx.query_opt(
+                    "SELECT id FROM reservations WHERE event = $1 AND user_id = $2 AND state = $3 AND ticket = $4 AND quantity = $5 ORDER BY ts DESC LIMIT 1",
+                    &[&(booking.event_id as i64), &(booking.user_id as i64), &(ReservationState::PaymentPending as i64), &(ticket as i64), &(*quantity as i64)],
+                )? {
+                    Some(row) => ids.push(row.try_get::<_, i64>("id")?),
+                    None => return Err(Error::ReservationNotFound),
+                }
+            }
+            if ids.is_empty() {
+                return Err(Error::ReservationNotFound);
+            }
+            tx.execute(
+                "UPDATE reservations SET state = $1, payment = $2 WHERE id = ANY($3)",
+                &[&(ReservationState::PaymentCompleted as i64), &serde_json::to_string(&order_info)?, &ids],
+            )?;
+            Ok(())
+        })
+    }
+
+    fn cancel(&self, event_id: u64, user: u64, ticket: u64) -> Result<()> {
+        self.transaction(|tx| {
+            lock_event(tx, event_id)?;
+            let s = find_event(tx, event_id, 0)?;
+            let state_changed = !s.has_vacancies();
+            tx.execute(
+                "DELETE FROM reservations WHERE id IN (SELECT id FROM reservations WHERE event = $1 AND user_id = $2 AND ticket = $3 ORDER BY waiting_list DESC LIMIT 1)",
+                &[&(event_id as i64), &(user as i64), &(ticket as i64)],
+            )?;
+            fill_vacancies(tx, event_id, state_changed)?;
+            audit(tx, get_unix_time(), user, AuditAction::Cancel, event_id, user,
+                &reservation_details(&s.event, &one_ticket(ticket), 0))
+        })
+    }
+
+    fn wontgo(&self, event_id: u64, user: u64) -> Result<()> {
+        self.transaction(|tx| {
+            delete_reservation(tx, event_id, user)?;
+            audit(tx, get_unix_time(), user, AuditAction::Cancel, event_id, user, "all")
+        })
+    }
+
+    fn delete_reservation(&self, event_id: u64, user_id: u64, actor: u64) -> Result<()> {
+        self.transaction(|tx| {
+            delete_reservation(tx, event_id, user_id)?;
+            audit(tx, get_unix_time(), actor, AuditAction::DeleteReservation, event_id, user_id, "")
+        })
+    }
+
+    fn get_participants(
+        &self,
+        event_id: u64,
+        waiting_list: u64,
+        offset: u64,
+        limit: u64,
+        state: ReservationState,
+    ) -> Result<Vec<Participant>> {
+        // No limit means everybody.
+        let limit = if limit == 0 { None } else { Some(limit as i64) };
+        self.run(|c| {
+            let event = find_event(c, event_id, 0)?.event;
+            let ticket_types = event.tickets.len();
+            let state = state as i64;
+            let rows = c.query(
+                "SELECT a.*, u.user_name1, u.user_name2, b.attachment FROM \
+                (SELECT user_id, min(ts) AS ts \
+                FROM reservations WHERE waiting_list = $1 AND event = $2 AND state = $3 GROUP BY user_id) AS a \
+                JOIN users AS u ON a.user_id = u.user_id \
+                LEFT JOIN attachments AS b ON b.event = $2 AND a.user_id = b.user_id \
+                ORDER BY a.ts, a.user_id LIMIT $4 OFFSET $5",
+                &[&(waiting_list as i64), &(event_id as i64), &state, &limit, &(offset as i64 * limit.unwrap_or(0))],
+            )?;
+            let mut res = Vec::new();
+            for row in rows {
+                res.push(Participant {
+                    user_id: uint(&row, "user_id")?,
+                    user_name1: row.try_get("user_name1")?,
+                    user_name2: row.try_get("user_name2")?,
+                    tickets: vec![0; ticket_types],
+                    attachment: row.try_get("attachment")?,
+                    answers: Vec::new(),
+                });
+            }
+            for p in res.iter_mut() {
+                p.answers = get_answers(c, event_id, p.user_id, event.questions.len())?;
+            }
+            for row in c.query(
+                "SELECT user_id, ticket, sum(quantity)::bigint AS quantity FROM reservations \
+                WHERE waiting_list = $1 AND event = $2 AND state = $3 GROUP BY user_id, ticket",
+                &[&(waiting_list as i64), &(event_id as i64), &state],
+            )? {
+                let user_id = uint(&row, "user_id")?;
+                if let Some(p) = res.iter_mut().find(|p| p.user_id == user_id) {
+                    if let Some(n) = p.tickets.get_mut(uint(&row, "ticket")? as usize) {
+                        *n = uint(&row, "quantity")?;
+                    }
+                }
+            }
+            Ok(res)
+        })
+    }
+
+    fn add_attachment(&self, event_id: u64, user: u64, attachment: &str) -> Result<usize> {
+        let msg = if attachment.len() < 256 {
+            format!("{}...", attachment.chars().take(256).collect::<String>())
+        } else {
+            attachment.to_string()
+        };
+
+        self.run(|c| {
+            let s = get_event(c, event_id, user)?;
+            if s.my_reservation() > 0 || s.my_waiting() > 0 {
+                Ok(c.execute(
+                    "INSERT INTO attachments (event, user_id, attachment) VALUES ($1, $2, $3) \
+                    ON CONFLICT (event, user_id) DO UPDATE SET attachment = excluded.attachment",
+                    &[&(event_id as i64), &(user as i64), &msg],
+                )? as usize)
+            } else {
+                Ok(0)
+            }
+        })
+    }
+
+    fn get_attachment(&self, event_id: u64, user: u64) -> Result<Option<String>> {
+        self.run(|c| {
+            match c.query_opt(
+                "SELECT attachment FROM attachments WHERE event = $1 AND user_id = $2",
+                &[&(event_id as i64), &(user as i64)],
+            )? {
+                Some(row) => Ok(Some(row.try_get("attachment")?)),
+                None => Ok(None),
+            }
+        })
+    }
+
+    fn clear_failed_payments(&self, ts: u64) -> Result<()> {
+        self.run(|c| {
+            c.execute(
+                "DELETE FROM reservations WHERE state = $1 AND ts < $2",
+                &[&(ReservationState::PaymentPending as i64), &(ts as i64)],
+            )?;
+            Ok(())
+        })
+    }
+
+    fn add_to_black_list(
+        &self,
+        user: u64,
+        cancel_future_reservations: bool,
+        actor: u64,
+    ) -> Result<()> {
+        let reason = "banned by admin";
+        self.transaction(|tx| {
+            // Users who never talked to the bot are known by id only.
+            ban_user(
+                tx,
+                user,
+                &user.to_string(),
+                "",
+                reason,
+                cancel_future_reservations,
+            )?;
+            audit(tx, get_unix_time(), actor, AuditAction::Ban, 0, user, reason)
+        })
+    }
+
+    fn ban_user(
+        &self,
+        user: u64,
+        user_name1: &str,
+        user_name2: &str,
+        reason: &str,
+        cancel_future_reservations: bool,
+    ) -> Result<()> {
+        self.transaction(|tx| {
+            ban_user(tx, user, user_name1, user_name2, reason, cancel_future_reservations)
+        })
+    }
+
+    fn remove_from_black_list(&self, user: u64) -> Result<()> {
+        self.run(|c| {
+            c.execute("DELETE FROM black_list WHERE user_id = $1", &[&(user as i64)])?;
+            Ok(())
+        })
+    }
+
+    fn get_black_list(&self, offset: u64, limit: u64) -> Result<Vec<User>> {
+        self.run(|c| {
+            let rows = c.query(
+                "SELECT b.user_id, u.user_name1, u.user_name2, u.language_code FROM black_list AS b JOIN users AS u ON b.user_id = u.user_id \
+                ORDER BY u.user_name1, b.user_id LIMIT $1 OFFSET $2",
+                &[&(limit as i64), &((offset * limit) as i64)],
+            )?;
+            let mut res = Vec::new();
+            for row in rows {
+                res.push(User {
+                    id: teloxide::types::UserId(uint(&row, "user_id")?),
+                    user_name1: row.try_get("user_name1")?,
+                    user_name2: row.try_get("user_name2")?,
+                    language_code: row.try_get("language_code")?,
+                    is_admin: false,
+                });
+            }
+            Ok(res)
+        })
+    }
+
+    fn get_ban_reason(&self, user_id: u64) -> Result<String> {
+        self.run(|c| {
+            match c.query_opt("SELECT reason FROM black_list WHERE user_id = $1", &[&(user_id as i64)])? {
+                Some(row) => Ok(row.try_get("reason")?),
+                None => Ok("unknown user".to_string()),
+            }
+        })
+    }
+
+    fn clear_black_list(&self, ts: u64) -> Result<()> {
+        self.run(|c| {
+            c.execute("DELETE FROM black_list WHERE ts < $1", &[&(ts as i64)])?;
+            Ok(())
+        })
+    }
+
+    fn enqueue_message(
+        &self,
+        event_id: u64,
+        sender: &str,
+        waiting_list: u64,
+        message_type: MessageType,
+        text: &str,
+        send_at: u64,
+    ) -> Result<()> {
+        self.transaction(|tx| {
+            enqueue_message(tx, event_id, sender, waiting_list, message_type, text, send_at)
+        })
+    }
+
+    fn get_pending_messages(&self, ts: u64, mut max_messages: u64) -> Result<Vec<MessageBatch>> {
+        self.run(|c| {
+            let rows = c.query(
+                "SELECT m.id, m.event, m.type, m.sender, m.waiting_list, m.text, e.tickets FROM message_outbox AS o \
+                JOIN messages AS m ON o.message = m.id \
+                JOIN events AS e ON m.event = e.id \
+                WHERE o.send_at < $1 ORDER BY o.send_at, m.id",
+                &[&(ts as i64)],
+            )?;
+            let mut res = Vec::new();
+            for row in rows {
+                let mut batch = MessageBatch {
+                    message_id: uint(&row, "id")?,
+                    event_id: uint(&row, "event")?,
+                    sender: row.try_get("sender")?,
+                    message_type: num::FromPrimitive::from_u64(uint(&row, "type")?).unwrap(),
+                    waiting_list: uint(&row, "waiting_list")?,
+                    text: row.try_get("text")?,
+                    is_paid: serde_json::from_str::<Vec<TicketType>>(&row.try_get::<_, String>("tickets")?)?
+                        .iter()
+                        .any(|t| t.price != 0),
+                    recipients: Vec::new(),
+                };
+
+                let collect_users = batch.message_type != MessageType::WaitingListPrompt
+                    || have_vacancies(c, batch.event_id)?;
+                if collect_users {
+                    let recipients = if batch.message_type == MessageType::RegistrationOpened {
+                        c.query(
+                            "SELECT r.user_id FROM registration_subscriptions AS r WHERE r.event = $1 \
+                            AND NOT EXISTS (SELECT 1 FROM message_sent AS s WHERE s.message = $2 AND s.user_id = r.user_id) \
+                            ORDER BY r.ts, r.user_id LIMIT $3",
+                            &[&(batch.event_id as i64), &(batch.message_id as i64), &(max_messages as i64)],
+                        )?
+                    } else if batch.message_type == MessageType::Promoted {
+                        // Promoted users are told only while they still hold the place.
+                        c.query(
+                            "SELECT p.user_id FROM promotions AS p WHERE p.event = $1 \
+                            AND EXISTS (SELECT 1 FROM reservations AS r WHERE r.event = p.event AND r.user_id = p.user_id AND r.waiting_list = 0) \
+                            AND NOT EXISTS (SELECT 1 FROM message_sent AS s WHERE s.message = $2 AND s.user_id = p.user_id) \
+                            ORDER BY p.ts, p.user_id LIMIT $3",
+                            &[&(batch.event_id as i64), &(batch.message_id as i64), &(max_messages as i64)],
+                        )?
+                    } else {
+                        c.query(
+                            "SELECT r.user_id FROM \
+                            (SELECT user_id, min(ts) AS ts FROM reservations WHERE event = $1 AND waiting_list = $2 GROUP BY user_id) AS r \
+                            WHERE NOT EXISTS (SELECT 1 FROM message_sent AS s WHERE s.message = $3 AND s.user_id = r.user_id) \
+                            ORDER BY r.ts, r.user_id LIMIT $4",
+                            &[&(batch.event_id as i64), &(batch.waiting_list as i64), &(batch.message_id as i64), &(max_messages as i64)],
+                        )?
+                    };
+                    for row in recipients {
+                        batch.recipients.push(uint(&row, "user_id")?);
+                        max_messages -= 1;
+                        if max_messages == 0 {
+                            res.push(batch);
+                            return Ok(res);
+                        }
+
+                        if batch.message_type == MessageType::WaitingListPrompt {
+                            break; // take not more than one at a time
+                        }
+                    }
+                }
+                if batch.recipients.is_empty() {
+                    // Done with the message.
+                    debug!("finished sending message {}", batch.message_id);
+                    if batch.message_type == MessageType::Promoted {
+                        c.execute("DELETE FROM promotions WHERE event = $1", &[&(batch.event_id as i64)])?;
+                    }
+                    c.execute("DELETE FROM message_outbox WHERE message = $1", &[&(batch.message_id as i64)])?;
+                    c.execute("DELETE FROM message_sent WHERE message = $1", &[&(batch.message_id as i64)])?;
+                }
+                res.push(batch);
+            }
+            Ok(res)
+        })
+    }
+
+    fn save_receipt(&self, message_id: u64, user: u64) -> Result<()> {
+        self.run(|c| {
+            c.execute(
+                "INSERT INTO message_sent (message, user_id, ts) VALUES ($1, $2, $3)",
+                &[&(message_id as i64), &(user as i64), &(util::get_unix_time() as i64)],
+            )?;
+            Ok(())
+        })
+    }
+
+    fn get_group_messages(
+        &self,
+        event_id: u64,
+        waiting_list: Option<u64>,
+    ) -> Result<Vec<GroupMessage>> {
+        self.run(|c| {
+            let rows = c.query(
+                "SELECT sender, text, ts, waiting_list FROM messages \
+                WHERE event = $1 AND type = $2 AND ($3::bigint IS NULL OR waiting_list = $3) \
+                ORDER BY ts DESC, id DESC LIMIT 3",
+                &[&(event_id as i64), &(MessageType::Direct as i64), &waiting_list.map(|w| w as i64)],
+            )?;
+            let mut messages = Vec::new();
+            for row in rows {
+                let msg = GroupMessage {
+                    sender: row.try_get("sender")?,
+                    text: row.try_get("text")?,
+                    ts: uint(&row, "ts")?,
+                    waiting_list: uint(&row, "waiting_list")?,
+                };
+                if msg.sender.is_empty() {
+                    continue;
+                }
+                messages.push(msg);
+            }
+            messages.reverse();
+            Ok(messages)
+        })
+    }
+
+    fn get_presence_list(&self, event_id: u64, offset: u64, limit: u64) -> Result<Vec<Presence>> {
+        self.run(|c| {
+            let rows = c.query(
+                "SELECT r.*, u.user_name1, u.user_name2, a.attachment FROM \
+                (SELECT user_id, sum(quantity)::bigint AS reserved FROM reservations WHERE event = $1 AND waiting_list = 0 GROUP BY user_id) AS r \
+                JOIN users AS u ON r.user_id = u.user_id \
+                LEFT JOIN presence AS p ON p.event = $1 AND r.user_id = p.user_id \
+                LEFT JOIN attachments AS a ON a.event = $1 AND r.user_id = a.user_id \
+                WHERE p.user_id IS NULL ORDER BY u.user_name1, r.user_id LIMIT $2 OFFSET $3",
+                &[&(event_id as i64), &(limit as i64), &((offset * limit) as i64)],
+            )?;
+            let mut res = Vec::new();
+            for row in rows {
+                res.push(Presence {
+                    user_id: uint(&row, "user_id")?,
+                    user_name1: row.try_get("user_name1")?,
+                    user_name2: row.try_get("user_name2")?,
+                    reserved: uint(&row, "reserved")?,
+                    attachment: row.try_get("attachment")?,
+                    answers: Vec::new(),
+                    present: false,
+                });
+            }
+            presence_answers(c, event_id, &mut res)?;
+            Ok(res)
+        })
+    }
+
+    fn get_attendance_list(&self, event_id: u64, offset: u64, limit: u64) -> Result<Vec<Presence>> {
+        self.run(|c| {
+            let rows = c.query(
+                "SELECT r.*, u.user_name1, u.user_name2, a.attachment, p.user_id IS NOT NULL AS present FROM \
+                (SELECT user_id, sum(quantity)::bigint AS reserved FROM reservations WHERE event = $1 AND waiting_list = 0 GROUP BY user_id) AS r \
+                JOIN users AS u ON r.user_id = u.user_id \
+                LEFT JOIN presence AS p ON p.event = $1 AND r.user_id = p.user_id \
+                LEFT JOIN attachments AS a ON a.event = $1 AND r.user_id = a.user_id \
+                ORDER BY u.user_name1, r.user_id LIMIT $2 OFFSET $3",
+                &[&(event_id as i64), &(limit as i64), &((offset * limit) as i64)],
+            )?;
+            let mut res = Vec::new();
+            for row in rows {
+                res.push(Presence {
+                    user_id: uint(&row, "user_id")?,
+                    user_name1: row.try_get("user_name1")?,
+                    user_name2: row.try_get("user_name2")?,
+                    reserved: uint(&row, "reserved")?,
+                    attachment: row.try_get("attachment")?,
+                    answers: Vec::new(),
+                    present: row.try_get("present")?,
+                });
+            }
+            presence_answers(c, event_id, &mut res)?;
+            Ok(res)
+        })
+    }
+
+    fn confirm_presence(&self, event_id: u64, user_id: u64) -> Result<()> {
+        self.run(|c| {
+            c.execute(
+                "INSERT INTO presence (event, user_id) VALUES ($1, $2)",
+                &[&(event_id as i64), &(user_id as i64)],
+            )?;
+            Ok(())
+        })
+    }
+
+    fn is_group_leader(&self, event_id: u64, user_id: u64) -> Result<bool> {
+        self.run(|c| {
+            Ok(c.query_opt(
+                "SELECT event FROM group_leaders WHERE event = $1 AND user_id = $2",
+                &[&(event_id as i64), &(user_id as i64)],
+            )?
+            .is_some())
+        })
+    }
+
+    fn set_group_leader(&self, event_id: u64, user_id: u64) -> Result<()> {
+        self.run(|c| {
+            c.execute(
+                "INSERT INTO group_leaders (event, user_id) VALUES ($1, $2)",
+                &[&(event_id as i64), &(user_id as i64)],
+            )?;
+            Ok(())
+        })
+    }
+
+    fn save_user(&self, user: &User, ts: u64) -> Result<()> {
+        self.run(|c| save_user(c, user, ts))
+    }
+
+    fn set_user_reachable(&self, user_id: u64, reachable: bool) -> Result<()> {
+        self.run(|c| {
+            c.execute(
+                "UPDATE users SET reachable = $1 WHERE user_id = $2",
+                &[&reachable, &(user_id as i64)],
+            )?;
+            Ok(())
+        })
+    }
+
+    fn get_user(&self, user_id: u64) -> Result<Option<UserProfile>> {
+        self.run(|c| {
+            match c.query_opt("SELECT * FROM users WHERE user_id = $1", &[&(user_id as i64)])? {
+                Some(row) => Ok(Some(user_profile(&row)?)),
+                None => Ok(None),
+            }
+        })
+    }
+
+    fn get_users(&self, offset: u64, limit: u64) -> Result<Vec<UserProfile>> {
+        self.run(|c| {
+            let rows = c.query(
+                "SELECT * FROM users ORDER BY last_seen DESC, user_id LIMIT $1 OFFSET $2",
+                &[&(limit as i64), &((offset * limit) as i64)],
+            )?;
+            let mut res = Vec::new();
+            for row in rows {
+                res.push(user_profile(&row)?);
+            }
+            Ok(res)
+        })
+    }
+
+    fn add_series(&self, s: Series, actor: u64) -> Result<u64> {
+        let e = &s.template;
+        let tickets = serde_json::to_string(&e.tickets)?;
+        let categories = serde_json::to_string(&e.categories)?;
+        let questions = serde_json::to_string(&e.questions)?;
+        self.transaction(|tx| {
+            if s.id == 0 {
+                let row = tx.query_one(
+                    "INSERT INTO series (name, link, ts, remind, tickets, currency, period, until, next_ts, venue, duration, allow_overlap, \
+                    categories, questions, time_zone, auto_promote) \
+                    VALUES ($1, $2, $3, $4, $5, $6, $7, $8, $9, $10, $11, $12, $13, $14, $15, $16) RETURNING id",
+                    &[&e.name, &e.link, &(e.ts as i64), &(e.remind as i64), &tickets, &e.currency,
+                        &(s.period as i64), &(s.until as i64), &(e.ts as i64), &(e.venue_id as i64), &(e.duration as i64), &e.allow_overlap,
+                        &categories, &questions, &e.time_zone, &e.auto_promote],
+                )?;
+                return uint(&row, "id");
+            }
+
+            let old = lock_series(tx, s.id)?;
+            for o in get_upcoming_occurrences(tx, s.id)? {
+                let mut changed = occurrence(&s, shift_local(&s.template, old.template.ts, s.template.ts, o.event.ts));
+                changed.id = o.event.id;
+                save_event(tx, &changed, o.state)?;
+            }
+            tx.execute(
+                "UPDATE series SET name = $1, link = $2, ts = $3, remind = $4, tickets = $5, currency = $6, period = $7, until = $8, next_ts = $9, \
+                venue = $10, duration = $11, allow_overlap = $12, categories = $13, questions = $14, time_zone = $15, auto_promote = $16 \
+                WHERE id = $17",
+                &[&e.name, &e.link, &(e.ts as i64), &(e.remind as i64), &tickets, &e.currency,
+                    &(s.period as i64), &(s.until as i64), &(next_occurrence(&old, &s) as i64), &(e.venue_id as i64),
+                    &(e.duration as i64), &e.allow_overlap, &categories, &questions, &e.time_zone, &e.auto_promote, &(s.id as i64)],
+            )?;
+            audit(tx, get_unix_time(), actor, AuditAction::EditSeries, 0, 0, &format!("series {}", s.id))?;
+            Ok(s.id)
+        })
+    }
+
+    fn get_series(&self, series_id: u64) -> Result<Series> {
+        self.run(|c| get_series(c, series_id))
+    }
+
+    fn get_series_list(&self) -> Result<Vec<Series>> {
+        self.run(|c| {
+            let mut res = Vec::new();
+            for row in c.query("SELECT * FROM series WHERE NOT cancelled ORDER BY id", &[])? {
+                res.push(series(&row)?);
+            }
+            Ok(res)
+        })
+    }
+
+    fn cancel_series(&self, series_id: u64, actor: u64) -> Result<()> {
+        self.transaction(|tx| {
+            lock_series(tx, series_id)?;
+            tx.execute("UPDATE series SET cancelled = TRUE WHERE id = $1", &[&(series_id as i64)])?;
+            for o in get_upcoming_occurrences(tx, series_id)? {
+                set_event_state(tx, o.event.id, EventState::Cancelled, actor)?;
+            }
+            audit(tx, get_unix_time(), actor, AuditAction::CancelSeries, 0, 0, &format!("series {}", series_id))
+        })
+    }
+
+    fn materialize_series(&self, ts: u64) -> Result<Vec<u64>> {
+        let now = get_unix_time();
+        let mut created = Vec::new();
+        for s in self.get_series_list()? {
+            if s.next_ts >= ts || s.next_ts > s.until {
+                continue;
+            }
+            self.transaction(|tx| {
+                // Another instance may have been faster.
+                let mut s = lock_series(tx, s.id)?;
+                while s.next_ts < ts && s.next_ts <= s.until {
+                    // Occurrences missed while the bot was down are not created retroactively.
+                    if s.next_ts > now {
+                        created.push(save_event(tx, &occurrence(&s, s.next_ts), EventState::Open)?);
+                    }
+                    s.next_ts = add_days(&s.template, s.next_ts, s.period as i64);
+                }
+                tx.execute(
+                    "UPDATE series SET next_ts = $1 WHERE id = $2",
+                    &[&(s.next_ts as i64), &(s.id as i64)],
+                )?;
+                Ok(())
+            })?;
+        }
+        Ok(created)
+    }
+
+    fn add_venue(&self, v: Venue, actor: u64) -> Result<u64> {
+        self.transaction(|tx| {
+            let venue_id = if v.id == 0 {
+                let row = tx.query_one(
+                    "INSERT INTO venues (name, address, latitude, longitude) VALUES ($1, $2, $3, $4) RETURNING id",
+                    &[&v.name, &v.address, &v.latitude, &v.longitude],
+                )?;
+                uint(&row, "id")?
+            } else {
+                let res = tx.execute(
+                    "UPDATE venues SET name = $1, address = $2, latitude = $3, longitude = $4 WHERE id = $5",
+                    &[&v.name, &v.address, &v.latitude, &v.longitude, &(v.id as i64)],
+                )?;
+                if res == 0 {
+                    return Err(Error::VenueNotFound(v.id));
+                }
+                v.id
+            };
+            audit(tx, get_unix_time(), actor, AuditAction::EditVenue, 0, 0, &format!("venue {}", venue_id))?;
+            Ok(venue_id)
+        })
+    }
+
+    fn get_venue(&self, venue_id: u64) -> Result<Venue> {
+        self.run(|c| match c.query_opt("SELECT * FROM venues WHERE id = $1", &[&(venue_id as i64)])? {
+            Some(row) => venue(&row),
+            None => Err(Error::VenueNotFound(venue_id)),
+        })
+    }
+
+    fn get_venues(&self) -> Result<Vec<Venue>> {
+        self.run(|c| {
+            let mut res = Vec::new();
+            for row in c.query("SELECT * FROM venues ORDER BY name, id", &[])? {
+                res.push(venue(&row)?);
+            }
+            Ok(res)
+        })
+    }
+
+    fn add_category(&self, name: &str) -> Result<u64> {
+        self.run(|c| {
+            let row = c.query_one(
+                "INSERT INTO categories (name) VALUES ($1) ON CONFLICT (name) DO UPDATE SET name = EXCLUDED.name RETURNING id",
+                &[&name],
+            )?;
+            uint(&row, "id")
+        })
+    }
+
+    fn get_categories(&self) -> Result<Vec<Category>> {
+        self.run(|c| {
+            let mut res = Vec::new();
+            for row in c.query("SELECT id, name FROM categories ORDER BY name", &[])? {
+                res.push(Category {
+                    id: uint(&row, "id")?,
+                    name: row.try_get("name")?,
+                });
+            }
+            Ok(res)
+        })
+    }
+
+    fn get_answers(&self, event_id: u64, user: u64) -> Result<Vec<String>> {
+        self.run(|c| {
+            let s = find_event(c, event_id, 0)?;
+            get_answers(c, event_id, user, s.event.questions.len())
+        })
+    }
+
+    fn save_answer(&self, event_id: u64, user: u64, question: u64, answer: &str) -> Result<()> {
+        self.run(|c| {
+            let s = find_event(c, event_id, user)?;
+            if s.my_reservation() == 0 && s.my_waiting() == 0 {
+                return Err(Error::ReservationNotFound);
+            }
+            if question < s.event.questions.len() as u64 {
+                c.execute(
+                    "INSERT INTO answers (event, user_id, question, answer) VALUES ($1, $2, $3, $4) \
+                    ON CONFLICT (event, user_id, question) DO UPDATE SET answer = EXCLUDED.answer",
+                    &[&(event_id as i64), &(user as i64), &(question as i64), &answer],
+                )?;
+            }
+            Ok(())
+        })
+    }
+
+    fn clear_answers(&self, event_id: u64, user: u64) -> Result<()> {
+        self.run(|c| {
+            c.execute(
+                "DELETE FROM answers WHERE event = $1 AND user_id = $2",
+                &[&(event_id as i64), &(user as i64)],
+            )?;
+            Ok(())
+        })
+    }
+
+    fn get_questionnaire(&self, user: u64) -> Result<u64> {
+        self.run(|c| {
+            match c.query_opt("SELECT event FROM questionnaires WHERE user_id = $1", &[&(user as i64)])? {
+                Some(row) => Ok(uint(&row, "event")?),
+                None => Ok(0),
+            }
+        })
+    }
+
+    fn set_questionnaire(&self, user: u64, event_id: u64) -> Result<()> {
+        self.run(|c| {
+            if event_id == 0 {
+                c.execute("DELETE FROM questionnaires WHERE user_id = $1", &[&(user as i64)])?;
+            } else {
+                find_event(c, event_id, 0)?;
+                c.execute(
+                    "INSERT INTO questionnaires (user_id, event) VALUES ($1, $2) \
+                    ON CONFLICT (user_id) DO UPDATE SET event = EXCLUDED.event",
+                    &[&(user as i64), &(event_id as i64)],
+                )?;
+            }
+            Ok(())
+        })
+    }
+
+    fn get_refunds(&self, offset: u64, limit: u64) -> Result<Vec<Refund>> {
+        self.run(|c| {
+            let rows = c.query(
+                "SELECT * FROM refunds ORDER BY refunded > 0, ts DESC, id DESC LIMIT $1 OFFSET $2",
+                &[&(limit as i64), &((offset * limit) as i64)],
+            )?;
+            let mut res = Vec::new();
+            for row in rows {
+                res.push(refund(&row)?);
+            }
+            Ok(res)
+        })
+    }
+
+    fn complete_refund(&self, refund_id: u64, actor: u64) -> Result<()> {
+        self.transaction(|tx| {
+            let r = match tx.query_opt(
+                "SELECT * FROM refunds WHERE id = $1 AND refunded = 0 FOR UPDATE",
+                &[&(refund_id as i64)],
+            )? {
+                Some(row) => refund(&row)?,
+                None => return Err(Error::RefundNotFound(refund_id)),
+            };
+            let now = get_unix_time();
+            tx.execute(
+                "UPDATE refunds SET refunded = $1 WHERE id = $2",
+                &[&(now as i64), &(refund_id as i64)],
+            )?;
+            audit(tx, now, actor, AuditAction::CompleteRefund, r.event_id, r.user_id,
+                &format!("{} {} {}", r.amount as f32 / 100f32, r.currency, r.payment))
+        })
+    }
+
+    fn save_search(&self, user: u64, text: &str) -> Result<()> {
+        self.run(|c| {
+            c.execute(
+                "INSERT INTO searches (user_id, text) VALUES ($1, $2) \
+                ON CONFLICT (user_id) DO UPDATE SET text = EXCLUDED.text",
+                &[&(user as i64), &text],
+            )?;
+            Ok(())
+        })
+    }
+
+    fn get_search(&self, user: u64) -> Result<String> {
+        self.run(|c| {
+            match c.query_opt("SELECT text FROM searches WHERE user_id = $1", &[&(user as i64)])? {
+                Some(row) => Ok(row.try_get("text")?),
+                None => Ok(String::new()),
+            }
+        })
+    }
+
+    fn save_import(&self, user: u64, events: &str) -> Result<()> {
+        self.run(|c| {
+            c.execute(
+                "INSERT INTO imports (user_id, events) VALUES ($1, $2) \
+                ON CONFLICT (user_id) DO UPDATE SET events = EXCLUDED.events",
+                &[&(user as i64), &events],
+            )?;
+            Ok(())
+        })
+    }
+
+    fn take_import(&self, user: u64) -> Result<Option<String>> {
+        self.run(|c| {
+            match c.query_opt("DELETE FROM imports WHERE user_id = $1 RETURNING events", &[&(user as i64)])? {
+                Some(row) => Ok(Some(row.try_get("events")?)),
+                None => Ok(None),
+            }
+        })
+    }
+
+    fn get_audit_log(
+        &self,
+        filter: AuditFilter,
+        offset: u64,
+        limit: u64,
+    ) -> Result<Vec<AuditRecord>> {
+        self.run(|c| {
+            let (limit, offset) = (limit as i64, (offset * limit) as i64);
+            let rows = match filter {
+                AuditFilter::All => c.query(
+                    "SELECT * FROM audit_log ORDER BY ts DESC, id DESC LIMIT $1 OFFSET $2",
+                    &[&limit, &offset],
+                )?,
+                AuditFilter::Event(event_id) => c.query(
+                    "SELECT * FROM audit_log WHERE event = $3 ORDER BY ts DESC, id DESC LIMIT $1 OFFSET $2",
+                    &[&limit, &offset, &(event_id as i64)],
+                )?,
+                AuditFilter::User(user_id) => c.query(
+                    "SELECT * FROM audit_log WHERE actor = $3 OR user_id = $3 ORDER BY ts DESC, id DESC LIMIT $1 OFFSET $2",
+                    &[&limit, &offset, &(user_id as i64)],
+                )?,
+            };
+            let mut res = Vec::new();
+            for row in rows {
+                res.push(AuditRecord {
+                    ts: uint(&row, "ts")?,
+                    actor: uint(&row, "actor")?,
+                    action: num::FromPrimitive::from_u64(uint(&row, "action")?).unwrap(),
+                    event_id: uint(&row, "event")?,
+                    user_id: uint(&row, "user_id")?,
+                    details: row.try_get("details")?,
+                });
+            }
+            Ok(res)
+        })
+    }
+}
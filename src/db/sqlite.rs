@@ -0,0 +1,2173 @@
+use super::{
+    add_days, check_amount, check_editable, check_sign_up, check_ticket_types, counters, event_cancelled_text, event_change_text, event_name, is_upcoming, migrations, next_occurrence,
+    occurrence, one_ticket, promoted_text, promotions, refunds, registration_opened_text, reminder_text, reservation_details,
+    search_words, shift_local, took_place, waiting_list_prompt_text, AuditFilter, AuditRecord, Error, EventFilter, EventStats,
+    GroupMessage, Refund, Result, SignUp, SignUpChecks, Storage, SYSTEM_ACTOR, UserProfile, WaitingReservation,
+};
+use crate::types::{
+    AuditAction, Booking, Category, Event, EventState, EventType, MessageBatch, MessageType, OrderInfo, Participant,
+    Presence, ReservationState, Series, TicketType, User, Venue,
+};
+use crate::util::{self, get_unix_time};
+use r2d2_sqlite::SqliteConnectionManager;
+use rusqlite::{params, Connection, Row};
+use std::collections::HashSet;
+use std::time::Duration;
+use url::Url;
+
+pub type DbPool = r2d2::Pool<SqliteConnectionManager>;
+
+/// SQLite storage.
+pub struct SqliteStorage {
+    pool: DbPool,
+}
+
+impl SqliteStorage {
+    /// Opens a database file and brings its schema up to date.
+    pub fn open(path: &str) -> anyhow::Result<SqliteStorage> {
+        let manager = SqliteConnectionManager::file(path).with_init(init_connection);
+        let pool = r2d2::Pool::new(manager)?;
+        let version = migrations::migrate(&mut *pool.get()?)?;
+        info!("Database schema version {}", version);
+        Ok(SqliteStorage { pool })
+    }
+}
+
+/// Connection setup for the pool: wait for locks held by other connections or processes
+/// instead of failing immediately, and enforce references to users.
+fn init_connection(conn: &mut Connection) -> rusqlite::Result<()> {
+    conn.busy_timeout(Duration::from_secs(10))?;
+    conn.execute_batch("PRAGMA journal_mode = WAL; PRAGMA foreign_keys = ON;")
+}
+
+/// Runs a booking operation in an immediate transaction.
+/// The write lock is taken before the first read, so vacancies can't change between the check
+/// and the update, even if the database is shared by several processes.
+fn immediate<T, F>(conn: &Connection, f: F) -> Result<T>
+where
+    F: FnOnce() -> Result<T>,
+{
+    conn.execute_batch("BEGIN IMMEDIATE")?;
+    match f() {
+        Ok(v) => {
+            conn.execute_batch("COMMIT")?;
+            Ok(v)
+        }
+        Err(e) => {
+            if let Err(e) = conn.execute_batch("ROLLBACK") {
+                error!("Failed to roll back: {}", e);
+            }
+            Err(e)
+        }
+    }
+}
+
+const EVENT_COLUMNS: &str = "events.id, events.name, events.link, events.ts, events.remind, events.state, events.tickets, events.currency, events.series, events.venue, events.duration, events.allow_overlap, events.categories, events.poster, events.registration_opens, events.registration_closes, events.questions, events.time_zone, events.auto_promote, events.revision";
+
+fn event_stats(conn: &Connection, row: &Row, user: u64) -> Result<EventStats> {
+    let state: u64 = row.get("state")?;
+    let tickets: String = row.get("tickets")?;
+    let categories: String = row.get("categories")?;
+    let questions: String = row.get("questions")?;
+    let event = Event {
+        id: row.get("id")?,
+        name: row.get("name")?,
+        link: row.get("link")?,
+        ts: row.get("ts")?,
+        remind: row.get("remind")?,
+        tickets: serde_json::from_str(&tickets)?,
+        currency: row.get("currency")?,
+        series_id: row.get("series")?,
+        venue_id: row.get("venue")?,
+        duration: row.get("duration")?,
+        allow_overlap: row.get("allow_overlap")?,
+        categories: serde_json::from_str(&categories)?,
+        poster: row.get("poster")?,
+        registration_opens: row.get("registration_opens")?,
+        registration_closes: row.get("registration_closes")?,
+        questions: serde_json::from_str(&questions)?,
+        time_zone: row.get("time_zone")?,
+        auto_promote: row.get("auto_promote")?,
+    };
+    let mut stmt = conn.prepare(
+        "SELECT ticket, waiting_list, user = ?2, sum(quantity) FROM reservations WHERE event = ?1 GROUP BY ticket, waiting_list, user = ?2",
+    )?;
+    let reservations = stmt
+        .query_map(params![event.id, user], |row| {
+            Ok((row.get(0)?, row.get(1)?, row.get(2)?, row.get(3)?))
+        })?
+        .collect::<rusqlite::Result<Vec<_>>>()?;
+    Ok(EventStats {
+        tickets: counters(event.tickets.len(), reservations),
+        event,
+        state: num::FromPrimitive::from_u64(state).unwrap_or(EventState::Closed),
+        revision: row.get("revision")?,
+    })
+}
+
+fn add_event(conn: &Connection, e: Event) -> Result<u64> {
+    immediate(conn, || save_event(conn, &e, EventState::Draft))
+}
+
+/// Inserts the event in the given state or updates it, without a transaction of its own.
+fn save_event(conn: &Connection, e: &Event, state: EventState) -> Result<u64> {
+    let event_type = e.get_type();
+    if event_type == EventType::Announcement && Url::parse(&e.link).is_err() {
+        return Err(Error::InvalidLink(e.link.clone()));
+    }
+    let tickets = serde_json::to_string(&e.tickets)?;
+    let categories = serde_json::to_string(&e.categories)?;
+    let questions = serde_json::to_string(&e.questions)?;
+    let mut event_id = e.id;
+    // Subscribers are told once, unless the opening is moved to the future again.
+    let mut announce_registration = e.registration_opens > get_unix_time();
+    if e.id == 0 {
+        let res = conn.execute(
+            "INSERT INTO events (name, link, ts, remind, tickets, currency, state, series, venue, duration, allow_overlap, categories, poster, \
+            registration_opens, registration_closes, questions, time_zone, auto_promote) \
+            VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?10, ?11, ?12, ?13, ?14, ?15, ?16, ?17, ?18)",
+            params![e.name, e.link, e.ts, e.remind, tickets, e.currency, state as u64, e.series_id, e.venue_id, e.duration, e.allow_overlap,
+                categories, e.poster, e.registration_opens, e.registration_closes, questions, e.time_zone, e.auto_promote],
+        )?;
+        if res > 0 {
+            event_id = conn.last_insert_rowid() as u64;
+        }
+    } else {
+        let old = find_event(conn, e.id, 0)?;
+        let archived = conn.query_row("SELECT archived FROM events WHERE id = ?1", [e.id], |row| row.get(0))?;
+        check_editable(&old, archived)?;
+        let old = old.event;
+        let mut stmt = conn.prepare("SELECT DISTINCT ticket FROM reservations WHERE event = ?1 ORDER BY ticket")?;
+        let booked = stmt.query_map([e.id], |row| row.get(0))?.collect::<rusqlite::Result<Vec<u64>>>()?;
+        check_ticket_types(&old, e, booked)?;
+        conn.execute(
+            "UPDATE events SET name = ?1, link = ?2, ts = ?3, remind = ?4, tickets = ?5, currency = ?6, venue = ?7, duration = ?8, \
+            allow_overlap = ?9, categories = ?10, registration_opens = ?11, registration_closes = ?12, questions = ?13, time_zone = ?14, \
+            auto_promote = ?15, revision = revision + 1 WHERE id = ?16",
+            params![e.name, e.link, e.ts, e.remind, tickets, e.currency, e.venue_id, e.duration, e.allow_overlap, categories,
+                e.registration_opens, e.registration_closes, questions, e.time_zone, e.auto_promote, e.id],
+        )?;
+        delete_enqueued_messages(conn, e.id, MessageType::Reminder)?;
+        delete_enqueued_messages(conn, e.id, MessageType::RegistrationOpened)?;
+        announce_registration |= old.registration_opens > get_unix_time();
+        if let Some(text) = event_change_text(&old, e) {
+            notify_participants(conn, e.id, MessageType::EventChanged, &text)?;
+        }
+    }
+
+    if event_id != 0 && event_type != EventType::Announcement {
+        let text = reminder_text(e);
+        enqueue_message(conn, 
+            event_id,
+            "Bot",
+            0,
+            MessageType::Reminder,
+            &text,
+            e.remind,
+        )?;
+    }
+    if event_id != 0 && event_type != EventType::Announcement && announce_registration {
+        enqueue_message(conn,
+            event_id,
+            "Bot",
+            0,
+            MessageType::RegistrationOpened,
+            &registration_opened_text(e),
+            e.registration_opens,
+        )?;
+    }
+    Ok(event_id)
+}
+
+/// Sends the message to both confirmed participants and the waiting list.
+fn notify_participants(
+    conn: &Connection,
+    event_id: u64,
+    message_type: MessageType,
+    text: &str,
+) -> Result<()> {
+    for waiting_list in [0, 1] {
+        let mut stmt =
+            conn.prepare("SELECT id FROM reservations WHERE event = ?1 AND waiting_list = ?2 LIMIT 1")?;
+        if stmt.exists(params![event_id, waiting_list])? {
+            enqueue_message(conn, event_id, "Bot", waiting_list, message_type, text, get_unix_time())?;
+        }
+    }
+    Ok(())
+}
+
+fn enqueue_message(
+    conn: &Connection,
+    event_id: u64,
+    sender: &str,
+    waiting_list: u64,
+    message_type: MessageType,
+    text: &str,
+    send_at: u64,
+) -> Result<()> {
+    debug!("enqueue message {} {}", util::get_unix_time(), send_at);
+    conn.execute(
+        "INSERT INTO messages (event, type, sender, waiting_list, text, ts) VALUES (?1, ?2, ?3, ?4, ?5, ?6)",
+        params![event_id, message_type as u64, sender, waiting_list, text, util::get_unix_time()],
+    )?;
+    let mut stmt = conn.prepare("SELECT last_insert_rowid()")?;
+    let mut rows = stmt.query([])?;
+    if let Some(row) = rows.next()? {
+        let message_id: u64 = row.get(0)?;
+        conn.execute(
+            "INSERT INTO message_outbox (message, send_at) VALUES (?1, ?2)",
+            params![message_id, send_at],
+        )?;
+    }
+    Ok(())
+}
+
+fn delete_enqueued_messages(
+    conn: &Connection,
+    event_id: u64,
+    message_type: MessageType,
+) -> Result<()> {
+    let mut stmt = conn.prepare("SELECT id FROM messages WHERE event = ?1 AND type = ?2")?;
+    let mut rows = stmt.query([event_id, message_type as u64])?;
+    while let Some(row) = rows.next()? {
+        let message_id: u64 = row.get(0)?;
+        conn.execute(
+            "DELETE FROM message_outbox WHERE message = ?1",
+            params![message_id],
+        )?;
+        conn.execute(
+            "DELETE FROM messages WHERE id = ?1",
+            params![message_id],
+        )?;
+    }
+    Ok(())
+}
+
+fn prompt_waiting_list(conn: &Connection, event_id: u64) -> Result<()> {
+    if !have_vacancies(conn, event_id)? {
+        debug!("prompt_waiting_list - no tickets, event {}", event_id);
+        return Ok(());
+    }
+
+    let send_at = get_unix_time() + 10; // give some time to finish multiple cancellations
+    let mut stmt = conn
+        .prepare("SELECT id FROM messages WHERE event = ?1 AND type = ?2")?;
+    let mut rows = stmt.query([event_id, MessageType::WaitingListPrompt as u64])?;
+    if let Some(row) = rows.next()? {
+        let message_id: u64 = row.get("id")?;
+        conn.execute(
+            "INSERT INTO message_outbox (message, send_at) VALUES (?1, ?2)",
+            params![message_id, send_at],
+        )?;
+    } else {
+        if let Ok(event_name) = get_event_name(conn, event_id) {
+            enqueue_message(conn, 
+                event_id,
+                "Bot",
+                1,
+                MessageType::WaitingListPrompt,
+                &waiting_list_prompt_text(&event_name),
+                send_at
+            )?;
+        }
+    }
+    Ok(())
+}
+
+/// Gives freed places to the waiting list: the oldest sign-ups that fit are confirmed if the event
+/// promotes automatically, otherwise the list is invited to sign up once places appear.
+fn fill_vacancies(conn: &Connection, event_id: u64, state_changed: bool) -> Result<()> {
+    let s = find_event(conn, event_id, 0)?;
+    if s.event.auto_promote {
+        promote_waiting_list(conn, &s)
+    } else if state_changed {
+        prompt_waiting_list(conn, event_id)
+    } else {
+        Ok(())
+    }
+}
+
+fn promote_waiting_list(conn: &Connection, s: &EventStats) -> Result<()> {
+    let event_id = s.event.id;
+    let mut stmt = conn.prepare("SELECT id, user, ticket, quantity, ts FROM reservations WHERE event = ?1 AND waiting_list = 1")?;
+    let mut rows = stmt.query([event_id])?;
+    let mut waiting = Vec::new();
+    while let Some(row) = rows.next()? {
+        waiting.push(WaitingReservation {
+            id: row.get("id")?,
+            user: row.get("user")?,
+            ticket: row.get("ticket")?,
+            quantity: row.get("quantity")?,
+            ts: row.get("ts")?,
+        });
+    }
+    let promoted = promotions(s, &waiting);
+    if promoted.is_empty() {
+        return Ok(());
+    }
+
+    let ts = get_unix_time();
+    let mut stmt = conn.prepare("SELECT id FROM messages WHERE event = ?1 AND type = ?2")?;
+    let mut rows = stmt.query([event_id, MessageType::Promoted as u64])?;
+    let message_id: Option<u64> = match rows.next()? {
+        Some(row) => Some(row.get("id")?),
+        None => None,
+    };
+    for p in &promoted {
+        for id in &p.reservations {
+            conn.execute("UPDATE reservations SET waiting_list = 0 WHERE id = ?1", params![id])?;
+        }
+        conn.execute(
+            "INSERT OR REPLACE INTO promotions (event, user, ts) VALUES (?1, ?2, ?3)",
+            params![event_id, p.user, ts],
+        )?;
+        if let Some(message_id) = message_id {
+            // Promoted again since the last message, tell once more.
+            conn.execute(
+                "DELETE FROM message_sent WHERE message = ?1 AND user = ?2",
+                params![message_id, p.user],
+            )?;
+        }
+        audit(conn, ts, SYSTEM_ACTOR, AuditAction::Promote, event_id, p.user, &reservation_details(&s.event, &p.tickets, 0))?;
+    }
+    match message_id {
+        Some(message_id) => {
+            conn.execute(
+                "INSERT INTO message_outbox (message, send_at) SELECT ?1, ?2 \
+                WHERE NOT EXISTS (SELECT 1 FROM message_outbox WHERE message = ?1)",
+                params![message_id, ts],
+            )?;
+        }
+        None => {
+            enqueue_message(conn, event_id, "Bot", 0, MessageType::Promoted, &promoted_text(&event_name(&s.event)), ts)?;
+        }
+    }
+    Ok(())
+}
+
+fn blacklist_absent_participants(
+    conn: &Connection,
+    event_id: u64,
+    admins: &HashSet<u64>,
+    cancel_future_reservations: bool,
+) -> Result<()> {
+    let mut stmt = conn.prepare(
+        "select r.*, p.user from (select r.event, r.user, u.user_name1, u.user_name2, count(r.user) as count from reservations as r join users as u on r.user = u.user where r.event = ?1 and r.waiting_list = 0 group by r.user) as r 
+        left join presence as p on r.event = p.event and r.user = p.user"
+    )?;
+    let mut rows = stmt.query(params![event_id])?;
+    let mut list: Vec<Presence> = Vec::new();
+    let mut presence_checked = false;
+    while let Some(row) = rows.next()? {
+        let present: rusqlite::Result<u64> = row.get(5);
+        if present.is_err() {
+            list.push(Presence {
+                user_id: row.get(1)?,
+                user_name1: row.get(2)?,
+                user_name2: row.get(3)?,
+                reserved: row.get(4)?,
+                attachment: None,
+                answers: Vec::new(),
+                present: false,
+            });
+        } else {
+            presence_checked = true;
+        }
+    }
+    if presence_checked && !list.is_empty() {
+        // Check at least one present.
+        if let Ok(reason) = get_event_name(conn, event_id) {
+            list.iter()
+                .filter(|p| !admins.contains(&p.user_id))
+                .try_for_each(|p| {
+                    ban_user(conn, 
+                        p.user_id,
+                        &p.user_name1,
+                        &p.user_name2,
+                        &reason,
+                        cancel_future_reservations,
+                    )
+                })?;
+        } else {
+            warn!("Failed to get event {}", event_id);
+        }
+    }
+    Ok(())
+}
+
+fn get_ban_reason(conn: &Connection, user_id: u64) -> Result<String> {
+    let mut stmt = conn
+        .prepare("SELECT reason FROM black_list WHERE user = ?1")?;
+    let mut rows = stmt.query([user_id])?;
+    if let Some(row) = rows.next()? {
+        let reason: String = row.get("reason")?;
+        Ok(reason)
+    } else {
+        Ok("unknown user".to_string())
+    }
+}
+
+fn delete_event(
+    conn: &Connection,
+    event_id: u64,
+    automatic_blacklisting: bool,
+    cancel_future_reservations_on_ban: bool,
+    admins: &HashSet<u64>
+) -> Result<()> {
+    let s = find_event(conn, event_id, 0)?;
+    if automatic_blacklisting && s.event.get_type() != EventType::Paid && took_place(&s, get_unix_time()) {
+        blacklist_absent_participants(
+            conn,
+            event_id,
+            admins,
+            cancel_future_reservations_on_ban,
+        )?;
+    }
+
+    if let Err(e) = conn
+        .execute("DELETE FROM reservations WHERE event=?1", params![event_id])
+    {
+        error!("{}", e);
+    }
+    if let Err(e) = conn
+        .execute("DELETE FROM events WHERE id=?1", params![event_id])
+    {
+        error!("{}", e);
+    }
+    if let Err(e) = conn
+        .execute("DELETE FROM attachments WHERE event=?1", params![event_id])
+    {
+        error!("{}", e);
+    }
+    if let Err(e) = conn
+        .execute("DELETE FROM presence WHERE event=?1", params![event_id])
+    {
+        error!("{}", e);
+    }
+    if let Err(e) = conn.execute(
+        "DELETE FROM group_leaders WHERE event=?1",
+        params![event_id],
+    ) {
+        error!("{}", e);
+    }
+    if let Err(e) = conn
+        .execute("DELETE FROM messages WHERE event=?1", params![event_id])
+    {
+        error!("{}", e);
+    }
+    if let Err(e) = conn.execute(
+        "DELETE FROM registration_subscriptions WHERE event=?1",
+        params![event_id],
+    ) {
+        error!("{}", e);
+    }
+    if let Err(e) = conn
+        .execute("DELETE FROM answers WHERE event=?1", params![event_id])
+    {
+        error!("{}", e);
+    }
+    if let Err(e) = conn
+        .execute("DELETE FROM questionnaires WHERE event=?1", params![event_id])
+    {
+        error!("{}", e);
+    }
+    if let Err(e) = conn
+        .execute("DELETE FROM promotions WHERE event=?1", params![event_id])
+    {
+        error!("{}", e);
+    }
+    Ok(())
+}
+
+fn delete_link(
+    conn: &Connection,
+    link: &str,
+) -> Result<()> {
+    let mut stmt = conn.prepare("select id from events where link = ?1")?;
+    let mut rows = stmt.query(params![link])?;
+    if let Some(row) = rows.next()? {
+        let event_id: u64 = row.get("id")?;
+        delete_event(conn, event_id, false, false, &HashSet::new())
+    } else {
+        Ok(())
+    }
+}
+
+fn sign_up(
+    conn: &Connection,
+    event_id: u64,
+    user: &User,
+    tickets: &[u64],
+    wait: u64,
+    ts: u64,
+    amount: u64,
+) -> Result<(usize, bool)> {
+    immediate(conn, || {
+        let user_id = user.id.0;
+        let s = get_event(conn, event_id, user_id)?;
+        save_user(conn, user, ts)?;
+        let black_listed = is_in_black_list(conn, user_id).unwrap_or(false);
+        let mut stmt = conn.prepare(
+            "select events.id from events join reservations as r on events.id = r.event where r.user = ?3 and events.id != ?4 \
+            and r.waiting_list = 0 and events.state != ?5 \
+            and events.allow_overlap = 0 and (events.ts = ?1 or (events.ts < ?2 and events.ts + events.duration > ?1))",
+        )?;
+        let time_conflict = stmt.exists(params![
+            s.event.ts,
+            s.event.end(),
+            user_id,
+            s.event.id,
+            EventState::Cancelled as u64
+        ])?;
+
+        match check_sign_up(
+            &s,
+            user,
+            tickets,
+            wait,
+            ts,
+            amount,
+            SignUpChecks { black_listed, time_conflict },
+        )? {
+            SignUp::Reserve(state) => {
+                let state = state as u64;
+                let mut res = 0;
+                for (ticket, quantity) in tickets.iter().enumerate().filter(|(_, n)| **n > 0) {
+                    res += conn.execute(
+                        "INSERT INTO reservations (event, user, ticket, quantity, waiting_list, ts, state) VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7)",
+                        params![event_id, user_id, ticket, quantity, wait, ts, state],
+                    )?;
+                }
+                audit(conn, ts, user_id, AuditAction::SignUp, event_id, user_id,
+                    &reservation_details(&s.event, tickets, wait))?;
+                Ok((res, false))
+            }
+            SignUp::Confirm { ticket } => {
+                move_from_waiting_list(conn, event_id, user_id, ticket)?;
+                audit(conn, ts, user_id, AuditAction::SignUp, event_id, user_id,
+                    &reservation_details(&s.event, &one_ticket(ticket), 0))?;
+                Ok((1, false))
+            }
+            SignUp::Rejected => Ok((0, false)),
+            SignUp::BlackListed => Ok((0, true)),
+        }
+    })
+}
+
+fn checkout(
+    conn: &Connection,
+    booking: &Booking,
+    order_info: OrderInfo,
+) -> Result<()> {
+    immediate(conn, || {
+        let s = get_event(conn, booking.event_id, booking.user_id)?;
+        check_amount(&s, booking, &order_info)?;
+
+        // The booking was reserved as one row per ticket type.
+        let mut ids = Vec::new();
+        for (ticket, quantity) in booking.tickets.iter().enumerate().filter(|(_, n)| **n > 0) {
+            let mut stmt = conn
+                .prepare("select id from reservations where event = ?1 and user = ?2 and state = ?3 and ticket = ?4 and quantity = ?5 order by ts desc limit 1")?;
+            let mut rows = stmt.query(params![booking.event_id, booking.user_id, ReservationState::PaymentPending as u64, ticket, quantity])?;
+            match rows.next()? {
+                Some(row) => ids.push(row.get::<&str, u64>("id")?),
+                None => return Err(Error::ReservationNotFound),
+            }
+        }
+        if ids.is_empty() {
+            return Err(Error::ReservationNotFound);
+        }
+        let payment = serde_json::to_string(&order_info)?;
+        for id in ids {
+            conn.execute("UPDATE reservations SET state = ?1, payment = ?2 WHERE id = ?3",
+                params![ReservationState::PaymentCompleted as u64, payment, id],
+            )?;
+        }
+        Ok(())
+    })
+}
+
+fn move_from_waiting_list(
+    conn: &Connection,
+    event_id: u64,
+    user_id: u64,
+    ticket: u64,
+) -> Result<()> {
+    conn.execute("UPDATE reservations SET waiting_list = 0  WHERE id in \
+        (SELECT id FROM reservations where event = ?1 and user = ?2 and waiting_list = 1 and ticket = ?3 and quantity = 1 order by ts limit 1)",
+        params![event_id, user_id, ticket],
+    )?;
+    Ok(())
+}
+
+fn add_attachment(
+    conn: &Connection,
+    event_id: u64,
+    user: u64,
+    attachment: &str,
+) -> Result<usize> {
+    let msg = if attachment.len() < 256 {
+        format!("{}...", attachment.chars().take(256).collect::<String>())
+    } else {
+        attachment.to_string()
+    };
+
+    let s = get_event(conn, event_id, user)?;
+    if s.my_reservation() > 0 || s.my_waiting() > 0 {
+        Ok(conn.execute(
+            "INSERT INTO attachments (event, user, attachment) VALUES (?1, ?2, ?3) ON CONFLICT (event, user) DO \
+            UPDATE SET attachment=excluded.attachment",
+            params![event_id, user, msg],
+        )?)
+    } else {
+        Ok(0)
+    }
+}
+
+fn cancel(conn: &Connection, event_id: u64, user: u64, ticket: u64) -> Result<()> {
+    immediate(conn, || {
+        let s = find_event(conn, event_id, 0)?;
+        let state_changed = !s.has_vacancies();
+        conn.execute(
+            "DELETE FROM reservations WHERE id IN (SELECT id FROM reservations WHERE event=?1 AND user=?2 AND ticket = ?3 ORDER BY waiting_list DESC LIMIT 1)",
+            params![event_id, user, ticket],
+        )?;
+        audit(conn, get_unix_time(), user, AuditAction::Cancel, event_id, user,
+            &reservation_details(&s.event, &one_ticket(ticket), 0))?;
+        fill_vacancies(conn, event_id, state_changed)
+    })
+}
+
+fn wontgo(conn: &Connection, event_id: u64, user: u64) -> Result<()> {
+    immediate(conn, || {
+        let state_changed = !have_vacancies(conn, event_id)?;
+        conn.execute(
+            "DELETE FROM reservations WHERE event=?1 AND user=?2",
+            params![event_id, user],
+        )?;
+        audit(conn, get_unix_time(), user, AuditAction::Cancel, event_id, user, "all")?;
+        fill_vacancies(conn, event_id, state_changed)
+    })
+}
+
+fn have_vacancies(conn: &Connection, event_id: u64) -> Result<bool> {
+    match find_event(conn, event_id, 0) {
+        Ok(s) => Ok(s.has_vacancies()),
+        Err(Error::EventNotFound(_)) => Ok(false),
+        Err(e) => Err(e),
+    }
+}
+
+fn get_attachment(
+    conn: &Connection,
+    event_id: u64,
+    user: u64,
+) -> Result<Option<String>> {
+    let mut stmt = conn
+        .prepare("SELECT attachment FROM attachments WHERE event = ?1 AND user = ?2")?;
+    let mut rows = stmt.query(params![event_id, user])?;
+    if let Some(row) = rows.next()? {
+        let attachment: String = row.get(0)?;
+        Ok(Some(attachment))
+    } else {
+        Ok(None)
+    }
+}
+
+fn get_events(
+    conn: &Connection,
+    user: u64,
+    is_admin: bool,
+    filter: &EventFilter,
+    offset: u64,
+    limit: u64,
+) -> Result<Vec<EventStats>> {
+    let mut stmt = conn.prepare(&format!(
+        "SELECT {} FROM events WHERE events.archived = 0 AND (?3 OR events.state IN (?4, ?5, ?6)) \
+        AND (?7 = 0 OR EXISTS (SELECT 1 FROM json_each(events.categories) WHERE value = ?7)) \
+        AND (?8 = 0 OR (events.state = ?4 AND EXISTS (SELECT 1 FROM json_each(events.tickets) AS t \
+            WHERE json_extract(t.value, '$.capacity') > (SELECT IFNULL(SUM(quantity), 0) FROM reservations \
+            WHERE event = events.id AND ticket = t.key AND waiting_list = 0)))) \
+        AND (?9 = 0 OR events.ts < ?9) \
+        AND (?10 = 0 OR EXISTS (SELECT 1 FROM reservations WHERE event = events.id AND user = ?11)) \
+        AND (?12 = '' OR events.id IN (SELECT rowid FROM event_search WHERE event_search MATCH ?12)) \
+        ORDER BY ts LIMIT ?1 OFFSET ?2",
+        EVENT_COLUMNS
+    ))?;
+    // Each word is a quoted prefix, so the query has no FTS5 syntax of its own.
+    let text: Vec<String> = search_words(&filter.text).iter().map(|w| format!("\"{}\"*", w)).collect();
+    let mut rows = stmt.query(params![limit, offset * limit, is_admin,
+        EventState::Open as u64, EventState::Closed as u64, EventState::Cancelled as u64,
+        filter.category, filter.vacancies, filter.until, filter.mine, user, text.join(" ")])?;
+    let mut res = Vec::new();
+    while let Some(row) = rows.next()? {
+        res.push(event_stats(conn, row, user)?);
+    }
+    Ok(res)
+}
+
+/// Like `get_event`, but doesn't remember the event as the user's current one.
+fn find_event(conn: &Connection, event_id: u64, user: u64) -> Result<EventStats> {
+    let mut stmt = conn.prepare(&format!("SELECT {} FROM events WHERE id = ?1", EVENT_COLUMNS))?;
+    let mut rows = stmt.query([event_id])?;
+    match rows.next()? {
+        Some(row) => event_stats(conn, row, user),
+        None => Err(Error::EventNotFound(event_id)),
+    }
+}
+
+fn get_event(conn: &Connection, event_id: u64, user: u64) -> Result<EventStats> {
+    let s = find_event(conn, event_id, user)?;
+    set_current_event(conn, user, event_id)?;
+    Ok(s)
+}
+
+fn get_event_name(conn: &Connection, event_id: u64) -> Result<String> {
+    Ok(event_name(&find_event(conn, event_id, 0)?.event))
+}
+
+fn get_participants(
+    conn: &Connection,
+    event_id: u64,
+    waiting_list: u64,
+    offset: u64,
+    limit: u64,
+    state: ReservationState,
+) -> Result<Vec<Participant>> {
+    let event = find_event(conn, event_id, 0)?.event;
+    let ticket_types = event.tickets.len();
+    let state = state as u64;
+    let mut stmt;
+    let mut rows = if limit == 0 {
+        stmt = conn.prepare(
+        "SELECT a.*, b.attachment FROM (SELECT r.user, u.user_name1, u.user_name2, r.event, r.ts FROM reservations as r JOIN users as u ON r.user = u.user WHERE r.waiting_list = ?1 AND r.event = ?2 AND r.state = ?3 group by r.event, r.user ORDER BY r.ts) as a \
+        LEFT JOIN attachments as b ON a.event = b.event and a.user = b.user"
+        )?;
+        stmt.query([waiting_list, event_id, state])?
+    } else {
+        stmt = conn.prepare(
+            "SELECT a.*, b.attachment FROM (SELECT r.user, u.user_name1, u.user_name2, r.event, r.ts FROM reservations as r JOIN users as u ON r.user = u.user WHERE r.waiting_list = ?1 AND r.event = ?2 AND r.state = ?3 group by r.event, r.user ORDER BY r.ts LIMIT ?4 OFFSET ?5) as a \
+            LEFT JOIN attachments as b ON a.event = b.event and a.user = b.user"
+            )?;
+        stmt.query([waiting_list, event_id, state, limit, offset * limit])?
+    };
+    let mut res = Vec::new();
+    while let Some(row) = rows.next()? {
+        res.push(Participant {
+            user_id: row.get("user")?,
+            user_name1: row.get("user_name1")?,
+            user_name2: row.get("user_name2")?,
+            tickets: vec![0; ticket_types],
+            attachment: row.get("attachment").ok(),
+            answers: Vec::new(),
+        });
+    }
+    for p in res.iter_mut() {
+        p.answers = get_answers(conn, event_id, p.user_id, event.questions.len())?;
+    }
+
+    let mut stmt = conn.prepare(
+        "SELECT user, ticket, sum(quantity) FROM reservations WHERE waiting_list = ?1 AND event = ?2 AND state = ?3 GROUP BY user, ticket",
+    )?;
+    let mut rows = stmt.query([waiting_list, event_id, state])?;
+    while let Some(row) = rows.next()? {
+        let user: u64 = row.get(0)?;
+        let ticket: usize = row.get(1)?;
+        if let Some(p) = res.iter_mut().find(|p| p.user_id == user) {
+            if let Some(n) = p.tickets.get_mut(ticket) {
+                *n = row.get(2)?;
+            }
+        }
+    }
+    Ok(res)
+}
+
+fn get_presence_list(
+    conn: &Connection,
+    event_id: u64,
+    offset: u64,
+    limit: u64,
+) -> Result<Vec<Presence>> {
+    let mut stmt = conn.prepare(
+            "select r.*, p.user, a.attachment from (select r.event, r.user, u.user_name1, u.user_name2, sum(r.quantity) from reservations as r join users as u on r.user = u.user where r.event = ?1 and r.waiting_list = 0 group by r.user) as r \
+            left join presence as p on r.event = p.event and r.user = p.user \
+            left join attachments as a on r.event = a.event and r.user = a.user \
+            where p.user IS NULL order by r.user_name1 LIMIT ?2 OFFSET ?3"
+    )?;
+    let mut rows = stmt.query([event_id, limit, offset * limit])?;
+    let mut res = Vec::new();
+    while let Some(row) = rows.next()? {
+        res.push(Presence {
+            user_id: row.get(1)?,
+            user_name1: row.get(2)?,
+            user_name2: row.get(3)?,
+            reserved: row.get(4)?,
+            attachment: row.get(6).ok(),
+            answers: Vec::new(),
+            present: false,
+        });
+    }
+    presence_answers(conn, event_id, &mut res)?;
+    Ok(res)
+}
+
+fn get_attendance_list(
+    conn: &Connection,
+    event_id: u64,
+    offset: u64,
+    limit: u64,
+) -> Result<Vec<Presence>> {
+    let mut stmt = conn.prepare(
+            "select r.*, p.user, a.attachment from (select r.event, r.user, u.user_name1, u.user_name2, sum(r.quantity) from reservations as r join users as u on r.user = u.user where r.event = ?1 and r.waiting_list = 0 group by r.user) as r \
+            left join presence as p on r.event = p.event and r.user = p.user \
+            left join attachments as a on r.event = a.event and r.user = a.user \
+            order by r.user_name1 LIMIT ?2 OFFSET ?3"
+    )?;
+    let mut rows = stmt.query([event_id, limit, offset * limit])?;
+    let mut res = Vec::new();
+    while let Some(row) = rows.next()? {
+        let present: Option<u64> = row.get(5)?;
+        res.push(Presence {
+            user_id: row.get(1)?,
+            user_name1: row.get(2)?,
+            user_name2: row.get(3)?,
+            reserved: row.get(4)?,
+            attachment: row.get(6).ok(),
+            answers: Vec::new(),
+            present: present.is_some(),
+        });
+    }
+    presence_answers(conn, event_id, &mut res)?;
+    Ok(res)
+}
+
+fn presence_answers(conn: &Connection, event_id: u64, list: &mut [Presence]) -> Result<()> {
+    let questions = find_event(conn, event_id, 0)?.event.questions.len();
+    for p in list.iter_mut() {
+        p.answers = get_answers(conn, event_id, p.user_id, questions)?;
+    }
+    Ok(())
+}
+
+fn confirm_presence(conn: &Connection, event_id: u64, user_id: u64) -> Result<()> {
+    conn.execute(
+        "insert into presence (event, user) values (?1, ?2)",
+        params![event_id, user_id],
+    )?;
+    Ok(())
+}
+
+fn is_group_leader(conn: &Connection, event_id: u64, user_id: u64) -> Result<bool> {
+    let mut stmt = conn
+        .prepare("SELECT event FROM group_leaders WHERE event = ?1 AND user = ?2")?;
+    let mut rows = stmt.query(params![event_id, user_id])?;
+    if rows.next()?.is_some() {
+        Ok(true)
+    } else {
+        Ok(false)
+    }
+}
+
+fn set_group_leader(conn: &Connection, event_id: u64, user_id: u64) -> Result<()> {
+    conn.execute(
+        "insert into group_leaders (event, user) values (?1, ?2)",
+        params![event_id, user_id],
+    )?;
+    Ok(())
+}
+
+fn delete_reservation(conn: &Connection, event_id: u64, user_id: u64, actor: u64) -> Result<()> {
+    immediate(conn, || {
+        let state_changed = !have_vacancies(conn, event_id)?;
+        conn.execute(
+            "delete from reservations where event = ?1 and user = ?2",
+            params![event_id, user_id],
+        )?;
+        audit(conn, get_unix_time(), actor, AuditAction::DeleteReservation, event_id, user_id, "")?;
+        fill_vacancies(conn, event_id, state_changed)
+    })
+}
+
+fn get_pending_messages(
+    conn: &Connection,
+    ts: u64,
+    mut max_messages: u64,
+) -> Result<Vec<MessageBatch>> {
+    //debug!("get_pending_messages {}", ts);
+    let mut stmt = conn.prepare(
+        "SELECT m.*, o.send_at, e.tickets FROM message_outbox as o \
+        JOIN messages as m ON o.message = m.id \
+        JOIN events as e ON m.event = e.id \
+        WHERE o.send_at < ?1",
+    )?;
+    let mut rows = stmt.query([ts])?;
+    let mut res = Vec::new();
+    while let Some(row) = rows.next()? {
+        let message_type: u64 = row.get("type")?;
+        let batch = MessageBatch {
+            message_id: row.get("id")?,
+            event_id: row.get("event")?,
+            sender: row.get("sender")?,
+            message_type: num::FromPrimitive::from_u64(message_type).unwrap(),
+            waiting_list: row.get("waiting_list")?,
+            text: row.get("text")?,
+            is_paid: serde_json::from_str::<Vec<TicketType>>(&row.get::<&str, String>("tickets")?)?
+                .iter()
+                .any(|t| t.price != 0),
+            recipients: Vec::new(),
+        };
+        res.push(batch);
+
+        let batch = res.last_mut().unwrap();
+        let mut collect_users = true;
+        if batch.message_type == MessageType::WaitingListPrompt
+            && !have_vacancies(conn, batch.event_id)?
+        {
+            collect_users = false;
+        }
+
+        if collect_users {
+            let promoted = batch.message_type == MessageType::Promoted;
+            let subscribers = batch.message_type == MessageType::RegistrationOpened;
+            // Promoted users are told only while they still hold the place.
+            let mut stmt = conn.prepare(if promoted {
+                "SELECT p.user FROM promotions as p \
+                        WHERE p.event = ?1 \
+                        AND EXISTS (SELECT 1 FROM reservations as r WHERE r.event = p.event AND r.user = p.user AND r.waiting_list = 0) \
+                        AND NOT EXISTS (SELECT 1 FROM message_sent as s WHERE s.message = ?2 AND s.user = p.user) \
+                        ORDER BY p.ts LIMIT ?3"
+            } else if subscribers {
+                "SELECT r.user FROM registration_subscriptions as r \
+                        WHERE r.event = ?1 \
+                        AND NOT EXISTS (SELECT 1 FROM message_sent as s WHERE s.message = ?2 AND s.user = r.user) \
+                        ORDER BY r.ts LIMIT ?3"
+            } else {
+                "SELECT r.user, s.message as sent FROM \
+                        (select user, ts from reservations WHERE event = ?1 AND waiting_list = ?2 GROUP BY user) as r 
+                        LEFT JOIN (select user, message from message_sent where message = ?3) as s 
+                        ON r.user = s.user
+                        WHERE sent is null ORDER BY r.ts LIMIT ?4"
+            })?;
+            let mut rows = if promoted || subscribers {
+                stmt.query([batch.event_id, batch.message_id, max_messages])?
+            } else {
+                stmt.query([
+                    batch.event_id,
+                    batch.waiting_list,
+                    batch.message_id,
+                    max_messages,
+                ])?
+            };
+
+            while let Some(row) = rows.next()? {
+                let recipient: u64 = row.get("user")?;
+                batch.recipients.push(recipient);
+                max_messages -= 1;
+                if max_messages == 0 {
+                    return Ok(res);
+                }
+
+                if batch.message_type == MessageType::WaitingListPrompt {
+                    break; // take not more than one at a time
+                }
+            }
+        }
+        if batch.recipients.is_empty() {
+            // Done with the message.
+            debug!("finished sending message {}", batch.message_id);
+            if batch.message_type == MessageType::Promoted {
+                conn.execute(
+                    "DELETE FROM promotions WHERE event = ?1",
+                    params![batch.event_id],
+                )?;
+            }
+            conn.execute(
+                "DELETE FROM message_outbox WHERE message = ?1",
+                params![batch.message_id],
+            )?;
+            conn.execute(
+                "DELETE FROM message_sent WHERE message = ?1",
+                params![batch.message_id],
+            )?;
+        }
+    }
+    Ok(res)
+}
+
+
+fn set_current_event(conn: &Connection, user_id: u64, event_id: u64) -> Result<()> {
+    conn.execute(
+        "insert or replace into current_events (user, event) values (?1, ?2)",
+        params![user_id, event_id],
+    )?;
+    Ok(())
+}
+
+fn subscribe_to_registration(conn: &Connection, event_id: u64, user: u64, subscribe: bool) -> Result<()> {
+    find_event(conn, event_id, 0)?;
+    if subscribe {
+        conn.execute(
+            "INSERT OR IGNORE INTO registration_subscriptions (event, user, ts) VALUES (?1, ?2, ?3)",
+            params![event_id, user, get_unix_time()],
+        )?;
+    } else {
+        conn.execute(
+            "DELETE FROM registration_subscriptions WHERE event = ?1 AND user = ?2",
+            params![event_id, user],
+        )?;
+    }
+    Ok(())
+}
+
+fn get_current_event(conn: &Connection, user_id: u64) -> Result<u64> {
+    let mut stmt = conn
+        .prepare("SELECT event FROM current_events WHERE user=?1")?;
+    let mut rows = stmt.query([user_id])?;
+    if let Some(row) = rows.next()? {
+        let event_id: u64 = row.get(0)?;
+        Ok(event_id)
+    } else {
+        Ok(0)
+    }
+}
+
+fn archive_old_events(
+    conn: &Connection,
+    ts: u64,
+    automatic_blacklisting: bool,
+    cancel_future_reservations: bool,
+    admins: &HashSet<u64>,
+) -> Result<()> {
+    let mut stmt = conn.prepare("SELECT id FROM events WHERE ts < ?1 AND archived = 0")?;
+    let mut rows = stmt.query([ts - util::get_seconds_before_midnight(ts)])?;
+    while let Some(row) = rows.next()? {
+        let event_id: u64 = row.get(0)?;
+        archive_event(conn, event_id, automatic_blacklisting, cancel_future_reservations, admins)?;
+    }
+    Ok(())
+}
+
+fn archive_event(
+    conn: &Connection,
+    event_id: u64,
+    automatic_blacklisting: bool,
+    cancel_future_reservations_on_ban: bool,
+    admins: &HashSet<u64>,
+) -> Result<()> {
+    immediate(conn, || {
+        let s = find_event(conn, event_id, 0)?;
+        if automatic_blacklisting && s.event.get_type() != EventType::Paid && took_place(&s, get_unix_time()) {
+            blacklist_absent_participants(
+                conn,
+                event_id,
+                admins,
+                cancel_future_reservations_on_ban,
+            )?;
+        }
+        conn.execute(
+            "DELETE FROM message_outbox WHERE message IN (SELECT id FROM messages WHERE event = ?1)",
+            params![event_id],
+        )?;
+        conn.execute("UPDATE events SET archived = 1 WHERE id = ?1", params![event_id])?;
+        Ok(())
+    })
+}
+
+fn get_archived_events(conn: &Connection, offset: u64, limit: u64) -> Result<Vec<EventStats>> {
+    let mut stmt = conn.prepare(&format!(
+        "SELECT {} FROM events WHERE events.archived = 1 ORDER BY ts DESC LIMIT ?1 OFFSET ?2",
+        EVENT_COLUMNS
+    ))?;
+    let mut rows = stmt.query([limit, offset * limit])?;
+    let mut res = Vec::new();
+    while let Some(row) = rows.next()? {
+        res.push(event_stats(conn, row, 0)?);
+    }
+    Ok(res)
+}
+
+fn purge_archive(conn: &Connection, ts: u64) -> Result<()> {
+    let mut stmt = conn.prepare("SELECT id FROM events WHERE ts < ?1 AND archived = 1")?;
+    let mut rows = stmt.query([ts])?;
+    while let Some(row) = rows.next()? {
+        let event_id: u64 = row.get(0)?;
+        delete_event(conn, event_id, false, false, &HashSet::new())?;
+    }
+    Ok(())
+}
+
+fn save_user(conn: &Connection, user: &User, ts: u64) -> Result<()> {
+    conn.execute(
+        "INSERT INTO users (user, user_name1, user_name2, language_code, first_seen, last_seen) VALUES (?1, ?2, ?3, ?4, ?5, ?5) \
+        ON CONFLICT (user) DO UPDATE SET user_name1 = excluded.user_name1, user_name2 = excluded.user_name2, \
+        language_code = COALESCE(excluded.language_code, users.language_code), last_seen = max(users.last_seen, excluded.last_seen), reachable = 1",
+        params![user.id.0, user.user_name1, user.user_name2, user.language_code, ts],
+    )?;
+    Ok(())
+}
+
+fn set_user_reachable(conn: &Connection, user_id: u64, reachable: bool) -> Result<()> {
+    conn.execute(
+        "UPDATE users SET reachable = ?1 WHERE user = ?2",
+        params![reachable, user_id],
+    )?;
+    Ok(())
+}
+
+fn user_profile(row: &Row) -> rusqlite::Result<UserProfile> {
+    let user_id: u64 = row.get("user")?;
+    Ok(UserProfile {
+        user: User {
+            id: teloxide::types::UserId(user_id),
+            user_name1: row.get("user_name1")?,
+            user_name2: row.get("user_name2")?,
+            language_code: row.get("language_code")?,
+            is_admin: false,
+        },
+        first_seen: row.get("first_seen")?,
+        last_seen: row.get("last_seen")?,
+        reachable: row.get("reachable")?,
+    })
+}
+
+fn get_user(conn: &Connection, user_id: u64) -> Result<Option<UserProfile>> {
+    let mut stmt = conn.prepare("SELECT * FROM users WHERE user = ?1")?;
+    let mut rows = stmt.query([user_id])?;
+    if let Some(row) = rows.next()? {
+        Ok(Some(user_profile(row)?))
+    } else {
+        Ok(None)
+    }
+}
+
+fn get_users(conn: &Connection, offset: u64, limit: u64) -> Result<Vec<UserProfile>> {
+    let mut stmt = conn.prepare("SELECT * FROM users ORDER BY last_seen DESC, user LIMIT ?1 OFFSET ?2")?;
+    let mut rows = stmt.query([limit, offset * limit])?;
+    let mut res = Vec::new();
+    while let Some(row) = rows.next()? {
+        res.push(user_profile(row)?);
+    }
+    Ok(res)
+}
+
+fn save_receipt(conn: &Connection, message_id: u64, user: u64) -> Result<()> {
+    conn.execute(
+        "INSERT INTO message_sent (message, user, ts) VALUES (?1, ?2, ?3)",
+        params![message_id, user, util::get_unix_time()],
+    )?;
+    Ok(())
+}
+
+fn add_to_black_list(
+    conn: &Connection,
+    user: u64,
+    cancel_future_reservations: bool,
+    actor: u64,
+) -> Result<()> {
+    let reason = "banned by admin";
+    immediate(conn, || {
+        // Users who never talked to the bot are known by id only.
+        ban_user(conn,
+            user,
+            &user.to_string(),
+            "",
+            reason,
+            cancel_future_reservations,
+        )?;
+        audit(conn, get_unix_time(), actor, AuditAction::Ban, 0, user, reason)
+    })
+}
+
+fn ban_user(
+    conn: &Connection,
+    user: u64,
+    user_name1: &str,
+    user_name2: &str,
+    reason: &str,
+    cancel_future_reservations: bool,
+) -> Result<()> {
+    let ts = util::get_unix_time();
+    conn.execute(
+        "INSERT INTO users (user, user_name1, user_name2, first_seen, last_seen) VALUES (?1, ?2, ?3, ?4, ?4) \
+        ON CONFLICT (user) DO NOTHING",
+        params![user, user_name1, user_name2, ts],
+    )?;
+    conn.execute(
+        "INSERT INTO black_list (user, ts, reason) VALUES (?1, ?2, ?3)",
+        params![user, ts, reason],
+    )?;
+
+    if cancel_future_reservations {
+        // Past events keep the reservations for attendance and statistics.
+        let mut stmt = conn.prepare(
+            "SELECT DISTINCT r.event FROM reservations AS r JOIN events AS e ON r.event = e.id \
+            WHERE r.user = ?1 AND e.archived = 0",
+        )?;
+        let events = stmt.query_map([user], |row| row.get(0))?.collect::<rusqlite::Result<Vec<u64>>>()?;
+        for event_id in events {
+            let s = find_event(conn, event_id, 0)?;
+            if is_upcoming(&s, ts) {
+                let state_changed = !s.has_vacancies();
+                conn.execute(
+                    "DELETE FROM reservations WHERE event = ?1 AND user = ?2",
+                    params![event_id, user],
+                )?;
+                fill_vacancies(conn, event_id, state_changed)?;
+            }
+        }
+    }
+    Ok(())
+}
+
+fn remove_from_black_list(conn: &Connection, user: u64) -> Result<()> {
+    conn
+        .execute("DELETE FROM black_list WHERE user=?1", params![user])?;
+    Ok(())
+}
+fn get_black_list(conn: &Connection, offset: u64, limit: u64) -> Result<Vec<User>> {
+    let mut stmt = conn
+        .prepare("SELECT b.user, u.user_name1, u.user_name2, u.language_code FROM black_list as b JOIN users as u ON b.user = u.user \
+            order by u.user_name1 LIMIT ?1 OFFSET ?2")?;
+    let mut rows = stmt.query([limit, offset * limit])?;
+    let mut res = Vec::new();
+    while let Some(row) = rows.next()? {
+        let user_id: u64 = row.get(0)?;
+        res.push(User {
+            id: teloxide::types::UserId(user_id),
+            user_name1: row.get(1)?,
+            user_name2: row.get(2)?,
+            language_code: row.get(3)?,
+            is_admin: false,
+        });
+    }
+    Ok(res)
+}
+
+fn is_in_black_list(conn: &Connection, user: u64) -> Result<bool> {
+    let mut stmt = conn
+        .prepare("SELECT * FROM black_list WHERE user = ?1")?;
+    let mut rows = stmt.query([user])?;
+    if rows.next()?.is_some() {
+        Ok(true)
+    } else {
+        Ok(false)
+    }
+}
+
+fn clear_black_list(conn: &Connection, ts: u64) -> Result<()> {
+    conn
+        .execute("DELETE FROM black_list WHERE ts < ?1", params![ts])?;
+    Ok(())
+}
+
+fn clear_failed_payments(conn: &Connection, ts: u64) -> Result<()> {
+    conn
+        .execute("DELETE FROM reservations WHERE state = ?1 AND ts < ?2", params![ReservationState::PaymentPending as u64, ts])?;
+    Ok(())
+}
+
+fn change_event_state(conn: &Connection, event_id: u64, state: EventState, actor: u64) -> Result<()> {
+    immediate(conn, || set_event_state(conn, event_id, state, actor))
+}
+
+fn set_event_state(conn: &Connection, event_id: u64, state: EventState, actor: u64) -> Result<()> {
+    let s = find_event(conn, event_id, 0)?;
+    if !s.state.can_change_to(state) {
+        return Err(Error::InvalidStateChange(s.state, state));
+    }
+    if state == EventState::Cancelled {
+        delete_enqueued_messages(conn, event_id, MessageType::Reminder)?;
+        delete_enqueued_messages(conn, event_id, MessageType::WaitingListPrompt)?;
+        delete_enqueued_messages(conn, event_id, MessageType::RegistrationOpened)?;
+        notify_participants(conn, event_id, MessageType::EventCancelled, &event_cancelled_text(&s.event))?;
+        add_refunds(conn, &s)?;
+    }
+    conn.execute(
+        "UPDATE events SET state = ?1, revision = revision + 1 WHERE id = ?2",
+        params![state as u64, event_id],
+    )?;
+    audit(conn, get_unix_time(), actor, AuditAction::ChangeEventState, event_id, 0,
+        &format!("state {}", state as u64))
+}
+
+fn add_refunds(conn: &Connection, s: &EventStats) -> Result<()> {
+    let mut stmt = conn.prepare(
+        "SELECT user, payment FROM reservations \
+        WHERE event = ?1 AND state = ?2 AND payment IS NOT NULL ORDER BY id",
+    )?;
+    let mut rows = stmt.query(params![s.event.id, ReservationState::PaymentCompleted as u64])?;
+    let mut payments = Vec::new();
+    while let Some(row) = rows.next()? {
+        payments.push((row.get("user")?, row.get("payment")?));
+    }
+    for r in refunds(s, payments, get_unix_time())? {
+        conn.execute(
+            "INSERT INTO refunds (event, event_name, user, name, payment, amount, currency, ts) \
+            VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8)",
+            params![r.event_id, r.event_name, r.user_id, r.name, r.payment, r.amount, r.currency, r.ts],
+        )?;
+    }
+    Ok(())
+}
+
+fn get_refunds(conn: &Connection, offset: u64, limit: u64) -> Result<Vec<Refund>> {
+    let mut stmt = conn.prepare(
+        "SELECT * FROM refunds ORDER BY refunded > 0, ts DESC, id DESC LIMIT ?1 OFFSET ?2",
+    )?;
+    let mut rows = stmt.query([limit, offset * limit])?;
+    let mut res = Vec::new();
+    while let Some(row) = rows.next()? {
+        res.push(Refund {
+            id: row.get("id")?,
+            event_id: row.get("event")?,
+            event_name: row.get("event_name")?,
+            user_id: row.get("user")?,
+            name: row.get("name")?,
+            payment: row.get("payment")?,
+            amount: row.get("amount")?,
+            currency: row.get("currency")?,
+            ts: row.get("ts")?,
+            refunded: row.get("refunded")?,
+        });
+    }
+    Ok(res)
+}
+
+fn complete_refund(conn: &Connection, refund_id: u64, actor: u64) -> Result<()> {
+    immediate(conn, || {
+        let mut stmt = conn.prepare(
+            "SELECT event, user, payment, amount, currency FROM refunds WHERE id = ?1 AND refunded = 0",
+        )?;
+        let mut rows = stmt.query([refund_id])?;
+        let row = match rows.next()? {
+            Some(row) => row,
+            None => return Err(Error::RefundNotFound(refund_id)),
+        };
+        let event_id: u64 = row.get("event")?;
+        let user_id: u64 = row.get("user")?;
+        let payment: String = row.get("payment")?;
+        let amount: u64 = row.get("amount")?;
+        let currency: String = row.get("currency")?;
+        let now = get_unix_time();
+        conn.execute("UPDATE refunds SET refunded = ?1 WHERE id = ?2", params![now, refund_id])?;
+        audit(conn, now, actor, AuditAction::CompleteRefund, event_id, user_id,
+            &format!("{} {} {}", amount as f32 / 100f32, currency, payment))
+    })
+}
+
+fn set_event_limits(
+    conn: &Connection,
+    event_id: u64,
+    capacities: &[u64],
+    actor: u64,
+) -> Result<()> {
+    immediate(conn, || {
+        let mut s = find_event(conn, event_id, 0)?;
+        let state_changed = !s.has_vacancies();
+        for (t, capacity) in s.event.tickets.iter_mut().zip(capacities) {
+            t.capacity = *capacity;
+        }
+        conn.execute(
+            "UPDATE events SET tickets = ?1 WHERE id = ?2",
+            params![serde_json::to_string(&s.event.tickets)?, event_id],
+        )?;
+        audit(conn, get_unix_time(), actor, AuditAction::SetEventLimits, event_id, 0,
+            &reservation_details(&s.event, capacities, 0))?;
+        fill_vacancies(conn, event_id, state_changed)
+    })
+}
+
+fn series(row: &Row) -> Result<Series> {
+    let tickets: String = row.get("tickets")?;
+    let categories: String = row.get("categories")?;
+    let questions: String = row.get("questions")?;
+    Ok(Series {
+        id: row.get("id")?,
+        template: Event {
+            id: 0,
+            name: row.get("name")?,
+            link: row.get("link")?,
+            ts: row.get("ts")?,
+            remind: row.get("remind")?,
+            tickets: serde_json::from_str(&tickets)?,
+            currency: row.get("currency")?,
+            series_id: 0,
+            venue_id: row.get("venue")?,
+            duration: row.get("duration")?,
+            allow_overlap: row.get("allow_overlap")?,
+            categories: serde_json::from_str(&categories)?,
+            poster: String::new(),
+            registration_opens: 0,
+            registration_closes: 0,
+            questions: serde_json::from_str(&questions)?,
+            time_zone: row.get("time_zone")?,
+            auto_promote: row.get("auto_promote")?,
+        },
+        period: row.get("period")?,
+        until: row.get("until")?,
+        next_ts: row.get("next_ts")?,
+        cancelled: row.get("cancelled")?,
+    })
+}
+
+fn get_series(conn: &Connection, series_id: u64) -> Result<Series> {
+    let mut stmt = conn.prepare("SELECT * FROM series WHERE id = ?1")?;
+    let mut rows = stmt.query([series_id])?;
+    match rows.next()? {
+        Some(row) => series(row),
+        None => Err(Error::SeriesNotFound(series_id)),
+    }
+}
+
+fn get_series_list(conn: &Connection) -> Result<Vec<Series>> {
+    let mut stmt = conn.prepare("SELECT * FROM series WHERE cancelled = 0 ORDER BY id")?;
+    let mut rows = stmt.query([])?;
+    let mut res = Vec::new();
+    while let Some(row) = rows.next()? {
+        res.push(series(row)?);
+    }
+    Ok(res)
+}
+
+fn get_upcoming_occurrences(conn: &Connection, series_id: u64) -> Result<Vec<EventStats>> {
+    let mut stmt = conn.prepare("SELECT id FROM events WHERE series = ?1 AND archived = 0 ORDER BY ts")?;
+    let mut rows = stmt.query([series_id])?;
+    let now = get_unix_time();
+    let mut res = Vec::new();
+    while let Some(row) = rows.next()? {
+        let s = find_event(conn, row.get(0)?, 0)?;
+        if is_upcoming(&s, now) {
+            res.push(s);
+        }
+    }
+    Ok(res)
+}
+
+fn add_series(conn: &Connection, s: Series, actor: u64) -> Result<u64> {
+    let e = &s.template;
+    let tickets = serde_json::to_string(&e.tickets)?;
+    let categories = serde_json::to_string(&e.categories)?;
+    let questions = serde_json::to_string(&e.questions)?;
+    immediate(conn, || {
+        if s.id == 0 {
+            conn.execute(
+                "INSERT INTO series (name, link, ts, remind, tickets, currency, period, until, next_ts, venue, duration, allow_overlap, categories, \
+                questions, time_zone, auto_promote) VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?10, ?11, ?12, ?13, ?14, ?15, ?16)",
+                params![e.name, e.link, e.ts, e.remind, tickets, e.currency, s.period, s.until, e.ts, e.venue_id, e.duration, e.allow_overlap,
+                    categories, questions, e.time_zone, e.auto_promote],
+            )?;
+            return Ok(conn.last_insert_rowid() as u64);
+        }
+
+        let old = get_series(conn, s.id)?;
+        for o in get_upcoming_occurrences(conn, s.id)? {
+            let mut changed = occurrence(&s, shift_local(&s.template, old.template.ts, s.template.ts, o.event.ts));
+            changed.id = o.event.id;
+            save_event(conn, &changed, o.state)?;
+        }
+        conn.execute(
+            "UPDATE series SET name = ?1, link = ?2, ts = ?3, remind = ?4, tickets = ?5, currency = ?6, period = ?7, until = ?8, next_ts = ?9, venue = ?10, \
+            duration = ?11, allow_overlap = ?12, categories = ?13, questions = ?14, time_zone = ?15, auto_promote = ?16 WHERE id = ?17",
+            params![e.name, e.link, e.ts, e.remind, tickets, e.currency, s.period, s.until, next_occurrence(&old, &s), e.venue_id,
+                e.duration, e.allow_overlap, categories, questions, e.time_zone, e.auto_promote, s.id],
+        )?;
+        audit(conn, get_unix_time(), actor, AuditAction::EditSeries, 0, 0, &format!("series {}", s.id))?;
+        Ok(s.id)
+    })
+}
+
+fn cancel_series(conn: &Connection, series_id: u64, actor: u64) -> Result<()> {
+    immediate(conn, || {
+        get_series(conn, series_id)?;
+        conn.execute("UPDATE series SET cancelled = 1 WHERE id = ?1", params![series_id])?;
+        for o in get_upcoming_occurrences(conn, series_id)? {
+            set_event_state(conn, o.event.id, EventState::Cancelled, actor)?;
+        }
+        audit(conn, get_unix_time(), actor, AuditAction::CancelSeries, 0, 0, &format!("series {}", series_id))
+    })
+}
+
+fn materialize_series(conn: &Connection, ts: u64) -> Result<Vec<u64>> {
+    let now = get_unix_time();
+    let mut created = Vec::new();
+    for s in get_series_list(conn)? {
+        if s.next_ts >= ts || s.next_ts > s.until {
+            continue;
+        }
+        immediate(conn, || {
+            // Another instance may have been faster.
+            let mut s = get_series(conn, s.id)?;
+            while s.next_ts < ts && s.next_ts <= s.until {
+                // Occurrences missed while the bot was down are not created retroactively.
+                if s.next_ts > now {
+                    created.push(save_event(conn, &occurrence(&s, s.next_ts), EventState::Open)?);
+                }
+                s.next_ts = add_days(&s.template, s.next_ts, s.period as i64);
+            }
+            conn.execute("UPDATE series SET next_ts = ?1 WHERE id = ?2", params![s.next_ts, s.id])?;
+            Ok(())
+        })?;
+    }
+    Ok(created)
+}
+
+fn venue(row: &Row) -> Result<Venue> {
+    Ok(Venue {
+        id: row.get("id")?,
+        name: row.get("name")?,
+        address: row.get("address")?,
+        latitude: row.get("latitude")?,
+        longitude: row.get("longitude")?,
+    })
+}
+
+fn add_venue(conn: &Connection, v: Venue, actor: u64) -> Result<u64> {
+    immediate(conn, || {
+        let venue_id = if v.id == 0 {
+            conn.execute(
+                "INSERT INTO venues (name, address, latitude, longitude) VALUES (?1, ?2, ?3, ?4)",
+                params![v.name, v.address, v.latitude, v.longitude],
+            )?;
+            conn.last_insert_rowid() as u64
+        } else {
+            let res = conn.execute(
+                "UPDATE venues SET name = ?1, address = ?2, latitude = ?3, longitude = ?4 WHERE id = ?5",
+                params![v.name, v.address, v.latitude, v.longitude, v.id],
+            )?;
+            if res == 0 {
+                return Err(Error::VenueNotFound(v.id));
+            }
+            v.id
+        };
+        audit(conn, get_unix_time(), actor, AuditAction::EditVenue, 0, 0, &format!("venue {}", venue_id))?;
+        Ok(venue_id)
+    })
+}
+
+fn get_venue(conn: &Connection, venue_id: u64) -> Result<Venue> {
+    let mut stmt = conn.prepare("SELECT * FROM venues WHERE id = ?1")?;
+    let mut rows = stmt.query([venue_id])?;
+    match rows.next()? {
+        Some(row) => venue(row),
+        None => Err(Error::VenueNotFound(venue_id)),
+    }
+}
+
+fn get_venues(conn: &Connection) -> Result<Vec<Venue>> {
+    let mut stmt = conn.prepare("SELECT * FROM venues ORDER BY name, id")?;
+    let mut rows = stmt.query([])?;
+    let mut res = Vec::new();
+    while let Some(row) = rows.next()? {
+        res.push(venue(row)?);
+    }
+    Ok(res)
+}
+
+fn add_category(conn: &Connection, name: &str) -> Result<u64> {
+    immediate(conn, || {
+        conn.execute("INSERT OR IGNORE INTO categories (name) VALUES (?1)", params![name])?;
+        Ok(conn.query_row("SELECT id FROM categories WHERE name = ?1", params![name], |row| row.get(0))?)
+    })
+}
+
+fn get_categories(conn: &Connection) -> Result<Vec<Category>> {
+    let mut stmt = conn.prepare("SELECT id, name FROM categories ORDER BY name")?;
+    let res = stmt
+        .query_map([], |row| {
+            Ok(Category {
+                id: row.get(0)?,
+                name: row.get(1)?,
+            })
+        })?
+        .collect::<rusqlite::Result<Vec<_>>>()?;
+    Ok(res)
+}
+
+/// Answers to the first `questions` questions of the event.
+fn get_answers(conn: &Connection, event_id: u64, user: u64, questions: usize) -> Result<Vec<String>> {
+    let mut res = vec![String::new(); questions];
+    let mut stmt = conn.prepare("SELECT question, answer FROM answers WHERE event = ?1 AND user = ?2")?;
+    let mut rows = stmt.query(params![event_id, user])?;
+    while let Some(row) = rows.next()? {
+        let question: usize = row.get(0)?;
+        if let Some(a) = res.get_mut(question) {
+            *a = row.get(1)?;
+        }
+    }
+    Ok(res)
+}
+
+fn save_answer(conn: &Connection, event_id: u64, user: u64, question: u64, answer: &str) -> Result<()> {
+    let s = find_event(conn, event_id, user)?;
+    if s.my_reservation() == 0 && s.my_waiting() == 0 {
+        return Err(Error::ReservationNotFound);
+    }
+    if question < s.event.questions.len() as u64 {
+        conn.execute(
+            "INSERT INTO answers (event, user, question, answer) VALUES (?1, ?2, ?3, ?4) ON CONFLICT (event, user, question) DO \
+            UPDATE SET answer = excluded.answer",
+            params![event_id, user, question, answer],
+        )?;
+    }
+    Ok(())
+}
+
+fn audit(
+    conn: &Connection,
+    ts: u64,
+    actor: u64,
+    action: AuditAction,
+    event_id: u64,
+    user_id: u64,
+    details: &str,
+) -> Result<()> {
+    conn.execute(
+        "INSERT INTO audit_log (ts, actor, action, event, user, details) VALUES (?1, ?2, ?3, ?4, ?5, ?6)",
+        params![ts, actor, action as u64, event_id, user_id, details],
+    )?;
+    Ok(())
+}
+
+fn get_audit_log(
+    conn: &Connection,
+    filter: AuditFilter,
+    offset: u64,
+    limit: u64,
+) -> Result<Vec<AuditRecord>> {
+    let mut args = vec![limit, offset * limit];
+    let condition = match filter {
+        AuditFilter::All => "",
+        AuditFilter::Event(event_id) => {
+            args.push(event_id);
+            "WHERE event = ?3"
+        }
+        AuditFilter::User(user_id) => {
+            args.push(user_id);
+            "WHERE actor = ?3 OR user = ?3"
+        }
+    };
+    let mut stmt = conn.prepare(&format!(
+        "SELECT ts, actor, action, event, user, details FROM audit_log {} \
+        ORDER BY ts DESC, id DESC LIMIT ?1 OFFSET ?2",
+        condition
+    ))?;
+    let mut rows = stmt.query(rusqlite::params_from_iter(args))?;
+    let mut res = Vec::new();
+    while let Some(row) = rows.next()? {
+        let action: u64 = row.get("action")?;
+        res.push(AuditRecord {
+            ts: row.get("ts")?,
+            actor: row.get("actor")?,
+            action: num::FromPrimitive::from_u64(action).unwrap(),
+            event_id: row.get("event")?,
+            user_id: row.get("user")?,
+            details: row.get("details")?,
+        });
+    }
+    Ok(res)
+}
+
+fn get_group_messages(
+    conn: &Connection,
+    event_id: u64,
+    waiting_list: Option<u64>,
+) -> Result<Vec<GroupMessage>> {
+    let mut stmt;
+    let mut rows = if let Some(waiting_list) = waiting_list {
+        stmt = conn.prepare(
+            "SELECT sender, text, ts, waiting_list FROM messages WHERE event = ?1 AND type = 0 AND waiting_list = ?2 ORDER BY ts DESC LIMIT 3"
+        )?;
+        stmt.query(params![event_id, waiting_list])?
+    } else {
+        stmt = conn.prepare(
+            "SELECT sender, text, ts, waiting_list FROM messages WHERE event = ?1 AND type = 0 ORDER BY ts DESC LIMIT 3",
+        )?;
+        stmt.query(params![event_id])?
+    };
+    let mut messages = Vec::new();
+    while let Some(row) = rows.next()? {
+        let msg = GroupMessage {
+            sender: row.get("sender")?,
+            text: row.get("text")?,
+            ts: row.get("ts")?,
+            waiting_list: row.get("waiting_list")?,
+        };
+        // todo: remove after message format migration
+        if msg.sender.is_empty() {
+            continue;
+        }
+        messages.push(msg);
+    }
+    messages.reverse();
+    Ok(messages)
+}
+
+impl Storage for SqliteStorage {
+    fn add_event(&self, e: Event) -> Result<u64> {
+        let conn = self.pool.get()?;
+        add_event(&conn, e)
+    }
+
+    fn add_events(&self, events: &[Event]) -> Result<Vec<u64>> {
+        let conn = self.pool.get()?;
+        immediate(&conn, || events.iter().map(|e| save_event(&conn, e, EventState::Draft)).collect())
+    }
+
+    fn get_events(
+        &self,
+        user: u64,
+        is_admin: bool,
+        filter: &EventFilter,
+        offset: u64,
+        limit: u64,
+    ) -> Result<Vec<EventStats>> {
+        let conn = self.pool.get()?;
+        get_events(&conn, user, is_admin, filter, offset, limit)
+    }
+
+    fn get_event(&self, event_id: u64, user: u64) -> Result<EventStats> {
+        let conn = self.pool.get()?;
+        get_event(&conn, event_id, user)
+    }
+
+    fn find_event(&self, event_id: u64, user: u64) -> Result<EventStats> {
+        let conn = self.pool.get()?;
+        find_event(&conn, event_id, user)
+    }
+
+    fn get_current_event(&self, user: u64) -> Result<u64> {
+        let conn = self.pool.get()?;
+        get_current_event(&conn, user)
+    }
+
+    fn subscribe_to_registration(&self, event_id: u64, user: u64, subscribe: bool) -> Result<()> {
+        let conn = self.pool.get()?;
+        subscribe_to_registration(&conn, event_id, user, subscribe)
+    }
+
+    fn is_subscribed_to_registration(&self, event_id: u64, user: u64) -> Result<bool> {
+        let conn = self.pool.get()?;
+        let mut stmt = conn.prepare("SELECT 1 FROM registration_subscriptions WHERE event = ?1 AND user = ?2")?;
+        Ok(stmt.exists(params![event_id, user])?)
+    }
+
+    fn change_event_state(&self, event_id: u64, state: EventState, actor: u64) -> Result<()> {
+        let conn = self.pool.get()?;
+        change_event_state(&conn, event_id, state, actor)
+    }
+
+    fn set_event_limits(&self, event_id: u64, capacities: &[u64], actor: u64) -> Result<()> {
+        let conn = self.pool.get()?;
+        set_event_limits(&conn, event_id, capacities, actor)
+    }
+
+    fn set_event_poster(&self, event_id: u64, file_id: &str) -> Result<()> {
+        let conn = self.pool.get()?;
+        if conn.execute("UPDATE events SET poster = ?1 WHERE id = ?2", params![file_id, event_id])? == 0 {
+            return Err(Error::EventNotFound(event_id));
+        }
+        Ok(())
+    }
+
+    fn delete_event(
+        &self,
+        event_id: u64,
+        automatic_blacklisting: bool,
+        cancel_future_reservations_on_ban: bool,
+        admins: &HashSet<u64>,
+        actor: u64,
+    ) -> Result<()> {
+        let conn = self.pool.get()?;
+        immediate(&conn, || {
+            let s = find_event(&conn, event_id, 0)?;
+            delete_event(&conn,
+                event_id,
+                automatic_blacklisting,
+                cancel_future_reservations_on_ban,
+                admins,
+            )?;
+            audit(&conn, get_unix_time(), actor, AuditAction::DeleteEvent, event_id, 0,
+                &event_name(&s.event))
+        })
+    }
+
+    fn delete_link(&self, link: &str) -> Result<()> {
+        let conn = self.pool.get()?;
+        delete_link(&conn, link)
+    }
+
+    fn archive_old_events(
+        &self,
+        ts: u64,
+        automatic_blacklisting: bool,
+        cancel_future_reservations: bool,
+        admins: &HashSet<u64>,
+    ) -> Result<()> {
+        let conn = self.pool.get()?;
+        archive_old_events(&conn,
+            ts,
+            automatic_blacklisting,
+            cancel_future_reservations,
+            admins,
+        )
+    }
+
+    fn get_archived_events(&self, offset: u64, limit: u64) -> Result<Vec<EventStats>> {
+        let conn = self.pool.get()?;
+        get_archived_events(&conn, offset, limit)
+    }
+
+    fn purge_archive(&self, ts: u64) -> Result<()> {
+        let conn = self.pool.get()?;
+        purge_archive(&conn, ts)
+    }
+
+    fn sign_up(
+        &self,
+        event_id: u64,
+        user: &User,
+        tickets: &[u64],
+        wait: u64,
+        ts: u64,
+        amount: u64,
+    ) -> Result<(usize, bool)> {
+        let conn = self.pool.get()?;
+        sign_up(&conn, event_id, user, tickets, wait, ts, amount)
+    }
+
+    fn checkout(&self, booking: &Booking, order_info: OrderInfo) -> Result<()> {
+        let conn = self.pool.get()?;
+        checkout(&conn, booking, order_info)
+    }
+
+    fn cancel(&self, event_id: u64, user: u64, ticket: u64) -> Result<()> {
+        let conn = self.pool.get()?;
+        cancel(&conn, event_id, user, ticket)
+    }
+
+    fn wontgo(&self, event_id: u64, user: u64) -> Result<()> {
+        let conn = self.pool.get()?;
+        wontgo(&conn, event_id, user)
+    }
+
+    fn delete_reservation(&self, event_id: u64, user_id: u64, actor: u64) -> Result<()> {
+        let conn = self.pool.get()?;
+        delete_reservation(&conn, event_id, user_id, actor)
+    }
+
+    fn get_participants(
+        &self,
+        event_id: u64,
+        waiting_list: u64,
+        offset: u64,
+        limit: u64,
+        state: ReservationState,
+    ) -> Result<Vec<Participant>> {
+        let conn = self.pool.get()?;
+        get_participants(&conn, event_id, waiting_list, offset, limit, state)
+    }
+
+    fn add_attachment(&self, event_id: u64, user: u64, attachment: &str) -> Result<usize> {
+        let conn = self.pool.get()?;
+        add_attachment(&conn, event_id, user, attachment)
+    }
+
+    fn get_attachment(&self, event_id: u64, user: u64) -> Result<Option<String>> {
+        let conn = self.pool.get()?;
+        get_attachment(&conn, event_id, user)
+    }
+
+    fn clear_failed_payments(&self, ts: u64) -> Result<()> {
+        let conn = self.pool.get()?;
+        clear_failed_payments(&conn, ts)
+    }
+
+    fn add_to_black_list(
+        &self,
+        user: u64,
+        cancel_future_reservations: bool,
+        actor: u64,
+    ) -> Result<()> {
+        let conn = self.pool.get()?;
+        add_to_black_list(&conn, user, cancel_future_reservations, actor)
+    }
+
+    fn ban_user(
+        &self,
+        user: u64,
+        user_name1: &str,
+        user_name2: &str,
+        reason: &str,
+        cancel_future_reservations: bool,
+    ) -> Result<()> {
+        let conn = self.pool.get()?;
+        immediate(&conn, || {
+            ban_user(&conn,
+                user,
+                user_name1,
+                user_name2,
+                reason,
+                cancel_future_reservations,
+            )
+        })
+    }
+
+    fn remove_from_black_list(&self, user: u64) -> Result<()> {
+        let conn = self.pool.get()?;
+        remove_from_black_list(&conn, user)
+    }
+
+    fn get_black_list(&self, offset: u64, limit: u64) -> Result<Vec<User>> {
+        let conn = self.pool.get()?;
+        get_black_list(&conn, offset, limit)
+    }
+
+    fn get_ban_reason(&self, user_id: u64) -> Result<String> {
+        let conn = self.pool.get()?;
+        get_ban_reason(&conn, user_id)
+    }
+
+    fn clear_black_list(&self, ts: u64) -> Result<()> {
+        let conn = self.pool.get()?;
+        clear_black_list(&conn, ts)
+    }
+
+    fn enqueue_message(
+        &self,
+        event_id: u64,
+        sender: &str,
+        waiting_list: u64,
+        message_type: MessageType,
+        text: &str,
+        send_at: u64,
+    ) -> Result<()> {
+        let conn = self.pool.get()?;
+        enqueue_message(&conn,
+            event_id,
+            sender,
+            waiting_list,
+            message_type,
+            text,
+            send_at,
+        )
+    }
+
+    fn get_pending_messages(&self, ts: u64, max_messages: u64) -> Result<Vec<MessageBatch>> {
+        let conn = self.pool.get()?;
+        get_pending_messages(&conn, ts, max_messages)
+    }
+
+    fn save_receipt(&self, message_id: u64, user: u64) -> Result<()> {
+        let conn = self.pool.get()?;
+        save_receipt(&conn, message_id, user)
+    }
+
+    fn get_group_messages(
+        &self,
+        event_id: u64,
+        waiting_list: Option<u64>,
+    ) -> Result<Vec<GroupMessage>> {
+        let conn = self.pool.get()?;
+        get_group_messages(&conn, event_id, waiting_list)
+    }
+
+    fn get_presence_list(&self, event_id: u64, offset: u64, limit: u64) -> Result<Vec<Presence>> {
+        let conn = self.pool.get()?;
+        get_presence_list(&conn, event_id, offset, limit)
+    }
+
+    fn get_attendance_list(&self, event_id: u64, offset: u64, limit: u64) -> Result<Vec<Presence>> {
+        let conn = self.pool.get()?;
+        get_attendance_list(&conn, event_id, offset, limit)
+    }
+
+    fn confirm_presence(&self, event_id: u64, user_id: u64) -> Result<()> {
+        let conn = self.pool.get()?;
+        confirm_presence(&conn, event_id, user_id)
+    }
+
+    fn is_group_leader(&self, event_id: u64, user_id: u64) -> Result<bool> {
+        let conn = self.pool.get()?;
+        is_group_leader(&conn, event_id, user_id)
+    }
+
+    fn set_group_leader(&self, event_id: u64, user_id: u64) -> Result<()> {
+        let conn = self.pool.get()?;
+        set_group_leader(&conn, event_id, user_id)
+    }
+
+    fn save_user(&self, user: &User, ts: u64) -> Result<()> {
+        let conn = self.pool.get()?;
+        save_user(&conn, user, ts)
+    }
+
+    fn set_user_reachable(&self, user_id: u64, reachable: bool) -> Result<()> {
+        let conn = self.pool.get()?;
+        set_user_reachable(&conn, user_id, reachable)
+    }
+
+    fn get_user(&self, user_id: u64) -> Result<Option<UserProfile>> {
+        let conn = self.pool.get()?;
+        get_user(&conn, user_id)
+    }
+
+    fn get_users(&self, offset: u64, limit: u64) -> Result<Vec<UserProfile>> {
+        let conn = self.pool.get()?;
+        get_users(&conn, offset, limit)
+    }
+
+    fn add_series(&self, s: Series, actor: u64) -> Result<u64> {
+        let conn = self.pool.get()?;
+        add_series(&conn, s, actor)
+    }
+
+    fn get_series(&self, series_id: u64) -> Result<Series> {
+        let conn = self.pool.get()?;
+        get_series(&conn, series_id)
+    }
+
+    fn get_series_list(&self) -> Result<Vec<Series>> {
+        let conn = self.pool.get()?;
+        get_series_list(&conn)
+    }
+
+    fn cancel_series(&self, series_id: u64, actor: u64) -> Result<()> {
+        let conn = self.pool.get()?;
+        cancel_series(&conn, series_id, actor)
+    }
+
+    fn materialize_series(&self, ts: u64) -> Result<Vec<u64>> {
+        let conn = self.pool.get()?;
+        materialize_series(&conn, ts)
+    }
+
+    fn add_venue(&self, v: Venue, actor: u64) -> Result<u64> {
+        let conn = self.pool.get()?;
+        add_venue(&conn, v, actor)
+    }
+
+    fn get_venue(&self, venue_id: u64) -> Result<Venue> {
+        let conn = self.pool.get()?;
+        get_venue(&conn, venue_id)
+    }
+
+    fn get_venues(&self) -> Result<Vec<Venue>> {
+        let conn = self.pool.get()?;
+        get_venues(&conn)
+    }
+
+    fn add_category(&self, name: &str) -> Result<u64> {
+        let conn = self.pool.get()?;
+        add_category(&conn, name)
+    }
+
+    fn get_categories(&self) -> Result<Vec<Category>> {
+        let conn = self.pool.get()?;
+        get_categories(&conn)
+    }
+
+    fn get_answers(&self, event_id: u64, user: u64) -> Result<Vec<String>> {
+        let conn = self.pool.get()?;
+        let s = find_event(&conn, event_id, 0)?;
+        get_answers(&conn, event_id, user, s.event.questions.len())
+    }
+
+    fn save_answer(&self, event_id: u64, user: u64, question: u64, answer: &str) -> Result<()> {
+        let conn = self.pool.get()?;
+        save_answer(&conn, event_id, user, question, answer)
+    }
+
+    fn clear_answers(&self, event_id: u64, user: u64) -> Result<()> {
+        let conn = self.pool.get()?;
+        conn.execute("DELETE FROM answers WHERE event = ?1 AND user = ?2", params![event_id, user])?;
+        Ok(())
+    }
+
+    fn get_questionnaire(&self, user: u64) -> Result<u64> {
+        let conn = self.pool.get()?;
+        let mut stmt = conn.prepare("SELECT event FROM questionnaires WHERE user = ?1")?;
+        let mut rows = stmt.query([user])?;
+        match rows.next()? {
+            Some(row) => Ok(row.get(0)?),
+            None => Ok(0),
+        }
+    }
+
+    fn set_questionnaire(&self, user: u64, event_id: u64) -> Result<()> {
+        let conn = self.pool.get()?;
+        if event_id == 0 {
+            conn.execute("DELETE FROM questionnaires WHERE user = ?1", params![user])?;
+        } else {
+            find_event(&conn, event_id, 0)?;
+            conn.execute(
+                "INSERT OR REPLACE INTO questionnaires (user, event) VALUES (?1, ?2)",
+                params![user, event_id],
+            )?;
+        }
+        Ok(())
+    }
+
+    fn get_refunds(&self, offset: u64, limit: u64) -> Result<Vec<Refund>> {
+        let conn = self.pool.get()?;
+        get_refunds(&conn, offset, limit)
+    }
+
+    fn complete_refund(&self, refund_id: u64, actor: u64) -> Result<()> {
+        let conn = self.pool.get()?;
+        complete_refund(&conn, refund_id, actor)
+    }
+
+    fn save_search(&self, user: u64, text: &str) -> Result<()> {
+        let conn = self.pool.get()?;
+        conn.execute(
+            "INSERT OR REPLACE INTO searches (user, text) VALUES (?1, ?2)",
+            params![user, text],
+        )?;
+        Ok(())
+    }
+
+    fn get_search(&self, user: u64) -> Result<String> {
+        let conn = self.pool.get()?;
+        let mut stmt = conn.prepare("SELECT text FROM searches WHERE user = ?1")?;
+        let mut rows = stmt.query([user])?;
+        match rows.next()? {
+            Some(row) => Ok(row.get(0)?),
+            None => Ok(String::new()),
+        }
+    }
+
+    fn save_import(&self, user: u64, events: &str) -> Result<()> {
+        let conn = self.pool.get()?;
+        conn.execute(
+            "INSERT OR REPLACE INTO imports (user, events) VALUES (?1, ?2)",
+            params![user, events],
+        )?;
+        Ok(())
+    }
+
+    fn take_import(&self, user: u64) -> Result<Option<String>> {
+        let mut conn = self.pool.get()?;
+        let tx = conn.transaction()?;
+        let events: Option<String> = {
+            let mut stmt = tx.prepare("SELECT events FROM imports WHERE user = ?1")?;
+            let mut rows = stmt.query([user])?;
+            match rows.next()? {
+                Some(row) => Some(row.get(0)?),
+                None => None,
+            }
+        };
+        tx.execute("DELETE FROM imports WHERE user = ?1", [user])?;
+        tx.commit()?;
+        Ok(events)
+    }
+
+    fn get_audit_log(
+        &self,
+        filter: AuditFilter,
+        offset: u64,
+        limit: u64,
+    ) -> Result<Vec<AuditRecord>> {
+        let conn = self.pool.get()?;
+        get_audit_log(&conn, filter, offset, limit)
+    }
+}
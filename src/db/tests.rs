@@ -1,248 +1,1599 @@
-#[cfg(test)]
-mod tests {
-    use crate::db::*;
-    use r2d2_sqlite::SqliteConnectionManager;
-    use teloxide::types::UserId;
-
-    #[test]
-    fn test_db() -> Result<(), rusqlite::Error> {
-        let db_file = "./test.db3";
-        let _ = std::fs::remove_file(db_file);
-        let manager = SqliteConnectionManager::file(db_file);
-        let pool = r2d2::Pool::new(manager).unwrap();
-        let conn = pool.get().unwrap();
-        create(&conn).expect("Failed to create db.");
+use crate::db::*;
+use crate::types::{Configuration, Context, TicketType, Venue};
+use crate::util;
+use std::sync::Arc;
+use teloxide::types::UserId;
 
-        let ts = 1650445814;
+/// Runs a test against every storage backend.
+/// SQLite databases are created in the temp directory and removed afterwards.
+fn for_each_storage(name: &str, test: impl Fn(Arc<dyn Storage>) -> anyhow::Result<()>) {
+    test(Arc::new(MemoryStorage::new())).expect("memory storage");
 
-        let e = Event {
-            id: 0,
-            name: "test event 1".to_string(),
-            link: "https://example.com/1".to_string(),
-            max_adults: 2,
-            max_children: 2,
-            max_adults_per_reservation: 1,
-            max_children_per_reservation: 3,
-            ts: ts,
-            remind: ts - 10,
-            adult_ticket_price: 0,
-            child_ticket_price: 0,
-        };
-        let event_id = 1;
+    let path = std::env::temp_dir().join(format!("{}-{}.db3", name, std::process::id()));
+    let path = path.to_str().unwrap();
+    let remove = || {
+        for suffix in ["", "-wal", "-shm"] {
+            let _ = std::fs::remove_file(format!("{}{}", path, suffix));
+        }
+    };
+    remove();
+    let res = SqliteStorage::open(path).and_then(|storage| test(Arc::new(storage)));
+    remove();
+    res.expect("sqlite storage");
+
+    // PostgreSQL runs only when a server is available, e.g.
+    // TEST_DATABASE_URL=postgres://postgres@localhost/postgres cargo test
+    if let Ok(url) = std::env::var("TEST_DATABASE_URL") {
+        // Every test gets an own schema, so tests can run in parallel.
+        let schema = format!("{}_{}", name, std::process::id());
+        let mut client = ::postgres::Client::connect(&url, ::postgres::NoTls).unwrap();
+        client
+            .batch_execute(&format!("DROP SCHEMA IF EXISTS {0} CASCADE; CREATE SCHEMA {0};", schema))
+            .unwrap();
+        let separator = if url.contains('?') { '&' } else { '?' };
+        let res = PostgresStorage::open(&format!(
+            "{}{}options=-csearch_path%3D{}",
+            url, separator, schema
+        ))
+        .and_then(|storage| test(Arc::new(storage)));
+        client
+            .batch_execute(&format!("DROP SCHEMA {} CASCADE", schema))
+            .unwrap();
+        res.expect("postgres storage");
+    }
+}
+
+/// Bot context with the memory storage, for handler tests.
+pub(crate) fn test_context(admins: &[u64]) -> Context {
+    Context {
+        config: Configuration {
+            event_list_page_size: 10,
+            event_page_size: 10,
+            presence_page_size: 10,
+            ..Default::default()
+        },
+        storage: Box::new(MemoryStorage::new()),
+        event_locks: Default::default(),
+        admins: admins.iter().copied().collect(),
+    }
+}
+
+pub(crate) fn user(id: u64) -> User {
+    User {
+        id: UserId(id),
+        user_name1: format!("user_name1_{}", id),
+        user_name2: format!("user_name2_{}", id),
+        language_code: None,
+        is_admin: false,
+    }
+}
+
+/// New events are drafts, most tests need them open for registration.
+pub(crate) fn add_published_event(db: &dyn Storage, e: Event) -> Result<u64> {
+    let event_id = db.add_event(e)?;
+    db.change_event_state(event_id, EventState::Open, 0)?;
+    Ok(event_id)
+}
+
+fn ticket_type(name: &str, capacity: u64, max_per_reservation: u64) -> TicketType {
+    TicketType {
+        name: name.to_string(),
+        capacity,
+        max_per_reservation,
+        price: 0,
+    }
+}
+
+/// Event with two ticket types: adults at index 0 and children at index 1.
+pub(crate) fn event(max_adults: u64, max_children: u64, ts: u64) -> Event {
+    Event {
+        id: 0,
+        name: "test event 1".to_string(),
+        link: "https://example.com/1".to_string(),
+        ts,
+        remind: ts - 10,
+        tickets: vec![
+            ticket_type("взрослые", max_adults, 1),
+            ticket_type("дети", max_children, 3),
+        ],
+        currency: "EUR".to_string(),
+        series_id: 0,
+        venue_id: 0,
+        duration: 0,
+        allow_overlap: false,
+        categories: vec![],
+        poster: String::new(),
+        registration_opens: 0,
+        registration_closes: 0,
+        questions: vec![],
+        time_zone: String::new(),
+        auto_promote: false,
+    }
+}
+
+#[test]
+fn test_db() {
+    for_each_storage("test_db", |db| {
+        let ts = 1650445814;
+        let e = event(2, 2, ts);
 
         // new event
-        assert_eq!(add_event(&conn, e.clone()), Ok(1));
+        let event_id = add_published_event(&*db, e.clone())?;
+        assert_eq!(event_id, 1);
 
         // user 1000 reserves for two children and places one on the waiting list
         for i in 0..3 {
             assert_eq!(
-                sign_up(
-                    &conn,
-                    event_id,
-                    &User {
-                        id: UserId(1000),
-                        user_name1: "user_name1_1000".to_string(),
-                        user_name2: "user_name2_1000".to_string(),
-                        is_admin: false
-                    },
-                    0,
-                    1,
-                    match i == 2 {
-                        true => 1,
-                        false => 0,
-                    },
-                    e.ts - 20,
-                    0,
-                )
-                .unwrap(),
+                db.sign_up(event_id, &user(1000), &[0, 1], (i == 2) as u64, e.ts - 20, 0)?,
                 (1, false)
             );
         }
 
-        let s = get_event(&conn, event_id, 1000)?;
-        assert_eq!(s.children.my_reservation, 2);
-        assert_eq!(s.children.my_waiting, 1);
+        let s = db.get_event(event_id, 1000)?;
+        assert_eq!(s.tickets[1].my_reservation, 2);
+        assert_eq!(s.tickets[1].my_waiting, 1);
 
         // user 2000 places one child on the waiting list
         assert_eq!(
-            sign_up(
-                &conn,
-                event_id,
-                &User {
-                    id: UserId(2000),
-                    user_name1: "user_name1_2000".to_string(),
-                    user_name2: "user_name1_2000".to_string(),
-                    is_admin: false
-                },
-                0,
-                1,
-                1,
-                e.ts - 20,
-                0,
-            )
-            .unwrap(),
+            db.sign_up(event_id, &user(2000), &[0, 1], 1, e.ts - 20, 0)?,
             (1, false)
         );
 
-        let s = get_event(&conn, event_id, 2000).unwrap();
-        assert_eq!(s.children.my_waiting, 1);
+        let s = db.get_event(event_id, 2000)?;
+        assert_eq!(s.tickets[1].my_waiting, 1);
+        assert_eq!(db.get_current_event(2000)?, event_id);
+        // Admin and background reads don't touch the current event.
+        assert_eq!(db.find_event(event_id, 0)?.tickets[1].my_waiting, 0);
+        assert_eq!(db.get_current_event(0)?, 0);
+
+        // no vacancies left for children, and it's too late to sign up afterwards
+        assert!(matches!(
+            db.sign_up(event_id, &user(3000), &[0, 1], 0, e.ts - 20, 0),
+            Err(Error::SoldOut)
+        ));
+        assert!(matches!(
+            db.sign_up(event_id, &user(3000), &[1, 0], 0, e.ts + 20, 0),
+            Err(Error::RegistrationClosed)
+        ));
+        assert!(matches!(db.get_event(100, 1000), Err(Error::EventNotFound(100))));
 
-        let events = get_events(&conn, 0, 0, 20).unwrap();
+        let events = db.get_events(0, false, &EventFilter::default(), 0, 20)?;
         assert_eq!(events.len(), 1);
 
         // time for cleanup
-        clear_old_events(
-            &conn,
-            ts + 20 * 60 * 60,
-            false,
-            false,
-            &HashSet::<u64>::new(),
-        )?;
-
-        let events = get_events(&conn, 0, 0, 20).unwrap();
+        db.archive_old_events(ts + 20 * 60 * 60, false, false, &HashSet::<u64>::new())?;
+
+        let events = db.get_events(0, false, &EventFilter::default(), 0, 20)?;
         assert_eq!(events.len(), 0);
+        Ok(())
+    });
+}
+
+#[test]
+fn test_archive() {
+    for_each_storage("test_archive", |db| {
+        let ts = 1650445814;
+        let event_id = add_published_event(&*db, event(3, 0, ts))?;
+        for user_id in [10, 20, 30] {
+            db.sign_up(event_id, &user(user_id), &[1, 0], 0, ts - 30, 0)?;
+        }
+        db.confirm_presence(event_id, 10)?;
 
+        // Past events leave the event list, but keep their attendance.
+        db.archive_old_events(ts + 48 * 60 * 60, true, false, &HashSet::new())?;
+        assert_eq!(db.get_events(0, false, &EventFilter::default(), 0, 20)?.len(), 0);
+        assert_eq!(db.get_pending_messages(ts + 48 * 60 * 60, 10)?.len(), 0);
+        let archive = db.get_archived_events(0, 20)?;
+        assert_eq!(archive.len(), 1);
+        assert_eq!(archive[0].event.id, event_id);
+        assert_eq!(archive[0].tickets[0].reserved, 3);
+        assert_eq!(
+            db.get_attendance_list(event_id, 0, 10)?
+                .iter()
+                .map(|p| (p.user_id, p.present))
+                .collect::<Vec<(u64, bool)>>(),
+            vec![(10, true), (20, false), (30, false)]
+        );
+        assert_eq!(
+            db.get_black_list(0, 10)?
+                .iter()
+                .map(|u| u.id.0)
+                .collect::<Vec<u64>>(),
+            vec![20, 30]
+        );
+
+        // Archived events are not processed twice.
+        db.archive_old_events(ts + 72 * 60 * 60, true, false, &HashSet::new())?;
+
+        // Retention is counted from the start of the event.
+        db.purge_archive(ts)?;
+        assert_eq!(db.get_archived_events(0, 20)?.len(), 1);
+        db.purge_archive(ts + 1)?;
+        assert_eq!(db.get_archived_events(0, 20)?.len(), 0);
+        assert!(matches!(db.get_event(event_id, 0), Err(Error::EventNotFound(_))));
         Ok(())
-    }
+    });
+}
+
+#[test]
+fn test_ban_cancels_upcoming_reservations() {
+    for_each_storage("test_ban_cancels_upcoming_reservations", |db| {
+        let now = util::get_unix_time();
+        let day = 24 * 60 * 60;
+        let missed = add_published_event(&*db, event(3, 0, now - 3 * day))?;
+        let attended = add_published_event(&*db, event(3, 0, now - 2 * day))?;
+        let mut e = event(1, 0, now + day);
+        e.auto_promote = true;
+        let upcoming = add_published_event(&*db, e)?;
+        for event_id in [missed, attended] {
+            db.sign_up(event_id, &user(10), &[1, 0], 0, now - 4 * day, 0)?;
+            db.sign_up(event_id, &user(20), &[1, 0], 0, now - 4 * day, 0)?;
+        }
+        db.confirm_presence(missed, 20)?;
+        db.confirm_presence(attended, 10)?;
+        db.confirm_presence(attended, 20)?;
+        db.sign_up(upcoming, &user(10), &[1, 0], 0, now, 0)?;
+        db.sign_up(upcoming, &user(30), &[1, 0], 1, now, 0)?;
 
-    #[test]
-    #[ignore]
-    fn test_waiting_list() -> Result<(), rusqlite::Error> {
-        let db_file = "./test1.db3";
-        let _ = std::fs::remove_file(db_file);
-        let manager = SqliteConnectionManager::file(db_file);
-        let pool = r2d2::Pool::new(manager).unwrap();
-        let conn = pool.get().unwrap();
-        create(&conn).expect("Failed to create db.");
+        // The absent participant loses upcoming places only, past events keep their attendance.
+        db.archive_old_events(now, true, true, &HashSet::new())?;
+        assert_eq!(db.get_black_list(0, 10)?.iter().map(|u| u.id.0).collect::<Vec<u64>>(), vec![10]);
+        for event_id in [missed, attended] {
+            let mut list: Vec<u64> = db.get_attendance_list(event_id, 0, 10)?.iter().map(|p| p.user_id).collect();
+            list.sort();
+            assert_eq!(list, vec![10, 20]);
+        }
+        assert_eq!(db.get_event(upcoming, 10)?.my_reservation(), 0);
+        assert_eq!(db.get_event(upcoming, 30)?.my_reservation(), 1);
+        Ok(())
+    });
+}
 
+#[test]
+fn test_users() {
+    for_each_storage("test_users", |db| {
         let ts = 1650445814;
+        assert!(db.get_user(10)?.is_none());
+
+        let mut u = user(10);
+        u.language_code = Some("ru".to_string());
+        db.save_user(&u, 100)?;
+        db.save_user(&user(20), 200)?;
+        let p = db.get_user(10)?.unwrap();
+        assert_eq!(p.user.user_name1, "user_name1_10");
+        assert_eq!(p.user.language_code.as_deref(), Some("ru"));
+        assert_eq!((p.first_seen, p.last_seen, p.reachable), (100, 100, true));
+
+        // Renames propagate to reservations and the language is kept when unknown.
+        let event_id = add_published_event(&*db, event(5, 0, ts))?;
+        db.sign_up(event_id, &user(10), &[1, 0], 0, 300, 0)?;
+        u = user(10);
+        u.user_name1 = "renamed".to_string();
+        db.save_user(&u, 400)?;
+        let p = db.get_user(10)?.unwrap();
+        assert_eq!(p.user.language_code.as_deref(), Some("ru"));
+        assert_eq!((p.first_seen, p.last_seen), (100, 400));
+        let participants = db.get_participants(event_id, 0, 0, 10, ReservationState::Free)?;
+        assert_eq!(participants[0].user_name1, "renamed");
+
+        // Unreachable until the user shows up again.
+        db.set_user_reachable(10, false)?;
+        assert!(!db.get_user(10)?.unwrap().reachable);
+        db.save_user(&u, 500)?;
+        assert!(db.get_user(10)?.unwrap().reachable);
+
+        // Banning an unknown user creates a profile known by id only.
+        db.add_to_black_list(30, false, 1)?;
+        assert_eq!(db.get_user(30)?.unwrap().user.user_name1, "30");
+        assert_eq!(db.get_black_list(0, 10)?[0].id.0, 30);
+
+        // Most recently seen first.
+        assert_eq!(
+            db.get_users(0, 2)?
+                .iter()
+                .map(|p| p.user.id.0)
+                .collect::<Vec<u64>>(),
+            vec![30, 10]
+        );
+        assert_eq!(db.get_users(1, 2)?[0].user.id.0, 20);
+        Ok(())
+    });
+}
+
+#[test]
+fn test_edit_event() {
+    for_each_storage("test_edit_event", |db| {
+        let now = util::get_unix_time();
+        let ts = now + 24 * 60 * 60;
+        let event_id = add_published_event(&*db, event(1, 0, ts))?;
+        db.sign_up(event_id, &user(10), &[1, 0], 0, now, 0)?;
+        db.sign_up(event_id, &user(20), &[1, 0], 1, now, 0)?;
+
+        // Posters are kept on edits.
+        db.set_event_poster(event_id, "poster-file-id")?;
+        assert!(matches!(db.set_event_poster(event_id + 1, "poster-file-id"), Err(Error::EventNotFound(_))));
+
+        // Prices and currency are updated, participants are not bothered.
+        let mut e = event(1, 0, ts);
+        e.id = event_id;
+        e.tickets[0].price = 1000;
+        e.currency = "USD".to_string();
+        e.time_zone = "Europe/Vienna".to_string();
+        assert_eq!(db.add_event(e.clone())?, event_id);
+        let s = db.get_event(event_id, 0)?;
+        assert_eq!(s.event.tickets[0].price, 1000);
+        assert_eq!(s.event.currency, "USD");
+        assert_eq!(s.event.time_zone, "Europe/Vienna");
+        assert_eq!(s.event.poster, "poster-file-id");
+        // Published once, edited once.
+        assert_eq!(s.revision, 2);
+        assert_eq!(db.get_pending_messages(now + 60, 10)?.len(), 0);
+
+        // A new start time is announced to everybody.
+        e.ts = ts + 60 * 60;
+        e.remind = e.ts - 10;
+        db.add_event(e.clone())?;
+        let mut batches: Vec<(u64, Vec<u64>)> = db
+            .get_pending_messages(now + 60, 10)?
+            .into_iter()
+            .filter(|b| b.message_type == MessageType::EventChanged)
+            .map(|b| (b.waiting_list, b.recipients))
+            .collect();
+        batches.sort();
+        assert_eq!(batches, vec![(0, vec![10]), (1, vec![20])]);
+
+        e.id = event_id + 1;
+        assert!(matches!(db.add_event(e), Err(Error::EventNotFound(_))));
+        Ok(())
+    });
+}
+
+#[test]
+fn test_add_events() {
+    for_each_storage("test_add_events", |db| {
+        let ts = util::get_unix_time() + 24 * 60 * 60;
+        let mut announcement = event(0, 0, ts);
+        announcement.link = "not a link".to_string();
+        assert!(matches!(
+            db.add_events(&[event(5, 0, ts), announcement.clone()]),
+            Err(Error::InvalidLink(_))
+        ));
+        assert_eq!(db.get_events(0, true, &EventFilter::default(), 0, 20)?.len(), 0);
+
+        announcement.link = "https://example.com/2".to_string();
+        let ids = db.add_events(&[event(5, 0, ts), announcement])?;
+        assert_eq!(ids.len(), 2);
+        assert_eq!(db.get_event(ids[1], 0)?.event.link, "https://example.com/2");
+        Ok(())
+    });
+}
+
+#[test]
+fn test_edit_finished_event() {
+    for_each_storage("test_edit_finished_event", |db| {
+        let now = util::get_unix_time();
+        let day = 24 * 60 * 60;
+        let cancelled = add_published_event(&*db, event(1, 0, now + day))?;
+        db.sign_up(cancelled, &user(10), &[1, 0], 0, now, 0)?;
+        db.change_event_state(cancelled, EventState::Cancelled, 1)?;
+        let completed = add_published_event(&*db, event(1, 0, now + day))?;
+        db.sign_up(completed, &user(10), &[1, 0], 0, now, 0)?;
+        db.change_event_state(completed, EventState::Completed, 1)?;
+        let archived = add_published_event(&*db, event(1, 0, now - 2 * day))?;
+        db.sign_up(archived, &user(10), &[1, 0], 0, now - 3 * day, 0)?;
+        db.archive_old_events(now, false, false, &HashSet::new())?;
+
+        // Finished events are left as they are, nobody is reminded or told about changes.
+        let pending = db.get_pending_messages(now + 2 * day, 10)?.len();
+        for event_id in [cancelled, completed, archived] {
+            let mut e = db.get_event(event_id, 0)?.event;
+            e.name = "Новое название".to_string();
+            e.ts += 60 * 60;
+            assert!(matches!(db.add_event(e), Err(Error::EventNotEditable(id)) if id == event_id));
+            assert_ne!(db.get_event(event_id, 0)?.event.name, "Новое название");
+        }
+        assert_eq!(db.get_pending_messages(now + 2 * day, 10)?.len(), pending);
+        Ok(())
+    });
+}
+
+#[test]
+fn test_clone_event() {
+    for_each_storage("test_clone_event", |db| {
+        let ts = util::get_unix_time() + 24 * 60 * 60;
+        let mut e = event(3, 2, ts);
+        e.tickets[0].price = 1000;
+        e.duration = 3600;
+        e.questions = vec!["Телефон".to_string()];
+        e.registration_opens = ts - 3 * 60 * 60;
+        e.registration_closes = ts - 60 * 60;
+        let event_id = add_published_event(&*db, e)?;
+        db.set_event_poster(event_id, "poster-file-id")?;
+        db.sign_up(event_id, &user(10), &[1, 0], 0, ts - 2 * 60 * 60, 1000)?;
+
+        // The copy is a draft without reservations, times keep their distance to the start.
+        let week = 7 * 24 * 60 * 60;
+        let original = db.get_event(event_id, 0)?.event;
+        let clone_id = db.add_event(clone_event(&original, ts + week))?;
+        assert_ne!(clone_id, event_id);
+        let s = db.get_event(clone_id, 10)?;
+        assert_eq!(s.state, EventState::Draft);
+        assert_eq!(s.reserved(), 0);
+        assert_eq!(s.my_reservation(), 0);
+        let c = s.event;
+        assert_eq!((c.ts, c.remind), (ts + week, ts + week - 10));
+        assert_eq!(
+            (c.registration_opens, c.registration_closes),
+            (ts + week - 3 * 60 * 60, ts + week - 60 * 60)
+        );
+        assert_eq!((c.name, c.link, c.currency), (original.name, original.link, original.currency));
+        assert_eq!(
+            c.tickets.iter().map(|t| (t.capacity, t.price)).collect::<Vec<(u64, u64)>>(),
+            vec![(3, 1000), (2, 0)]
+        );
+        assert_eq!((c.duration, c.questions, c.poster), (3600, original.questions, "poster-file-id".to_string()));
+
+        // A week later in local time, even if summer time starts in between.
+        use chrono::TimeZone;
+        let vienna = chrono_tz::Europe::Vienna;
+        let local = |month: u32, day: u32, hour: u32| {
+            vienna.with_ymd_and_hms(2030, month, day, hour, 0, 0).unwrap().timestamp() as u64
+        };
+        let mut e = event(3, 0, local(3, 29, 10));
+        e.time_zone = "Europe/Vienna".to_string();
+        e.remind = local(3, 28, 18);
+        e.registration_opens = local(3, 25, 9);
+        assert_eq!(add_days(&e, e.ts, 7), local(4, 5, 10));
+        let c = clone_event(&e, local(4, 5, 10));
+        assert_eq!((c.remind, c.registration_opens, c.registration_closes), (local(4, 4, 18), local(4, 1, 9), 0));
+        Ok(())
+    });
+}
+
+#[test]
+fn test_event_states() {
+    for_each_storage("test_event_states", |db| {
+        let now = util::get_unix_time();
+        let mut admin = user(1);
+        admin.is_admin = true;
+
+        // Drafts are seen and booked by admins only.
+        let event_id = db.add_event(event(5, 0, now + 24 * 60 * 60))?;
+        assert_eq!(db.get_event(event_id, 0)?.state, EventState::Draft);
+        assert_eq!(db.get_event(event_id, 0)?.revision, 0);
+        assert_eq!(db.get_events(0, false, &EventFilter::default(), 0, 20)?.len(), 0);
+        assert_eq!(db.get_events(0, true, &EventFilter::default(), 0, 20)?.len(), 1);
+        assert!(matches!(
+            db.sign_up(event_id, &user(10), &[1, 0], 0, now, 0),
+            Err(Error::RegistrationClosed)
+        ));
+        db.sign_up(event_id, &admin, &[1, 0], 0, now, 0)?;
+
+        db.change_event_state(event_id, EventState::Open, 1)?;
+        assert_eq!(db.get_events(0, false, &EventFilter::default(), 0, 20)?.len(), 1);
+        db.sign_up(event_id, &user(10), &[1, 0], 0, now, 0)?;
+        // Every state change makes a new revision of the event for calendar apps.
+        assert_eq!(db.get_event(event_id, 0)?.revision, 1);
+
+        // Cancelling tells reservation holders and drops the reminder.
+        db.change_event_state(event_id, EventState::Cancelled, 1)?;
+        let batches = db.get_pending_messages(now + 24 * 60 * 60, 10)?;
+        assert_eq!(batches.len(), 1);
+        assert!(batches[0].message_type == MessageType::EventCancelled);
+        assert_eq!(batches[0].recipients, vec![1, 10]);
+        assert_eq!(db.get_events(0, false, &EventFilter::default(), 0, 20)?[0].state, EventState::Cancelled);
+        assert_eq!(db.get_event(event_id, 0)?.revision, 2);
+        assert!(matches!(
+            db.sign_up(event_id, &admin, &[1, 0], 0, now, 0),
+            Err(Error::RegistrationClosed)
+        ));
+
+        // Cancelled and completed events stay so, published ones don't go back to drafts.
+        for state in [EventState::Open, EventState::Completed, EventState::Cancelled] {
+            assert!(matches!(
+                db.change_event_state(event_id, state, 1),
+                Err(Error::InvalidStateChange(EventState::Cancelled, _))
+            ));
+        }
+        let event_id = add_published_event(&*db, event(5, 0, now + 24 * 60 * 60))?;
+        assert!(matches!(
+            db.change_event_state(event_id, EventState::Draft, 1),
+            Err(Error::InvalidStateChange(EventState::Open, EventState::Draft))
+        ));
+
+        // Completed events leave the list.
+        db.change_event_state(event_id, EventState::Completed, 1)?;
+        assert_eq!(db.get_events(0, false, &EventFilter::default(), 0, 20)?.len(), 1);
+        assert_eq!(db.get_events(0, true, &EventFilter::default(), 0, 20)?.len(), 2);
+        assert!(matches!(
+            db.change_event_state(event_id, EventState::Open, 1),
+            Err(Error::InvalidStateChange(EventState::Completed, EventState::Open))
+        ));
+        Ok(())
+    });
+}
+
+#[test]
+fn test_series() {
+    for_each_storage("test_series", |db| {
+        let now = util::get_unix_time();
+        let day = 24 * 60 * 60;
+        let series = |id: u64, template: Event| Series {
+            id,
+            template,
+            period: 7,
+            until: now + 30 * day,
+            next_ts: 0,
+            cancelled: false,
+        };
+        let series_id = db.add_series(series(0, event(5, 0, now + day)), 1)?;
+
+        // Weekly occurrences within three weeks are open right away, only once.
+        let created = db.materialize_series(now + 21 * day)?;
+        assert_eq!(created.len(), 3);
+        assert_eq!(db.materialize_series(now + 21 * day)?.len(), 0);
+        let events = db.get_events(0, false, &EventFilter::default(), 0, 20)?;
+        assert_eq!(events.len(), 3);
+        assert_eq!(events[1].event.id, created[1]);
+        assert_eq!(events[1].event.ts, now + 8 * day);
+        assert_eq!(events[1].event.series_id, series_id);
+        assert_eq!(events[1].state, EventState::Open);
+
+        // A single occurrence is cancelled, another one is booked.
+        db.change_event_state(created[0], EventState::Cancelled, 1)?;
+        db.sign_up(created[1], &user(10), &[1, 0], 0, now, 0)?;
+
+        // Changes of the series move upcoming occurrences and notify participants.
+        let mut template = event(5, 0, now + day + 3600);
+        template.name = "renamed".to_string();
+        db.add_series(series(series_id, template), 1)?;
+        let s = db.get_event(created[0], 0)?;
+        assert_eq!(s.event.name, "test event 1");
+        assert_eq!(s.state, EventState::Cancelled);
+        let s = db.get_event(created[1], 0)?;
+        assert_eq!(s.event.name, "renamed");
+        assert_eq!(s.event.ts, now + 8 * day + 3600);
+        assert_eq!(db.get_series(series_id)?.next_ts, now + 22 * day + 3600);
+        let changed: Vec<MessageBatch> = db
+            .get_pending_messages(now + 60, 10)?
+            .into_iter()
+            .filter(|b| b.message_type == MessageType::EventChanged)
+            .collect();
+        assert_eq!(changed.len(), 1);
+        assert_eq!(changed[0].event_id, created[1]);
+        assert_eq!(changed[0].recipients, vec![10]);
+
+        // No occurrences after the end of the series.
+        assert_eq!(db.materialize_series(now + 60 * day)?.len(), 2);
+        assert_eq!(db.get_events(0, false, &EventFilter::default(), 0, 20)?.len(), 5);
+
+        // Cancelling the series cancels every upcoming occurrence.
+        db.cancel_series(series_id, 1)?;
+        assert_eq!(db.get_series_list()?.len(), 0);
+        assert!(db.get_series(series_id)?.cancelled);
+        let events = db.get_events(0, false, &EventFilter::default(), 0, 20)?;
+        assert!(events.iter().all(|s| s.state == EventState::Cancelled));
+        assert_eq!(db.get_audit_log(AuditFilter::User(1), 0, 1)?[0].action, AuditAction::CancelSeries);
+        assert!(matches!(db.get_series(series_id + 1), Err(Error::SeriesNotFound(_))));
+        Ok(())
+    });
+}
+
+#[test]
+fn test_series_daylight_saving() {
+    for_each_storage("test_series_daylight_saving", |db| {
+        use chrono::TimeZone;
+        let vienna = chrono_tz::Europe::Vienna;
+        let local = |month: u32, day: u32, hour: u32| {
+            vienna.with_ymd_and_hms(2030, month, day, hour, 0, 0).unwrap().timestamp() as u64
+        };
+        let mut template = event(5, 0, local(3, 24, 10));
+        template.time_zone = "Europe/Vienna".to_string();
+        template.remind = local(3, 23, 18);
+        let series = |id: u64, template: Event| Series {
+            id,
+            template,
+            period: 7,
+            until: local(4, 30, 0),
+            next_ts: 0,
+            cancelled: false,
+        };
+        let series_id = db.add_series(series(0, template.clone()), 1)?;
+
+        // Summer time starts on March 31, the occurrence a week later still starts at 10:00.
+        let created = db.materialize_series(local(3, 31, 12))?;
+        assert_eq!(created.len(), 2);
+        let e = db.get_event(created[1], 0)?.event;
+        assert_eq!((e.ts, e.remind), (local(3, 31, 10), local(3, 30, 18)));
+        assert_eq!(e.ts - db.get_event(created[0], 0)?.event.ts, 7 * 24 * 60 * 60 - 60 * 60);
+        assert_eq!(db.get_series(series_id)?.next_ts, local(4, 7, 10));
+
+        // Moving the series by an hour keeps the local times as well.
+        template.ts = local(3, 24, 11);
+        db.add_series(series(series_id, template), 1)?;
+        let e = db.get_event(created[1], 0)?.event;
+        assert_eq!((e.ts, e.remind), (local(3, 31, 11), local(3, 30, 18)));
+        assert_eq!(db.get_series(series_id)?.next_ts, local(4, 7, 11));
+        Ok(())
+    });
+}
+
+#[test]
+fn test_venues() {
+    for_each_storage("test_venues", |db| {
+        let now = util::get_unix_time();
+        let mut venue = Venue {
+            id: 0,
+            name: "Библиотека".to_string(),
+            address: "Skodagasse 20, Wien".to_string(),
+            latitude: 48.2098,
+            longitude: 16.348,
+        };
+        let library = db.add_venue(venue.clone(), 1)?;
+        venue.name = "Кафе".to_string();
+        let cafe = db.add_venue(venue.clone(), 1)?;
+        assert_ne!(library, cafe);
+
+        // Edits keep the id, venues are listed by name.
+        venue.id = library;
+        venue.name = "Библиотека, 2 этаж".to_string();
+        assert_eq!(db.add_venue(venue.clone(), 1)?, library);
+        assert_eq!(db.get_venue(library)?, venue);
+        let names: Vec<String> = db.get_venues()?.into_iter().map(|v| v.name).collect();
+        assert_eq!(names, vec!["Библиотека, 2 этаж", "Кафе"]);
+        assert_eq!(db.get_audit_log(AuditFilter::User(1), 0, 1)?[0].action, AuditAction::EditVenue);
+        venue.id = cafe + 1;
+        assert!(matches!(db.add_venue(venue, 1), Err(Error::VenueNotFound(_))));
+        assert!(matches!(db.get_venue(cafe + 1), Err(Error::VenueNotFound(_))));
+
+        // Events refer to venues, moving an event is announced to participants.
+        let mut e = event(5, 0, now + 24 * 60 * 60);
+        e.venue_id = library;
+        let event_id = add_published_event(&*db, e.clone())?;
+        assert_eq!(db.get_event(event_id, 0)?.event.venue_id, library);
+        db.sign_up(event_id, &user(10), &[1, 0], 0, now, 0)?;
+        e.id = event_id;
+        e.venue_id = cafe;
+        db.add_event(e)?;
+        assert_eq!(db.get_event(event_id, 0)?.event.venue_id, cafe);
+        let changed: Vec<MessageBatch> = db
+            .get_pending_messages(now + 60, 10)?
+            .into_iter()
+            .filter(|b| b.message_type == MessageType::EventChanged)
+            .collect();
+        assert_eq!(changed.len(), 1);
+        assert_eq!(changed[0].recipients, vec![10]);
+        Ok(())
+    });
+}
+
+#[test]
+fn test_time_conflicts() {
+    for_each_storage("test_time_conflicts", |db| {
+        let now = util::get_unix_time();
+        let ts = now + 24 * 60 * 60;
+        let hour = 60 * 60;
+        let mut e = event(5, 0, ts);
+        e.duration = 2 * hour;
+        let booked = add_published_event(&*db, e.clone())?;
+        db.sign_up(booked, &user(10), &[1, 0], 0, now, 0)?;
+
+        // Events starting during the booked one conflict, those starting at its end don't.
+        e.ts = ts + hour;
+        let overlapping = add_published_event(&*db, e.clone())?;
+        assert!(matches!(
+            db.sign_up(overlapping, &user(10), &[1, 0], 0, now, 0),
+            Err(Error::TimeConflict)
+        ));
+        db.sign_up(overlapping, &user(20), &[1, 0], 0, now, 0)?;
+        e.ts = ts + 2 * hour;
+        let next = add_published_event(&*db, e.clone())?;
+        db.sign_up(next, &user(10), &[1, 0], 0, now, 0)?;
+
+        // Without a known end only the same start is a conflict.
+        e.ts = ts - hour;
+        e.duration = 0;
+        let before = add_published_event(&*db, e.clone())?;
+        db.sign_up(before, &user(10), &[1, 0], 0, now, 0)?;
+        e.ts = ts;
+        let same_start = add_published_event(&*db, e.clone())?;
+        assert!(matches!(
+            db.sign_up(same_start, &user(10), &[1, 0], 0, now, 0),
+            Err(Error::TimeConflict)
+        ));
+
+        // Fairs allow overlaps in both directions.
+        e.ts = ts - 12 * hour;
+        e.duration = 24 * hour;
+        e.allow_overlap = true;
+        let fair = add_published_event(&*db, e.clone())?;
+        db.sign_up(fair, &user(10), &[1, 0], 0, now, 0)?;
+        e.ts = ts + hour;
+        e.duration = hour;
+        e.allow_overlap = false;
+        let during_fair = add_published_event(&*db, e.clone())?;
+        db.sign_up(fair, &user(30), &[1, 0], 0, now, 0)?;
+        db.sign_up(during_fair, &user(30), &[1, 0], 0, now, 0)?;
+        assert_eq!(db.get_event(fair, 0)?.event.duration, 24 * hour);
+        assert!(db.get_event(fair, 0)?.event.allow_overlap);
+
+        // Reservations of cancelled events and places on the waiting list don't block.
+        e.ts = ts + 10 * hour;
+        e.allow_overlap = false;
+        let cancelled = add_published_event(&*db, e.clone())?;
+        db.sign_up(cancelled, &user(10), &[1, 0], 0, now, 0)?;
+        db.change_event_state(cancelled, EventState::Cancelled, 0)?;
+        let replacement = add_published_event(&*db, e.clone())?;
+        db.sign_up(replacement, &user(10), &[1, 0], 0, now, 0)?;
+        e.ts = ts + 20 * hour;
+        let mut sold_out = e.clone();
+        sold_out.tickets[0].capacity = 1;
+        let full = add_published_event(&*db, sold_out)?;
+        db.sign_up(full, &user(20), &[1, 0], 0, now, 0)?;
+        db.sign_up(full, &user(10), &[1, 0], 1, now, 0)?;
+        let alternative = add_published_event(&*db, e)?;
+        db.sign_up(alternative, &user(10), &[1, 0], 0, now, 0)?;
+        Ok(())
+    });
+}
+
+#[test]
+fn test_event_filters() {
+    for_each_storage("test_event_filters", |db| {
+        let now = util::get_unix_time();
+        let day = 24 * 60 * 60;
+        let kids = db.add_category("дети")?;
+        let online = db.add_category("онлайн")?;
+        assert_eq!(db.add_category("дети")?, kids);
+        let names: Vec<String> = db.get_categories()?.into_iter().map(|c| c.name).collect();
+        assert_eq!(names, vec!["дети", "онлайн"]);
+
+        let mut e = event(1, 1, now + day);
+        e.categories = vec![kids];
+        let sold_out = add_published_event(&*db, e.clone())?;
+        e.categories = vec![kids, online];
+        e.ts = now + 10 * day;
+        let later = add_published_event(&*db, e.clone())?;
+        e.categories = vec![];
+        e.ts = now + 2 * day;
+        let closed = add_published_event(&*db, e)?;
+        db.change_event_state(closed, EventState::Closed, 0)?;
+        db.sign_up(sold_out, &user(10), &[1, 1], 0, now, 0)?;
+        db.sign_up(later, &user(20), &[1, 0], 1, now, 0)?;
+
+        let list = |user: u64, filter: EventFilter| -> Result<Vec<u64>> {
+            Ok(db.get_events(user, false, &filter, 0, 20)?.into_iter().map(|s| s.event.id).collect())
+        };
+        assert_eq!(list(0, EventFilter::default())?, vec![sold_out, closed, later]);
+        assert_eq!(list(0, EventFilter { category: kids, ..Default::default() })?, vec![sold_out, later]);
+        assert_eq!(list(0, EventFilter { category: online, ..Default::default() })?, vec![later]);
+        // Closed events don't take bookings, the waiting list doesn't take places.
+        assert_eq!(list(0, EventFilter { vacancies: true, ..Default::default() })?, vec![later]);
+        db.cancel(sold_out, 10, 1)?;
+        assert_eq!(list(0, EventFilter { vacancies: true, ..Default::default() })?, vec![sold_out, later]);
+        assert_eq!(list(0, EventFilter { until: now + 3 * day, ..Default::default() })?, vec![sold_out, closed]);
+        assert_eq!(list(20, EventFilter { mine: true, ..Default::default() })?, vec![later]);
+        assert_eq!(
+            list(10, EventFilter { mine: true, category: online, ..Default::default() })?,
+            Vec::<u64>::new()
+        );
+        Ok(())
+    });
+}
 
-        let e = Event {
+#[test]
+fn test_search() {
+    for_each_storage("test_search", |db| {
+        let now = util::get_unix_time();
+        let day = 24 * 60 * 60;
+        let mut venue = Venue {
             id: 0,
-            name: "test event 1".to_string(),
-            link: "https://example.com/1".to_string(),
-            max_adults: 1,
-            max_children: 0,
-            max_adults_per_reservation: 1,
-            max_children_per_reservation: 3,
-            ts: ts,
-            remind: ts - 10,
-            adult_ticket_price: 0,
-            child_ticket_price: 0,
+            name: "Museum Quarter".to_string(),
+            address: "Museumsplatz 1, Wien".to_string(),
+            latitude: 48.2034,
+            longitude: 16.3585,
         };
-        let event_id = 1;
+        venue.id = db.add_venue(venue.clone(), 1)?;
+        let mut e = event(5, 0, now + day);
+        e.name = "Museum night".to_string();
+        let mut night = e.clone();
+        night.id = add_published_event(&*db, e.clone())?;
+        e.name = "Сказки для детей".to_string();
+        e.link = "https://example.com/fairy-tales".to_string();
+        e.venue_id = venue.id;
+        e.ts = now + 2 * day;
+        let tales = add_published_event(&*db, e.clone())?;
+        e.name = "Concert".to_string();
+        e.link = "https://example.com/concert".to_string();
+        e.venue_id = 0;
+        e.ts = now + 3 * day;
+        add_published_event(&*db, e)?;
+
+        let search = |text: &str| -> Result<Vec<u64>> {
+            let filter = EventFilter { text: text.to_string(), ..Default::default() };
+            Ok(db.get_events(0, false, &filter, 0, 20)?.into_iter().map(|s| s.event.id).collect())
+        };
+        // Names, links and venue names are searched by word prefixes, all words have to match.
+        assert_eq!(search("museum")?, vec![night.id, tales]);
+        assert_eq!(search("MUS")?, vec![night.id, tales]);
+        assert_eq!(search("useum")?, Vec::<u64>::new());
+        assert_eq!(search("fairy")?, vec![tales]);
+        assert_eq!(search("для детей")?, vec![tales]);
+        assert_eq!(search("night museum")?, vec![night.id]);
+        // Query syntax is not interpreted.
+        assert_eq!(search("mus\"* OR concert")?, Vec::<u64>::new());
+        assert_eq!(search("\"night\"")?, vec![night.id]);
+
+        // The index follows edits of events and venues.
+        venue.name = "Library".to_string();
+        db.add_venue(venue, 1)?;
+        assert_eq!(search("museum")?, vec![night.id]);
+        night.name = "Opera".to_string();
+        db.add_event(night.clone())?;
+        assert_eq!(search("museum")?, Vec::<u64>::new());
+        assert_eq!(search("opera library")?, Vec::<u64>::new());
+        assert_eq!(search("opera")?, vec![night.id]);
+
+        assert_eq!(db.get_search(10)?, "");
+        db.save_search(10, "opera")?;
+        db.save_search(10, "museum")?;
+        assert_eq!(db.get_search(10)?, "museum");
+        Ok(())
+    });
+}
+
+#[test]
+fn test_registration_window() {
+    for_each_storage("test_registration_window", |db| {
+        let now = util::get_unix_time();
+        let day = 24 * 60 * 60;
+        let mut admin = user(1);
+        admin.is_admin = true;
+        let mut e = event(5, 0, now + 10 * day);
+        e.registration_opens = now + day;
+        e.registration_closes = now + 2 * day;
+        let event_id = add_published_event(&*db, e.clone())?;
+
+        // Only admins book outside of the window.
+        assert!(matches!(
+            db.sign_up(event_id, &user(10), &[1, 0], 0, now, 0),
+            Err(Error::RegistrationNotOpen)
+        ));
+        db.sign_up(event_id, &admin, &[1, 0], 0, now, 0)?;
+        db.sign_up(event_id, &user(10), &[1, 0], 0, now + day, 0)?;
+        assert!(matches!(
+            db.sign_up(event_id, &user(20), &[1, 0], 0, now + 2 * day, 0),
+            Err(Error::RegistrationClosed)
+        ));
+
+        db.subscribe_to_registration(event_id, 20, true)?;
+        db.subscribe_to_registration(event_id, 20, true)?;
+        db.subscribe_to_registration(event_id, 30, true)?;
+        db.subscribe_to_registration(event_id, 30, false)?;
+        assert!(db.is_subscribed_to_registration(event_id, 20)?);
+        assert!(!db.is_subscribed_to_registration(event_id, 30)?);
+        assert!(matches!(
+            db.subscribe_to_registration(event_id + 1, 20, true),
+            Err(Error::EventNotFound(_))
+        ));
+
+        // Subscribers are told when registration opens, not before.
+        let registration = |ts: u64| -> Result<Vec<(u64, Vec<u64>)>> {
+            Ok(db
+                .get_pending_messages(ts, 10)?
+                .into_iter()
+                .filter(|b| b.message_type == MessageType::RegistrationOpened && !b.recipients.is_empty())
+                .map(|b| (b.message_id, b.recipients))
+                .collect())
+        };
+        assert_eq!(registration(now + 60)?.len(), 0);
+        assert_eq!(registration(now + day + 1)?.len(), 1);
+
+        // Opening early tells them right away, and only once.
+        e.id = event_id;
+        e.registration_opens = now - 60;
+        db.add_event(e.clone())?;
+        let batches = registration(now + 1)?;
+        assert_eq!(batches.len(), 1);
+        assert_eq!(batches[0].1, vec![20]);
+        db.save_receipt(batches[0].0, 20)?;
+        assert_eq!(registration(now + 1)?.len(), 0);
+        e.name = "renamed".to_string();
+        db.add_event(e)?;
+        assert_eq!(registration(now + day + 1)?.len(), 0);
+        Ok(())
+    });
+}
+
+#[test]
+fn test_questionnaires() {
+    for_each_storage("test_questionnaires", |db| {
+        let now = util::get_unix_time();
+        let mut e = event(2, 0, now + 24 * 60 * 60);
+        e.questions = vec!["Возраст детей".to_string(), "Телефон".to_string()];
+        let event_id = add_published_event(&*db, e)?;
+        assert_eq!(db.get_event(event_id, 0)?.event.questions.len(), 2);
+
+        // Only participants answer.
+        assert!(matches!(
+            db.save_answer(event_id, 10, 0, "5"),
+            Err(Error::ReservationNotFound)
+        ));
+        db.sign_up(event_id, &user(10), &[1, 0], 0, now, 0)?;
+        db.sign_up(event_id, &user(20), &[1, 0], 0, now, 0)?;
+        db.save_answer(event_id, 10, 1, "+43 1 234")?;
+        db.save_answer(event_id, 10, 0, "5")?;
+        db.save_answer(event_id, 10, 0, "5 и 7")?;
+        db.save_answer(event_id, 10, 2, "ignored")?;
+        assert_eq!(db.get_answers(event_id, 10)?, vec!["5 и 7", "+43 1 234"]);
+        assert_eq!(db.get_answers(event_id, 20)?, vec!["", ""]);
+
+        // Answers come with participant and presence lists.
+        let participants = db.get_participants(event_id, 0, 0, 0, ReservationState::Free)?;
+        let answers: Vec<(u64, Vec<String>)> = participants.into_iter().map(|p| (p.user_id, p.answers)).collect();
+        assert_eq!(
+            answers,
+            vec![
+                (10, vec!["5 и 7".to_string(), "+43 1 234".to_string()]),
+                (20, vec![String::new(), String::new()])
+            ]
+        );
+        let presence = db.get_presence_list(event_id, 0, 10)?;
+        assert_eq!(presence.iter().find(|p| p.user_id == 10).unwrap().answers[0], "5 и 7");
+        db.confirm_presence(event_id, 10)?;
+        let attendance = db.get_attendance_list(event_id, 0, 10)?;
+        assert_eq!(attendance.iter().find(|p| p.user_id == 10).unwrap().answers[1], "+43 1 234");
+
+        db.clear_answers(event_id, 10)?;
+        assert_eq!(db.get_answers(event_id, 10)?, vec!["", ""]);
+
+        assert_eq!(db.get_questionnaire(10)?, 0);
+        db.set_questionnaire(10, event_id)?;
+        assert_eq!(db.get_questionnaire(10)?, event_id);
+        assert!(matches!(db.set_questionnaire(10, event_id + 1), Err(Error::EventNotFound(_))));
+        db.set_questionnaire(10, 0)?;
+        assert_eq!(db.get_questionnaire(10)?, 0);
+        Ok(())
+    });
+}
+
+#[test]
+fn test_imports() {
+    for_each_storage("test_imports", |db| {
+        assert_eq!(db.take_import(1)?, None);
+        db.save_import(1, "[1]")?;
+        db.save_import(1, "[2]")?;
+        db.save_import(2, "[3]")?;
+        // The latest import replaces the previous one and is taken once.
+        assert_eq!(db.take_import(1)?, Some("[2]".to_string()));
+        assert_eq!(db.take_import(1)?, None);
+        assert_eq!(db.take_import(2)?, Some("[3]".to_string()));
+        Ok(())
+    });
+}
+
+#[test]
+fn test_audit_log() {
+    for_each_storage("test_audit_log", |db| {
+        let now = util::get_unix_time();
+        let event_id = add_published_event(&*db, event(1, 0, now + 24 * 60 * 60))?;
+        db.sign_up(event_id, &user(10), &[1, 0], 0, now - 100, 0)?;
+        db.sign_up(event_id, &user(20), &[1, 0], 1, now - 90, 0)?;
+        db.cancel(event_id, 10, 0)?;
+        db.delete_reservation(event_id, 20, 1)?;
+        db.change_event_state(event_id, EventState::Closed, 1)?;
+        db.set_event_limits(event_id, &[5, 5], 1)?;
+        db.add_to_black_list(30, false, 1)?;
+        db.delete_event(event_id, false, false, &HashSet::new(), 1)?;
+
+        let actions = |filter| -> anyhow::Result<Vec<(u64, AuditAction, u64)>> {
+            Ok(db
+                .get_audit_log(filter, 0, 20)?
+                .iter()
+                .map(|r| (r.actor, r.action, r.user_id))
+                .collect())
+        };
+        assert_eq!(
+            actions(AuditFilter::Event(event_id))?,
+            vec![
+                (1, AuditAction::DeleteEvent, 0),
+                (1, AuditAction::SetEventLimits, 0),
+                (1, AuditAction::ChangeEventState, 0),
+                (1, AuditAction::DeleteReservation, 20),
+                (10, AuditAction::Cancel, 10),
+                (0, AuditAction::ChangeEventState, 0),
+                (20, AuditAction::SignUp, 20),
+                (10, AuditAction::SignUp, 10),
+            ]
+        );
+        assert_eq!(
+            actions(AuditFilter::User(20))?,
+            vec![
+                (1, AuditAction::DeleteReservation, 20),
+                (20, AuditAction::SignUp, 20),
+            ]
+        );
+        assert_eq!(actions(AuditFilter::User(1))?.len(), 5);
+
+        // Records outlive the event and keep its name.
+        let all = db.get_audit_log(AuditFilter::All, 0, 20)?;
+        assert_eq!(all.len(), 9);
+        assert!(all[0].details.ends_with("test event 1"));
+        assert_eq!((all[1].action, all[1].user_id), (AuditAction::Ban, 30));
+        assert_eq!(all[8].details, "взрослые 1");
+        assert_eq!(all[7].details, "взрослые 1, waiting list");
+        assert_eq!(all[2].details, "взрослые 5, дети 5");
+        assert_eq!(db.get_audit_log(AuditFilter::All, 2, 3)?.len(), 3);
+        assert_eq!(db.get_audit_log(AuditFilter::All, 3, 3)?.len(), 0);
+        Ok(())
+    });
+}
+
+#[test]
+fn test_waiting_list() {
+    for_each_storage("test_waiting_list", |db| {
+        let ts = 1650445814;
+        let mut e = event(1, 0, ts);
+        e.auto_promote = true;
 
         // new event
-        assert_eq!(add_event(&conn, e.clone()), Ok(1));
+        let event_id = add_published_event(&*db, e.clone())?;
+        assert_eq!(event_id, 1);
 
         // sign up
+        assert_eq!(db.sign_up(event_id, &user(10), &[1, 0], 0, e.ts - 30, 0)?, (1, false));
+
+        // add to waiting list
+        assert_eq!(db.sign_up(event_id, &user(20), &[1, 0], 1, e.ts - 20, 0)?, (1, false));
+        assert_eq!(db.sign_up(event_id, &user(30), &[1, 0], 1, e.ts - 10, 0)?, (1, false));
+
+        let s = db.get_event(event_id, 10)?;
+        assert_eq!(s.tickets[0].my_reservation, 1);
+
+        let s = db.get_event(event_id, 20)?;
+        assert_eq!(s.tickets[0].my_reservation, 0);
+        assert_eq!(s.tickets[0].my_waiting, 1);
+
+        let s = db.get_event(event_id, 30)?;
+        assert_eq!(s.tickets[0].my_reservation, 0);
+        assert_eq!(s.tickets[0].my_waiting, 1);
+
+        db.cancel(event_id, 10, 0)?;
+
+        let s = db.get_event(event_id, 10)?;
+        assert_eq!(s.tickets[0].my_reservation, 0);
+
+        let s = db.get_event(event_id, 20)?;
+        assert_eq!(s.tickets[0].my_reservation, 1);
+        assert_eq!(s.tickets[0].my_waiting, 0);
+
+        let s = db.get_event(event_id, 30)?;
+        assert_eq!(s.tickets[0].my_reservation, 0);
+        assert_eq!(s.tickets[0].my_waiting, 1);
+
+        db.cancel(event_id, 20, 0)?;
+
+        let s = db.get_event(event_id, 20)?;
+        assert_eq!(s.tickets[0].my_reservation, 0);
+
+        let s = db.get_event(event_id, 30)?;
+        assert_eq!(s.tickets[0].my_reservation, 1);
+        assert_eq!(s.tickets[0].my_waiting, 0);
+
+        db.cancel(event_id, 30, 0)?;
+
+        for user_id in [10, 20, 30] {
+            let s = db.get_event(event_id, user_id)?;
+            assert_eq!(s.tickets[0].my_reservation, 0);
+            assert_eq!(s.tickets[0].my_waiting, 0);
+        }
+        Ok(())
+    });
+}
+
+#[test]
+fn test_waiting_list_prompt() {
+    for_each_storage("test_waiting_list_prompt", |db| {
+        let ts = util::get_unix_time() + 24 * 60 * 60;
+        let event_id = add_published_event(&*db, event(1, 0, ts))?;
+
+        assert_eq!(db.sign_up(event_id, &user(10), &[1, 0], 0, ts - 30, 0)?, (1, false));
+        assert_eq!(db.sign_up(event_id, &user(20), &[1, 0], 1, ts - 20, 0)?, (1, false));
+        assert_eq!(db.sign_up(event_id, &user(30), &[1, 0], 1, ts - 10, 0)?, (1, false));
+
+        // The reminder goes to the participant only.
+        let batches = db.get_pending_messages(ts, 10)?;
+        assert_eq!(batches.len(), 1);
+        assert!(batches[0].message_type == MessageType::Reminder);
+        assert_eq!(batches[0].recipients, vec![10]);
+        db.save_receipt(batches[0].message_id, 10)?;
+        assert_eq!(db.get_pending_messages(ts, 10)?[0].recipients.len(), 0);
+        assert_eq!(db.get_pending_messages(ts, 10)?.len(), 0);
+
+        // A released place is offered to the waiting list, one user at a time.
+        db.wontgo(event_id, 10)?;
+        let batches = db.get_pending_messages(ts, 10)?;
+        assert_eq!(batches.len(), 1);
+        assert!(batches[0].message_type == MessageType::WaitingListPrompt);
+        assert_eq!(batches[0].recipients, vec![20]);
+        assert_eq!(batches[0].text, waiting_list_prompt_text(&event_name(&event(1, 0, ts))));
+
+        // Nobody is prompted when there are no vacancies.
+        db.sign_up(event_id, &user(20), &[1, 0], 0, ts - 5, 0)?;
+        assert_eq!(db.get_pending_messages(ts, 10)?[0].recipients.len(), 0);
+        Ok(())
+    });
+}
+
+#[test]
+fn test_auto_promote() {
+    for_each_storage("test_auto_promote", |db| {
+        let ts = util::get_unix_time() + 24 * 60 * 60;
+        let mut e = event(2, 2, ts);
+        e.auto_promote = true;
+        let event_id = add_published_event(&*db, e.clone())?;
+
+        db.sign_up(event_id, &user(10), &[1, 0], 0, ts - 100, 0)?;
+        db.sign_up(event_id, &user(11), &[1, 0], 0, ts - 99, 0)?;
+        db.sign_up(event_id, &user(12), &[0, 2], 0, ts - 98, 0)?;
+        assert_eq!(db.sign_up(event_id, &user(20), &[1, 1], 1, ts - 50, 0)?, (2, false));
+        db.sign_up(event_id, &user(30), &[1, 0], 1, ts - 40, 0)?;
+        db.sign_up(event_id, &user(40), &[0, 1], 1, ts - 30, 0)?;
+        db.sign_up(event_id, &user(50), &[1, 0], 1, ts - 20, 0)?;
+        let waiting = |user: u64| -> Result<u64> { Ok(db.get_event(event_id, user)?.my_waiting()) };
+
+        // An adult place is free, but the family needs a child place too.
+        db.cancel(event_id, 10, 0)?;
+        assert_eq!(waiting(20)?, 2);
+        assert_eq!(db.get_event(event_id, 30)?.tickets[0].my_reservation, 1);
+        assert_eq!(waiting(50)?, 1);
+
+        db.wontgo(event_id, 12)?;
+        assert_eq!(waiting(20)?, 2);
+        assert_eq!(db.get_event(event_id, 40)?.tickets[1].my_reservation, 1);
+
+        // The family moves as a whole once both places are there.
+        db.set_event_limits(event_id, &[3, 2], 0)?;
+        let s = db.get_event(event_id, 20)?;
+        assert_eq!((s.tickets[0].my_reservation, s.tickets[1].my_reservation, s.my_waiting()), (1, 1, 0));
+        assert_eq!(waiting(50)?, 1);
+
+        db.delete_reservation(event_id, 30, 0)?;
+        assert_eq!(db.get_event(event_id, 50)?.my_reservation(), 1);
+        let s = db.get_event(event_id, 0)?;
+        assert_eq!((s.vacancies(0), s.vacancies(1)), (0, 0));
+
+        let log = db.get_audit_log(AuditFilter::Event(event_id), 0, 100)?;
+        let promotions: Vec<_> = log.iter().filter(|r| r.action == AuditAction::Promote).collect();
+        assert_eq!(promotions.len(), 4);
+        assert!(promotions.iter().all(|r| r.actor == SYSTEM_ACTOR));
+
+        // Everybody still holding the place is told once.
+        let now = util::get_unix_time() + 10;
+        let batches = db.get_pending_messages(now, 10)?;
+        assert_eq!(batches.len(), 1);
+        assert!(batches[0].message_type == MessageType::Promoted);
+        assert_eq!(batches[0].text, promoted_text(&event_name(&e)));
+        let mut recipients = batches[0].recipients.clone();
+        recipients.sort();
+        assert_eq!(recipients, vec![20, 40, 50]);
+        for u in recipients {
+            db.save_receipt(batches[0].message_id, u)?;
+        }
+        assert_eq!(db.get_pending_messages(now, 10)?[0].recipients.len(), 0);
+        assert_eq!(db.get_pending_messages(now, 10)?.len(), 0);
+
+        // The next promotion reuses the message.
+        db.sign_up(event_id, &user(60), &[0, 1], 1, ts - 10, 0)?;
+        db.cancel(event_id, 40, 1)?;
+        let batches = db.get_pending_messages(now, 10)?;
+        assert_eq!(batches.len(), 1);
+        assert_eq!(batches[0].recipients, vec![60]);
+        Ok(())
+    });
+}
+
+#[test]
+fn test_black_list() {
+    for_each_storage("test_black_list", |db| {
+        let ts = util::get_unix_time() + 24 * 60 * 60;
+        let event_id = add_published_event(&*db, event(2, 0, ts))?;
+
+        db.sign_up(event_id, &user(10), &[1, 0], 0, ts - 30, 0)?;
+        db.add_to_black_list(10, true, 1)?;
+        assert_eq!(db.get_ban_reason(10)?, "banned by admin");
+        assert_eq!(db.get_ban_reason(20)?, "unknown user");
+        assert_eq!(db.get_event(event_id, 10)?.tickets[0].my_reservation, 0);
+
+        let list = db.get_black_list(0, 10)?;
+        assert_eq!(list.len(), 1);
+        assert_eq!(list[0].id, UserId(10));
+        assert_eq!(list[0].user_name1, "user_name1_10");
+
+        // Black listed users can't book free events.
+        assert_eq!(db.sign_up(event_id, &user(10), &[1, 0], 0, ts - 20, 0)?, (0, true));
+
+        db.remove_from_black_list(10)?;
+        assert_eq!(db.get_black_list(0, 10)?.len(), 0);
+        assert_eq!(db.sign_up(event_id, &user(10), &[1, 0], 0, ts - 20, 0)?, (1, false));
+        Ok(())
+    });
+}
+
+#[test]
+fn test_presence() {
+    for_each_storage("test_presence", |db| {
+        let ts = util::get_unix_time() - 60;
+        let event_id = add_published_event(&*db, event(3, 0, ts))?;
+        for user_id in [10, 20, 30] {
+            db.sign_up(event_id, &user(user_id), &[1, 0], 0, ts - 30, 0)?;
+        }
+        assert_eq!(db.add_attachment(event_id, 20, "note")?, 1);
+        assert_eq!(db.add_attachment(event_id, 40, "note")?, 0);
+
+        db.set_group_leader(event_id, 10)?;
+        assert!(db.is_group_leader(event_id, 10)?);
+        assert!(!db.is_group_leader(event_id, 20)?);
+
+        db.confirm_presence(event_id, 10)?;
+        let list = db.get_presence_list(event_id, 0, 10)?;
+        assert_eq!(list.iter().map(|p| p.user_id).collect::<Vec<u64>>(), vec![20, 30]);
+        assert_eq!(list[0].attachment, db.get_attachment(event_id, 20)?);
+
+        // Absent participants of a past event get banned.
+        db.delete_event(event_id, true, false, &HashSet::new(), 1)?;
+        assert!(db.get_event(event_id, 0).is_err());
         assert_eq!(
-            sign_up(
-                &conn,
-                event_id,
-                &User {
-                    id: UserId(10),
-                    user_name1: "".to_string(),
-                    user_name2: "".to_string(),
-                    is_admin: false
-                },
-                1,
-                0,
-                0,
-                e.ts - 30,
-                0,
-            )
-            .unwrap(),
-            (1, false)
+            db.get_black_list(0, 10)?
+                .iter()
+                .map(|u| u.id.0)
+                .collect::<Vec<u64>>(),
+            vec![20, 30]
         );
+        Ok(())
+    });
+}
 
-        // add to waiting list
+#[test]
+fn test_paid_event() {
+    for_each_storage("test_paid_event", |db| {
+        let ts = util::get_unix_time() + 24 * 60 * 60;
+        let mut e = event(2, 2, ts);
+        e.tickets[0].price = 1000;
+        e.tickets[1].price = 500;
+        let event_id = add_published_event(&*db, e)?;
+
+        assert!(db.sign_up(event_id, &user(10), &[1, 1], 0, ts - 30, 1000).is_err());
+        // One reservation per ticket type.
+        assert_eq!(db.sign_up(event_id, &user(10), &[1, 1], 0, ts - 30, 1500)?, (2, false));
         assert_eq!(
-            sign_up(
-                &conn,
-                event_id,
-                &User {
-                    id: UserId(20),
-                    user_name1: "".to_string(),
-                    user_name2: "".to_string(),
-                    is_admin: false
-                },
-                1,
-                0,
-                1,
-                e.ts - 20,
-                0,
-            )
-            .unwrap(),
-            (1, false)
+            db.get_participants(event_id, 0, 0, 0, ReservationState::PaymentPending)?
+                .len(),
+            1
+        );
+
+        let booking = Booking {
+            event_id,
+            tickets: vec![1, 1],
+            user_id: 10,
+        };
+        let order_info = OrderInfo {
+            id: "1".to_string(),
+            name: "Payer".to_string(),
+            amount: 1500,
+        };
+        db.checkout(&booking, order_info.clone())?;
+        assert!(db.checkout(&booking, order_info).is_err());
+
+        let participants =
+            db.get_participants(event_id, 0, 0, 0, ReservationState::PaymentCompleted)?;
+        assert_eq!(participants.len(), 1);
+        // Participants are listed under their profile name, not the payer's one.
+        assert_eq!(participants[0].user_name1, "user_name1_10");
+
+        // Completed payments survive cleanup of failed ones.
+        db.clear_failed_payments(ts)?;
+        assert_eq!(db.get_event(event_id, 10)?.tickets[0].reserved, 1);
+        Ok(())
+    });
+}
+
+#[test]
+fn test_cancel_event() {
+    for_each_storage("test_cancel_event", |db| {
+        let ts = util::get_unix_time() + 24 * 60 * 60;
+        let mut e = event(2, 2, ts);
+        e.tickets[0].price = 1000;
+        e.tickets[1].price = 500;
+        let event_id = add_published_event(&*db, e)?;
+        for (user_id, tickets, amount) in [(10, vec![1, 1], 1500), (20, vec![1, 0], 1000)] {
+            db.sign_up(event_id, &user(user_id), &tickets, 0, ts - 30, amount)?;
+            let booking = Booking { event_id, tickets, user_id };
+            let order_info = OrderInfo {
+                id: format!("charge{}", user_id),
+                name: format!("Payer {}", user_id),
+                amount,
+            };
+            db.checkout(&booking, order_info)?;
+        }
+        // Unpaid bookings are not refunded.
+        db.sign_up(event_id, &user(30), &[0, 1], 0, ts - 20, 500)?;
+        assert_eq!(db.get_refunds(0, 10)?.len(), 0);
+
+        // Every checkout is refunded once, though it spans several ticket types.
+        db.change_event_state(event_id, EventState::Cancelled, 1)?;
+        assert!(db.change_event_state(event_id, EventState::Cancelled, 1).is_err());
+        let refunds = db.get_refunds(0, 10)?;
+        assert_eq!(refunds.len(), 2);
+        let mut paid: Vec<(u64, &str, &str, u64)> = refunds
+            .iter()
+            .map(|r| (r.user_id, r.name.as_str(), r.payment.as_str(), r.amount))
+            .collect();
+        paid.sort();
+        assert_eq!(paid, vec![(10, "Payer 10", "charge10", 1500), (20, "Payer 20", "charge20", 1000)]);
+        assert!(refunds.iter().all(|r| r.event_id == event_id
+            && r.event_name == event_name(&event(2, 2, ts))
+            && r.currency == "EUR"
+            && r.refunded == 0));
+        let batches = db.get_pending_messages(ts, 10)?;
+        assert_eq!(batches.len(), 1);
+        assert!(batches[0].text.contains("Оплата будет возвращена"));
+
+        // Completed refunds go to the end of the list and are recorded once.
+        let refund_id = refunds[0].id;
+        db.complete_refund(refund_id, 1)?;
+        assert!(matches!(db.complete_refund(refund_id, 1), Err(Error::RefundNotFound(_))));
+        assert!(matches!(db.complete_refund(refund_id + 10, 1), Err(Error::RefundNotFound(_))));
+        let refunds = db.get_refunds(0, 10)?;
+        assert_eq!(refunds[0].refunded, 0);
+        assert_eq!(refunds[1].id, refund_id);
+        assert!(refunds[1].refunded > 0);
+        let log = db.get_audit_log(AuditFilter::User(refunds[1].user_id), 0, 10)?;
+        assert!(log.iter().any(|r| r.action == AuditAction::CompleteRefund && r.actor == 1));
+
+        // Refunds outlive the event.
+        db.delete_event(event_id, true, false, &HashSet::new(), 1)?;
+        assert_eq!(db.get_refunds(0, 10)?.len(), 2);
+
+        // Both participants and the waiting list learn about a cancelled free event,
+        // nobody is banned for missing it.
+        let event_id = add_published_event(&*db, event(1, 0, ts))?;
+        db.sign_up(event_id, &user(10), &[1, 0], 0, ts - 30, 0)?;
+        db.sign_up(event_id, &user(20), &[1, 0], 1, ts - 20, 0)?;
+        db.change_event_state(event_id, EventState::Cancelled, 1)?;
+        let mut recipients: Vec<u64> = db
+            .get_pending_messages(ts, 10)?
+            .iter()
+            .filter(|b| b.event_id == event_id && b.message_type == MessageType::EventCancelled)
+            .flat_map(|b| b.recipients.clone())
+            .collect();
+        recipients.sort();
+        assert_eq!(recipients, vec![10, 20]);
+        assert_eq!(db.get_event(event_id, 0)?.state, EventState::Cancelled);
+        db.archive_old_events(ts + 48 * 60 * 60, true, false, &HashSet::new())?;
+        db.delete_event(event_id, true, false, &HashSet::new(), 1)?;
+        assert_eq!(db.get_black_list(0, 10)?.len(), 0);
+        Ok(())
+    });
+}
+
+#[test]
+fn test_ticket_types() {
+    for_each_storage("test_ticket_types", |db| {
+        let ts = util::get_unix_time() + 24 * 60 * 60;
+        let mut e = event(0, 0, ts);
+        e.tickets = vec![
+            ticket_type("взрослые", 2, 2),
+            ticket_type("студенты", 1, 1),
+            ticket_type("дети до 2 лет", 5, 2),
+        ];
+        let event_id = add_published_event(&*db, e)?;
+
+        assert_eq!(db.sign_up(event_id, &user(10), &[1, 1, 2], 0, ts - 30, 0)?, (3, false));
+        let s = db.get_event(event_id, 10)?;
+        assert_eq!(
+            s.tickets.iter().map(|c| c.my_reservation).collect::<Vec<u64>>(),
+            vec![1, 1, 2]
         );
+        assert_eq!((s.vacancies(0), s.vacancies(1), s.vacancies(2)), (1, 0, 3));
+
+        // Every type has its own capacity and per-booking limit.
+        assert!(matches!(
+            db.sign_up(event_id, &user(20), &[0, 1, 0], 0, ts - 20, 0),
+            Err(Error::SoldOut)
+        ));
+        assert_eq!(db.sign_up(event_id, &user(10), &[0, 0, 1], 0, ts - 20, 0)?, (0, false));
+        assert!(matches!(
+            db.sign_up(event_id, &user(20), &[0, 0, 0, 1], 0, ts - 20, 0),
+            Err(Error::NotBookable)
+        ));
+        assert_eq!(db.sign_up(event_id, &user(20), &[1, 0, 1], 0, ts - 20, 0)?, (2, false));
+
+        db.cancel(event_id, 10, 1)?;
+        assert_eq!(db.sign_up(event_id, &user(20), &[0, 1, 0], 0, ts - 10, 0)?, (1, false));
+
+        let participants = db.get_participants(event_id, 0, 0, 0, ReservationState::Free)?;
         assert_eq!(
-            sign_up(
-                &conn,
-                event_id,
-                &User {
-                    id: UserId(30),
-                    user_name1: "".to_string(),
-                    user_name2: "".to_string(),
-                    is_admin: false
-                },
-                1,
-                0,
-                1,
-                e.ts - 10,
-                0,
-            )
-            .unwrap(),
-            (1, false)
+            participants
+                .iter()
+                .map(|p| (p.user_id, p.tickets.clone()))
+                .collect::<Vec<(u64, Vec<u64>)>>(),
+            vec![(10, vec![1, 0, 2]), (20, vec![1, 1, 1])]
         );
 
-        let s = get_event(&conn, event_id, 10)?;
-        assert_eq!(s.adults.my_reservation, 1);
+        db.set_event_limits(event_id, &[2, 2, 5], 1)?;
+        let s = db.get_event(event_id, 0)?;
+        assert_eq!(
+            s.event.tickets.iter().map(|t| t.capacity).collect::<Vec<u64>>(),
+            vec![2, 2, 5]
+        );
+        assert_eq!(s.event.tickets[2].name, "дети до 2 лет");
+        assert_eq!(s.vacancies(1), 1);
 
-        let s = get_event(&conn, event_id, 20)?;
-        assert_eq!(s.adults.my_reservation, 0);
-        assert_eq!(s.adults.my_waiting, 1);
+        // Booked types keep their places on edits, new ones go to the end.
+        let mut e = s.event.clone();
+        e.tickets.swap(1, 2);
+        assert!(matches!(db.add_event(e.clone()), Err(Error::TicketTypeBooked(name)) if name == "студенты"));
+        e.tickets.remove(1);
+        assert!(matches!(db.add_event(e), Err(Error::TicketTypeBooked(_))));
+        let mut e = s.event.clone();
+        e.tickets[0].capacity = 3;
+        e.tickets.push(ticket_type("пенсионеры", 2, 1));
+        db.add_event(e)?;
+        let s = db.get_event(event_id, 0)?;
+        assert_eq!(s.event.tickets.len(), 4);
+        assert_eq!((s.vacancies(0), s.vacancies(1), s.vacancies(2)), (1, 1, 2));
+        Ok(())
+    });
+}
 
-        let s = get_event(&conn, event_id, 30)?;
-        assert_eq!(s.adults.my_reservation, 0);
-        assert_eq!(s.adults.my_waiting, 1);
+#[test]
+fn test_migrations() -> anyhow::Result<()> {
+    // Database created by db::create before schema versioning was introduced.
+    let mut conn = rusqlite::Connection::open_in_memory()?;
+    conn.execute_batch(
+        "CREATE TABLE events (
+            id              INTEGER PRIMARY KEY AUTOINCREMENT,
+            name            TEXT NOT NULL,
+            link            TEXT NOT NULL,
+            max_adults      INTEGER NOT NULL,
+            max_children    INTEGER NOT NULL,
+            max_adults_per_reservation   INTEGER NOT NULL,
+            max_children_per_reservation INTEGER NOT NULL,
+            ts              INTEGER NOT NULL,
+            remind          INTEGER NOT NULL
+            );
+        CREATE TABLE reservations (
+            id              INTEGER PRIMARY KEY,
+            event           INTEGER NOT NULL,
+            user            INTEGER NOT NULL,
+            user_name1      TEXT NOT NULL,
+            user_name2      TEXT NOT NULL,
+            adults          INTEGER NOT NULL,
+            children        INTEGER NOT NULL,
+            waiting_list    INTEGER DEFAULT 0 NOT NULL,
+            ts              INTEGER NOT NULL
+            );
+        CREATE TABLE presence (event INTEGER NOT NULL, user INTEGER NOT NULL);
+        CREATE TABLE group_leaders (event INTEGER NOT NULL, user INTEGER NOT NULL);
+        CREATE UNIQUE INDEX group_leaders_event_user_unique_idx ON presence (event, user);
+        INSERT INTO events (name, link, max_adults, max_children, max_adults_per_reservation, max_children_per_reservation, ts, remind)
+            VALUES ('old event', 'https://example.com', 1, 1, 1, 1, 1650445814, 1650445814);
+        INSERT INTO group_leaders (event, user) VALUES (1, 10), (1, 10);
+        INSERT INTO reservations (event, user, user_name1, user_name2, adults, children, ts)
+            VALUES (1, 10, 'old name', '', 1, 0, 100), (1, 10, 'new name', 'nick', 1, 0, 200);
+        INSERT INTO events (name, link, max_adults, max_children, max_adults_per_reservation, max_children_per_reservation, ts, remind)
+            VALUES ('children only', 'https://example.com', 0, 3, 0, 2, 1650445814, 1650445814);
+        INSERT INTO reservations (event, user, user_name1, user_name2, adults, children, ts)
+            VALUES (1, 20, 'parent', '', 1, 2, 150), (2, 20, 'parent', '', 0, 2, 160);",
+    )?;
 
-        cancel(&conn, event_id, 10, 1)?;
+    assert_eq!(migrations::migrate(&mut conn)?, migrations::latest_version());
+    assert_eq!(migrations::get_version(&conn)?, migrations::latest_version());
 
-        let s = get_event(&conn, event_id, 10)?;
-        assert_eq!(s.adults.my_reservation, 0);
+    // Missing columns are added with their defaults.
+    let (state, currency): (u64, String) = conn.query_row(
+        "SELECT state, currency FROM events WHERE id = 1",
+        [],
+        |row| Ok((row.get(0)?, row.get(1)?)),
+    )?;
+    assert_eq!(state, 0);
+    assert_eq!(currency, "EUR");
 
-        let s = get_event(&conn, event_id, 20)?;
-        assert_eq!(s.adults.my_reservation, 1);
-        assert_eq!(s.adults.my_waiting, 0);
+    // Adults and children become ticket types, unless there are no places for them.
+    let tickets = |event_id: u64| -> anyhow::Result<Vec<TicketType>> {
+        let tickets: String =
+            conn.query_row("SELECT tickets FROM events WHERE id = ?1", [event_id], |row| row.get(0))?;
+        Ok(serde_json::from_str(&tickets)?)
+    };
+    assert_eq!(
+        tickets(1)?,
+        vec![ticket_type("взрослые", 1, 1), ticket_type("дети", 1, 1)]
+    );
+    assert_eq!(tickets(2)?, vec![ticket_type("дети", 3, 2)]);
 
-        let s = get_event(&conn, event_id, 30)?;
-        assert_eq!(s.adults.my_reservation, 0);
-        assert_eq!(s.adults.my_waiting, 1);
+    // Bookings of both are split, reservations refer to the new types.
+    let mut stmt = conn.prepare(
+        "SELECT event, ticket, quantity FROM reservations WHERE user = 20 ORDER BY event, ticket",
+    )?;
+    let reservations = stmt
+        .query_map([], |row| Ok((row.get(0)?, row.get(1)?, row.get(2)?)))?
+        .collect::<rusqlite::Result<Vec<(u64, u64, u64)>>>()?;
+    assert_eq!(reservations, vec![(1, 0, 1), (1, 1, 2), (2, 0, 2)]);
+    drop(stmt);
 
-        cancel(&conn, event_id, 20, 1)?;
+    // The misplaced index is rebuilt on group_leaders and duplicates are removed.
+    let table: String = conn.query_row(
+        "SELECT tbl_name FROM sqlite_master WHERE type = 'index' AND name = 'group_leaders_event_user_unique_idx'",
+        [],
+        |row| row.get(0),
+    )?;
+    assert_eq!(table, "group_leaders");
+    let leaders: u64 = conn.query_row("SELECT count(*) FROM group_leaders", [], |row| row.get(0))?;
+    assert_eq!(leaders, 1);
+    conn.execute("INSERT INTO presence (event, user) VALUES (1, 20)", [])?;
+    assert!(conn
+        .execute("INSERT INTO group_leaders (event, user) VALUES (1, 10)", [])
+        .is_err());
 
-        let s = get_event(&conn, event_id, 20)?;
-        assert_eq!(s.adults.my_reservation, 0);
+    // Users are taken over from reservations with their latest names.
+    let (user_name1, user_name2, first_seen, last_seen): (String, String, u64, u64) = conn.query_row(
+        "SELECT user_name1, user_name2, first_seen, last_seen FROM users WHERE user = 10",
+        [],
+        |row| Ok((row.get(0)?, row.get(1)?, row.get(2)?, row.get(3)?)),
+    )?;
+    assert_eq!((user_name1.as_str(), user_name2.as_str()), ("new name", "nick"));
+    assert_eq!((first_seen, last_seen), (100, 200));
 
-        let s = get_event(&conn, event_id, 30)?;
-        assert_eq!(s.adults.my_reservation, 1);
-        assert_eq!(s.adults.my_waiting, 0);
+    // Reservations and bans refer to known users.
+    conn.execute_batch("PRAGMA foreign_keys = ON;")?;
+    assert!(conn
+        .execute("INSERT INTO reservations (event, user, ticket, quantity, ts) VALUES (1, 30, 0, 1, 300)", [])
+        .is_err());
+    assert!(conn
+        .execute("INSERT INTO black_list (user, ts) VALUES (30, 300)", [])
+        .is_err());
+    conn.execute("INSERT INTO black_list (user, ts) VALUES (10, 300)", [])?;
 
-        cancel(&conn, event_id, 30, 1)?;
+    // Migrating again is a no-op.
+    assert_eq!(migrations::migrate(&mut conn)?, migrations::latest_version());
 
-        let s = get_event(&conn, event_id, 10)?;
-        assert_eq!(s.adults.my_reservation, 0);
-        assert_eq!(s.adults.my_waiting, 0);
+    // Refuse to work with a schema from the future.
+    conn.execute(
+        "INSERT INTO schema_migrations (version, description, ts) VALUES (?1, 'future', 0)",
+        [migrations::latest_version() + 1],
+    )?;
+    assert!(migrations::migrate(&mut conn).is_err());
+    Ok(())
+}
 
-        let s = get_event(&conn, event_id, 20)?;
-        assert_eq!(s.adults.my_reservation, 0);
-        assert_eq!(s.adults.my_waiting, 0);
+#[test]
+fn test_concurrent_sign_up() {
+    for_each_storage("test_concurrent_sign_up", |db| {
+        let ts = 1650445814;
+        let mut e = event(5, 0, ts);
+        e.tickets[1].max_per_reservation = 0;
+        let event_id = add_published_event(&*db, e)?;
 
-        let s = get_event(&conn, event_id, 30)?;
-        assert_eq!(s.adults.my_reservation, 0);
-        assert_eq!(s.adults.my_waiting, 0);
+        // Every user gets an own connection, as if the bot was running in several processes.
+        let threads: Vec<_> = (0..20)
+            .map(|i| {
+                let db = db.clone();
+                std::thread::spawn(move || {
+                    db.sign_up(event_id, &user(100 + i), &[1, 0], 0, ts - 20, 0)
+                        .is_ok()
+                })
+            })
+            .collect();
+        let booked = threads
+            .into_iter()
+            .map(|t| t.join().unwrap_or(false))
+            .filter(|booked| *booked)
+            .count();
+        assert_eq!(booked, 5);
 
+        let s = db.get_event(event_id, 0)?;
+        assert_eq!(s.tickets[0].reserved, 5);
         Ok(())
-    }
+    });
 }
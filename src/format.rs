@@ -1,71 +1,202 @@
 use crate::types::Event;
-use crate::types::{EventState, Participant};
-use chrono::{DateTime, Local, NaiveDateTime, Utc};
+use crate::types::{AuditAction, EventState, Participant, Venue};
+use crate::util::get_unix_time;
+use chrono::{DateTime, Datelike, Utc};
+use chrono_tz::Tz;
+use std::sync::OnceLock;
 
 use crate::db;
-use db::EventStats;
-use r2d2::PooledConnection;
-use r2d2_sqlite::SqliteConnectionManager;
+use db::{AuditRecord, EventStats, Refund, Storage};
 
+const WEEKDAYS: [&str; 7] = ["пн", "вт", "ср", "чт", "пт", "сб", "вс"];
+
+/// Zone of events without an own one and of timestamps not related to events.
+/// Taken from the configuration at startup, UTC until then.
+static DEFAULT_TIME_ZONE: OnceLock<Tz> = OnceLock::new();
+
+pub fn set_default_time_zone(tz: Tz) {
+    // The configuration is read once, later calls change nothing.
+    let _ = DEFAULT_TIME_ZONE.set(tz);
+}
+
+pub fn default_time_zone() -> Tz {
+    DEFAULT_TIME_ZONE.get().copied().unwrap_or(Tz::UTC)
+}
+
+/// Zone the event is shown in, the default one if the event has none.
+pub fn time_zone(event: &Event) -> Tz {
+    event.time_zone.parse().unwrap_or_else(|_| default_time_zone())
+}
+
+/// Time not related to an event, in the default zone.
 pub fn ts(ts: u64) -> String {
-    let naive = NaiveDateTime::from_timestamp(ts as i64, 0);
-    let datetime: DateTime<Utc> = DateTime::from_utc(naive, Utc);
-    let local: DateTime<Local> = datetime.into();
-    local.format("%d.%m %H:%M").to_string()
+    ts_in(ts, default_time_zone(), get_unix_time())
+}
+
+/// Time of the event, e.g. its start, in the zone of the event.
+pub fn event_ts(event: &Event, ts: u64) -> String {
+    ts_in(ts, time_zone(event), get_unix_time())
+}
+
+fn datetime(ts: u64, tz: Tz) -> DateTime<Tz> {
+    let datetime: DateTime<Utc> = DateTime::from_timestamp(ts as i64, 0).unwrap_or_default();
+    datetime.with_timezone(&tz)
+}
+
+/// E.g. "ср 20.04 11:10", the year is shown if it differs from the one of `now`.
+fn ts_in(ts: u64, tz: Tz, now: u64) -> String {
+    let (datetime, now) = (datetime(ts, tz), datetime(now, tz));
+    let weekday = WEEKDAYS[datetime.weekday().num_days_from_monday() as usize];
+    if datetime.year() == now.year() {
+        format!("{} {}", weekday, datetime.format("%d.%m %H:%M"))
+    } else {
+        format!("{} {}", weekday, datetime.format("%d.%m.%Y %H:%M"))
+    }
+}
+
+/// End of the event, just the time if it ends on the day it starts.
+pub fn end(event: &Event) -> String {
+    end_in(event, time_zone(event), get_unix_time())
+}
+
+fn end_in(event: &Event, tz: Tz, now: u64) -> String {
+    let (start, end) = (datetime(event.ts, tz), datetime(event.end(), tz));
+    if start.date_naive() == end.date_naive() {
+        end.format("%H:%M").to_string()
+    } else {
+        ts_in(event.end(), tz, now)
+    }
 }
 
 pub fn event_title(event: &Event) -> String {
-    if event.link.len() > 0 {
+    if !event.link.is_empty() {
         format!("<a href=\"{}\">{}</a>", event.link, event.name,)
     } else {
         event.name.to_string()
     }
 }
 
-pub fn header(
-    s: &EventStats,
-    free_adults: i64,
-    free_children: i64,
-    is_admin: bool,
-    no_age_distinction: bool,
-) -> String {
+/// Numbers of tickets by type, e.g. "взрослые 2, дети 1". Just the number for events
+/// with a single ticket type.
+pub fn tickets(event: &Event, counts: &[u64]) -> String {
+    if event.tickets.len() < 2 {
+        return counts.iter().sum::<u64>().to_string();
+    }
+    let list: Vec<String> = event
+        .tickets
+        .iter()
+        .zip(counts)
+        .filter(|(_, n)| **n > 0)
+        .map(|(t, n)| format!("{} {}", t.name, n))
+        .collect();
+    if list.is_empty() {
+        "0".to_string()
+    } else {
+        list.join(", ")
+    }
+}
+
+/// Time left, e.g. "2 дн. 5 ч." or "40 мин.".
+pub fn countdown(seconds: u64) -> String {
+    let (days, hours, minutes) = (seconds / 86400, seconds % 86400 / 3600, seconds % 3600 / 60);
+    if days > 0 {
+        format!("{} дн. {} ч.", days, hours)
+    } else if hours > 0 {
+        format!("{} ч. {} мин.", hours, minutes)
+    } else {
+        format!("{} мин.", minutes.max(1))
+    }
+}
+
+/// Registration window as seen at `now`, empty if it doesn't limit registration.
+fn registration(event: &Event, now: u64) -> String {
+    if now < event.registration_opens {
+        format!(
+            "\nЗапись откроется {} (через {}).",
+            event_ts(event, event.registration_opens),
+            countdown(event.registration_opens - now)
+        )
+    } else if event.registration_closes == 0 {
+        String::new()
+    } else if now < event.registration_closes {
+        format!("\nЗапись до {}.", event_ts(event, event.registration_closes))
+    } else {
+        "\nЗапись закрыта.".to_string()
+    }
+}
+
+/// Answered questions, e.g. "Возраст детей: 5 и 7; Аллергии: нет".
+pub fn answers(event: &Event, answers: &[String]) -> String {
+    event
+        .questions
+        .iter()
+        .zip(answers)
+        .filter(|(_, a)| !a.is_empty())
+        .map(|(q, a)| format!("{}: {}", q, a))
+        .collect::<Vec<String>>()
+        .join("; ")
+}
+
+pub fn venue(v: &Venue) -> String {
+    format!(
+        "\nМесто: <b>{}</b>, {}",
+        teloxide::utils::html::escape(&v.name),
+        teloxide::utils::html::escape(&v.address)
+    )
+}
+
+/// `selected` are tickets chosen but not booked yet, they are not shown as free places.
+pub fn header(s: &EventStats, selected: &[u64], is_admin: bool) -> String {
     let mut header = format!(
-        "\n \n{}\nНачало: {}.",
+        "\n \n{}\nНачало: {}",
         event_title(&s.event),
-        ts(s.event.ts)
+        event_ts(&s.event, s.event.ts)
     );
+    if s.event.duration > 0 {
+        header.push_str(&format!(", окончание: {}", end(&s.event)));
+    }
+    header.push('.');
     if is_admin {
+        let capacities: Vec<u64> = s.event.tickets.iter().map(|t| t.capacity).collect();
         header.push_str(&format!(
-            " Мероприятие {} / {}({})",
-            s.event.id, s.event.max_adults, s.event.max_children
+            " Мероприятие {} / {}",
+            s.event.id,
+            tickets(&s.event, &capacities)
         ));
+        if s.event.series_id != 0 {
+            header.push_str(&format!(" Серия {}", s.event.series_id));
+        }
     }
 
     if s.state == EventState::Open {
-        if no_age_distinction {
+        let free = |i: usize| s.vacancies(i) - selected.get(i).copied().unwrap_or(0) as i64;
+        if s.event.tickets.len() < 2 {
             header.push_str(&format!(
                 " Свободные места: {}",
-                free_adults + free_children,
+                (0..s.event.tickets.len()).map(free).sum::<i64>(),
             ));
         } else {
-            header.push_str(&format!("\nВзрослые свободные места: {}", free_adults));
-            header.push_str(&format!("\nДетские свободные места: {}", free_children));
+            for (i, t) in s.event.tickets.iter().enumerate() {
+                header.push_str(&format!("\nСвободные места, {}: {}", t.name, free(i)));
+            }
         }
+        header.push_str(&registration(&s.event, get_unix_time()));
     } else {
-        header.push_str(" Запись остановлена.");
+        header.push_str(match s.state {
+            EventState::Draft => " Черновик, виден только администраторам.",
+            EventState::Cancelled => " Мероприятие отменено.",
+            EventState::Completed => " Мероприятие завершено.",
+            _ => " Запись остановлена.",
+        });
     }
     header
 }
 
-pub fn participants(
-    s: &EventStats,
-    participants: &Vec<Participant>,
-    is_admin: bool,
-    no_age_distinction: bool,
-) -> String {
+pub fn participants(s: &EventStats, participants: &[Participant], is_admin: bool) -> String {
     let mut list = "".to_string();
-    if participants.len() != 0 {
-        list.push_str(&format!("\n\nЗаписались {}({}):", s.adults.reserved, s.children.reserved));
+    if !participants.is_empty() {
+        let reserved: Vec<u64> = s.tickets.iter().map(|c| c.reserved).collect();
+        list.push_str(&format!("\n\nЗаписались {}:", tickets(&s.event, &reserved)));
     }
 
     list.push_str(
@@ -78,7 +209,7 @@ pub fn participants(
                 } else {
                     "".to_string()
                 };
-                if p.user_name2.len() > 0 {
+                if !p.user_name2.is_empty() {
                     entry.push_str(&format!(
                         "\n{}<a href=\"https://t.me/{}\">{} ({})</a>",
                         id, p.user_name2, p.user_name1, p.user_name2
@@ -89,14 +220,13 @@ pub fn participants(
                         id, p.user_id, p.user_name1
                     ));
                 }
-                if no_age_distinction {
-                    entry.push_str(&format!(" {}", p.adults + p.children));
-                } else {
-                    entry.push_str(&format!(" {}({})", p.adults, p.children));
-                }
+                entry.push_str(&format!(" {}", tickets(&s.event, &p.tickets)));
                 if let Some(a) = &p.attachment {
                     entry.push_str(&format!(" {}", a));
                 }
+                if is_admin && p.answers.iter().any(|a| !a.is_empty()) {
+                    entry.push_str(&format!(" ({})", answers(&s.event, &p.answers)));
+                }
                 entry
             })
             .collect::<String>(),
@@ -105,7 +235,7 @@ pub fn participants(
 }
 
 pub fn messages(
-    conn: &PooledConnection<SqliteConnectionManager>,
+    db: &dyn Storage,
     s: &EventStats,
     event_id: u64,
     is_admin: bool,
@@ -113,24 +243,24 @@ pub fn messages(
     let waiting_list = if is_admin {
         None
     } else {
-        Some((s.adults.my_reservation == 0 && s.children.my_reservation == 0) as u64)
+        Some((s.my_reservation() == 0) as u64)
     };
 
-    if let Ok(messages) = db::get_group_messages(conn, event_id, waiting_list) {
-        if messages.len() > 0 {
+    if let Ok(messages) = db.get_group_messages(event_id, waiting_list) {
+        if !messages.is_empty() {
             let formatted_list: String = messages.iter().map(|msg| {
                 if waiting_list.is_some() {
                     format!(
                         "\n{}, {}:\n{}\n",
                         msg.sender,
-                        ts(msg.ts),
+                        event_ts(&s.event, msg.ts),
                         msg.text
                     )
                 } else {
                     format!(
                         "\n{}, {} ({}):\n{}\n",
                         msg.sender,
-                        ts(msg.ts),
+                        event_ts(&s.event, msg.ts),
                         if msg.waiting_list == 0 {
                             "для забронировавших"
                         } else {
@@ -146,7 +276,139 @@ pub fn messages(
     None
 }
 
+/// One line of the audit log.
+pub fn audit_record(r: &AuditRecord) -> String {
+    let actor = if r.actor == db::SYSTEM_ACTOR {
+        "бот".to_string()
+    } else {
+        format!("<a href=\"tg://user?id={0}\">{0}</a>", r.actor)
+    };
+    let mut line = format!(
+        "{} {} {}",
+        ts(r.ts),
+        actor,
+        match r.action {
+            AuditAction::SignUp => "записался",
+            AuditAction::Cancel => "отменил бронь",
+            AuditAction::DeleteReservation => "удалил бронь",
+            AuditAction::ChangeEventState => "изменил статус",
+            AuditAction::SetEventLimits => "изменил число мест",
+            AuditAction::Ban => "забанил",
+            AuditAction::DeleteEvent => "удалил мероприятие",
+            AuditAction::EditSeries => "изменил серию",
+            AuditAction::CancelSeries => "отменил серию",
+            AuditAction::EditVenue => "изменил место",
+            AuditAction::CompleteRefund => "вернул оплату",
+            AuditAction::Promote => "записал из листа ожидания",
+        }
+    );
+    if r.event_id != 0 {
+        line.push_str(&format!(" мероприятие {}", r.event_id));
+    }
+    if r.user_id != 0 && r.user_id != r.actor {
+        line.push_str(&format!(" <a href=\"tg://user?id={0}\">{0}</a>", r.user_id));
+    }
+    if !r.details.is_empty() {
+        line.push_str(&format!(" ({})", teloxide::utils::html::escape(&r.details)));
+    }
+    line
+}
+
+/// One entry of the refund list.
+pub fn refund(r: &Refund) -> String {
+    format!(
+        "№{} {}\n<a href=\"tg://user?id={}\">{}</a> {} {}, платёж {}, {}",
+        r.id,
+        teloxide::utils::html::escape(&r.event_name),
+        r.user_id,
+        teloxide::utils::html::escape(&r.name),
+        r.amount as f32 / 100f32,
+        r.currency,
+        teloxide::utils::html::escape(&r.payment),
+        if r.refunded > 0 {
+            format!("возвращено {}", ts(r.refunded))
+        } else {
+            "ожидает возврата".to_string()
+        }
+    )
+}
+
+/// Error text for users. Storage failures are not disclosed.
+pub fn error(e: &anyhow::Error) -> String {
+    match e.chain().find_map(|e| e.downcast_ref::<db::Error>()) {
+        Some(db::Error::EventNotFound(_)) => "Мероприятие не найдено.".to_string(),
+        Some(db::Error::SeriesNotFound(_)) => "Серия не найдена.".to_string(),
+        Some(db::Error::VenueNotFound(_)) => "Место не найдено.".to_string(),
+        Some(db::Error::RefundNotFound(_)) => "Возврат не найден или уже выполнен.".to_string(),
+        Some(db::Error::ReservationNotFound) => "Бронь не найдена.".to_string(),
+        Some(db::Error::SoldOut) => "К сожалению, свободные места закончились.".to_string(),
+        Some(db::Error::RegistrationClosed) => "Запись остановлена.".to_string(),
+        Some(db::Error::RegistrationNotOpen) => "Запись ещё не открыта.".to_string(),
+        Some(db::Error::TimeConflict) => {
+            "Вы уже записаны на другое мероприятие в это время.".to_string()
+        }
+        Some(db::Error::WrongAmount) => "Неверная сумма платежа.".to_string(),
+        Some(db::Error::NotBookable) => "На это мероприятие запись не ведётся.".to_string(),
+        Some(db::Error::InvalidLink(link)) => format!("Неверная ссылка: {}", link),
+        Some(db::Error::TicketTypeBooked(name)) => {
+            format!("Билеты типа \"{}\" уже забронированы, этот тип нельзя удалить или переставить.", name)
+        }
+        Some(db::Error::EventNotEditable(_)) => {
+            "Мероприятие уже прошло, отменено или завершено, его нельзя изменить.".to_string()
+        }
+        Some(db::Error::InvalidStateChange(..)) => {
+            "Мероприятие уже отменено или завершено.".to_string()
+        }
+        Some(db::Error::Storage(_)) => {
+            "Не удалось выполнить операцию. Пожалуйста, попробуйте позже.".to_string()
+        }
+        None => {
+            // Other errors carry English or internal details, so they only go to the log.
+            error!("Unexpected error: {:#}", e);
+            "Что-то пошло не так. Пожалуйста, попробуйте позже.".to_string()
+        }
+    }
+}
+
 #[test]
 fn test_format() {
-    assert_eq!(ts(1650445814), "20.04 11:10");
+    let vienna = chrono_tz::Europe::Vienna;
+    let now = 1650445814;
+    assert_eq!(ts_in(1650445814, vienna, now), "ср 20.04 11:10");
+    assert_eq!(ts_in(1650445814, Tz::UTC, now), "ср 20.04 09:10");
+    // Winter time.
+    assert_eq!(ts_in(1670000000, vienna, now), "пт 02.12 17:53");
+    assert_eq!(ts_in(1650445814, vienna, now + 365 * 24 * 60 * 60), "ср 20.04.2022 11:10");
+    let mut event = crate::db::tests::event(0, 0, 1650445814);
+    event.duration = 2 * 60 * 60;
+    event.time_zone = "Europe/Vienna".to_string();
+    assert_eq!(time_zone(&event), vienna);
+    assert_eq!(end_in(&event, vienna, now), "13:10");
+    event.duration = 24 * 60 * 60;
+    assert_eq!(end_in(&event, vienna, now), "чт 21.04 11:10");
+    event.time_zone = "Mars/Olympus".to_string();
+    assert_eq!(time_zone(&event), default_time_zone());
+
+    assert_eq!(countdown(30), "1 мин.");
+    assert_eq!(countdown(2 * 60 * 60 + 5 * 60), "2 ч. 5 мин.");
+    assert_eq!(countdown(3 * 24 * 60 * 60 + 60 * 60), "3 дн. 1 ч.");
+    assert_eq!(registration(&event, event.ts), "");
+    event.questions = vec!["Возраст детей".to_string(), "Аллергии".to_string(), "Телефон".to_string()];
+    let list = ["5 и 7".to_string(), String::new(), "нет".to_string()];
+    assert_eq!(answers(&event, &list), "Возраст детей: 5 и 7; Телефон: нет");
+    event.registration_closes = event.ts - 60;
+    assert_eq!(registration(&event, event.ts), "\nЗапись закрыта.");
+}
+
+#[test]
+fn test_error() {
+    use anyhow::Context as _;
+    let e = Err::<(), _>(db::Error::SoldOut).context("Failed to sign up").unwrap_err();
+    assert_eq!(error(&e), "К сожалению, свободные места закончились.");
+    let e = anyhow::Error::from(db::Error::Storage("no such table: events".into()));
+    assert_eq!(error(&e), "Не удалось выполнить операцию. Пожалуйста, попробуйте позже.");
+    assert_eq!(
+        error(&anyhow::anyhow!("Unknown command")),
+        "Что-то пошло не так. Пожалуйста, попробуйте позже."
+    );
 }
@@ -7,7 +7,6 @@ use std::collections::HashSet;
 use std::sync::Arc;
 use std::{fs::File, io::prelude::*, time::Duration};
 use std::env;
-use tokio::sync::Mutex;
 #[macro_use]
 extern crate log;
 extern crate r2d2;
@@ -16,13 +15,15 @@ extern crate rusqlite;
 use teloxide::{
     prelude::*,
     types::{
-        InlineKeyboardButton, InlineKeyboardMarkup, LabeledPrice, MessageKind,
+        Document, InlineKeyboardButton, InlineKeyboardMarkup, InputFile, LabeledPrice, MessageKind,
         MessageSuccessfulPayment, ParseMode, PreCheckoutQuery, Update, UserId,
     },
-    RequestError,
+    net::Download,
+    ApiError, RequestError,
 };
 
 mod admin_message_handler;
+mod calendar;
 mod db;
 mod format;
 mod message_handler;
@@ -32,11 +33,13 @@ mod types;
 mod util;
 
 use crate::reply::*;
-use crate::types::MessageType;
-use r2d2_sqlite::SqliteConnectionManager;
-use types::{Configuration, Context};
+use crate::types::{Booking, MessageType};
+use types::{Configuration, Context, EventLocks};
 use util::get_unix_time;
 
+/// Imported files are small, bigger documents are not downloaded.
+const MAX_DOCUMENT_SIZE: u32 = 1024 * 1024;
+
 #[tokio::main]
 async fn main() {
     env_logger::init();
@@ -62,20 +65,25 @@ async fn main() {
         .map_err(|e| format!("Error loading configuration: {}", e))
         .unwrap();
 
-    config.parse().unwrap();
+    if let Err(e) = config.parse() {
+        panic!("Error loading configuration: {}", e);
+    }
+    format::set_default_time_zone(config.default_time_zone.unwrap_or(chrono_tz::Tz::UTC));
 
     let admins: HashSet<u64> = config
         .admin_ids
         .split(',')
-        .into_iter()
         .filter_map(|id| id.parse::<u64>().ok())
         .collect();
 
-    let manager = SqliteConnectionManager::file("/data/events.db3");
-    let pool = r2d2::Pool::new(manager).unwrap();
-    if let Ok(conn) = pool.get() {
-        db::create(&conn).expect("Failed to create db.");
-    }
+    let database_url = config
+        .database_url
+        .clone()
+        .unwrap_or_else(|| "/data/events.db3".to_string());
+    let storage = match db::open(&database_url) {
+        Ok(storage) => storage,
+        Err(e) => panic!("Failed to open db: {}", e),
+    };
 
     let bot = Bot::new(&config.telegram_bot_token).auto_send();
 
@@ -87,9 +95,9 @@ async fn main() {
 
     let context = Arc::new(Context {
         config,
-        pool,
+        storage,
         admins,
-        sign_up_mutex: Arc::new(Mutex::new(0u64)),
+        event_locks: EventLocks::default(),
     });
 
     tokio::spawn(perform_bulk_tasks(bot.clone(), context.clone()));
@@ -122,107 +130,46 @@ async fn message_handler(
 
     match &msg.kind {
         MessageKind::Common(_) => {
-            if let Some(text) = msg.text() {
-                if let Some(user) = msg.from() {
-                    if user.is_bot {
-                        warn!("Bot ignored");
-                        return Ok(());
-                    }
-                    trace!("received {:?}", msg);
-                    let u = crate::types::User::new(&user, &context.admins);
-                    if let Ok(conn) = context.pool.get() {
-                        let reply = if u.is_admin {
-                            crate::admin_message_handler::handle_message(&conn, &u, text, &context)
+            if let Some(user) = msg.from() {
+                if user.is_bot {
+                    warn!("Bot ignored");
+                    return Ok(());
+                }
+                trace!("received {:?}", msg);
+                let u = crate::types::User::new(user, &context.admins);
+                save_user(&context, &u);
+                let reply = match (msg.text(), msg.photo(), msg.document()) {
+                    (Some(text), _, _) => {
+                        if u.is_admin {
+                            crate::admin_message_handler::handle_message(context.storage.as_ref(), &u, text, &context)
                         } else {
-                            crate::message_handler::handle_message(&conn, &u, text, &context)
-                        };
-                        match reply {
-                            Ok(reply) => match reply {
-                                Reply::Message(r) => {
-                                    r.send(&msg, &bot).await?;
-                                }
-                                Reply::Invoice {
-                                    title,
-                                    description,
-                                    payload,
-                                    currency,
-                                    amount,
-                                } => {
-                                    match bot
-                                        .send_invoice(
-                                            msg.chat.id,
-                                            title,
-                                            description,
-                                            payload,
-                                            &context.config.payment_provider_token,
-                                            &currency,
-                                            vec![LabeledPrice {
-                                                label: currency.to_owned(),
-                                                amount: amount as i32,
-                                            }],
-                                        )
-                                        .need_name(false)
-                                        .await
-                                    {
-                                        Ok(_) => {}
-                                        Err(e) => {
-                                            error!("failed to send invoice {:?}", e);
-                                        }
-                                    }
-                                }
-                            },
-                            Err(e) => {
-                                error!("Error in reply: {}", e);
-                                bot.send_message(msg.chat.id, e.to_string()).await?;
-                            }
+                            crate::message_handler::handle_message(context.storage.as_ref(), &u, text, &context)
                         }
                     }
-                }
-            }
-        }
-        MessageKind::SuccessfulPayment(MessageSuccessfulPayment { successful_payment }) => {
-            trace!("successful_payment {:?}", &successful_payment);
-            if let Ok(conn) = context.pool.get() {
-                let res = crate::payments::checkout(&conn, successful_payment, &context);
-                if let Err(e) = res {
-                    error!("Failed to check out: {}", e);
-                    bot.send_message(msg.chat.id, e.to_string()).await?;
-                }
-            }
-        }
-        _ => {
-            warn!("unknown message");
-        }
-    }
-    Ok(())
-}
-
-async fn callback_handler(
-    q: CallbackQuery,
-    bot: AutoSend<Bot>,
-    context: Arc<Context>,
-) -> Result<(), RequestError> {
-    match (q.message, q.data) {
-        (Some(msg), Some(data)) => {
-            trace!("received {:?} {:?}", &msg, &data);
-            let u = crate::types::User::new(&q.from, &context.admins);
-            let mut lock;
-            if data.starts_with("sign_up ") {
-                lock = context.sign_up_mutex.lock().await;
-                *lock = *lock + 1;
-                // todo: use event based locking
-            }
-            if let Ok(conn) = context.pool.get() {
-                let reply = if u.is_admin {
-                    crate::admin_message_handler::handle_callback(&conn, &u, &data, &context)
-                } else {
-                    crate::message_handler::handle_callback(&conn, &u, &data, &context)
+                    // Posters come as photos with the event id as caption.
+                    (None, Some(photo), _) if u.is_admin => crate::admin_message_handler::set_poster(
+                        context.storage.as_ref(),
+                        &u,
+                        msg.caption().unwrap_or_default(),
+                        photo,
+                        &context,
+                    ),
+                    // Events are imported from CSV and iCalendar files.
+                    (None, None, Some(document)) if u.is_admin => match download(&bot, document).await {
+                        Ok(content) => crate::admin_message_handler::import_events(
+                            context.storage.as_ref(),
+                            &u,
+                            document.file_name.as_deref().unwrap_or_default(),
+                            &content,
+                        ),
+                        Err(e) => Err(e),
+                    },
+                    _ => return Ok(()),
                 };
                 match reply {
                     Ok(reply) => match reply {
                         Reply::Message(r) => {
-                            trace!("reply {:?}", r);
-                            r.edit(&msg, &bot).await?;
+                            r.send(&msg, &bot).await?;
                         }
                         Reply::Invoice {
                             title,
@@ -244,7 +191,7 @@ async fn callback_handler(
                                         amount: amount as i32,
                                     }],
                                 )
-                                .need_name(true)
+                                .need_name(false)
                                 .await
                             {
                                 Ok(_) => {}
@@ -253,11 +200,122 @@ async fn callback_handler(
                                 }
                             }
                         }
+                        Reply::Venue {
+                            latitude,
+                            longitude,
+                            title,
+                            address,
+                        } => {
+                            bot.send_venue(msg.chat.id, latitude, longitude, title, address)
+                                .await?;
+                        }
+                        Reply::Document { file_name, content } => {
+                            bot.send_document(
+                                msg.chat.id,
+                                InputFile::memory(content.into_bytes()).file_name(file_name),
+                            )
+                            .await?;
+                        }
                     },
                     Err(e) => {
-                        error!("Error in reply: {}", e);
-                        bot.send_message(msg.chat.id, e.to_string()).await?;
+                        error!("Error in reply: {:#}", e);
+                        bot.send_message(msg.chat.id, format::error(&e)).await?;
+                    }
+                }
+            }
+        }
+        MessageKind::SuccessfulPayment(MessageSuccessfulPayment { successful_payment }) => {
+            trace!("successful_payment {:?}", &successful_payment);
+            let res = crate::payments::checkout(context.storage.as_ref(), successful_payment, &context);
+            if let Err(e) = res {
+                error!("Failed to check out: {:#}", e);
+                bot.send_message(msg.chat.id, format::error(&e)).await?;
+            }
+        }
+        _ => {
+            warn!("unknown message");
+        }
+    }
+    Ok(())
+}
+
+async fn callback_handler(
+    q: CallbackQuery,
+    bot: AutoSend<Bot>,
+    context: Arc<Context>,
+) -> Result<(), RequestError> {
+    match (q.message, q.data) {
+        (Some(msg), Some(data)) => {
+            trace!("received {:?} {:?}", &msg, &data);
+            let u = crate::types::User::new(&q.from, &context.admins);
+            save_user(&context, &u);
+            let _lock = match serde_json::from_str::<message_handler::CallbackQuery>(&data) {
+                Ok(q) => match q.booking_event() {
+                    Some(event_id) => Some(context.event_locks.lock(event_id).await),
+                    None => None,
+                },
+                Err(_) => None,
+            };
+            let reply = if u.is_admin {
+                crate::admin_message_handler::handle_callback(context.storage.as_ref(), &u, &data, &context)
+            } else {
+                crate::message_handler::handle_callback(context.storage.as_ref(), &u, &data, &context)
+            };
+            match reply {
+                Ok(reply) => match reply {
+                    Reply::Message(r) => {
+                        trace!("reply {:?}", r);
+                        r.edit(&msg, &bot).await?;
+                    }
+                    Reply::Invoice {
+                        title,
+                        description,
+                        payload,
+                        currency,
+                        amount,
+                    } => {
+                        match bot
+                            .send_invoice(
+                                msg.chat.id,
+                                title,
+                                description,
+                                payload,
+                                &context.config.payment_provider_token,
+                                &currency,
+                                vec![LabeledPrice {
+                                    label: currency.to_owned(),
+                                    amount: amount as i32,
+                                }],
+                            )
+                            .need_name(true)
+                            .await
+                        {
+                            Ok(_) => {}
+                            Err(e) => {
+                                error!("failed to send invoice {:?}", e);
+                            }
+                        }
+                    }
+                    Reply::Venue {
+                        latitude,
+                        longitude,
+                        title,
+                        address,
+                    } => {
+                        bot.send_venue(msg.chat.id, latitude, longitude, title, address)
+                            .await?;
+                    }
+                    Reply::Document { file_name, content } => {
+                        bot.send_document(
+                            msg.chat.id,
+                            InputFile::memory(content.into_bytes()).file_name(file_name),
+                        )
+                        .await?;
                     }
+                },
+                Err(e) => {
+                    error!("Error in reply: {:#}", e);
+                    bot.send_message(msg.chat.id, format::error(&e)).await?;
                 }
             }
         }
@@ -307,25 +365,47 @@ async fn pre_checkout_handler(
 ) -> Result<(), RequestError> {
     trace!("pre_checkout_handler::received {:?}", pre_checkout);
     let u = crate::types::User::new(&pre_checkout.from, &context.admins);
-    if let Ok(conn) = context.pool.get() {
-        let mut lock = context.sign_up_mutex.lock().await;
-        *lock = *lock + 1;
-
-        let reply = crate::payments::pre_checkout(&conn, &u, &pre_checkout, &context);
-        match reply {
-            Ok(_) => {
-                bot.answer_pre_checkout_query(pre_checkout.id, true).await?;
-            }
-            Err(e) => {
-                bot.answer_pre_checkout_query(pre_checkout.id, false)
-                    .await?;
-                bot.send_message(u.id, e.to_string()).await?;
-            }
+    save_user(&context, &u);
+    let _lock = match serde_json::from_str::<Booking>(&pre_checkout.invoice_payload) {
+        Ok(booking) if booking.event_id != 0 => {
+            Some(context.event_locks.lock(booking.event_id).await)
+        }
+        _ => None,
+    };
+
+    let reply = crate::payments::pre_checkout(context.storage.as_ref(), &u, &pre_checkout, &context);
+    match reply {
+        Ok(_) => {
+            bot.answer_pre_checkout_query(pre_checkout.id, true).await?;
+        }
+        Err(e) => {
+            error!("Failed to pre-check out: {:#}", e);
+            bot.answer_pre_checkout_query(pre_checkout.id, false)
+                .await?;
+            bot.send_message(u.id, format::error(&e)).await?;
         }
     }
     Ok(())
 }
 
+/// Contents of a document sent to the bot.
+async fn download(bot: &AutoSend<Bot>, document: &Document) -> anyhow::Result<Vec<u8>> {
+    if document.file_size.unwrap_or(0) > MAX_DOCUMENT_SIZE {
+        return Err(anyhow::anyhow!("The file is too large"));
+    }
+    let file = bot.get_file(&document.file_id).await?;
+    let mut content = Vec::new();
+    bot.download_file(&file.file_path, &mut content).await?;
+    Ok(content)
+}
+
+/// Remembers the user and refreshes the profile on every update.
+fn save_user(context: &Context, u: &crate::types::User) {
+    if let Err(e) = context.storage.save_user(u, get_unix_time()) {
+        error!("Failed to save user {}: {}", u.id, e);
+    }
+}
+
 /// Bulk mailing and houskeeping task
 async fn perform_bulk_tasks(bot: AutoSend<Bot>, ctx: Arc<Context>) -> Result<bool, RequestError> {
     let mut next_break = tokio::time::Instant::now() + Duration::from_millis(1000);
@@ -340,52 +420,66 @@ async fn perform_bulk_tasks(bot: AutoSend<Bot>, ctx: Arc<Context>) -> Result<boo
         if num_seconds_from_midnight >= ctx.config.mailing_hours_from.unwrap()
             && num_seconds_from_midnight < ctx.config.mailing_hours_to.unwrap()
         {
-            let messages = if let Ok(conn) = ctx.pool.get() {
-                match db::get_pending_messages(
-                    &conn,
-                    ts,
-                    ctx.config.limit_bulk_notifications_per_second,
-                ) {
-                    Ok(messages) => messages,
-                    Err(_e) => return Ok(false),
-                }
-            } else {
-                Vec::new()
+            let messages = match ctx.storage.get_pending_messages(
+                ts,
+                ctx.config.limit_bulk_notifications_per_second,
+            ) {
+                Ok(messages) => messages,
+                Err(_e) => return Ok(false),
             };
 
             for m in messages {
                 notifications += m.recipients.len();
-                let keyboard: Vec<Vec<InlineKeyboardButton>> =
-                    vec![vec![InlineKeyboardButton::callback(
-                        "К мероприятию",
-                        if m.is_paid {
-                            serde_json::to_string(&message_handler::CallbackQuery::PaidEvent {
-                                event_id: m.event_id,
-                                adults: 0,
-                                children: 0,
-                                offset: 0,
-                            })
-                        } else {
-                            serde_json::to_string(&message_handler::CallbackQuery::Event {
-                                event_id: m.event_id,
-                                offset: 0,
-                            })
-                        }
-                        .unwrap(),
-                    )]];
-                let keyboard = InlineKeyboardMarkup::new(keyboard);
+                let mut buttons = vec![InlineKeyboardButton::callback(
+                    "К мероприятию",
+                    if m.is_paid {
+                        serde_json::to_string(&message_handler::CallbackQuery::PaidEvent {
+                            event_id: m.event_id,
+                            tickets: vec![],
+                            offset: 0,
+                        })
+                    } else {
+                        serde_json::to_string(&message_handler::CallbackQuery::Event {
+                            event_id: m.event_id,
+                            offset: 0,
+                        })
+                    }
+                    .unwrap(),
+                )];
+                if m.message_type == MessageType::Promoted {
+                    buttons.push(InlineKeyboardButton::callback(
+                        "Отменить бронь",
+                        serde_json::to_string(&message_handler::CallbackQuery::WontGo { event_id: m.event_id }).unwrap(),
+                    ));
+                }
+                let keyboard = InlineKeyboardMarkup::new(vec![buttons]);
                 for u in m.recipients {
-                    debug!("Sending notification {} to {} {}", m.message_id, u, &m.text);
-                    bot.send_message(UserId(u), &m.text)
+                    debug!("Sending notification {} from {} to {} {}", m.message_id, m.sender, u, &m.text);
+                    match bot
+                        .send_message(UserId(u), &m.text)
                         .parse_mode(ParseMode::Html)
                         .disable_web_page_preview(true)
                         .reply_markup(keyboard.clone())
-                        .await?;
-
-                    if let Ok(conn) = ctx.pool.get() {
-                        if let Err(e) = db::save_receipt(&conn, m.message_id, u) {
-                            error!("Failed to save receipt: {}", e);
+                        .await
+                    {
+                        Ok(_) => {}
+                        Err(RequestError::Api(
+                            ApiError::BotBlocked
+                            | ApiError::BotKicked
+                            | ApiError::ChatNotFound
+                            | ApiError::UserDeactivated,
+                        )) => {
+                            // Don't retry, the message would never be delivered.
+                            warn!("User {} is unreachable", u);
+                            if let Err(e) = ctx.storage.set_user_reachable(u, false) {
+                                error!("Failed to mark user unreachable: {}", e);
+                            }
                         }
+                        Err(e) => return Err(e),
+                    }
+
+                    if let Err(e) = ctx.storage.save_receipt(m.message_id, u) {
+                        error!("Failed to save receipt: {}", e);
                     }
                     if m.message_type == MessageType::WaitingListPrompt {
                         batch_contains_waiting_list_prompt = true;
@@ -395,44 +489,52 @@ async fn perform_bulk_tasks(bot: AutoSend<Bot>, ctx: Arc<Context>) -> Result<boo
         }
 
         if ctx.config.cleanup_old_events {
-            if let Ok(conn) = ctx.pool.get() {
-                // Clean up.
-                if db::clear_old_events(
-                    &conn,
+            // Archive past events.
+            if ctx
+                .storage
+                .archive_old_events(
                     ts - ctx.config.drop_events_after_hours * 60 * 60,
                     ctx.config.automatic_blacklisting,
                     ctx.config.cancel_future_reservations_on_ban,
                     &ctx.admins,
                 )
-                .is_ok()
-                    == false
-                {
-                    error!("Failed to clear old events at {}", ts);
-                }
+                .is_err()
+            {
+                error!("Failed to archive old events at {}", ts);
+            }
 
-                // Age black lists.
-                if db::clear_black_list(
-                    &conn,
-                    ts - ctx.config.delete_from_black_list_after_days * 24 * 60 * 60,
-                )
-                .is_ok()
-                    == false
-                {
-                    error!("Failed to clear black list at {}", ts);
-                }
+            // Purge the archive.
+            if ctx
+                .storage
+                .purge_archive(ts - ctx.config.delete_archived_events_after_days.unwrap_or(365) * 24 * 60 * 60)
+                .is_err()
+            {
+                error!("Failed to purge archived events at {}", ts);
+            }
+
+            // Age black lists.
+            if ctx
+                .storage
+                .clear_black_list(ts - ctx.config.delete_from_black_list_after_days * 24 * 60 * 60)
+                .is_err()
+            {
+                error!("Failed to clear black list at {}", ts);
             }
         }
 
+        // Open registration for upcoming occurrences of event series.
+        if let Err(e) = ctx.storage.materialize_series(ts + ctx.config.series_horizon()) {
+            error!("Failed to create series occurrences at {}: {}", ts, e);
+        }
+
         // Clear failed payments.
-        if let Ok(conn) = ctx.pool.get() {
-            if db::clear_failed_payments(&conn, ts - 5 * 60).is_ok() == false {
-                error!("Failed to clear failed payments at {}", ts);
-            }
+        if ctx.storage.clear_failed_payments(ts - 5 * 60).is_err() {
+            error!("Failed to clear failed payments at {}", ts);
         }
 
         next_break = tokio::time::Instant::now()
             + Duration::from_millis(
-                if notifications > 0 && batch_contains_waiting_list_prompt == false {
+                if notifications > 0 && !batch_contains_waiting_list_prompt {
                     1000
                 } else {
                     30000
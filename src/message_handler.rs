@@ -1,8 +1,11 @@
 use crate::get_unix_time;
 use crate::payments::{prepare_invoice, show_paid_event, donate};
-use crate::types::{Context, EventState, EventType, ReservationState, User};
+use crate::types::{Context, EventState, EventType, ReservationState, User, Venue};
+use crate::util;
+use crate::calendar;
+use std::env;
 use crate::reply::*;
-use anyhow::anyhow;
+use anyhow::{anyhow, Context as _};
 use teloxide::{
     types::{InlineKeyboardButton},
     utils::html,
@@ -11,21 +14,26 @@ use url::Url;
 
 use crate::db;
 use crate::format;
-use db::EventStats;
-use r2d2::PooledConnection;
-use r2d2_sqlite::SqliteConnectionManager;
+use db::{EventStats, Storage};
 use serde_compact::compact;
 
+/// Longer answers to event questions are cut.
+const MAX_ANSWER_LENGTH: usize = 256;
+/// Longer search queries are cut.
+const MAX_SEARCH_LENGTH: usize = 64;
+/// Upcoming reservations exported by /calendar.
+const MAX_CALENDAR_EVENTS: u64 = 100;
+
 /// User dialog handler.
 /// Command line processor.
 pub fn handle_message(
-    conn: &PooledConnection<SqliteConnectionManager>,
+    db: &dyn Storage,
     user: &User,
     data: &str,
     ctx: &Context,
 ) -> anyhow::Result<Reply> {
     let pars: Vec<&str> = data.splitn(3, ' ').collect();
-    if pars.len() == 0 {
+    if pars.is_empty() {
         return Err(anyhow!("Unknown command"));
     }
     match pars[0] {
@@ -38,20 +46,33 @@ pub fn handle_message(
                     }
                 } else {
                     if let Ok(event_id) = pars[1].parse::<u64>() {
-                        return show_event(conn, user, event_id, ctx, None, 0);
+                        return show_event(db, user, event_id, ctx, None, 0);
                     }
                 }
             } else {
-                return show_event_list(conn, user.id.0, ctx, 0);
+                db.save_search(user.id.0, "").context("Failed to end search")?;
+                return show_event_list(db, user.id.0, ctx, 0, ListFilter::default());
             }
         }
         "/donate" => {
                 return donate(user, 500, ctx);
         }
+        "/calendar" => {
+            return user_calendar(db, user, ctx);
+        }
+        "/search" => {
+            let text = data["/search".len()..].trim();
+            if text.is_empty() {
+                return Ok(ReplyMessage::new("Напишите, что найти, например: /search музей").into());
+            }
+            return search(db, user, text, ctx);
+        }
         "/help" => {
             return Ok(ReplyMessage::new(format!(
                 "Здесь вы можете бронировать места на мероприятия.\n \
                             \n /start - показать список мероприятий \
+                            \n /calendar - мои бронирования для календаря \
+                            \n /search текст - поиск мероприятий, можно просто написать текст \
                             \n /help - эта подсказка \
                             \n <a href=\"{}\">Подробная инструкция</a> \
                             \n /donate - поддержать канал.",
@@ -60,18 +81,38 @@ pub fn handle_message(
             .into());
         }
         _ => {
-            // Message from user - try to add as attachment to the last reservation.
-            return add_attachment(conn, &user, data, ctx);
+            // Message from user - an answer to the questions of the event, if any, an attachment
+            // to the reservation of the last viewed event, if it's upcoming, otherwise a search.
+            let event_id = db.get_questionnaire(user.id.0).context("Failed to get questionnaire")?;
+            if event_id != 0 {
+                return answer_question(db, user, event_id, data, ctx);
+            }
+            if holds_current_event(db, user.id.0)? {
+                return add_attachment(db, user, data, ctx);
+            }
+            return search(db, user, data, ctx);
         }
     }
     Err(anyhow!("Unknown command"))
 }
 
+/// Event list filter chosen by the user.
+#[compact]
+#[derive(Serialize, Deserialize, Clone, Default)]
+pub struct ListFilter {
+    /// Category id, 0 for any.
+    category: u64,
+    vacancies: bool,
+    week: bool,
+    mine: bool,
+}
+
 #[compact]
 #[derive(Serialize, Deserialize, Clone)]
 pub enum CallbackQuery {
     EventList {
         offset: u64,
+        filter: ListFilter,
     },
     Event {
         event_id: u64,
@@ -79,12 +120,12 @@ pub enum CallbackQuery {
     },
     SignUp {
         event_id: u64,
-        is_adult: bool,
+        ticket: u64,
         wait: bool,
     },
     Cancel {
         event_id: u64,
-        is_adult: bool,
+        ticket: u64,
     },
     WontGo {
         event_id: u64,
@@ -102,16 +143,15 @@ pub enum CallbackQuery {
         user_id: u64,
         offset: u64,
     },
+    /// `tickets` are numbers of tickets by type selected for payment.
     PaidEvent {
         event_id: u64,
-        adults: u64,
-        children: u64,
+        tickets: Vec<u64>,
         offset: u64,
     },
     SendInvoice {
         event_id: u64,
-        adults: u64,
-        children: u64,
+        tickets: Vec<u64>,
     },
 
     // admin callbacks
@@ -128,37 +168,101 @@ pub enum CallbackQuery {
     ConfirmRemoveFromBlackList {
         user_id: u64,
     },
+    ShowArchive {
+        offset: u64,
+    },
+    ArchivedEvent {
+        event_id: u64,
+        offset: u64,
+    },
+    ShowUsers {
+        offset: u64,
+    },
+    ShowUser {
+        user_id: u64,
+    },
+    AuditLog {
+        event_id: u64,
+        user_id: u64,
+        offset: u64,
+    },
+    ConfirmCancelEvent {
+        event_id: u64,
+    },
+    ConfirmCancelSeries {
+        series_id: u64,
+    },
+    CancelSeries {
+        series_id: u64,
+    },
+    ShowVenue {
+        venue_id: u64,
+    },
+    SubscribeToRegistration {
+        event_id: u64,
+        subscribe: bool,
+    },
+    ChangeAnswers {
+        event_id: u64,
+    },
+    ShowRefunds {
+        offset: u64,
+    },
+    CompleteRefund {
+        refund_id: u64,
+    },
+    DuplicateEvent {
+        event_id: u64,
+    },
+    Calendar {
+        event_id: u64,
+    },
+    EndSearch {
+        filter: ListFilter,
+    },
+    ImportEvents {
+        create: bool,
+    },
+}
+
+impl CallbackQuery {
+    /// Event whose reservations are changed by the query.
+    pub fn booking_event(&self) -> Option<u64> {
+        match self {
+            CallbackQuery::SignUp { event_id, .. }
+            | CallbackQuery::Cancel { event_id, .. }
+            | CallbackQuery::WontGo { event_id } => Some(*event_id),
+            _ => None,
+        }
+    }
 }
 
 /// Callback query processor.
 pub fn handle_callback(
-    conn: &PooledConnection<SqliteConnectionManager>,
+    db: &dyn Storage,
     user: &User,
     data: &str,
     ctx: &Context,
 ) -> anyhow::Result<Reply> {
-    if let Ok(q) = serde_json::from_str::<CallbackQuery>(&data) {
+    if let Ok(q) = serde_json::from_str::<CallbackQuery>(data) {
         use CallbackQuery::*;
         match q {
-            EventList { offset } => show_event_list(conn, user.id.0, ctx, offset),
-            Event { event_id, offset } => show_event(conn, user, event_id, ctx, None, offset),
+            EventList { offset, filter } => show_event_list(db, user.id.0, ctx, offset, filter),
+            Event { event_id, offset } => show_event(db, user, event_id, ctx, None, offset),
             SignUp {
                 event_id,
-                is_adult,
+                ticket,
                 wait,
             } => {
-                match db::sign_up(
-                    conn,
-                    event_id,
+                match db.sign_up(event_id,
                     user,
-                    is_adult as u64,
-                    !is_adult as u64,
+                    &db::one_ticket(ticket),
                     wait as u64,
                     get_unix_time(),
                     0,
                 ) {
                     Ok((_, black_listed)) => show_event(
-                        conn,
+                        db,
                         user,
                         event_id,
                         ctx,
@@ -170,68 +274,65 @@ pub fn handle_callback(
                         },
                         0,
                     ),
-                    Err(e) => Err(anyhow!("{}", e)),
+                    Err(e) => Err(e.into()),
                 }
             }
-            Cancel { event_id, is_adult } => {
+            Cancel { event_id, ticket } => {
                 let user_id = user.id.0;
-                match db::cancel(conn, event_id, user_id, is_adult as u64) {
+                match db.cancel(event_id, user_id, ticket) {
                     Ok(_) => {
                         let mut ps = None;
-                        if is_too_late_to_cancel(conn, event_id, user, ctx) {
-                            if let Ok(s) = db::get_event(conn, event_id, user_id) {
-                                if s.adults.my_reservation + s.children.my_reservation == 0 && s.event.adult_ticket_price == 0 && s.event.child_ticket_price == 0 {
+                        if is_too_late_to_cancel(db, event_id, user, ctx) {
+                            if let Ok(s) = db.get_event(event_id, user_id) {
+                                if s.my_reservation() == 0 && s.event.get_type() != EventType::Paid {
                                     // Complete cancellation
-                                    if db::ban_user(
-                                        conn,
-                                        user_id,
+                                    if db.ban_user(user_id,
                                         &user.user_name1,
                                         &user.user_name2,
                                         &format!(
                                             "late cancel {} {}",
-                                            format::ts(s.event.ts),
+                                            format::event_ts(&s.event, s.event.ts),
                                             s.event.name
                                         ),
                                         ctx.config.cancel_future_reservations_on_ban,
                                     )
-                                    .is_ok()
-                                        == false
+                                    .is_err()
                                     {
                                         return Err(anyhow!(
                                             "Failed to add user {} to black list",
                                             user.id
                                         ));
                                     }
-                                    ps = Some(format!("\n\nВНИМАНИЕ!\nК сожалению, вы отказались от билетов слишком поздно и не сможете больше бронировать бесплатные билеты."));
+                                    ps = Some("\n\nВНИМАНИЕ!\nК сожалению, вы отказались от билетов слишком поздно и не сможете больше бронировать бесплатные билеты.".to_string());
                                 }
                             }
                         }
-                        show_event(conn, user, event_id, ctx, ps, 0)
+                        show_event(db, user, event_id, ctx, ps, 0)
                     }
-                    Err(e) => Err(anyhow!("Failed to cancel reservation: {}.", e)),
+                    Err(e) => Err(e).context("Failed to cancel reservation"),
                 }
             }
             WontGo { event_id } => {
-                match db::wontgo(conn, event_id, user.id.0) {
+                match db.wontgo(event_id, user.id.0) {
                     Ok(_) => {
-                        if is_too_late_to_cancel(conn, event_id, user, ctx) {
+                        if is_too_late_to_cancel(db, event_id, user, ctx) {
                             Ok(ReplyMessage::new("К сожалению, вы отказываетесь от билетов слишком поздно и не сможете больше бронировать бесплатные билеты.".to_string()).into())
                         } else {
                             Ok(ReplyMessage::new("Мы сожалеем, что вы не сможете пойти. Увидимся в другой раз. Спасибо!".to_string()).into())
                         }
                     }
-                    Err(e) => Err(anyhow!("Failed to add event: {}.", e)),
+                    Err(e) => Err(e).context("Failed to add event"),
                 }
             }
             ShowWaitingList { event_id, offset } => {
-                if ctx.config.public_lists || user.is_admin != false {
-                    show_waiting_list(conn, user, event_id, ctx, offset)
+                if ctx.config.public_lists || user.is_admin {
+                    show_waiting_list(db, user, event_id, ctx, offset)
                 } else {
                     Err(anyhow!("not allowed"))
                 }
             }
             ShowPresenceList { event_id, offset } => {
-                show_presence_list(conn, event_id, user, ctx, offset)
+                show_presence_list(db, event_id, user, ctx, offset)
             }
             ConfirmPresence {
                 event_id,
@@ -241,15 +342,12 @@ pub fn handle_callback(
                 let user_has_permissions = if user.is_admin {
                     true
                 } else {
-                    match db::is_group_leader(conn, event_id, user.id.0) {
-                        Ok(res) => res,
-                        Err(_) => false,
-                    }
+                    db.is_group_leader(event_id, user.id.0).unwrap_or_default()
                 };
                 if user_has_permissions {
-                    match db::confirm_presence(conn, event_id, user_id) {
-                        Ok(_) => show_presence_list(conn, event_id, user, ctx, offset),
-                        Err(e) => Err(anyhow!("Failed to confirm presence: {}.", e)),
+                    match db.confirm_presence(event_id, user_id) {
+                        Ok(_) => show_presence_list(db, event_id, user, ctx, offset),
+                        Err(e) => Err(e).context("Failed to confirm presence"),
                     }
                 } else {
                     Err(anyhow!("not allowed"))
@@ -257,15 +355,37 @@ pub fn handle_callback(
             }
             PaidEvent {
                 event_id,
-                adults,
-                children,
+                tickets,
                 offset,
-            } => show_paid_event(event_id, adults, children, offset, conn, user, ctx),
-            SendInvoice {
-                event_id,
-                adults,
-                children,
-            } => prepare_invoice(event_id, adults, children, conn, user, ctx),
+            } => show_paid_event(event_id, tickets, offset, db, user, ctx),
+            SendInvoice { event_id, tickets } => prepare_invoice(event_id, tickets, db, user, ctx),
+            ShowVenue { venue_id } => {
+                let v = db.get_venue(venue_id).context("Failed to find venue")?;
+                Ok(Reply::Venue {
+                    latitude: v.latitude,
+                    longitude: v.longitude,
+                    title: v.name,
+                    address: v.address,
+                })
+            }
+            SubscribeToRegistration { event_id, subscribe } => {
+                let s = db.get_event(event_id, user.id.0).context("Failed to fetch event")?;
+                if !s.state.is_public() && !user.is_admin {
+                    return Err(db::Error::EventNotFound(event_id).into());
+                }
+                db.subscribe_to_registration(event_id, user.id.0, subscribe)
+                    .context("Failed to subscribe to registration")?;
+                event_card(db, user, event_id, ctx)
+            }
+            ChangeAnswers { event_id } => {
+                db.clear_answers(event_id, user.id.0).context("Failed to clear answers")?;
+                event_card(db, user, event_id, ctx)
+            }
+            Calendar { event_id } => event_calendar(db, user, event_id, ctx),
+            EndSearch { filter } => {
+                db.save_search(user.id.0, "").context("Failed to end search")?;
+                show_event_list(db, user.id.0, ctx, 0, filter)
+            }
             _ => Err(anyhow!("Not allowed.")),
         }
     } else {
@@ -273,54 +393,182 @@ pub fn handle_callback(
     }
 }
 
+/// Card of a free or a paid event.
+fn event_card(db: &dyn Storage, user: &User, event_id: u64, ctx: &Context) -> anyhow::Result<Reply> {
+    let s = db.get_event(event_id, user.id.0).context("Failed to fetch event")?;
+    if s.event.get_type() == EventType::Paid {
+        show_paid_event(event_id, vec![], 0, db, user, ctx)
+    } else {
+        show_event(db, user, event_id, ctx, None, 0)
+    }
+}
+
+fn question(s: &EventStats, i: usize) -> String {
+    format!(
+        "<b>Вопрос {} из {}:</b> {}\nПожалуйста, ответьте сообщением.",
+        i + 1,
+        s.event.questions.len(),
+        s.event.questions[i]
+    )
+}
+
+/// Answers of the user to the event questions or, until all are answered, the next question.
+/// Text messages of the user answer it from then on.
+pub fn questionnaire(
+    db: &dyn Storage,
+    s: &EventStats,
+    user_id: u64,
+) -> anyhow::Result<(Option<String>, Vec<Vec<InlineKeyboardButton>>)> {
+    if s.event.questions.is_empty() || (s.my_reservation() == 0 && s.my_waiting() == 0) {
+        return Ok((None, Vec::new()));
+    }
+    let answers = db.get_answers(s.event.id, user_id)?;
+    if let Some(i) = answers.iter().position(|a| a.is_empty()) {
+        db.set_questionnaire(user_id, s.event.id)?;
+        return Ok((Some(format!("\n\n{}", question(s, i))), Vec::new()));
+    }
+    Ok((
+        Some(format!("\n\nВаши ответы: {}", format::answers(&s.event, &answers))),
+        vec![vec![InlineKeyboardButton::callback(
+            "Изменить ответы",
+            serde_json::to_string(&CallbackQuery::ChangeAnswers { event_id: s.event.id })?,
+        )]],
+    ))
+}
+
+/// Saves the text as the answer to the next question and asks the one after it.
+fn answer_question(
+    db: &dyn Storage,
+    user: &User,
+    event_id: u64,
+    data: &str,
+    ctx: &Context,
+) -> anyhow::Result<Reply> {
+    let user_id = user.id.0;
+    let s = db.get_event(event_id, user_id).context("Failed to find event")?;
+    let answers = db.get_answers(event_id, user_id)?;
+    let answer: String = data.trim().chars().take(MAX_ANSWER_LENGTH).collect();
+    let saved = match answers.iter().position(|a| a.is_empty()) {
+        Some(i) => match db.save_answer(event_id, user_id, i as u64, &html::escape(&answer)) {
+            Ok(_) => Some(i),
+            // Cancelled the reservation meanwhile.
+            Err(db::Error::ReservationNotFound) => None,
+            Err(e) => return Err(e).context("Failed to save answer"),
+        },
+        None => None,
+    };
+    let i = match saved {
+        Some(i) => i,
+        None => {
+            db.set_questionnaire(user_id, 0)?;
+            return add_attachment(db, user, data, ctx);
+        }
+    };
+
+    match answers.iter().enumerate().find(|(j, a)| *j != i && a.is_empty()) {
+        Some((next, _)) => Ok(ReplyMessage::new(question(&s, next)).into()),
+        None => {
+            db.set_questionnaire(user_id, 0)?;
+            let back = if s.event.get_type() == EventType::Paid {
+                CallbackQuery::PaidEvent { event_id, tickets: vec![], offset: 0 }
+            } else {
+                CallbackQuery::Event { event_id, offset: 0 }
+            };
+            Ok(ReplyMessage::new("Спасибо, ответы сохранены.")
+                .keyboard(vec![vec![InlineKeyboardButton::callback(
+                    "К мероприятию",
+                    serde_json::to_string(&back)?,
+                )]])
+                .into())
+        }
+    }
+}
+
 pub fn add_attachment(
-    conn: &PooledConnection<SqliteConnectionManager>,
+    db: &dyn Storage,
     user: &User,
     data: &str,
     ctx: &Context,
 ) -> anyhow::Result<Reply> {
     let user_id: u64 = user.id.0;
-    match db::get_current_event(conn, user_id) {
+    match db.get_current_event(user_id) {
         Ok(event_id) if event_id > 0 => {
-            match db::add_attachment(conn, event_id, user_id, &html::escape(data)) {
-                Ok(_v) => {
-                    return show_event(
-                        conn,
-                        user,
-                        event_id,
-                        ctx,
-                        if data.chars().any(char::is_numeric) {
-                            Some("\n\nВНИМАНИЕ!\nВаше примечание содержит цифры. Они никак не влияют на количество забронированных мест. Количество мест можно менять только кнопками \"Записать/Отписать\".".to_string())
-                        } else {
-                            None
-                        },
-                        0,
-                    )
-                }
-                _ => return Err(anyhow!("Failed to parse attachment: {}", data)),
+            match db.add_attachment(event_id, user_id, &html::escape(data)) {
+                Ok(_v) => show_event(
+                    db,
+                    user,
+                    event_id,
+                    ctx,
+                    if data.chars().any(char::is_numeric) {
+                        Some("\n\nВНИМАНИЕ!\nВаше примечание содержит цифры. Они никак не влияют на количество забронированных мест. Количество мест можно менять только кнопками \"Записать/Отписать\".".to_string())
+                    } else {
+                        None
+                    },
+                    0,
+                ),
+                _ => Err(anyhow!("Failed to parse attachment: {}", data)),
             }
         }
-        _ => return Err(anyhow!("Failed to find event")),
+        _ => Err(anyhow!("Failed to find event")),
+    }
+}
+
+/// Whether the user has a reservation or a place in the waiting list for the upcoming event
+/// they looked at last.
+fn holds_current_event(db: &dyn Storage, user_id: u64) -> anyhow::Result<bool> {
+    let event_id = db.get_current_event(user_id).context("Failed to get current event")?;
+    if event_id == 0 {
+        return Ok(false);
+    }
+    match db.get_event(event_id, user_id) {
+        Ok(s) => Ok((s.my_reservation() > 0 || s.my_waiting() > 0) && s.event.ts > get_unix_time()),
+        Err(db::Error::EventNotFound(_)) => Ok(false),
+        Err(e) => Err(e).context("Failed to fetch event"),
     }
 }
 
+/// Event list of the events matching the text, until the user ends the search.
+fn search(db: &dyn Storage, user: &User, text: &str, ctx: &Context) -> anyhow::Result<Reply> {
+    let text: String = text.trim().chars().take(MAX_SEARCH_LENGTH).collect();
+    db.save_search(user.id.0, &text).context("Failed to save search")?;
+    show_event_list(db, user.id.0, ctx, 0, ListFilter::default())
+}
+
 pub fn show_event_list(
-    conn: &PooledConnection<SqliteConnectionManager>,
+    db: &dyn Storage,
     user_id: u64,
     ctx: &Context,
     offset: u64,
+    filter: ListFilter,
 ) -> anyhow::Result<Reply> {
-    match db::get_events(conn, user_id, offset, ctx.config.event_list_page_size) {
+    let event_filter = db::EventFilter {
+        category: filter.category,
+        vacancies: filter.vacancies,
+        until: if filter.week { util::get_end_of_week(get_unix_time(), &format::default_time_zone()) } else { 0 },
+        mine: filter.mine,
+        text: db.get_search(user_id).context("Failed to get search")?,
+    };
+    let events = if !event_filter.text.is_empty() && db::search_words(&event_filter.text).is_empty() {
+        // Text without letters or digits matches nothing.
+        Ok(Vec::new())
+    } else {
+        db.get_events(user_id, ctx.admins.contains(&user_id), &event_filter, offset, ctx.config.event_list_page_size)
+    };
+    match events {
         Ok(events) => {
             Ok(
                 // header
                 ReplyMessage::new(
-                    if offset != 0 || events.len() != 0 {
-                        format!("Программа\nвремя / взросл.(детск.) места  / мероприятие\n<a href=\"{}\">инструкция</a> /donate", ctx.config.help)
+                    if !event_filter.text.is_empty() && offset == 0 && events.is_empty() {
+                        format!("По запросу \"{}\" ничего не найдено.", html::escape(&event_filter.text))
+                    } else if offset != 0 || !events.is_empty() {
+                        format!("Программа\nвремя / свободные места / мероприятие\n<a href=\"{}\">инструкция</a> /donate", ctx.config.help)
                     } else {
                         "Нет мероприятий.".to_string()
                     }                      
                 )
+                // filter
+                .keyboard(filter_controls(db, &filter, &event_filter.text)?)
                 .keyboard(
                     events
                     .iter()
@@ -328,7 +576,7 @@ pub fn show_event_list(
                         let event_type = s.event.get_type();
                         if event_type == EventType::Announcement {
                             if let Ok(url) = Url::parse(&s.event.link) {
-                                vec![InlineKeyboardButton::url(format!("ℹ️ {} {}", format::ts(s.event.ts), s.event.name), url)]
+                                vec![InlineKeyboardButton::url(format!("ℹ️ {} {}", format::event_ts(&s.event, s.event.ts), s.event.name), url)]
                             } else {
                                 vec![]
                             }
@@ -336,38 +584,36 @@ pub fn show_event_list(
                             vec![InlineKeyboardButton::callback(
                                 format!(
                                     "{} {} / {} / {}",
-                                    if s.adults.my_reservation != 0 || s.children.my_reservation != 0 {
+                                    if s.my_reservation() != 0 {
                                         "✅"
-                                    } else if s.adults.my_waiting != 0 || s.children.my_waiting != 0 {
+                                    } else if s.my_waiting() != 0 {
                                         "⏳"
                                     } else if event_type != EventType::Paid {
                                         "✨" // todo: find a better emoji for free events
                                     } else {
                                         ""
                                     },
-                                    format::ts(s.event.ts),
+                                    format::event_ts(&s.event, s.event.ts),
                                     if s.state == EventState::Open {
-                                        if s.event.max_adults == 0 || s.event.max_children == 0 {
-                                            (s.event.max_adults - s.adults.reserved + s.event.max_children
-                                                - s.children.reserved)
-                                                .to_string()
-                                        } else {
-                                            format!(
-                                                "{}({})",
-                                                s.event.max_adults - s.adults.reserved,
-                                                s.event.max_children - s.children.reserved
-                                            )
-                                        }
+                                        (0..s.event.tickets.len())
+                                            .map(|i| s.vacancies(i).max(0))
+                                            .sum::<i64>()
+                                            .to_string()
                                     } else {
-                                        "-".to_string()
+                                        match s.state {
+                                            EventState::Draft => "черновик",
+                                            EventState::Cancelled => "отменено",
+                                            EventState::Completed => "завершено",
+                                            _ => "-",
+                                        }
+                                        .to_string()
                                     },
                                     s.event.name
                                 ),
                                 if event_type == EventType::Paid {
                                     serde_json::to_string(&CallbackQuery::PaidEvent {
                                         event_id: s.event.id,
-                                        adults: 0,
-                                        children: 0,
+                                        tickets: vec![],
                                         offset: 0,
                                     })
                                 } else {
@@ -384,8 +630,8 @@ pub fn show_event_list(
                 )
                 // pagination
                 .pagination(
-                    &CallbackQuery::EventList {offset: offset.saturating_sub(1)},
-                    &CallbackQuery::EventList {offset: offset + 1},
+                    &CallbackQuery::EventList {offset: offset.saturating_sub(1), filter: filter.clone()},
+                    &CallbackQuery::EventList {offset: offset + 1, filter: filter.clone()},
                     events.len() as u64,
                     ctx.config.event_list_page_size,
                     offset,
@@ -393,28 +639,73 @@ pub fn show_event_list(
                 .into()
             )
         }
-        Err(e) => Err(anyhow!("Failed to query events: {}", e)),
+        Err(e) => Err(e).context("Failed to query events"),
     }
 }
 
+/// Toggles for the event list filter and one button per category.
+fn filter_controls(
+    db: &dyn Storage,
+    filter: &ListFilter,
+    text: &str,
+) -> anyhow::Result<Vec<Vec<InlineKeyboardButton>>> {
+    let button = |text: &str, active: bool, filter: ListFilter| -> anyhow::Result<InlineKeyboardButton> {
+        Ok(InlineKeyboardButton::callback(
+            if active { format!("✔️ {}", text) } else { text.to_string() },
+            serde_json::to_string(&CallbackQuery::EventList { offset: 0, filter })?,
+        ))
+    };
+    let mut keyboard = vec![vec![
+        button("Есть места", filter.vacancies, ListFilter { vacancies: !filter.vacancies, ..filter.clone() })?,
+        button("Эта неделя", filter.week, ListFilter { week: !filter.week, ..filter.clone() })?,
+        button("Мои записи", filter.mine, ListFilter { mine: !filter.mine, ..filter.clone() })?,
+    ]];
+    if !text.is_empty() {
+        keyboard.insert(
+            0,
+            vec![InlineKeyboardButton::callback(
+                format!("✔️ Поиск: {}", text),
+                serde_json::to_string(&CallbackQuery::EndSearch { filter: filter.clone() })?,
+            )],
+        );
+    }
+    let categories = db.get_categories()?;
+    if !categories.is_empty() {
+        let mut row = vec![button("Все", filter.category == 0, ListFilter { category: 0, ..filter.clone() })?];
+        for c in categories {
+            if row.len() == 3 {
+                keyboard.push(row);
+                row = Vec::new();
+            }
+            row.push(button(
+                &c.name,
+                filter.category == c.id,
+                ListFilter { category: c.id, ..filter.clone() },
+            )?);
+        }
+        keyboard.push(row);
+    }
+    Ok(keyboard)
+}
+
 pub fn show_event(
-    conn: &PooledConnection<SqliteConnectionManager>,
+    db: &dyn Storage,
     user: &User,
     event_id: u64,
     ctx: &Context,
     ps: Option<String>,
     offset: u64,
 ) -> anyhow::Result<Reply> {
-    match db::get_event(conn, event_id, user.id.0) {
+    match db.get_event(event_id, user.id.0) {
         Ok(s) => {
-            let free_adults = s.event.max_adults as i64 - s.adults.reserved as i64;
-            let free_children = s.event.max_children as i64 - s.children.reserved as i64;
-            let no_age_distinction = s.event.max_adults == 0 || s.event.max_children == 0;
             let is_admin = ctx.admins.contains(&user.id.0);
+            if !s.state.is_public() && !is_admin {
+                return Err(db::Error::EventNotFound(event_id).into());
+            }
+            let (venue, venue_controls) = venue(db, &s)?;
+            let (questions, question_controls) = questionnaire(db, &s, user.id.0)?;
             let (participants, participants_len) = if ctx.config.public_lists || is_admin {
-                let participants = db::get_participants(
-                    conn,
-                    event_id,
+                let participants = db.get_participants(event_id,
                     0,
                     offset,
                     ctx.config.event_page_size,
@@ -428,37 +719,21 @@ pub fn show_event(
 
             Ok(
                 // header
-                ReplyMessage::new(
-                    format::header(
-                        &s,
-                        free_adults,
-                        free_children,
-                        is_admin,
-                        no_age_distinction,
-                    )                    
-                )
+                ReplyMessage::new(format::header(&s, &[], is_admin))
+                .photo(&s.event.poster)
+                .text(venue)
                 // participants
-                .text(participants.and_then(|participants| {
-                    Some(format::participants(
-                        &s,
-                        &participants,
-                        is_admin,
-                        no_age_distinction,
-                    ))
+                .text(participants.map(|participants| {
+                    format::participants(&s, &participants, is_admin)
                 }))
                 // messages
-                .text(format::messages(conn, &s, event_id, is_admin))
+                .text(format::messages(db, &s, event_id, is_admin))
                 // attachment
                 .text({
-                    if is_admin
-                        || s.adults.my_reservation > 0
-                        || s.adults.my_waiting > 0
-                        || s.children.my_reservation > 0
-                        || s.children.my_waiting > 0
-                    {
+                    if is_admin || s.my_reservation() > 0 || s.my_waiting() > 0 {
                         let mut text = "".to_string();
-                        if ctx.config.public_lists == false {
-                            match db::get_attachment(conn, event_id, user.id.0) {
+                        if !ctx.config.public_lists {
+                            match db.get_attachment(event_id, user.id.0) {
                                 Ok(v) => {
                                     if let Some(attachment) = v {
                                         text.push_str(&format!("\nПримечание: {}.", attachment));
@@ -467,19 +742,21 @@ pub fn show_event(
                                 Err(e) => error!("Failed to get attachment: {}", e),
                             }
                         }
-                        if is_admin == false {
+                        if !is_admin {
                             text.push_str("\nКоличество мест можно менять кнопками \"Записаться/Отписаться\". Примечание к брони можно добавить, послав сообщение боту.\n");
                         }
-                        if s.adults.my_reservation + s.children.my_reservation > 0 {
+                        if s.my_reservation() > 0 {
+                            let mine: Vec<u64> = s.tickets.iter().map(|c| c.my_reservation).collect();
                             text.push_str(&format!(
                                 "\n<b>У вас забронировано: {}</b>",
-                                s.adults.my_reservation + s.children.my_reservation
+                                format::tickets(&s.event, &mine)
                             ));
                         }
-                        if s.adults.my_waiting + s.children.my_waiting > 0 {
+                        if s.my_waiting() > 0 {
+                            let mine: Vec<u64> = s.tickets.iter().map(|c| c.my_waiting).collect();
                             text.push_str(&format!(
                                 "\n<b>У вас в списке ожидания: {}</b>",
-                                s.adults.my_waiting + s.children.my_waiting
+                                format::tickets(&s.event, &mine)
                             ));
                         }
                         Some(text)
@@ -487,16 +764,13 @@ pub fn show_event(
                         None
                     }
                 })
+                .text(questions)
                 // controls
-                .keyboard(get_signup_controls(
-                    &s,
-                    free_adults,
-                    free_children,
-                    no_age_distinction,
-                    is_admin,
-                    user.id.0,
-                    conn,
-                )?)
+                .keyboard(venue_controls)
+                .keyboard(calendar_controls(&s)?)
+                .keyboard(registration_controls(db, &s, user.id.0)?)
+                .keyboard(question_controls)
+                .keyboard(get_signup_controls(&s, is_admin, user.id.0, db)?)
                 // pagination
                 .pagination(
                     &CallbackQuery::Event {
@@ -515,120 +789,189 @@ pub fn show_event(
                 .into()
             )
         }
-        Err(e) => Err(anyhow!("Failed to fetch event: {}", e)),
+        Err(e) => Err(e).context("Failed to fetch event"),
     }
 }
 
+/// Address of the event venue and a button sending it as a Telegram venue.
+pub fn venue(
+    db: &dyn Storage,
+    s: &EventStats,
+) -> anyhow::Result<(Option<String>, Vec<Vec<InlineKeyboardButton>>)> {
+    if s.event.venue_id == 0 {
+        return Ok((None, Vec::new()));
+    }
+    match db.get_venue(s.event.venue_id) {
+        Ok(v) => Ok((
+            Some(format::venue(&v)),
+            vec![vec![InlineKeyboardButton::callback(
+                "📍 Место проведения",
+                serde_json::to_string(&CallbackQuery::ShowVenue { venue_id: v.id })?,
+            )]],
+        )),
+        Err(e) => {
+            error!("Failed to get venue: {}", e);
+            Ok((None, Vec::new()))
+        }
+    }
+}
+
+/// Button sending the event as an iCalendar file.
+pub fn calendar_controls(s: &EventStats) -> anyhow::Result<Vec<Vec<InlineKeyboardButton>>> {
+    Ok(vec![vec![InlineKeyboardButton::callback(
+        "📅 В календарь",
+        serde_json::to_string(&CallbackQuery::Calendar { event_id: s.event.id })?,
+    )]])
+}
+
+/// Event with its venue, if set, for the calendar export.
+fn calendar_entry(db: &dyn Storage, s: EventStats) -> (EventStats, Option<Venue>) {
+    let venue = if s.event.venue_id != 0 {
+        db.get_venue(s.event.venue_id)
+            .map_err(|e| error!("Failed to get venue: {}", e))
+            .ok()
+    } else {
+        None
+    };
+    (s, venue)
+}
+
+fn calendar_document(
+    db: &dyn Storage,
+    events: Vec<EventStats>,
+    file_name: String,
+) -> Reply {
+    let events: Vec<(EventStats, Option<Venue>)> =
+        events.into_iter().map(|s| calendar_entry(db, s)).collect();
+    Reply::Document {
+        file_name,
+        content: calendar::calendar(&events, &env::var("BOT_NAME").unwrap(), get_unix_time()),
+    }
+}
+
+/// The event as an .ics file.
+fn event_calendar(
+    db: &dyn Storage,
+    user: &User,
+    event_id: u64,
+    ctx: &Context,
+) -> anyhow::Result<Reply> {
+    let s = db.get_event(event_id, user.id.0).context("Failed to fetch event")?;
+    if !s.state.is_public() && !ctx.admins.contains(&user.id.0) {
+        return Err(db::Error::EventNotFound(event_id).into());
+    }
+    Ok(calendar_document(db, vec![s], format!("event-{}.ics", event_id)))
+}
+
+/// Confirmed upcoming reservations of the user as one .ics file.
+fn user_calendar(db: &dyn Storage, user: &User, ctx: &Context) -> anyhow::Result<Reply> {
+    let filter = db::EventFilter {
+        mine: true,
+        ..Default::default()
+    };
+    let now = get_unix_time();
+    let events: Vec<EventStats> = db
+        .get_events(user.id.0, ctx.admins.contains(&user.id.0), &filter, 0, MAX_CALENDAR_EVENTS)
+        .context("Failed to fetch events")?
+        .into_iter()
+        .filter(|s| s.my_reservation() > 0 && s.event.end() > now)
+        .collect();
+    if events.is_empty() {
+        return Ok(ReplyMessage::new("У вас нет предстоящих бронирований.").into());
+    }
+    Ok(calendar_document(db, events, "calendar.ics".to_string()))
+}
+
+/// Button asking to be told when registration opens, shown until it does.
+pub fn registration_controls(
+    db: &dyn Storage,
+    s: &EventStats,
+    user_id: u64,
+) -> anyhow::Result<Vec<Vec<InlineKeyboardButton>>> {
+    if s.state != EventState::Open || get_unix_time() >= s.event.registration_opens {
+        return Ok(Vec::new());
+    }
+    let subscribed = db.is_subscribed_to_registration(s.event.id, user_id)?;
+    Ok(vec![vec![InlineKeyboardButton::callback(
+        if subscribed {
+            "🔕 Не сообщать об открытии записи"
+        } else {
+            "🔔 Сообщить об открытии записи"
+        },
+        serde_json::to_string(&CallbackQuery::SubscribeToRegistration {
+            event_id: s.event.id,
+            subscribe: !subscribed,
+        })?,
+    )]])
+}
+
 fn get_signup_controls(
     s: &EventStats,
-    free_adults: i64,
-    free_children: i64,
-    no_age_distinction: bool,
     is_admin: bool,
     user_id: u64,
-    conn: &PooledConnection<SqliteConnectionManager>,
+    db: &dyn Storage,
 ) -> anyhow::Result<Vec<Vec<InlineKeyboardButton>>> {
     let mut keyboard: Vec<Vec<InlineKeyboardButton>> = Vec::new();
-    let mut row: Vec<InlineKeyboardButton> = Vec::new();
-    if s.state == EventState::Open && s.adults.my_reservation < s.event.max_adults_per_reservation {
-        if free_adults > 0 {
-            row.push(InlineKeyboardButton::callback(
-                if no_age_distinction {
-                    "Записаться +1"
-                } else {
-                    "Записать взрослого +1"
-                },
-                &serde_json::to_string(&CallbackQuery::SignUp {
-                    event_id: s.event.id,
-                    is_adult: true,
-                    wait: false,
-                })?,
-            ));
-        } else if s.adults.my_reservation + s.adults.my_waiting < s.event.max_adults_per_reservation
-        {
-            row.push(InlineKeyboardButton::callback(
-                if no_age_distinction {
-                    "В лист ожидания +1"
-                } else {
-                    "В лист ожидания взрослого +1"
-                },
-                &serde_json::to_string(&CallbackQuery::SignUp {
-                    event_id: s.event.id,
-                    is_adult: true,
-                    wait: true,
-                })?,
-            ));
+    let mut row: Vec<InlineKeyboardButton>;
+    // With a single ticket type there is nothing to tell apart.
+    let single = s.event.tickets.len() == 1;
+    let open = s.state == EventState::Open && s.event.registration_open(get_unix_time());
+    for (i, (t, c)) in s.event.tickets.iter().zip(&s.tickets).enumerate() {
+        let ticket = i as u64;
+        row = Vec::new();
+        if open && c.my_reservation < t.max_per_reservation {
+            if s.vacancies(i) > 0 {
+                row.push(InlineKeyboardButton::callback(
+                    if single {
+                        "Записаться +1".to_string()
+                    } else {
+                        format!("Записать: {} +1", t.name)
+                    },
+                    &serde_json::to_string(&CallbackQuery::SignUp {
+                        event_id: s.event.id,
+                        ticket,
+                        wait: false,
+                    })?,
+                ));
+            } else if c.my_reservation + c.my_waiting < t.max_per_reservation {
+                row.push(InlineKeyboardButton::callback(
+                    if single {
+                        "В лист ожидания +1".to_string()
+                    } else {
+                        format!("В лист ожидания: {} +1", t.name)
+                    },
+                    &serde_json::to_string(&CallbackQuery::SignUp {
+                        event_id: s.event.id,
+                        ticket,
+                        wait: true,
+                    })?,
+                ));
+            }
         }
-    }
-    if s.adults.my_reservation > 0 || s.adults.my_waiting > 0 {
-        row.push(InlineKeyboardButton::callback(
-            if no_age_distinction {
-                "Отписаться -1"
-            } else {
-                "Отписать взрослого -1"
-            },
-            &serde_json::to_string(&CallbackQuery::Cancel {
-                event_id: s.event.id,
-                is_adult: true,
-            })?,
-        ));
-    }
-    keyboard.push(row);
-    row = Vec::new();
-    if s.state == EventState::Open && s.children.my_reservation < s.event.max_children_per_reservation {
-        if free_children > 0 {
+        if c.my_reservation > 0 || c.my_waiting > 0 {
             row.push(InlineKeyboardButton::callback(
-                if no_age_distinction {
-                    "Записаться +1"
+                if single {
+                    "Отписаться -1".to_string()
                 } else {
-                    "Записать ребёнка +1"
+                    format!("Отписать: {} -1", t.name)
                 },
-                &serde_json::to_string(&CallbackQuery::SignUp {
+                &serde_json::to_string(&CallbackQuery::Cancel {
                     event_id: s.event.id,
-                    is_adult: false,
-                    wait: false,
-                })?,
-            ));
-        } else if s.children.my_reservation + s.children.my_waiting
-            < s.event.max_children_per_reservation
-        {
-            row.push(InlineKeyboardButton::callback(
-                if no_age_distinction {
-                    "В лист ожидания +1"
-                } else {
-                    "В лист ожидания ребёнка +1"
-                },
-                &serde_json::to_string(&CallbackQuery::SignUp {
-                    event_id: s.event.id,
-                    is_adult: false,
-                    wait: true,
+                    ticket,
                 })?,
             ));
         }
+        keyboard.push(row);
     }
-    if s.children.my_reservation > 0 || s.children.my_waiting > 0 {
-        row.push(InlineKeyboardButton::callback(
-            if no_age_distinction {
-                "Отписаться -1"
-            } else {
-                "Отписать ребёнка -1"
-            },
-            &serde_json::to_string(&CallbackQuery::Cancel {
-                event_id: s.event.id,
-                is_adult: false,
-            })?,
-        ));
-    }
-    keyboard.push(row);
 
     row = Vec::new();
     row.push(InlineKeyboardButton::callback(
         "Список мероприятий",
-        serde_json::to_string(&CallbackQuery::EventList { offset: 0 })?,
+        serde_json::to_string(&CallbackQuery::EventList { offset: 0, filter: ListFilter::default() })?,
     ));
 
     let event_id = s.event.id;
-    if s.adults.reserved > 0 || s.children.reserved > 0 {
+    if s.reserved() > 0 {
         row.push(InlineKeyboardButton::callback(
             "Список ожидания",
             &serde_json::to_string(&CallbackQuery::ShowWaitingList {
@@ -639,7 +982,7 @@ fn get_signup_controls(
     }
     
     if is_admin {
-        if s.adults.reserved > 0 || s.children.reserved > 0 {
+        if s.reserved() > 0 {
             row.push(InlineKeyboardButton::callback(
                 "Присутствие",
                 &serde_json::to_string(&CallbackQuery::ShowPresenceList {
@@ -648,20 +991,11 @@ fn get_signup_controls(
                 })?,
             ));
         }
-        if s.state == EventState::Open {
-            row.push(InlineKeyboardButton::callback(
-                "Остановить запись",
-                serde_json::to_string(&CallbackQuery::ChangeEventState { event_id, state: 1 })?,
-            ));
-        } else {
-            row.push(InlineKeyboardButton::callback(
-                "Разрешить запись",
-                serde_json::to_string(&CallbackQuery::ChangeEventState { event_id, state: 0 })?,
-            ));
-        }
+        keyboard.push(row);
+        row = state_controls(s)?;
     } else {
-        if s.adults.reserved > 0 || s.children.reserved > 0 {
-            if let Ok(check) = db::is_group_leader(conn, event_id, user_id) {
+        if s.reserved() > 0 {
+            if let Ok(check) = db.is_group_leader(event_id, user_id) {
                 if check {
                     row.push(InlineKeyboardButton::callback(
                         "Присутствие",
@@ -678,32 +1012,72 @@ fn get_signup_controls(
     Ok(keyboard)
 }
 
+/// Admin buttons moving the event through its lifecycle.
+pub fn state_controls(s: &EventStats) -> anyhow::Result<Vec<InlineKeyboardButton>> {
+    let event_id = s.event.id;
+    let button = |text: &str, state: EventState| -> anyhow::Result<InlineKeyboardButton> {
+        Ok(InlineKeyboardButton::callback(
+            text,
+            serde_json::to_string(&CallbackQuery::ChangeEventState {
+                event_id,
+                state: state as u64,
+            })?,
+        ))
+    };
+    let mut row = Vec::new();
+    match s.state {
+        EventState::Draft => row.push(button("Опубликовать", EventState::Open)?),
+        EventState::Open => row.push(button("Остановить запись", EventState::Closed)?),
+        EventState::Closed => row.push(button("Разрешить запись", EventState::Open)?),
+        EventState::Cancelled | EventState::Completed => {}
+    }
+    if s.state == EventState::Open || s.state == EventState::Closed {
+        row.push(InlineKeyboardButton::callback(
+            "Отменить мероприятие",
+            serde_json::to_string(&CallbackQuery::ConfirmCancelEvent { event_id })?,
+        ));
+        if s.event.ts <= get_unix_time() {
+            row.push(button("Завершить", EventState::Completed)?);
+        }
+    }
+    if s.event.series_id != 0 && s.state != EventState::Completed {
+        row.push(InlineKeyboardButton::callback(
+            "Отменить серию",
+            serde_json::to_string(&CallbackQuery::ConfirmCancelSeries {
+                series_id: s.event.series_id,
+            })?,
+        ));
+    }
+    row.push(InlineKeyboardButton::callback(
+        "Дублировать",
+        serde_json::to_string(&CallbackQuery::DuplicateEvent { event_id })?,
+    ));
+    Ok(row)
+}
+
 fn show_waiting_list(
-    conn: &PooledConnection<SqliteConnectionManager>,
+    db: &dyn Storage,
     user: &User,
     event_id: u64,
     ctx: &Context,
     offset: u64,
 ) -> anyhow::Result<Reply> {
     let mut list = "".to_string();
-    let no_age_distinction;
     let is_admin = ctx.admins.contains(&user.id.0);
-    match db::get_event(conn, event_id, user.id.0) {
+    let event = match db.get_event(event_id, user.id.0) {
         Ok(s) => {
-            no_age_distinction = s.event.max_adults == 0 || s.event.max_children == 0;
             list.push_str(&format!(
                 "\n \n{}\nНачало: {}\n",
                 format::event_title(&s.event),
-                format::ts(s.event.ts)
+                format::event_ts(&s.event, s.event.ts)
             ));
+            s.event
         }
         Err(e) => {
-            return Err(anyhow!("Failed to find event: {}", e));
+            return Err(e).context("Failed to find event");
         }
-    }
-    match db::get_participants(
-        conn,
-        event_id,
+    };
+    match db.get_participants(event_id,
         1,
         offset,
         ctx.config.event_page_size,
@@ -712,7 +1086,7 @@ fn show_waiting_list(
         Ok(participants) => {
             Ok(
                 ReplyMessage::new(                    
-                    if participants.len() == 0 {
+                    if participants.is_empty() {
                         "Пустой список ожидания.".to_string()
                     } else {
                         "Список ожидания:\n".to_string() +
@@ -725,7 +1099,7 @@ fn show_waiting_list(
                                 } else {
                                     "".to_string()
                                 };
-                                if p.user_name2.len() > 0 {
+                                if !p.user_name2.is_empty() {
                                     entry.push_str(&format!(
                                         "\n{} <a href=\"https://t.me/{}\">{} ({})</a>",
                                         id, p.user_name2, p.user_name1, p.user_name2
@@ -736,14 +1110,13 @@ fn show_waiting_list(
                                         id, p.user_id, p.user_name1
                                     ));
                                 }
-                                if no_age_distinction {
-                                    entry.push_str(&format!(" {}", p.adults + p.children));
-                                } else {
-                                    entry.push_str(&format!(" {}({})", p.adults, p.children));
-                                }
+                                entry.push_str(&format!(" {}", format::tickets(&event, &p.tickets)));
                                 if let Some(a) = &p.attachment {
                                     entry.push_str(&format!(" {}", a));
                                 }
+                                if is_admin && p.answers.iter().any(|a| !a.is_empty()) {
+                                    entry.push_str(&format!(" ({})", format::answers(&event, &p.answers)));
+                                }
                                 entry
                             })
                             .collect::<String>()
@@ -775,17 +1148,17 @@ fn show_waiting_list(
                 .into()
             )
         }
-        Err(e) => Err(anyhow!("Failed to get participants: {}", e)),
+        Err(e) => Err(e).context("Failed to get participants"),
     }
 }
 
 fn is_too_late_to_cancel(
-    conn: &PooledConnection<SqliteConnectionManager>,
+    db: &dyn Storage,
     event_id: u64,
     user: &User,
     ctx: &Context,
 ) -> bool {
-    if let Ok(s) = db::get_event(conn, event_id, user.id.0) {
+    if let Ok(s) = db.get_event(event_id, user.id.0) {
         if s.event.ts - get_unix_time() < ctx.config.too_late_to_cancel_hours * 60 * 60 {
             return true;
         }
@@ -794,31 +1167,32 @@ fn is_too_late_to_cancel(
 }
 
 fn show_presence_list(
-    conn: &PooledConnection<SqliteConnectionManager>,
+    db: &dyn Storage,
     event_id: u64,
     user: &User,
     ctx: &Context,
     offset: u64,
 ) -> anyhow::Result<Reply> {
     let mut header = "".to_string();
-    match db::get_event(conn, event_id, user.id.0) {
+    let event = match db.get_event(event_id, user.id.0) {
         Ok(s) => {
             header.push_str(&format!(
                 "\n \n{}\nНачало: {}\n",
                 format::event_title(&s.event),
-                format::ts(s.event.ts)
+                format::event_ts(&s.event, s.event.ts)
             ));
+            s.event
         }
         Err(e) => {
-            return Err(anyhow!("Failed to find event: {}", e));
+            return Err(e).context("Failed to find event");
         }
-    }
-    match db::get_presence_list(conn, event_id, offset, ctx.config.presence_page_size) {
+    };
+    match db.get_presence_list(event_id, offset, ctx.config.presence_page_size) {
         Ok(participants) => {
             Ok(
                 // header
                 ReplyMessage::new(              
-                    if participants.len() == 0 {
+                    if participants.is_empty() {
                         "Пустой список ожидания."
                     } else {
                         "Пожалуйста, выберите присутствующих:\n"
@@ -830,7 +1204,7 @@ fn show_presence_list(
                     .map(|p| {
                         vec![{
                             let mut text;
-                            if p.user_name2.len() > 0 {
+                            if !p.user_name2.is_empty() {
                                 text = format!("{} ({}) {}", p.user_name1, p.user_name2, p.reserved);
                             } else {
                                 text = format!("{} {}", p.user_name1, p.reserved);
@@ -838,13 +1212,16 @@ fn show_presence_list(
                             if let Some(a) = &p.attachment {
                                 text.push_str(&format!(" - {}", a));
                             }
+                            if p.answers.iter().any(|a| !a.is_empty()) {
+                                text.push_str(&format!(" ({})", format::answers(&event, &p.answers)));
+                            }
     
                             InlineKeyboardButton::callback(
                                 text,
-                                &serde_json::to_string(&CallbackQuery::ConfirmPresence {
+                                serde_json::to_string(&CallbackQuery::ConfirmPresence {
                                     event_id,
                                     user_id: p.user_id,
-                                    offset: offset,
+                                    offset,
                                 })
                                 .unwrap(),
                             )
@@ -878,6 +1255,150 @@ fn show_presence_list(
                 .into()
             )
         }
-        Err(e) => Err(anyhow!("Failed to get precense list: {}", e)),
+        Err(e) => Err(e).context("Failed to get precense list"),
     }
 }
+
+#[test]
+fn test_sign_up() -> anyhow::Result<()> {
+    use crate::db::tests::{add_published_event, event, test_context, user};
+
+    let ctx = test_context(&[]);
+    let db = ctx.storage.as_ref();
+    let mut e = event(1, 0, get_unix_time() + 24 * 60 * 60);
+    e.tickets.truncate(1);
+    let event_id = add_published_event(db, e)?;
+    let (user, other) = (user(10), user(20));
+
+    let sign_up = serde_json::to_string(&CallbackQuery::SignUp {
+        event_id,
+        ticket: 0,
+        wait: false,
+    })?;
+    match handle_callback(db, &user, &sign_up, &ctx)? {
+        Reply::Message(m) => assert!(m.message.contains("Свободные места: 0")),
+        _ => panic!("unexpected reply"),
+    }
+    assert_eq!(db.get_event(event_id, 10)?.my_reservation(), 1);
+
+    // Other users can't book anymore.
+    assert!(handle_callback(db, &other, &sign_up, &ctx).is_err());
+
+    // A text message becomes a note to the current reservation.
+    handle_message(db, &user, "with a dog", &ctx)?;
+    assert_eq!(
+        db.get_attachment(event_id, 10)?,
+        Some("with a dog...".to_string())
+    );
+    Ok(())
+}
+
+#[test]
+fn test_questionnaire() -> anyhow::Result<()> {
+    use crate::db::tests::{add_published_event, event, test_context, user};
+
+    let ctx = test_context(&[]);
+    let db = ctx.storage.as_ref();
+    let mut e = event(5, 0, get_unix_time() + 24 * 60 * 60);
+    e.questions = vec!["Возраст детей".to_string(), "Аллергии".to_string()];
+    let event_id = add_published_event(db, e.clone())?;
+    e.questions = vec![];
+    let other_event = add_published_event(db, e)?;
+    let user = user(10);
+    let text = |reply: Reply| match reply {
+        Reply::Message(m) => m.message,
+        _ => panic!("unexpected reply"),
+    };
+
+    let sign_up = serde_json::to_string(&CallbackQuery::SignUp {
+        event_id,
+        ticket: 0,
+        wait: false,
+    })?;
+    assert!(text(handle_callback(db, &user, &sign_up, &ctx)?).contains("Вопрос 1 из 2"));
+
+    // Answers go to the booked event even after looking at another one.
+    show_event(db, &user, other_event, &ctx, None, 0)?;
+    assert!(text(handle_message(db, &user, "5 и 7", &ctx)?).contains("Вопрос 2 из 2"));
+    assert!(text(handle_message(db, &user, "нет", &ctx)?).contains("ответы сохранены"));
+    assert_eq!(db.get_answers(event_id, 10)?, vec!["5 и 7", "нет"]);
+    assert_eq!(db.get_questionnaire(10)?, 0);
+    assert!(text(show_event(db, &user, event_id, &ctx, None, 0)?).contains("Возраст детей: 5 и 7; Аллергии: нет"));
+
+    // Further messages are notes again.
+    handle_message(db, &user, "with a dog", &ctx)?;
+    assert_eq!(db.get_attachment(event_id, 10)?, Some("with a dog...".to_string()));
+
+    let change = serde_json::to_string(&CallbackQuery::ChangeAnswers { event_id })?;
+    assert!(text(handle_callback(db, &user, &change, &ctx)?).contains("Вопрос 1 из 2"));
+    assert_eq!(db.get_answers(event_id, 10)?, vec!["", ""]);
+    Ok(())
+}
+
+#[test]
+fn test_event_list_filter() -> anyhow::Result<()> {
+    // Telegram limits callback data to 64 bytes.
+    let q = CallbackQuery::EventList {
+        offset: 999,
+        filter: ListFilter {
+            category: 99999,
+            vacancies: true,
+            week: false,
+            mine: false,
+        },
+    };
+    assert!(serde_json::to_string(&q)?.len() <= 64);
+    Ok(())
+}
+
+#[test]
+fn test_search() -> anyhow::Result<()> {
+    use crate::db::tests::{add_published_event, event, test_context, user};
+
+    let ctx = test_context(&[]);
+    let db = ctx.storage.as_ref();
+    let mut e = event(5, 0, get_unix_time() + 24 * 60 * 60);
+    e.name = "Museum night".to_string();
+    add_published_event(db, e.clone())?;
+    e.name = "Concert".to_string();
+    let concert = add_published_event(db, e)?;
+    let user = user(10);
+    let buttons = |reply: Reply| -> Vec<String> {
+        match reply {
+            Reply::Message(m) => m.keyboard.unwrap_or_default().into_iter().flatten().map(|b| b.text).collect(),
+            _ => panic!("unexpected reply"),
+        }
+    };
+    let listed = |buttons: &[String], name: &str| buttons.iter().any(|b| b.ends_with(name));
+
+    // Without a reservation to attach it to, free text is searched for.
+    let found = buttons(handle_message(db, &user, "museum", &ctx)?);
+    assert!(listed(&found, "Museum night") && !listed(&found, "Concert"));
+    assert!(found.contains(&"✔️ Поиск: museum".to_string()));
+    // Paging and returning to the list keep the search.
+    let list = serde_json::to_string(&CallbackQuery::EventList { offset: 0, filter: ListFilter::default() })?;
+    assert!(!listed(&buttons(handle_callback(db, &user, &list, &ctx)?), "Concert"));
+    let end = serde_json::to_string(&CallbackQuery::EndSearch { filter: ListFilter::default() })?;
+    let all = buttons(handle_callback(db, &user, &end, &ctx)?);
+    assert!(listed(&all, "Museum night") && listed(&all, "Concert"));
+    assert_eq!(db.get_search(10)?, "");
+
+    let found = buttons(handle_message(db, &user, "/search !!!", &ctx)?);
+    assert!(!listed(&found, "Concert") && !listed(&found, "Museum night"));
+    let found = buttons(handle_message(db, &user, "/search concert", &ctx)?);
+    assert!(listed(&found, "Concert") && !listed(&found, "Museum night"));
+    buttons(handle_message(db, &user, "/start", &ctx)?);
+    assert_eq!(db.get_search(10)?, "");
+
+    // With one, it's a note.
+    let sign_up = serde_json::to_string(&CallbackQuery::SignUp {
+        event_id: concert,
+        ticket: 0,
+        wait: false,
+    })?;
+    handle_callback(db, &user, &sign_up, &ctx)?;
+    handle_message(db, &user, "museum", &ctx)?;
+    assert_eq!(db.get_attachment(concert, 10)?, Some("museum...".to_string()));
+    assert_eq!(db.get_search(10)?, "");
+    Ok(())
+}
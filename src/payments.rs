@@ -1,23 +1,19 @@
-use crate::message_handler::CallbackQuery;
+use crate::message_handler::{self, CallbackQuery};
 use crate::types::{
     Booking, Context, EventState, OrderInfo, ReservationState, User,
 };
 use crate::reply::*;
 use crate::util::{get_unix_time};
-use anyhow::anyhow;
-use teloxide::types::{
-    Currency, InlineKeyboardButton, PreCheckoutQuery, SuccessfulPayment,
-};
+use anyhow::{anyhow, Context as _};
+use teloxide::types::{InlineKeyboardButton, PreCheckoutQuery, SuccessfulPayment};
 
 use crate::db;
 use crate::format;
-use db::EventStats;
-use r2d2::PooledConnection;
-use r2d2_sqlite::SqliteConnectionManager;
+use db::{EventStats, Storage};
 
 /// Book tickets and wait for payment checkout.
 pub fn pre_checkout(
-    conn: &PooledConnection<SqliteConnectionManager>,
+    db: &dyn Storage,
     user: &User,
     pre_checkout: &PreCheckoutQuery,
     _ctx: &Context,
@@ -25,24 +21,21 @@ pub fn pre_checkout(
     // if pre_checkout.currency != Currency::EUR {
     //     return Err(anyhow!("Only EUR is currently accepted"));
     // }
-    if let Some(_) = &pre_checkout.order_info.name {
+    if pre_checkout.order_info.name.is_some() {
         let booking: Booking = serde_json::from_str(&pre_checkout.invoice_payload)?;
         if booking.event_id == 0 {
             // Donation
             Ok(())
         } else {
-            match db::sign_up(
-                conn,
-                booking.event_id,
+            match db.sign_up(booking.event_id,
                 user,
-                booking.adults,
-                booking.children,
+                &booking.tickets,
                 0,
                 get_unix_time(),
                 pre_checkout.total_amount as u64,
             ) {
                 Ok(_) => Ok(()),
-                Err(e) => Err(anyhow!("{}", e)),
+                Err(e) => Err(e.into()),
             }
         }
     } else {
@@ -52,7 +45,7 @@ pub fn pre_checkout(
 
 /// Payment successful.
 pub fn checkout(
-    conn: &PooledConnection<SqliteConnectionManager>,
+    db: &dyn Storage,
     payment: &SuccessfulPayment,
     _ctx: &Context,
 ) -> anyhow::Result<()> {
@@ -63,9 +56,7 @@ pub fn checkout(
             // todo: save to donations table
             Ok(())
         } else {
-            match db::checkout(
-                conn,
-                &booking,
+            match db.checkout(&booking,
                 OrderInfo {
                     id: payment.telegram_payment_charge_id.to_owned(),
                     name: name.to_owned(),
@@ -73,7 +64,7 @@ pub fn checkout(
                 },
             ) {
                 Ok(_) => Ok(()),
-                Err(e) => Err(anyhow!("{}", e)),
+                Err(e) => Err(e.into()),
             }
         }
     } else {
@@ -83,24 +74,25 @@ pub fn checkout(
 
 pub fn show_paid_event(
     event_id: u64,
-    adults: u64,
-    children: u64,
+    mut tickets: Vec<u64>,
     offset: u64,
-    conn: &PooledConnection<SqliteConnectionManager>,
+    db: &dyn Storage,
     user: &User,
     ctx: &Context,
 ) -> anyhow::Result<Reply> {
-    match db::get_event(conn, event_id, user.id.0) {
+    match db.get_event(event_id, user.id.0) {
         Ok(s) => {
-            let free_adults = s.event.max_adults as i64 - s.adults.reserved as i64 - adults as i64;
-            let free_children = s.event.max_children as i64 - s.children.reserved as i64 - children as i64;
-            let no_age_distinction = s.event.max_adults == 0 || s.event.max_children == 0;
+            // Nothing is selected when coming from the event list.
+            tickets.resize(s.event.tickets.len(), 0);
             let is_admin = ctx.admins.contains(&user.id.0);
+            if !s.state.is_public() && !is_admin {
+                return Err(crate::db::Error::EventNotFound(event_id).into());
+            }
+            let (venue, venue_controls) = message_handler::venue(db, &s)?;
+            let (questions, question_controls) = message_handler::questionnaire(db, &s, user.id.0)?;
 
             let (participants, participants_len) = if is_admin {
-                let participants = db::get_participants(
-                    conn,
-                    event_id,
+                let participants = db.get_participants(event_id,
                     0,
                     offset,
                     ctx.config.event_page_size,
@@ -114,35 +106,21 @@ pub fn show_paid_event(
 
             Ok(
                 // header
-                ReplyMessage::new(format::header(
-                    &s,
-                    free_adults,
-                    free_children,
-                    is_admin,
-                    no_age_distinction,
-                ))
+                ReplyMessage::new(format::header(&s, &tickets, is_admin))
+                .photo(&s.event.poster)
+                .text(venue)
                 // participants
-                .text(participants.and_then(|participants| {
-                    Some(format::participants(
-                        &s,
-                        &participants,
-                        is_admin,
-                        no_age_distinction,
-                    ))
+                .text(participants.map(|participants| {
+                    format::participants(&s, &participants, is_admin)
                 }))
                 // messages
-                .text(format::messages(conn, &s, event_id, is_admin))
+                .text(format::messages(db, &s, event_id, is_admin))
                 // attachment
                 .text({
-                    if is_admin
-                        || s.adults.my_reservation > 0
-                        || s.adults.my_waiting > 0
-                        || s.children.my_reservation > 0
-                        || s.children.my_waiting > 0
-                    {
+                    if is_admin || s.my_reservation() > 0 || s.my_waiting() > 0 {
                         let mut text = "".to_string();
-                        if ctx.config.public_lists == false {
-                            match db::get_attachment(conn, event_id, user.id.0) {
+                        if !ctx.config.public_lists {
+                            match db.get_attachment(event_id, user.id.0) {
                                 Ok(v) => {
                                     if let Some(attachment) = v {
                                         text.push_str(&format!("\nПримечание: {}.", attachment));
@@ -151,19 +129,19 @@ pub fn show_paid_event(
                                 Err(e) => error!("Failed to get attachment: {}", e),
                             }
                         }
-                        if is_admin == false {
+                        if !is_admin {
                             text.push_str("\nКоличество мест можно менять кнопками \"Записаться/Отписаться\". Примечание к брони можно добавить, послав сообщение боту.\n");
                         }
-                        if s.adults.my_reservation + s.children.my_reservation > 0 {
+                        if s.my_reservation() > 0 {
                             text.push_str(&format!(
                                 "\n<b>У вас забронировано: {}</b>",
-                                s.adults.my_reservation + s.children.my_reservation
+                                s.my_reservation()
                             ));
                         }
-                        if s.adults.my_waiting + s.children.my_waiting > 0 {
+                        if s.my_waiting() > 0 {
                             text.push_str(&format!(
                                 "\n<b>У вас в списке ожидания: {}</b>",
-                                s.adults.my_waiting + s.children.my_waiting
+                                s.my_waiting()
                             ));
                         }
                         Some(text)
@@ -173,64 +151,42 @@ pub fn show_paid_event(
                 })
                 // footer
                 .text(
-                    if s.adults.my_reservation + s.children.my_reservation > 0 {
-                        Some(format!("\n<b>Вы ранее купили: {}</b>", s.adults.my_reservation + s.children.my_reservation))
+                    if s.my_reservation() > 0 {
+                        let mine: Vec<u64> = s.tickets.iter().map(|c| c.my_reservation).collect();
+                        Some(format!("\n<b>Вы ранее купили: {}</b>", format::tickets(&s.event, &mine)))
                     } else {
                         None
                     }        
                 )
                 // order
                 .text(
-                    if adults + children > 0 {
-                        let mut order = "Сбор за бронирование: ".to_string();
-                        if no_age_distinction {
-                            order.push_str(&format!("{}", adults));
-                        } else {
-                            if adults > 0 {
-                                order.push_str(&format!("{} взросл.", adults));
-                            }
-                            if children > 0 {
-                                order.push_str(&format!(", {} детск.", children));
-                            }
-                        }
-        
-                        let total_amount = (adults * s.event.adult_ticket_price
-                            + children * s.event.child_ticket_price)
-                            as f32
-                            / 100f32;
+                    if tickets.iter().sum::<u64>() > 0 {
+                        let total_amount = s.event.price(&tickets) as f32 / 100f32;
                         Some(format!(
-                            "\n<b>{}, всего {} {}</b>",
-                            order, total_amount, s.event.currency
+                            "\n<b>Сбор за бронирование: {}, всего {} {}</b>",
+                            format::tickets(&s.event, &tickets), total_amount, s.event.currency
                         ))
                     } else {
                         Some("\nВыберите необходимое количество билетов и нажмите \"К оплате\". Введённое имя будет на билете.".to_string())
                     }                    
                 )
+                .text(questions)
                 // controls
-                .keyboard(get_controls(
-                    &s,
-                    adults,
-                    children,
-                    offset,
-                    free_adults,
-                    free_children,
-                    no_age_distinction,
-                    is_admin,
-                    user.id.0,
-                    conn,
-                )?)
+                .keyboard(venue_controls)
+                .keyboard(message_handler::calendar_controls(&s)?)
+                .keyboard(message_handler::registration_controls(db, &s, user.id.0)?)
+                .keyboard(question_controls)
+                .keyboard(get_controls(&s, &tickets, offset, is_admin)?)
                 // pagination
                 .pagination(
                     &CallbackQuery::PaidEvent {
                         event_id,
-                        adults,
-                        children,
+                        tickets: tickets.clone(),
                         offset: offset.saturating_sub(1),
                     },
                     &CallbackQuery::PaidEvent {
                         event_id,
-                        adults,
-                        children,
+                        tickets: tickets.clone(),
                         offset: offset + 1,
                     },
                     participants_len,
@@ -240,177 +196,113 @@ pub fn show_paid_event(
                 .into()
             )
         }
-        Err(e) => Err(anyhow!("Failed to fetch event: {}", e)),
+        Err(e) => Err(e).context("Failed to fetch event"),
     }
 }
 
 fn get_controls(
     s: &EventStats,
-    adults: u64,
-    children: u64,
+    tickets: &[u64],
     offset: u64,
-    free_adults: i64,
-    free_children: i64,
-    no_age_distinction: bool,
     is_admin: bool,
-    _user_id: u64,
-    _conn: &PooledConnection<SqliteConnectionManager>,
 ) -> anyhow::Result<Vec<Vec<InlineKeyboardButton>>> {
     let mut keyboard: Vec<Vec<InlineKeyboardButton>> = Vec::new();
-    let mut row: Vec<InlineKeyboardButton> = Vec::new();
+    let mut row: Vec<InlineKeyboardButton>;
     let event_id = s.event.id;
+    // With a single ticket type there is nothing to tell apart.
+    let single = s.event.tickets.len() == 1;
+    let with = |i: usize, n: u64| -> anyhow::Result<String> {
+        let mut tickets = tickets.to_vec();
+        tickets[i] = n;
+        Ok(serde_json::to_string(&CallbackQuery::PaidEvent {
+            event_id,
+            tickets,
+            offset,
+        })?)
+    };
 
-    if s.state == EventState::Open {
-        if s.adults.my_reservation + adults < s.event.max_adults_per_reservation {
-            if free_adults > 0 {
+    if s.state == EventState::Open && s.event.registration_open(get_unix_time()) {
+        for (i, (t, c)) in s.event.tickets.iter().zip(&s.tickets).enumerate() {
+            row = Vec::new();
+            if c.my_reservation + tickets[i] < t.max_per_reservation
+                && s.vacancies(i) - (tickets[i] as i64) > 0
+            {
                 row.push(InlineKeyboardButton::callback(
-                    if no_age_distinction {
-                        "Забронировать +1"
+                    if single {
+                        "Забронировать +1".to_string()
                     } else {
-                        "Забронировать взрослый +1"
+                        format!("Забронировать: {} +1", t.name)
                     },
-                    &serde_json::to_string(&CallbackQuery::PaidEvent {
-                        event_id,
-                        adults: adults + 1,
-                        children,
-                        offset,
-                    })?,
+                    &with(i, tickets[i] + 1)?,
                 ));
             }
-        }
-        if adults > 0 {
-            row.push(InlineKeyboardButton::callback(
-                if no_age_distinction {
-                    "Отменить -1"
-                } else {
-                    "Отменить взрослый -1"
-                },
-                &serde_json::to_string(&CallbackQuery::PaidEvent {
-                    event_id,
-                    adults: adults - 1,
-                    children,
-                    offset,
-                })?,
-            ));
-        }
-        keyboard.push(row);
-        row = Vec::new();
-        if s.children.my_reservation + children < s.event.max_children_per_reservation
-        {
-            if free_children > 0 {
+            if tickets[i] > 0 {
                 row.push(InlineKeyboardButton::callback(
-                    if no_age_distinction {
-                        "Забронировать +1"
+                    if single {
+                        "Отменить -1".to_string()
                     } else {
-                        "Забронировать детский +1"
+                        format!("Отменить: {} -1", t.name)
                     },
-                    &serde_json::to_string(&CallbackQuery::PaidEvent {
-                        event_id,
-                        adults,
-                        children: children + 1,
-                        offset,
-                    })?,
+                    &with(i, tickets[i] - 1)?,
                 ));
             }
+            keyboard.push(row);
         }
-        if children > 0 {
-            row.push(InlineKeyboardButton::callback(
-                if no_age_distinction {
-                    "Отменить -1"
-                } else {
-                    "Отменить детский -1"
-                },
-                &serde_json::to_string(&CallbackQuery::PaidEvent {
-                    event_id,
-                    adults,
-                    children: children - 1,
-                    offset,
-                })?,
-            ));
-        }
-        keyboard.push(row);
     }
     row = Vec::new();
     row.push(InlineKeyboardButton::callback(
         "Список мероприятий",
-        serde_json::to_string(&CallbackQuery::EventList { offset: 0 })?,
+        serde_json::to_string(&CallbackQuery::EventList { offset: 0, filter: Default::default() })?,
     ));
 
-    if is_admin {
-        if s.state == EventState::Open {
-            row.push(InlineKeyboardButton::callback(
-                "Остановить запись",
-                serde_json::to_string(&CallbackQuery::ChangeEventState { event_id, state: 1 })?,
-            ));
-        } else {
-            row.push(InlineKeyboardButton::callback(
-                "Разрешить запись",
-                serde_json::to_string(&CallbackQuery::ChangeEventState { event_id, state: 0 })?,
-            ));
-        }
-    }
-
-    if adults + children > 0 {
+    if tickets.iter().sum::<u64>() > 0 {
         row.push(InlineKeyboardButton::callback(
             "К оплате",
             serde_json::to_string(&CallbackQuery::SendInvoice {
                 event_id,
-                adults,
-                children,
+                tickets: tickets.to_vec(),
             })?,
         ));
     }
     keyboard.push(row);
+    if is_admin {
+        keyboard.push(crate::message_handler::state_controls(s)?);
+    }
 
     Ok(keyboard)
 }
 
 pub fn prepare_invoice(
     event_id: u64,
-    adults: u64,
-    children: u64,
-    conn: &PooledConnection<SqliteConnectionManager>,
+    tickets: Vec<u64>,
+    db: &dyn Storage,
     user: &User,
     _ctx: &Context,
 ) -> anyhow::Result<Reply> {
-    match db::get_event(conn, event_id, user.id.0) {
+    match db.get_event(event_id, user.id.0) {
         Ok(s) => {
-            let no_age_distinction = s.event.max_adults == 0 || s.event.max_children == 0;
-            if s.state != EventState::Open {
+            let over_limit = tickets.len() > s.event.tickets.len()
+                || s.event.tickets.iter().zip(&s.tickets).zip(&tickets)
+                    .any(|((t, c), n)| c.my_reservation + n > t.max_per_reservation);
+            if s.state != EventState::Open || !s.event.registration_open(get_unix_time()) {
                 Err(anyhow!("Event has been closed"))
-            } else if s.adults.my_reservation + adults > s.event.max_adults_per_reservation
-                || s.children.my_reservation + children > s.event.max_children_per_reservation
-            {
+            } else if over_limit {
                 Err(anyhow!("Limits error"))
             } else {
-                let mut title = "Билеты: ".to_string();
-                if no_age_distinction {
-                    title.push_str(&format!("{}", adults + children));
-                } else {
-                    if adults > 0 {
-                        title.push_str(&format!("{} взросл. ", adults));
-                    }
-                    if children > 0 {
-                        title.push_str(&format!(", {} детск.", children));
-                    }
-                }
-
                 Ok(Reply::Invoice {
-                    title,
-                    description: format!("{} - {}", s.event.name, format::ts(s.event.ts)),
+                    title: format!("Билеты: {}", format::tickets(&s.event, &tickets)),
+                    description: format!("{} - {}", s.event.name, format::event_ts(&s.event, s.event.ts)),
+                    amount: s.event.price(&tickets),
                     currency: s.event.currency,
-                    amount: adults * s.event.adult_ticket_price
-                        + children * s.event.child_ticket_price,
                     payload: serde_json::to_string(&Booking {
                         event_id,
-                        adults,
-                        children,
+                        tickets,
                         user_id: user.id.0,
                     })?,
                 })
             }
         }
-        Err(e) => Err(anyhow!("Failed to fetch event: {}", e)),
+        Err(e) => Err(e).context("Failed to fetch event"),
     }
 }
 
@@ -426,8 +318,7 @@ pub fn donate(
             amount,
             payload: serde_json::to_string(&Booking {
                 event_id: 0,
-                adults: 0,
-                children: 0,
+                tickets: vec![],
                 user_id: user.id.0,
             })?,
         })
@@ -1,9 +1,12 @@
 use teloxide::{
     prelude::*,
-    types::{InlineKeyboardButton, InlineKeyboardMarkup, ParseMode},
+    types::{InlineKeyboardButton, InlineKeyboardMarkup, InputFile, InputMedia, InputMediaPhoto, ParseMode},
     RequestError,
 };
 
+/// Telegram limit for photo captions.
+const MAX_CAPTION_LENGTH: usize = 1024;
+
 /// Internal presentation for bot replies.
 pub enum Reply {
     Message(ReplyMessage),
@@ -14,6 +17,15 @@ pub enum Reply {
         currency: String,
         amount: u64,
     },
+    /// Native venue message with a map.
+    Venue {
+        latitude: f64,
+        longitude: f64,
+        title: String,
+        address: String,
+    },
+    /// File sent as a document.
+    Document { file_name: String, content: String },
 }
 #[derive(Debug)]
 pub struct ReplyMessage {
@@ -21,6 +33,8 @@ pub struct ReplyMessage {
     pub parse_mode: ParseMode,
     pub disable_preview: bool,
     pub keyboard: Option<Vec<Vec<InlineKeyboardButton>>>,
+    /// Telegram file id of a photo to send the message as its caption.
+    pub photo: Option<String>,
 }
 impl ReplyMessage {
     pub fn new<T>(message: T) -> Self
@@ -32,6 +46,7 @@ impl ReplyMessage {
             parse_mode: ParseMode::Html,
             disable_preview: true,
             keyboard: None,
+            photo: None,
         }
     }
 
@@ -46,7 +61,7 @@ impl ReplyMessage {
     }
 
     pub fn keyboard(mut self, mut keyboard: Vec<Vec<InlineKeyboardButton>>) -> Self {
-        if keyboard.len() != 0 {
+        if !keyboard.is_empty() {
             match self.keyboard {
                 Some(ref mut k) => k.append(&mut keyboard),
                 None => {
@@ -73,13 +88,13 @@ impl ReplyMessage {
             if offset > 0 {
                 pagination.push(InlineKeyboardButton::callback(
                     "⬅️",
-                    serde_json::to_string::<T>(&prev.into())?,
+                    serde_json::to_string::<T>(&prev)?,
                 ));
             }
             if participants == limit {
                 pagination.push(InlineKeyboardButton::callback(
                     "➡️",
-                    serde_json::to_string::<T>(&next.into())?,
+                    serde_json::to_string::<T>(&next)?,
                 ));
             }
             match self.keyboard {
@@ -97,7 +112,37 @@ impl ReplyMessage {
         self
     }
 
+    /// Empty file ids are ignored.
+    pub fn photo(mut self, file_id: &str) -> Self {
+        if !file_id.is_empty() {
+            self.photo = Some(file_id.to_string());
+        }
+        self
+    }
+
+    /// The photo, unless the text doesn't fit into a caption and is sent without it.
+    fn caption_photo(&self) -> Option<InputFile> {
+        match &self.photo {
+            Some(file_id) if self.message.chars().count() <= MAX_CAPTION_LENGTH => Some(InputFile::file_id(file_id)),
+            _ => None,
+        }
+    }
+
     pub async fn send(self, msg: &Message, bot: &AutoSend<Bot>) -> Result<(), RequestError> {
+        if let Some(photo) = self.caption_photo() {
+            let mut req = bot
+                .send_photo(msg.chat.id, photo)
+                .caption(self.message)
+                .parse_mode(self.parse_mode);
+            if let Some(keyboard) = self.keyboard {
+                req = req.reply_markup(InlineKeyboardMarkup::new(keyboard));
+            }
+            req.await.map_err(|e| {
+                error!("Failed to send photo to Telegram: {}", e);
+                e
+            })?;
+            return Ok(());
+        }
         let fut = if let Some(keyboard) = self.keyboard {
             bot.send_message(msg.chat.id, self.message)
                 .parse_mode(self.parse_mode)
@@ -108,14 +153,39 @@ impl ReplyMessage {
                 .parse_mode(self.parse_mode)
                 .disable_web_page_preview(self.disable_preview)
         };
-        fut.await.or_else(|e| {
+        fut.await.map_err(|e| {
             error!("Failed to send message to Telegram: {}", e);
-            Err(e)
+            e
         })?;
         Ok(())
     }
 
+    /// Text and photo messages can't be turned into each other, such messages are replaced.
     pub async fn edit(self, msg: &Message, bot: &AutoSend<Bot>) -> Result<(), RequestError> {
+        let photo = self.caption_photo();
+        if photo.is_some() != msg.photo().is_some() {
+            self.send(msg, bot).await?;
+            if let Err(e) = bot.delete_message(msg.chat.id, msg.id).await {
+                warn!("Failed to delete replaced message: {}", e);
+            }
+            return Ok(());
+        }
+        if let Some(photo) = photo {
+            let media = InputMedia::Photo(
+                InputMediaPhoto::new(photo)
+                    .caption(self.message)
+                    .parse_mode(self.parse_mode),
+            );
+            let mut req = bot.edit_message_media(msg.chat.id, msg.id, media);
+            if let Some(keyboard) = self.keyboard {
+                req = req.reply_markup(InlineKeyboardMarkup::new(keyboard));
+            }
+            req.await.map_err(|e| {
+                error!("Failed to send photo to Telegram: {}", e);
+                e
+            })?;
+            return Ok(());
+        }
         let fut = if let Some(keyboard) = self.keyboard {
             bot.edit_message_text(msg.chat.id, msg.id, self.message)
                 .parse_mode(self.parse_mode)
@@ -126,16 +196,16 @@ impl ReplyMessage {
                 .parse_mode(self.parse_mode)
                 .disable_web_page_preview(self.disable_preview)
         };
-        fut.await.or_else(|e| {
+        fut.await.map_err(|e| {
             error!("Failed to send message to Telegram: {}", e);
-            Err(e)
+            e
         })?;
         Ok(())
     }
 }
 
-impl Into<Reply> for ReplyMessage {
-    fn into(self) -> Reply {
-        Reply::Message(self)
+impl From<ReplyMessage> for Reply {
+    fn from(message: ReplyMessage) -> Self {
+        Reply::Message(message)
     }
 }
@@ -1,13 +1,13 @@
 use chrono::DateTime;
-use r2d2_sqlite::SqliteConnectionManager;
 use serde_compact::compact;
-use std::collections::HashSet;
+use std::collections::{HashMap, HashSet};
 use std::sync::Arc;
-use tokio::sync::Mutex;
+use tokio::sync::{Mutex, OwnedMutexGuard};
 
 use teloxide::{types::{UserId}};
 
-pub type DbPool = r2d2::Pool<SqliteConnectionManager>;
+use crate::db::Storage;
+
 //pub type EventId = u64;
 
 #[derive(Deserialize, Serialize, Clone, Default, Debug)]
@@ -18,6 +18,8 @@ pub struct Configuration {
     pub public_lists: bool,
     pub automatic_blacklisting: bool,
     pub drop_events_after_hours: u64,
+    pub delete_archived_events_after_days: Option<u64>,
+    pub series_horizon_days: Option<u64>,
     pub delete_from_black_list_after_days: u64,
     pub too_late_to_cancel_hours: u64,
     pub cleanup_old_events: bool,
@@ -31,10 +33,25 @@ pub struct Configuration {
     pub mailing_hours: String,
     pub mailing_hours_from: Option<u64>,
     pub mailing_hours_to: Option<u64>,
+    pub database_url: Option<String>,
+    /// IANA name of the zone for events without an own one.
+    pub time_zone: Option<String>,
+    /// Zone of events without an own one, set by `parse`. UTC if `time_zone` is not set.
+    #[serde(skip)]
+    pub default_time_zone: Option<chrono_tz::Tz>,
 }
 
 impl Configuration {
+    /// How far ahead occurrences of event series are created, in seconds.
+    pub fn series_horizon(&self) -> u64 {
+        self.series_horizon_days.unwrap_or(28) * 24 * 60 * 60
+    }
+
     pub fn parse(&mut self) -> Result<(), String> {
+        self.default_time_zone = Some(match self.time_zone.as_deref() {
+            Some(name) => name.parse().map_err(|_| format!("Unknown time zone {}", name))?,
+            None => chrono_tz::Tz::UTC,
+        });
         let parts: Vec<&str> = self.mailing_hours.split('.').collect();
         if parts.len() != 3 {
             return Err("Wrong mailing hours format.".to_string());
@@ -60,39 +77,143 @@ pub enum EventType {
     Paid = 2,
 }
 
+/// Kind of ticket, e.g. adults, children or students.
+/// Reservations refer to the ticket type by its index in `Event::tickets`.
+#[derive(Deserialize, Serialize, Clone, Debug, PartialEq)]
+pub struct TicketType {
+    pub name: String,
+    pub capacity: u64,
+    /// Most tickets of the type in one user's booking.
+    pub max_per_reservation: u64,
+    /// In cents, 0 for free tickets.
+    pub price: u64,
+}
+
 #[derive(Clone)]
 pub struct Event {
     pub id: u64,
     pub name: String,
     pub link: String,
-    pub max_adults: u64,
-    pub max_children: u64,
-    pub max_adults_per_reservation: u64,
-    pub max_children_per_reservation: u64,
     pub ts: u64,
     pub remind: u64,
-    pub adult_ticket_price: u64,
-    pub child_ticket_price: u64,
+    /// Empty for announcements.
+    pub tickets: Vec<TicketType>,
     pub currency: String,
+    /// Series the event is an occurrence of, 0 for standalone events.
+    pub series_id: u64,
+    /// Where the event takes place, 0 if not set.
+    pub venue_id: u64,
+    /// Seconds, 0 if the end is not known.
+    pub duration: u64,
+    /// Bookings of overlapping events are not a conflict, e.g. for all-day fairs.
+    pub allow_overlap: bool,
+    /// Category ids.
+    pub categories: Vec<u64>,
+    /// Telegram file id of the poster, empty if none. Set separately from other fields.
+    pub poster: String,
+    /// Registration doesn't start before this time, 0 if open as soon as the event is.
+    pub registration_opens: u64,
+    /// Registration stops at this time, 0 if it lasts until the start.
+    pub registration_closes: u64,
+    /// Asked after booking, answers are matched to them by position.
+    pub questions: Vec<String>,
+    /// IANA time zone the event is shown in, e.g. "Europe/Vienna". Empty for the default one.
+    pub time_zone: String,
+    /// Freed places go to the waiting list in the order of joining, instead of being offered
+    /// to whoever books first.
+    pub auto_promote: bool,
 }
 
 impl Event {
     pub fn get_type(&self) -> EventType {
         // todo: move to constructor
-        if self.adult_ticket_price != 0 || self.child_ticket_price != 0 {
+        if self.tickets.iter().any(|t| t.price != 0) {
             EventType::Paid
-        } else if self.max_adults != 0 || self.max_children != 0 {
+        } else if self.tickets.iter().any(|t| t.capacity != 0) {
             EventType::Free
         } else {
             EventType::Announcement
         }
     }
+
+    /// Total price of `tickets`, which are numbers of tickets by type.
+    pub fn price(&self, tickets: &[u64]) -> u64 {
+        self.tickets.iter().zip(tickets).map(|(t, n)| t.price * n).sum()
+    }
+
+    /// Whether `ts` is within the registration window, unset bounds don't limit it.
+    pub fn registration_open(&self, ts: u64) -> bool {
+        ts >= self.registration_opens && (self.registration_closes == 0 || ts < self.registration_closes)
+    }
+
+    pub fn end(&self) -> u64 {
+        self.ts + self.duration
+    }
 }
 
-#[derive(PartialEq)]
+/// Regularly repeated event. Occurrences are created as ordinary events some time ahead.
+#[derive(Clone)]
+pub struct Series {
+    pub id: u64,
+    /// Name, link, limits and prices of every occurrence. `ts` and `remind` are those of
+    /// the first occurrence, later ones keep the same reminder advance.
+    pub template: Event,
+    /// Days between occurrences. Starts keep their local time when daylight saving time
+    /// starts or ends.
+    pub period: u64,
+    /// No occurrences start after it.
+    pub until: u64,
+    /// Start of the next occurrence to create.
+    pub next_ts: u64,
+    pub cancelled: bool,
+}
+
+/// Tag for filtering the event list, e.g. "дети" or "онлайн".
+#[derive(Clone, Debug, PartialEq)]
+pub struct Category {
+    pub id: u64,
+    pub name: String,
+}
+
+/// Place where events take place, shown as a Telegram venue.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Venue {
+    pub id: u64,
+    pub name: String,
+    pub address: String,
+    pub latitude: f64,
+    pub longitude: f64,
+}
+
+/// Lifecycle of an event, stored in `events.state`.
+#[derive(FromPrimitive, ToPrimitive, PartialEq, Clone, Copy, Debug)]
 pub enum EventState {
-    Open,
-    Closed,
+    /// Published, registration is open.
+    Open = 0,
+    /// Published, registration is stopped.
+    Closed = 1,
+    /// Visible to admins only, until published.
+    Draft = 2,
+    /// Called off, reservation holders have been notified.
+    Cancelled = 3,
+    /// Took place and presence has been checked.
+    Completed = 4,
+}
+
+impl EventState {
+    /// Regular users see the event in the list and by direct link.
+    pub fn is_public(&self) -> bool {
+        *self != EventState::Draft
+    }
+
+    /// Cancelled and completed events are final, published ones don't go back to drafts.
+    pub fn can_change_to(&self, state: EventState) -> bool {
+        use EventState::*;
+        matches!(
+            (self, state),
+            (Draft, Open | Closed | Cancelled) | (Open | Closed, Open | Closed | Cancelled | Completed)
+        )
+    }
 }
 
 #[derive(Deserialize, Serialize, Clone, Debug)]
@@ -100,6 +221,7 @@ pub struct User {
     pub id: UserId,
     pub user_name1: String,
     pub user_name2: String,
+    pub language_code: Option<String>,
     pub is_admin: bool,
 }
 
@@ -107,7 +229,7 @@ impl User {
     pub fn new(u: &teloxide::types::User, admins: &HashSet<u64>) -> User {
         let mut user_name1 = u.first_name.clone();
         if let Some(v) = u.last_name.clone() {
-            user_name1.push_str(" ");
+            user_name1.push(' ');
             user_name1.push_str(&v);
         }
         let user_name2 = match u.username.clone() {
@@ -119,6 +241,7 @@ impl User {
             id: u.id,
             user_name1,
             user_name2: user_name2.clone(),
+            language_code: u.language_code.clone(),
             is_admin: admins.contains(&u.id.0),
         }
     }
@@ -128,9 +251,11 @@ pub struct Participant {
     pub user_id: u64,
     pub user_name1: String,
     pub user_name2: String,
-    pub adults: u64,
-    pub children: u64,
+    /// Numbers of tickets by type.
+    pub tickets: Vec<u64>,
     pub attachment: Option<String>,
+    /// Answers to the event questions, empty for unanswered ones.
+    pub answers: Vec<String>,
 }
 
 pub struct Presence {
@@ -139,6 +264,8 @@ pub struct Presence {
     pub user_name2: String,
     pub reserved: u64,
     pub attachment: Option<String>,
+    pub answers: Vec<String>,
+    pub present: bool,
 }
 
 pub struct MessageBatch {
@@ -152,27 +279,71 @@ pub struct MessageBatch {
     pub recipients: Vec<u64>,
 }
 
-#[derive(FromPrimitive, ToPrimitive, PartialEq)]
+#[derive(FromPrimitive, ToPrimitive, PartialEq, Clone, Copy)]
 pub enum MessageType {
     Direct = 0,
     Reminder = 1,
     WaitingListPrompt = 2,
+    /// Start time, link or name of the event changed.
+    EventChanged = 3,
+    EventCancelled = 4,
+    /// Goes to users subscribed to the opening of registration.
+    RegistrationOpened = 5,
+    /// Goes to users moved from the waiting list to the event.
+    Promoted = 6,
+}
+
+/// What an audit record is about.
+#[derive(FromPrimitive, ToPrimitive, PartialEq, Clone, Copy, Debug)]
+pub enum AuditAction {
+    SignUp = 0,
+    Cancel = 1,
+    DeleteReservation = 2,
+    ChangeEventState = 3,
+    SetEventLimits = 4,
+    Ban = 5,
+    DeleteEvent = 6,
+    EditSeries = 7,
+    CancelSeries = 8,
+    EditVenue = 9,
+    CompleteRefund = 10,
+    /// Moved from the waiting list when places freed up.
+    Promote = 11,
 }
 
 //#[derive(Clone)]
 pub struct Context {
     pub config: Configuration,
-    pub pool: DbPool,
-    pub sign_up_mutex: Arc<Mutex<u64>>,
+    pub storage: Box<dyn Storage>,
+    pub event_locks: EventLocks,
     pub admins: HashSet<u64>,
 }
 
+/// Per event locks serializing bookings within the process.
+/// Consistency across processes is guaranteed by db transactions.
+#[derive(Default)]
+pub struct EventLocks {
+    locks: std::sync::Mutex<HashMap<u64, Arc<Mutex<()>>>>,
+}
+
+impl EventLocks {
+    pub async fn lock(&self, event_id: u64) -> OwnedMutexGuard<()> {
+        let lock = {
+            let mut locks = self.locks.lock().unwrap();
+            // Forget locks nobody holds or waits for.
+            locks.retain(|_, lock| Arc::strong_count(lock) > 1);
+            locks.entry(event_id).or_default().clone()
+        };
+        lock.lock_owned().await
+    }
+}
+
 #[compact]
 #[derive(Serialize, Deserialize, Clone)]
 pub struct Booking {
     pub event_id: u64,
-    pub adults: u64,
-    pub children: u64,
+    /// Numbers of tickets by type.
+    pub tickets: Vec<u64>,
     pub user_id: u64,
 }
 
@@ -1,16 +1,33 @@
+use chrono::{Datelike, Duration, TimeZone};
 use std::time::{SystemTime, UNIX_EPOCH};
 
 pub fn get_unix_time() -> u64 {
     let t = SystemTime::now();
-    t.duration_since(UNIX_EPOCH).unwrap().as_secs() as u64
+    t.duration_since(UNIX_EPOCH).unwrap().as_secs()
 }
 
 pub fn get_seconds_before_midnight(ts: u64) -> u64 {
     86400 - ts % 86400
 }
 
+/// Start of the next Monday in the given time zone.
+pub fn get_end_of_week<Tz: TimeZone>(ts: u64, tz: &Tz) -> u64 {
+    let date = match tz.timestamp_opt(ts as i64, 0).earliest() {
+        Some(datetime) => datetime.date_naive(),
+        None => return ts + 7 * 86400,
+    };
+    let monday = date + Duration::days(7 - date.weekday().num_days_from_monday() as i64);
+    tz.from_local_datetime(&monday.and_hms_opt(0, 0, 0).unwrap_or_default())
+        .earliest()
+        .map_or(ts + 7 * 86400, |datetime| datetime.timestamp() as u64)
+}
+
 
 #[test]
 fn test_util() {
     assert_eq!(get_seconds_before_midnight(1651503600), 9 * 60 * 60);
+    // Monday 2022-05-02 17:00 in Vienna.
+    let vienna = chrono::FixedOffset::east_opt(2 * 60 * 60).unwrap();
+    assert_eq!(get_end_of_week(1651503600, &vienna), 1652047200);
+    assert_eq!(get_end_of_week(1652047200 - 1, &vienna), 1652047200);
 }